@@ -0,0 +1,101 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A CoreMark-style workload (matrix multiply, linked-list walk and CRC16)
+//! run with and without the decode cache and basic-block dispatch, and with
+//! the JIT if it's enabled.
+//!
+//! Run with `cargo bench [--features jit]`, optionally followed by
+//! `-- <iterations>`.
+
+extern crate risc_v_emulator;
+
+use std::env;
+use std::time::Instant;
+
+use risc_v_emulator::{CPU, RAM, StopReason};
+
+const ENTRY: u32 = 0x1000;
+
+// Hand-assembled RV32IM. Takes the iteration count in a0 and returns a
+// checksum in a0; uses 0x8000..0x9200 as scratch memory.
+const PROGRAM: &[u32] = &[
+    0x00050413, 0x00000493, 0x000082b7, 0x00000313, 0x10000393, 0x00131e13,
+    0x006e0e33, 0x001e0e13, 0x01c2a023, 0x05534e93, 0x41d2a023, 0x00428293,
+    0x00130313, 0xfe7340e3, 0x000092b7, 0x00000313, 0x04000393, 0x00828e13,
+    0x01c2a023, 0x02630eb3, 0x01d2a223, 0x00828293, 0x00130313, 0xfe7344e3,
+    0xfe02ac23, 0x00008f37, 0x00009fb7, 0x800f8f93, 0x01000e93, 0x00000593,
+    0x00000613, 0x00000693, 0x00000713, 0x00459293, 0x00d282b3, 0x00229293,
+    0x01e282b3, 0x0002a303, 0x00469393, 0x00c383b3, 0x00239393, 0x01e383b3,
+    0x4003ae03, 0x03c30333, 0x00670733, 0x00168693, 0xfdd6c6e3, 0x00459293,
+    0x00c282b3, 0x00229293, 0x01f282b3, 0x00e2a023, 0x00e484b3, 0x00160613,
+    0xfbd642e3, 0x00158593, 0xf9d5cce3, 0x000092b7, 0x00000313, 0x0042a383,
+    0x00730333, 0x0002a283, 0xfe029ae3, 0x0064c4b3, 0x01000293, 0x00048313,
+    0x0000a3b7, 0x00138393, 0x00010e37, 0xfffe0e13, 0x006e47b3, 0x0017f793,
+    0x001e5e13, 0x00078463, 0x007e4e33, 0x00135313, 0xfff28293, 0xfe0292e3,
+    0x01c484b3, 0xfff40413, 0xf20412e3, 0x00048513, 0x00008067,
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Uncached,
+    Cached,
+    Blocks,
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+fn run(iterations: u32, mode: Mode) -> (u32, u64, f64) {
+    let mut cpu = CPU::new(RAM::new(64 * 1024));
+    for (i, &word) in PROGRAM.iter().enumerate() {
+        cpu.bus.ram.set_u32(ENTRY + i as u32 * 4, word);
+    }
+    cpu.set_decode_cache_enabled(mode != Mode::Uncached);
+    cpu.set_block_dispatch_enabled(mode != Mode::Uncached && mode != Mode::Cached);
+    #[cfg(feature = "jit")]
+    cpu.set_jit_enabled(mode == Mode::Jit);
+    cpu.set_register(10, iterations as u64);
+
+    let start = Instant::now();
+    let result = match cpu.run(ENTRY) {
+        StopReason::Halted(result) => result,
+        reason => panic!("benchmark stopped early: {:?}", reason),
+    };
+    let elapsed = start.elapsed();
+
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    (result, cpu.cycles(), seconds)
+}
+
+fn main() {
+    let iterations = env::args()
+        .skip(1)
+        .filter_map(|arg| arg.parse().ok())
+        .next()
+        .unwrap_or(200);
+
+    let mut checksum = None;
+    let modes = [("uncached", Mode::Uncached),
+                 ("cached", Mode::Cached),
+                 ("blocks", Mode::Blocks),
+                 #[cfg(feature = "jit")]
+                 ("jit", Mode::Jit)];
+
+    for &(name, mode) in &modes {
+        let (result, instructions, seconds) = run(iterations, mode);
+        if let Some(expected) = checksum {
+            assert_eq!(result, expected, "checksum differs between runs");
+        }
+        checksum = Some(result);
+
+        println!("{:>10}: {} instructions in {:.3}s, {:.1} MIPS",
+                 name,
+                 instructions,
+                 seconds,
+                 instructions as f64 / seconds / 1e6);
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::mem;
+
+use ram::RAM;
+use snapshot::SnapshotError;
+use trap::Access;
+
+/// A memory-mapped device. Offsets are relative to the address the device was
+/// mapped at, and sizes are 1, 2 or 4 bytes. Returning `None` makes the access
+/// fault.
+pub trait Device {
+    fn read(&mut self, offset: u32, size: u32) -> Option<u32>;
+    fn write(&mut self, offset: u32, size: u32, value: u32) -> Option<()>;
+
+    /// The device's internal state, to go in a snapshot. Devices with no
+    /// state of their own can leave this alone.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restore state returned by `save_state`, or return `None` if it isn't
+    /// valid.
+    fn load_state(&mut self, state: &[u8]) -> Option<()> {
+        if state.is_empty() {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Whether the device's reads only ever depend on what the machine has
+    /// done, so that replaying a recording can leave the device to answer
+    /// them itself. Anything that talks to the host (a UART, a clock, a
+    /// disk) must leave this false, and then its accesses are logged while
+    /// recording and answered from the log when replaying.
+    fn is_deterministic(&self) -> bool {
+        false
+    }
+
+    /// Called after every write the device accepts, for devices that read or
+    /// write memory themselves in response (DMA). Returning an exit code
+    /// halts the machine once the storing instruction completes.
+    fn after_write(&mut self, _memory: &mut Memory) -> Option<u32> {
+        None
+    }
+}
+
+/// The RAM on a bus, as seen by a device that accesses it directly.
+pub struct Memory<'a> {
+    base: u32,
+    ram: &'a mut RAM,
+    // Where to log writes when recording.
+    log: Option<&'a mut Vec<DeviceEvent>>,
+}
+
+impl<'a> Memory<'a> {
+    pub fn read(&self, addr: u32, size: u32) -> Option<u32> {
+        let offset = addr.wrapping_sub(self.base);
+        if !self.ram.contains(offset, size) {
+            return None;
+        }
+        Some(match size {
+            1 => self.ram[offset] as u32,
+            2 => self.ram.get_u16(offset) as u32,
+            _ => self.ram.get_u32(offset),
+        })
+    }
+
+    /// How many bytes of RAM there are from `addr` to the end of it.
+    pub fn remaining(&self, addr: u32) -> u32 {
+        let offset = addr.wrapping_sub(self.base) as usize;
+        self.ram.len().saturating_sub(offset) as u32
+    }
+
+    pub fn write(&mut self, addr: u32, size: u32, value: u32) -> Option<()> {
+        let offset = addr.wrapping_sub(self.base);
+        if !self.ram.contains(offset, size) {
+            return None;
+        }
+        match size {
+            1 => self.ram[offset] = value as u8,
+            2 => self.ram.set_u16(offset, value as u16),
+            _ => self.ram.set_u32(offset, value),
+        }
+        if let Some(ref mut log) = self.log {
+            log.push(DeviceEvent {
+                addr,
+                size,
+                access: Access::Store,
+                value,
+                ok: true,
+                dma: true,
+                halt: false,
+            });
+        }
+        Some(())
+    }
+}
+
+// Splits a 64-bit device register at `offset` into the 32-bit half being
+// accessed.
+pub(crate) fn half(offset: u32) -> u32 {
+    (offset & 4) * 8
+}
+
+/// An access to a non-deterministic device, as logged by a recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceEvent {
+    pub addr: u32,
+    pub size: u32,
+    pub access: Access,
+    /// The value read or written.
+    pub value: u32,
+    /// Whether the device accepted the access, rather than faulting it.
+    pub ok: bool,
+    /// Whether this is a device writing to memory in response to the access
+    /// before it, rather than an access to the device.
+    pub dma: bool,
+    /// Whether this is the device asking to halt the machine in response to
+    /// the access before it, with `value` as the exit code.
+    pub halt: bool,
+}
+
+enum EventLog {
+    Off,
+    Record(Vec<DeviceEvent>),
+    Replay {
+        events: Vec<DeviceEvent>,
+        next: usize,
+        diverged: bool,
+    },
+}
+
+struct Mapping {
+    base: u32,
+    size: u32,
+    device: Box<dyn Device>,
+}
+
+/// The physical address space seen by the CPU: a single block of RAM plus any
+/// number of memory-mapped devices.
+pub struct Bus {
+    ram_base: u32,
+    pub ram: RAM,
+    devices: Vec<Mapping>,
+    // LR reservations as (hart ID, word address). Kept here rather than in
+    // each hart so that every hart sharing the bus sees the others' stores.
+    reservations: Vec<(u32, u32)>,
+    log: EventLog,
+    // An exit code from a device asking to halt.
+    halt: Option<u32>,
+}
+
+impl Bus {
+    /// A bus with `ram` mapped at address 0.
+    pub fn new(ram: RAM) -> Bus {
+        Bus::with_ram_base(0, ram)
+    }
+
+    pub fn with_ram_base(ram_base: u32, ram: RAM) -> Bus {
+        Bus {
+            ram_base,
+            ram,
+            devices: Vec::new(),
+            reservations: Vec::new(),
+            log: EventLog::Off,
+            halt: None,
+        }
+    }
+
+    pub fn ram_base(&self) -> u32 {
+        self.ram_base
+    }
+
+    /// Map `device` at the `size` bytes starting at `base`. Devices take
+    /// priority over RAM where they overlap.
+    pub fn map_device(&mut self, base: u32, size: u32, device: Box<dyn Device>) {
+        self.devices.push(Mapping {
+            base,
+            size,
+            device,
+        });
+    }
+
+    /// Whether `size` bytes at `addr` are backed by RAM (as opposed to a
+    /// device, or nothing at all).
+    pub fn is_ram(&self, addr: u32, size: u32) -> bool {
+        self.ram.contains(addr.wrapping_sub(self.ram_base), size)
+    }
+
+    /// Whether any device is mapped over part of the RAM.
+    #[cfg(feature = "jit")]
+    pub(crate) fn devices_shadow_ram(&self) -> bool {
+        let ram_end = self.ram_base as u64 + self.ram.len() as u64;
+        self.devices.iter().any(|m| {
+            (m.base as u64) < ram_end && (self.ram_base as u64) < m.base as u64 + m.size as u64
+        })
+    }
+
+    /// Reserve the doubleword around `addr` for `hart`, replacing any
+    /// reservation it already held (LR). Reservations cover doublewords so
+    /// that LR.D's is a single one.
+    pub(crate) fn reserve(&mut self, hart: u32, addr: u32) {
+        self.reservations.retain(|&(h, _)| h != hart);
+        self.reservations.push((hart, addr & !0b111));
+    }
+
+    /// Drop `hart`'s reservation, returning whether it was for the
+    /// doubleword around `addr` (SC).
+    pub(crate) fn take_reservation(&mut self, hart: u32, addr: u32) -> bool {
+        let held = self.reservations.contains(&(hart, addr & !0b111));
+        self.reservations.retain(|&(h, _)| h != hart);
+        held
+    }
+
+    pub(crate) fn has_reservations(&self) -> bool {
+        !self.reservations.is_empty()
+    }
+
+    /// Break other harts' reservations on the doublewords `hart` is storing
+    /// to.
+    pub(crate) fn break_reservations(&mut self, hart: u32, addr: u32, size: u32) {
+        let first = addr & !0b111;
+        let last = addr.wrapping_add(size - 1) & !0b111;
+        self.reservations.retain(|&(h, word)| h == hart || (word != first && word != last));
+    }
+
+    pub(crate) fn reservations(&self) -> &[(u32, u32)] {
+        &self.reservations
+    }
+
+    pub(crate) fn set_reservations(&mut self, reservations: Vec<(u32, u32)>) {
+        self.reservations = reservations;
+    }
+
+    pub(crate) fn save_device_states(&self) -> Vec<Vec<u8>> {
+        self.devices.iter().map(|m| m.device.save_state()).collect()
+    }
+
+    pub(crate) fn load_device_states(&mut self, states: &[&[u8]]) -> Result<(), SnapshotError> {
+        if states.len() != self.devices.len() {
+            return Err(SnapshotError::Mismatch("number of devices"));
+        }
+        for (mapping, state) in self.devices.iter_mut().zip(states) {
+            mapping.device
+                .load_state(state)
+                .ok_or(SnapshotError::Corrupt("device rejected its state"))?;
+        }
+        Ok(())
+    }
+
+    /// Start logging accesses to non-deterministic devices.
+    pub(crate) fn start_recording(&mut self) {
+        self.log = EventLog::Record(Vec::new());
+    }
+
+    /// Stop logging, returning everything logged since `start_recording`.
+    pub(crate) fn take_recording(&mut self) -> Vec<DeviceEvent> {
+        match mem::replace(&mut self.log, EventLog::Off) {
+            EventLog::Record(events) => events,
+            _ => Vec::new(),
+        }
+    }
+
+    pub(crate) fn recorded_events(&self) -> usize {
+        match self.log {
+            EventLog::Record(ref events) => events.len(),
+            _ => 0,
+        }
+    }
+
+    /// Answer accesses to non-deterministic devices from `events` instead of
+    /// the devices themselves.
+    pub(crate) fn start_replay(&mut self, events: Vec<DeviceEvent>) {
+        self.log = EventLog::Replay {
+            events,
+            next: 0,
+            diverged: false,
+        };
+    }
+
+    /// Carry on replaying from the `position`th event.
+    pub(crate) fn seek_replay(&mut self, position: usize) {
+        if let EventLog::Replay { ref mut next, ref mut diverged, .. } = self.log {
+            *next = position;
+            *diverged = false;
+        }
+    }
+
+    /// Whether an access has failed to match the next logged event since the
+    /// replay started or last moved.
+    pub(crate) fn replay_diverged(&self) -> bool {
+        match self.log {
+            EventLog::Replay { diverged, .. } => diverged,
+            _ => false,
+        }
+    }
+
+    /// The exit code a device asked to halt the machine with, if any, since
+    /// this was last called.
+    pub(crate) fn take_halt(&mut self) -> Option<u32> {
+        self.halt.take()
+    }
+
+    // Answer an access to a non-deterministic device from the replay log,
+    // along with any memory writes the device made in response. Returns
+    // `None` when not replaying, and the device should handle it.
+    fn replay_access(&mut self, addr: u32, size: u32, access: Access, value: u32)
+                     -> Option<Option<u32>> {
+        let (events, next, diverged) = match self.log {
+            EventLog::Off | EventLog::Record(_) => return None,
+            EventLog::Replay { ref events, ref mut next, ref mut diverged } => {
+                (events, next, diverged)
+            }
+        };
+
+        match events.get(*next) {
+            Some(event) if !*diverged && !event.dma && !event.halt && event.addr == addr &&
+                           event.size == size && event.access == access &&
+                           (access == Access::Load || event.value == value) => {
+                *next += 1;
+                let mut memory = Memory {
+                    base: self.ram_base,
+                    ram: &mut self.ram,
+                    log: None,
+                };
+                while let Some(after) = events.get(*next).filter(|event| event.dma || event.halt) {
+                    if after.halt {
+                        self.halt = Some(after.value);
+                    } else {
+                        memory.write(after.addr, after.size, after.value);
+                    }
+                    *next += 1;
+                }
+                Some(if event.ok { Some(event.value) } else { None })
+            }
+            _ => {
+                *diverged = true;
+                Some(None)
+            }
+        }
+    }
+
+    fn access_device(&mut self, addr: u32, size: u32, access: Access, value: u32)
+                     -> Option<Option<u32>> {
+        let index = self.devices.iter().position(|m| {
+            addr >= m.base && (addr - m.base) as u64 + size as u64 <= m.size as u64
+        })?;
+        let deterministic = self.devices[index].device.is_deterministic();
+        if !deterministic {
+            if let Some(result) = self.replay_access(addr, size, access, value) {
+                return Some(result);
+            }
+        }
+
+        let mapping = &mut self.devices[index];
+        let offset = addr - mapping.base;
+        let result = match access {
+            Access::Store => mapping.device.write(offset, size, value).map(|()| value),
+            _ => mapping.device.read(offset, size),
+        };
+
+        let mut log = match self.log {
+            EventLog::Record(ref mut events) if !deterministic => {
+                events.push(DeviceEvent {
+                    addr,
+                    size,
+                    access,
+                    value: result.unwrap_or(value),
+                    ok: result.is_some(),
+                    dma: false,
+                    halt: false,
+                });
+                Some(events)
+            }
+            _ => None,
+        };
+        if access == Access::Store && result.is_some() {
+            let mut memory = Memory {
+                base: self.ram_base,
+                ram: &mut self.ram,
+                log: log.take(),
+            };
+            if let Some(code) = mapping.device.after_write(&mut memory) {
+                if let Some(ref mut log) = memory.log {
+                    log.push(DeviceEvent {
+                        addr,
+                        size: 0,
+                        access,
+                        value: code,
+                        ok: true,
+                        dma: false,
+                        halt: true,
+                    });
+                }
+                self.halt = Some(code);
+            }
+        }
+        Some(result)
+    }
+
+    pub fn read(&mut self, addr: u32, size: u32) -> Option<u32> {
+        if let Some(result) = self.access_device(addr, size, Access::Load, 0) {
+            return result;
+        }
+
+        if !self.is_ram(addr, size) {
+            return None;
+        }
+
+        let offset = addr.wrapping_sub(self.ram_base);
+        Some(match size {
+            1 => self.ram[offset] as u32,
+            2 => self.ram.get_u16(offset) as u32,
+            _ => self.ram.get_u32(offset),
+        })
+    }
+
+    pub fn write(&mut self, addr: u32, size: u32, value: u32) -> Option<()> {
+        if let Some(result) = self.access_device(addr, size, Access::Store, value) {
+            return result.map(|_| ());
+        }
+
+        if !self.is_ram(addr, size) {
+            return None;
+        }
+
+        let offset = addr.wrapping_sub(self.ram_base);
+        match size {
+            1 => self.ram[offset] = value as u8,
+            2 => self.ram.set_u16(offset, value as u16),
+            _ => self.ram.set_u32(offset, value),
+        }
+        Some(())
+    }
+}
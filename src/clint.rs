@@ -0,0 +1,210 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use bus::{half, Device};
+use snapshot::{Reader, Writer};
+
+/// Where the CLINT usually lives, as on SiFive parts and QEMU's `virt`.
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x0001_0000;
+
+const MTIMECMP: u32 = 0x4000;
+const MTIME: u32 = 0xBFF8;
+
+struct State {
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+    mtime: u64,
+}
+
+/// The core-local interruptor: a software interrupt bit (`msip`) and a timer
+/// compare register (`mtimecmp`) per hart, and the shared `mtime` counter.
+///
+/// The CLINT only holds state. Cloning it gives another handle to the same
+/// registers, so one clone can be mapped on the bus while the `Scheduler`
+/// uses another to drive each hart's `mip` and advance `mtime`.
+#[derive(Clone)]
+pub struct Clint {
+    state: Rc<RefCell<State>>,
+}
+
+impl Clint {
+    /// A CLINT for `harts` harts, with every timer disarmed.
+    pub fn new(harts: usize) -> Clint {
+        Clint {
+            state: Rc::new(RefCell::new(State {
+                msip: vec![false; harts],
+                mtimecmp: vec![u64::MAX; harts],
+                mtime: 0,
+            })),
+        }
+    }
+
+    pub fn harts(&self) -> usize {
+        self.state.borrow().msip.len()
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.state.borrow().mtime
+    }
+
+    pub fn set_mtime(&self, mtime: u64) {
+        self.state.borrow_mut().mtime = mtime;
+    }
+
+    pub fn advance(&self, ticks: u64) {
+        let mut state = self.state.borrow_mut();
+        state.mtime = state.mtime.wrapping_add(ticks);
+    }
+
+    pub fn msip(&self, hart: usize) -> bool {
+        self.state.borrow().msip[hart]
+    }
+
+    pub fn mtimecmp(&self, hart: usize) -> u64 {
+        self.state.borrow().mtimecmp[hart]
+    }
+
+    /// Whether `hart`'s timer interrupt is pending.
+    pub fn timer_pending(&self, hart: usize) -> bool {
+        let state = self.state.borrow();
+        state.mtime >= state.mtimecmp[hart]
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u32, size: u32) -> Option<u32> {
+        if size != 4 || offset & 0b11 != 0 {
+            return None;
+        }
+
+        let state = self.state.borrow();
+        let harts = state.msip.len() as u32;
+        match offset {
+            _ if offset < 4 * harts => Some(state.msip[(offset / 4) as usize] as u32),
+            _ if offset >= MTIMECMP && offset < MTIMECMP + 8 * harts => {
+                let hart = ((offset - MTIMECMP) / 8) as usize;
+                Some((state.mtimecmp[hart] >> half(offset)) as u32)
+            }
+            MTIME | 0xBFFC => Some((state.mtime >> half(offset)) as u32),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: u32) -> Option<()> {
+        if size != 4 || offset & 0b11 != 0 {
+            return None;
+        }
+
+        let mut state = self.state.borrow_mut();
+        let harts = state.msip.len() as u32;
+        let merge = |old: u64| {
+            let shift = half(offset);
+            (old & !(0xFFFF_FFFF << shift)) | ((value as u64) << shift)
+        };
+        match offset {
+            _ if offset < 4 * harts => state.msip[(offset / 4) as usize] = value & 1 != 0,
+            _ if offset >= MTIMECMP && offset < MTIMECMP + 8 * harts => {
+                let hart = ((offset - MTIMECMP) / 8) as usize;
+                state.mtimecmp[hart] = merge(state.mtimecmp[hart]);
+            }
+            MTIME | 0xBFFC => state.mtime = merge(state.mtime),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let state = self.state.borrow();
+        let mut out = Writer::new();
+        for (&msip, &mtimecmp) in state.msip.iter().zip(&state.mtimecmp) {
+            out.u8(msip as u8);
+            out.u64(mtimecmp);
+        }
+        out.u64(state.mtime);
+        out.into_inner()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Option<()> {
+        let mut state = self.state.borrow_mut();
+        if data.len() != state.msip.len() * 9 + 8 {
+            return None;
+        }
+
+        let mut input = Reader::new(data);
+        for hart in 0..state.msip.len() {
+            state.msip[hart] = input.u8().ok()? != 0;
+            state.mtimecmp[hart] = input.u64().ok()?;
+        }
+        state.mtime = input.u64().ok()?;
+        Some(())
+    }
+
+    // mtime only moves as the harts retire instructions.
+    fn is_deterministic(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::Device;
+
+    #[test]
+    fn test_registers() {
+        let clint = Clint::new(2);
+        let mut device = clint.clone();
+
+        assert_eq!(device.write(4, 4, 3), Some(()));
+        assert!(!clint.msip(0));
+        assert!(clint.msip(1));
+        assert_eq!(device.read(4, 4), Some(1));
+
+        device.write(0x4008, 4, 0x1234).unwrap();
+        device.write(0x400C, 4, 0).unwrap();
+        assert_eq!(clint.mtimecmp(1), 0x1234);
+        assert_eq!(clint.mtimecmp(0), u64::MAX);
+
+        clint.set_mtime(0x1_0000_1233);
+        assert_eq!(device.read(0xBFF8, 4), Some(0x1233));
+        assert_eq!(device.read(0xBFFC, 4), Some(1));
+        assert!(clint.timer_pending(1));
+        device.write(0xBFFC, 4, 0).unwrap();
+        assert!(!clint.timer_pending(1));
+        clint.advance(1);
+        assert!(clint.timer_pending(1));
+
+        // Past the last hart, and misaligned or narrow accesses
+        assert_eq!(device.read(8, 4), None);
+        assert_eq!(device.read(0x4010, 4), None);
+        assert_eq!(device.read(0xBFF8, 2), None);
+        assert_eq!(device.write(2, 4, 0), None);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let clint = Clint::new(2);
+        let mut device = clint.clone();
+        device.write(4, 4, 1).unwrap();
+        device.write(0x4000, 4, 500).unwrap();
+        clint.set_mtime(123);
+
+        let state = device.save_state();
+        let mut restored = Clint::new(2);
+        restored.load_state(&state).expect("couldn't load state");
+        assert!(restored.msip(1));
+        assert_eq!(restored.mtimecmp(0) as u32, 500);
+        assert_eq!(restored.mtimecmp(1), u64::MAX);
+        assert_eq!(restored.mtime(), 123);
+
+        assert_eq!(Clint::new(3).load_state(&state), None);
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The hardware performance counters: `mcycle`, `minstret` and the
+//! programmable `mhpmcounter3`-`mhpmcounter31`, along with `mcountinhibit`
+//! and `mcounteren`.
+//!
+//! There's no timing model, so a cycle is an instruction and `mcycle` and
+//! `minstret` count the same thing. Both are kept as offsets from the
+//! number of instructions the hart has executed, which costs nothing to
+//! keep up to date. The programmable counters count `Event`s as they
+//! happen instead, and only while some counter is listening for them.
+
+use cpu::Privilege;
+use snapshot::{Reader, SnapshotError, Writer};
+
+/// `mhpmcounter3` to `mhpmcounter31`.
+pub const HPM_COUNTERS: usize = 29;
+
+// Sscofpmf's bits in `mhpmevent`: overflowed, and don't count in M-mode or
+// U-mode. There's no S-mode to inhibit.
+pub(crate) const MHPMEVENT_OF: u64 = 1 << 63;
+pub(crate) const MHPMEVENT_MINH: u64 = 1 << 62;
+pub(crate) const MHPMEVENT_UINH: u64 = 1 << 60;
+// The rest of `mhpmevent` selects the event.
+const SELECTOR: u64 = (1 << 56) - 1;
+
+// The counters in `mcountinhibit` and `mcounteren`, by bit.
+const CYCLE: u32 = 1 << 0;
+const INSTRET: u32 = 1 << 2;
+const HPM: u32 = !0b111;
+
+/// Something the programmable counters can count, numbered by the value
+/// that selects it in `mhpmevent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A read from memory. Doublewords count twice, and vector loads once
+    /// per element.
+    Load = 1,
+    /// A write to memory, counted like loads.
+    Store = 2,
+    /// A conditional branch that was taken.
+    BranchTaken = 3,
+    /// A conditional branch that went the other way from what a static
+    /// predictor would guess: backward branches taken, forward ones not.
+    BranchMispredicted = 4,
+    /// An exception that was taken, or stopped execution.
+    Exception = 5,
+    /// An interrupt that was taken.
+    Interrupt = 6,
+}
+
+impl Event {
+    const ALL: [Event; 6] = [Event::Load,
+                             Event::Store,
+                             Event::BranchTaken,
+                             Event::BranchMispredicted,
+                             Event::Exception,
+                             Event::Interrupt];
+
+    fn from_selector(selector: u64) -> Option<Event> {
+        Event::ALL.iter().cloned().find(|&event| event as u64 == selector)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Counters {
+    // `mcycle` and `minstret` while inhibited, and otherwise their
+    // difference from the number of instructions executed
+    cycle: u64,
+    instret: u64,
+    hpm: [u64; HPM_COUNTERS],
+    // The event each counter counts, with Sscofpmf's bits
+    events: [u64; HPM_COUNTERS],
+    inhibit: u32,
+    /// Which counters U-mode can read.
+    pub(crate) enable: u32,
+    // Bit n is set if a counter that isn't inhibited counts event n.
+    counted: u32,
+}
+
+impl Counters {
+    pub(crate) fn new() -> Counters {
+        Counters {
+            cycle: 0,
+            instret: 0,
+            hpm: [0; HPM_COUNTERS],
+            events: [0; HPM_COUNTERS],
+            inhibit: 0,
+            enable: 0,
+            counted: 0,
+        }
+    }
+
+    /// `mcycle`, given the number of instructions executed so far.
+    pub(crate) fn cycle(&self, executed: u64) -> u64 {
+        if self.inhibit & CYCLE != 0 {
+            self.cycle
+        } else {
+            executed.wrapping_add(self.cycle)
+        }
+    }
+
+    pub(crate) fn set_cycle(&mut self, executed: u64, value: u64) {
+        self.cycle = if self.inhibit & CYCLE != 0 {
+            value
+        } else {
+            value.wrapping_sub(executed)
+        };
+    }
+
+    pub(crate) fn instret(&self, executed: u64) -> u64 {
+        if self.inhibit & INSTRET != 0 {
+            self.instret
+        } else {
+            executed.wrapping_add(self.instret)
+        }
+    }
+
+    pub(crate) fn set_instret(&mut self, executed: u64, value: u64) {
+        self.instret = if self.inhibit & INSTRET != 0 {
+            value
+        } else {
+            value.wrapping_sub(executed)
+        };
+    }
+
+    /// `mhpmcounter<n>`, for n from 3 to 31.
+    pub(crate) fn hpm(&self, n: usize) -> u64 {
+        self.hpm[n - 3]
+    }
+
+    pub(crate) fn set_hpm(&mut self, n: usize, value: u64) {
+        self.hpm[n - 3] = value;
+    }
+
+    pub(crate) fn event(&self, n: usize) -> u64 {
+        self.events[n - 3]
+    }
+
+    /// Select what `mhpmcounter<n>` counts. Unknown events count nothing,
+    /// and read back as 0. `filters` are the Sscofpmf bits that can be set.
+    pub(crate) fn set_event(&mut self, n: usize, value: u64, filters: u64) {
+        let event = Event::from_selector(value & SELECTOR).map_or(0, |event| event as u64);
+        self.events[n - 3] = (value & filters) | event;
+        self.update_counted();
+    }
+
+    pub(crate) fn inhibit(&self) -> u32 {
+        self.inhibit
+    }
+
+    /// Write `mcountinhibit`, which has no bit for `time`.
+    pub(crate) fn set_inhibit(&mut self, executed: u64, value: u32) {
+        let (cycle, instret) = (self.cycle(executed), self.instret(executed));
+        self.inhibit = value & (CYCLE | INSTRET | HPM);
+        self.set_cycle(executed, cycle);
+        self.set_instret(executed, instret);
+        self.update_counted();
+    }
+
+    /// Whether any counter is counting events, which only the interpreter
+    /// notices.
+    #[cfg(feature = "jit")]
+    pub(crate) fn counting(&self) -> bool {
+        self.counted != 0
+    }
+
+    /// Count `event`, which happened at `privilege`, in every counter
+    /// listening for it. Returns the counters that wrapped around to zero,
+    /// by their bit in `mcountinhibit`.
+    #[inline]
+    pub(crate) fn count(&mut self, event: Event, privilege: Privilege) -> u32 {
+        if self.counted & (1 << event as u32) == 0 {
+            return 0;
+        }
+
+        let inhibit = match privilege {
+            Privilege::Machine => MHPMEVENT_MINH,
+            Privilege::User => MHPMEVENT_UINH,
+        };
+        let mut wrapped = 0;
+        for i in 0..HPM_COUNTERS {
+            let selected = self.events[i];
+            if self.inhibit & (1 << (i + 3)) != 0 || selected & SELECTOR != event as u64 ||
+               selected & inhibit != 0 {
+                continue;
+            }
+            self.hpm[i] = self.hpm[i].wrapping_add(1);
+            if self.hpm[i] == 0 {
+                wrapped |= 1 << (i + 3);
+            }
+        }
+        wrapped
+    }
+
+    /// Set the overflow bit of the `wrapped` counters. Returns whether any
+    /// of them didn't have it set already, which raises an interrupt.
+    pub(crate) fn overflow(&mut self, wrapped: u32) -> bool {
+        let mut raised = false;
+        for i in 0..HPM_COUNTERS {
+            if wrapped & (1 << (i + 3)) != 0 && self.events[i] & MHPMEVENT_OF == 0 {
+                self.events[i] |= MHPMEVENT_OF;
+                raised = true;
+            }
+        }
+        raised
+    }
+
+    fn update_counted(&mut self) {
+        self.counted = 0;
+        for i in 0..HPM_COUNTERS {
+            if self.inhibit & (1 << (i + 3)) == 0 {
+                self.counted |= 1 << (self.events[i] & SELECTOR);
+            }
+        }
+        // Event 0 is no event at all.
+        self.counted &= !1;
+    }
+
+    pub(crate) fn save(&self, out: &mut Writer) {
+        out.u64(self.cycle);
+        out.u64(self.instret);
+        for (&counter, &event) in self.hpm.iter().zip(self.events.iter()) {
+            out.u64(counter);
+            out.u64(event);
+        }
+        out.u32(self.inhibit);
+        out.u32(self.enable);
+    }
+
+    pub(crate) fn load(input: &mut Reader) -> Result<Counters, SnapshotError> {
+        let mut counters = Counters::new();
+        counters.cycle = input.u64()?;
+        counters.instret = input.u64()?;
+        for i in 0..HPM_COUNTERS {
+            counters.hpm[i] = input.u64()?;
+            counters.events[i] = input.u64()?;
+        }
+        counters.inhibit = input.u32()? & (CYCLE | INSTRET | HPM);
+        counters.enable = input.u32()?;
+        counters.update_counted();
+        Ok(counters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inhibit() {
+        let mut counters = Counters::new();
+        counters.set_cycle(10, 100);
+        assert_eq!((counters.cycle(15), counters.instret(15)), (105, 15));
+
+        // Inhibited counters hold their value, and pick up from there.
+        counters.set_inhibit(20, CYCLE);
+        assert_eq!((counters.cycle(30), counters.instret(30)), (110, 30));
+        counters.set_inhibit(30, 0);
+        assert_eq!(counters.cycle(35), 115);
+    }
+
+    #[test]
+    fn test_events() {
+        let mut counters = Counters::new();
+        counters.set_event(3, Event::Load as u64, 0);
+        counters.set_event(4, Event::Load as u64 | MHPMEVENT_MINH, MHPMEVENT_MINH);
+        counters.set_event(5, 0x1234, 0);
+        assert_eq!(counters.event(5), 0);
+
+        counters.set_hpm(3, u64::MAX);
+        assert_eq!(counters.count(Event::Load, Privilege::Machine), 1 << 3);
+        assert_eq!(counters.count(Event::Store, Privilege::Machine), 0);
+        assert_eq!(counters.count(Event::Load, Privilege::User), 0);
+        assert_eq!((counters.hpm(3), counters.hpm(4)), (1, 1));
+
+        assert!(counters.overflow(1 << 3));
+        assert!(!counters.overflow(1 << 3));
+        assert_eq!(counters.event(3), MHPMEVENT_OF | Event::Load as u64);
+
+        counters.set_inhibit(0, 0b11000);
+        counters.count(Event::Load, Privilege::User);
+        assert_eq!((counters.hpm(3), counters.hpm(4)), (1, 1));
+    }
+}
@@ -5,93 +5,2388 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::collections::HashSet;
 use std::fmt;
+use std::io::{self, Read, Write};
+#[cfg(feature = "jit")]
+use std::mem;
 
-use instruction;
+use bus::Bus;
+use clint::Clint;
+use counters::{self, Counters, Event};
+use debug::{Action, DebugCause, DebugState};
+use decode_cache::{self, DecodeCache};
+use hooks::{CsrAccess, HookAction, Hooks, MemoryAccess, TrapEvent};
+use instruction::{self, Decoded, Instruction};
+use isa::{Extension, Isa};
+#[cfg(feature = "jit")]
+use jit::{self, Jit, Native};
 use ram::RAM;
+use snapshot::{self, Reader, SnapshotError, Writer};
+use trap::{Access, Exception, Interrupt, StopReason};
+use vector::VectorState;
+use float::FloatState;
+
+const MSTATUS_MIE: u64 = 1 << 3;
+const MSTATUS_MPIE: u64 = 1 << 7;
+const MSTATUS_MPP_SHIFT: u32 = 11;
+const MSTATUS_MPP: u64 = 0b11 << MSTATUS_MPP_SHIFT;
+// The vector unit's state: Off, Initial, Clean or Dirty.
+const MSTATUS_VS_SHIFT: u32 = 9;
+const MSTATUS_VS: u64 = 0b11 << MSTATUS_VS_SHIFT;
+const VS_INITIAL: u64 = 1 << MSTATUS_VS_SHIFT;
+const VS_DIRTY: u64 = 3 << MSTATUS_VS_SHIFT;
+// The same for the floating point unit.
+const MSTATUS_FS_SHIFT: u32 = 13;
+const MSTATUS_FS: u64 = 0b11 << MSTATUS_FS_SHIFT;
+const FS_INITIAL: u64 = 1 << MSTATUS_FS_SHIFT;
+const FS_DIRTY: u64 = 3 << MSTATUS_FS_SHIFT;
+
+// Whether U-mode may run CBO.INVAL (as a flush if only the low bit is set),
+// CBO.CLEAN and CBO.FLUSH, and CBO.ZERO.
+pub(crate) const MENVCFG_CBIE: u64 = 0b11 << 4;
+pub(crate) const MENVCFG_CBCFE: u64 = 1 << 6;
+pub(crate) const MENVCFG_CBZE: u64 = 1 << 7;
+// U-mode XLEN on RV64, which is always 64.
+const MSTATUS_UXL_64: u64 = 2 << 32;
+
+const MIP_MSIP: u64 = 1 << Interrupt::Software as u32;
+const MIP_MTIP: u64 = 1 << Interrupt::Timer as u32;
+const MIP_MEIP: u64 = 1 << Interrupt::External as u32;
+const MIP_LCOFIP: u64 = 1 << Interrupt::CounterOverflow as u32;
 
 pub struct CPU {
-    regs: [u32; 32],
+    // On RV32 the upper halves are always zero.
+    regs: [u64; 32],
     csr: CSRs,
     pub pc: u32,
-    pub ram: RAM,
+    // Where execution continues once the current instruction completes.
+    next_pc: u32,
+    pub bus: Bus,
+    hart_id: u32,
+    // Where `time` comes from, if not the instruction count
+    clint: Option<Clint>,
+    privilege: Privilege,
+    isa: Isa,
+    float: FloatState,
+    vector: VectorState,
+    vector_agnostic_ones: bool,
+    cache_block_size: u32,
+    /// Print every executed instruction along with the register file.
+    pub trace: bool,
+    halt_conditions: Vec<HaltCondition>,
+    breakpoints: HashSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    // Set when we've just stopped on a breakpoint at this address, so that
+    // resuming executes the instruction instead of stopping again.
+    resume_breakpoint: Option<u32>,
+    // Set by instructions that want `run_for` to return after they complete.
+    pending_stop: Option<StopReason>,
+    // Boxed and optional so that a CPU without hooks only pays for a null
+    // check.
+    hooks: Option<Box<Hooks>>,
+    decode_cache: DecodeCache,
+    decode_cache_enabled: bool,
+    block_dispatch_enabled: bool,
+    #[cfg(feature = "jit")]
+    jit: Jit,
+    #[cfg(feature = "jit")]
+    jit_enabled: bool,
+    #[cfg(feature = "jit")]
+    jit_differential: bool,
 }
 
+#[derive(Clone)]
 struct CSRs {
+    // Instructions executed, which the counters are based on
     cycles: u64,
-    mepc: u32,
+    // The enabled single-letter extensions, within those the ISA has.
+    misa: u64,
+    mstatus: u64,
+    mie: u64,
+    mip: u64,
+    mtvec: u64,
+    mscratch: u64,
+    mepc: u64,
+    mcause: u64,
+    mtval: u64,
+    menvcfg: u64,
+    // Zcmt's jump table base, with mode 0 (jump table) in the low bits
+    jvt: u64,
+    counters: Counters,
+    // Debug mode and the triggers
+    debug: DebugState,
+}
+
+/// A hart's architectural state, parsed from a snapshot.
+pub(crate) struct SavedState {
+    pub(crate) xlen: u32,
+    regs: [u64; 32],
+    csr: CSRs,
+    float: FloatState,
+    vector: VectorState,
+    pc: u32,
+    hart_id: u32,
+    privilege: Privilege,
+}
+
+impl SavedState {
+    pub(crate) fn vlen(&self) -> u32 {
+        self.vector.vlen()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    User = 0,
+    Machine = 3,
+}
+
+/// A condition under which `run_for` stops with `StopReason::Halted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltCondition {
+    /// The pc reached the given address. `run` starts programs with a return
+    /// address of 0, so `PcEquals(0)` halts when the entry point returns.
+    PcEquals(u32),
+    /// An ECALL was made with the Linux `exit` system call number (93) in a7.
+    ExitSyscall,
+}
+
+struct Watchpoint {
+    addr: u32,
+    size: u32,
+    access: Access,
 }
 
 impl CPU {
+    /// A CPU with `ram` mapped at address 0.
     pub fn new(ram: RAM) -> CPU {
+        CPU::with_bus(Bus::new(ram))
+    }
+
+    pub fn with_bus(bus: Bus) -> CPU {
         let mut regs = [0; 32];
         regs[2] = 1020 * 1024; // Stack Pointer
+        let isa = Isa::default();
 
         CPU {
-            regs: regs,
+            regs,
             csr: CSRs {
                 cycles: 0,
+                misa: isa.misa(),
+                mstatus: initial_status(isa),
+                mie: 0,
+                mip: 0,
+                mtvec: 0,
+                mscratch: 0,
                 mepc: 0,
+                mcause: 0,
+                mtval: 0,
+                menvcfg: 0,
+                jvt: 0,
+                counters: Counters::new(),
+                debug: DebugState::new(),
             },
             pc: 0,
-            ram: ram,
+            next_pc: 0,
+            bus,
+            hart_id: 0,
+            clint: None,
+            privilege: Privilege::Machine,
+            isa,
+            float: FloatState::new(),
+            vector: VectorState::new(isa.vlen(), isa.xlen()),
+            vector_agnostic_ones: false,
+            cache_block_size: 64,
+            trace: false,
+            halt_conditions: vec![HaltCondition::PcEquals(0)],
+            breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+            resume_breakpoint: None,
+            pending_stop: None,
+            hooks: None,
+            decode_cache: DecodeCache::new(),
+            decode_cache_enabled: true,
+            block_dispatch_enabled: true,
+            #[cfg(feature = "jit")]
+            jit: Jit::new(),
+            #[cfg(feature = "jit")]
+            jit_enabled: true,
+            #[cfg(feature = "jit")]
+            jit_differential: false,
         }
     }
 
-    pub fn run(&mut self, entry_point: u32) {
+    /// Start executing at `entry_point` with a return address of 0, and keep
+    /// going until something stops execution.
+    pub fn run(&mut self, entry_point: u32) -> StopReason {
         self.set_register(1, 0); // Return address
         self.pc = entry_point;
 
-        while let Some(instr) = self.get_instruction() {
-            instr.execute(self);
-            println!("{:05X} {:?} {:08x}", self.pc, self, instr.to_raw());
-            self.pc = self.pc.wrapping_add(4);
+        loop {
+            match self.run_for(u64::MAX) {
+                StopReason::InstructionLimit => continue,
+                reason => return reason,
+            }
+        }
+    }
+
+    /// Execute a single instruction. Returns `StopReason::InstructionLimit` if
+    /// nothing else stopped execution.
+    pub fn step(&mut self) -> StopReason {
+        self.run_for(1)
+    }
+
+    /// Execute at most `instructions` instructions, stopping early on a
+    /// breakpoint, watchpoint, halting condition, WFI, untaken exception or
+    /// entry to debug mode.
+    pub fn run_for(&mut self, instructions: u64) -> StopReason {
+        let mut remaining = instructions;
+        while remaining > 0 {
+            self.update_clint_interrupts();
+            let debug = &self.csr.debug;
+            if debug.halt_request && !debug.mode {
+                let pc = self.pc;
+                return self.enter_debug_mode(DebugCause::HaltRequest, pc);
+            }
+            let stepping = debug.stepping();
+            if self.csr.mip & self.csr.mie != 0 && !debug.mode &&
+               (!stepping || debug.step_interrupts()) {
+                let pc = self.pc;
+                if let Some(reason) = self.take_interrupt() {
+                    return reason;
+                }
+                // A step into an interrupt handler stops before its first
+                // instruction.
+                if stepping && self.pc != pc {
+                    let pc = self.pc;
+                    return self.enter_debug_mode(DebugCause::Step, pc);
+                }
+            }
+
+            if self.breakpoints.contains(&self.pc) &&
+               self.resume_breakpoint.take() != Some(self.pc) {
+                self.resume_breakpoint = Some(self.pc);
+                return StopReason::Breakpoint;
+            }
+            self.resume_breakpoint = None;
+
+            let (executed, stop) = match self.execute_blocks(remaining) {
+                Some(result) => result,
+                None => (1, self.execute_instruction()),
+            };
+            remaining -= executed;
+            if let Some(reason) = stop {
+                return reason;
+            }
+            if stepping && !self.csr.debug.mode {
+                let pc = self.pc;
+                return self.enter_debug_mode(DebugCause::Step, pc);
+            }
+        }
+
+        StopReason::InstructionLimit
+    }
+
+    fn execute_instruction(&mut self) -> Option<StopReason> {
+        let pc = self.pc;
+        let privilege = self.privilege;
+        let result = self.decode(pc).and_then(|mut instr| {
+            // An override runs in place of the instruction in memory, which
+            // is what the pc moves past.
+            let size = instr.size();
+            if self.csr.debug.armed() {
+                let xlen = self.xlen();
+                self.csr.debug.check_execute(pc, instr.to_raw(), size, privilege, xlen)?;
+            }
+            if self.hooks.is_some() {
+                match self.with_hooks(|hooks, cpu| hooks.before_instruction(cpu, pc, &instr)) {
+                    HookAction::Stop => return Ok(false),
+                    HookAction::Override(raw) => {
+                        instr = self.parse(raw as u32)?;
+                    }
+                    HookAction::Continue => {}
+                }
+            }
+
+            self.next_pc = pc.wrapping_add(size);
+            instr.execute(self)?;
+            if self.trace {
+                println!("{:05X} {:?} {:08x}", self.pc, self, instr.to_raw());
+            }
+
+            if self.hooks.is_some() &&
+               self.with_hooks(|hooks, cpu| hooks.after_instruction(cpu, pc, &instr)) ==
+               HookAction::Stop {
+                self.pending_stop = Some(StopReason::Hook);
+            }
+
+            Ok(true)
+        });
+
+        match result {
+            Ok(true) => {}
+            Ok(false) => return Some(StopReason::Hook),
+            Err(exception) => {
+                self.pending_stop = None;
+                return self.take_trap(exception, pc);
+            }
+        }
+
+        self.pc = self.next_pc;
+        self.csr.cycles = self.csr.cycles.wrapping_add(1);
+        // An instruction count runs out once the instruction has retired.
+        if self.csr.debug.armed() && self.csr.debug.count_instruction(privilege) {
+            let pc = self.pc;
+            if let Some(reason) = self.take_trap(Exception::Breakpoint(pc as u64), pc) {
+                return Some(reason);
+            }
+        }
+        self.check_stop()
+    }
+
+    /// Run whole basic blocks, following chains from one to the next, for as
+    /// long as they fit in `budget` instructions and nothing needs looking at
+    /// between instructions. Returns how many instructions were attempted and
+    /// why execution stopped, or `None` if no block could be run at the
+    /// current pc and the caller should fall back to single-stepping.
+    fn execute_blocks(&mut self, budget: u64) -> Option<(u64, Option<StopReason>)> {
+        if !self.decode_cache_enabled || !self.block_dispatch_enabled || self.trace ||
+           self.hooks.is_some() || !self.watchpoints.is_empty() ||
+           self.csr.debug.single_step() {
+            return None;
+        }
+
+        #[cfg(feature = "jit")]
+        {
+            if self.jit.is_full() {
+                self.decode_cache.flush_blocks();
+                self.jit.reset();
+            }
+        }
+
+        let mut executed = 0;
+        let mut previous: Option<usize> = None;
+        loop {
+            let pc = self.pc;
+            if executed > 0 {
+                self.update_clint_interrupts();
+                if self.breakpoints.contains(&pc) || self.csr.mip & self.csr.mie != 0 ||
+                   self.csr.debug.single_step() {
+                    break;
+                }
+            }
+
+            let id = match previous.and_then(|from| self.decode_cache.chained(from, pc)) {
+                Some(id) => id,
+                None => {
+                    let generation = self.decode_cache.generation();
+                    let id = match self.find_or_build_block(pc) {
+                        Some(id) => id,
+                        None => break,
+                    };
+                    if let Some(from) = previous {
+                        if self.decode_cache.generation() == generation {
+                            self.decode_cache.chain(from, pc, id);
+                        }
+                    }
+                    id
+                }
+            };
+
+            if self.decode_cache.block(id).instrs.len() as u64 > budget - executed {
+                break;
+            }
+
+            let generation = self.decode_cache.generation();
+            let (count, stop) = self.execute_block(id);
+            executed += count;
+            if stop.is_some() {
+                return Some((executed, stop));
+            }
+
+            previous = if self.decode_cache.generation() == generation {
+                Some(id)
+            } else {
+                None
+            };
+        }
+
+        if executed == 0 {
+            None
+        } else {
+            Some((executed, None))
+        }
+    }
+
+    fn execute_block(&mut self, id: usize) -> (u64, Option<StopReason>) {
+        #[cfg(feature = "jit")]
+        {
+            if let Some(result) = self.execute_native(id) {
+                return result;
+            }
+        }
+
+        self.interpret_block(id, 0)
+    }
+
+    /// Interpret block `id` from its `first` instruction on, which the pc
+    /// must point at.
+    fn interpret_block(&mut self, id: usize, first: usize) -> (u64, Option<StopReason>) {
+        let generation = self.decode_cache.generation();
+        let len = self.decode_cache.block(id).instrs.len();
+
+        for i in first..len {
+            let instr = self.decode_cache.block(id).instrs[i];
+            let pc = self.pc;
+            self.next_pc = pc.wrapping_add(instr.size());
+            if let Err(exception) = instr.execute(self) {
+                self.pending_stop = None;
+                return ((i - first) as u64 + 1, self.take_trap(exception, pc));
+            }
+            self.pc = self.next_pc;
             self.csr.cycles = self.csr.cycles.wrapping_add(1);
-            if self.pc == 0 {
+
+            // A store rewrote this block (or FENCE.I threw it away), so the
+            // rest of it may be stale, or a device asked to halt.
+            if self.decode_cache.generation() != generation || self.pending_stop.is_some() {
+                return ((i - first) as u64 + 1, self.check_stop());
+            }
+        }
+
+        ((len - first) as u64, self.check_stop())
+    }
+
+    /// Run block `id` as native code, compiling it first if it's hot enough.
+    /// Returns `None` if the block should be interpreted instead.
+    #[cfg(feature = "jit")]
+    fn execute_native(&mut self, id: usize) -> Option<(u64, Option<StopReason>)> {
+        // Compiled stores don't break LR reservations or count events, and
+        // compiled code only knows about 32-bit registers.
+        if !self.jit_enabled || self.bus.devices_shadow_ram() || self.bus.has_reservations() ||
+           self.xlen() != 32 || self.csr.counters.counting() {
+            return None;
+        }
+
+        let code = match self.decode_cache.block(id).native {
+            Native::Compiled(code) => code,
+            Native::Failed => return None,
+            Native::Cold(heat) if heat < jit::HOT_THRESHOLD => {
+                self.decode_cache.block_mut(id).native = Native::Cold(heat + 1);
+                return None;
+            }
+            Native::Cold(_) => {
+                let native = {
+                    let block = self.decode_cache.block(id);
+                    self.jit.compile(block.start, &block.instrs)
+                };
+                self.decode_cache.block_mut(id).native = native;
+                match native {
+                    Native::Compiled(code) => code,
+                    _ => return None,
+                }
+            }
+        };
+
+        let start = self.pc;
+        let before = if self.jit_differential {
+            Some((self.regs, self.bus.ram.clone()))
+        } else {
+            None
+        };
+
+        let mut context = jit::Context {
+            ram: self.bus.ram.as_mut_ptr(),
+            ram_len: self.bus.ram.len() as u64,
+            code_pages: self.decode_cache.code_pages(),
+            ram_base: self.bus.ram_base(),
+            pc: 0,
+            retired: 0,
+        };
+        // The block was compiled for exactly this RAM layout, and only
+        // touches `regs` and the RAM described by `context`.
+        let exit = unsafe { code(self.regs.as_mut_ptr(), &mut context) };
+
+        let retired = context.retired;
+        let next_pc = if exit == jit::EXIT_DONE {
+            context.pc
+        } else {
+            start.wrapping_add(4 * retired)
+        };
+        if let Some((regs, ram)) = before {
+            self.check_native(id, regs, ram, retired, next_pc);
+        }
+
+        self.pc = next_pc;
+        self.csr.cycles = self.csr.cycles.wrapping_add(retired as u64);
+        if exit == jit::EXIT_DONE {
+            return Some((retired as u64, self.check_stop()));
+        }
+
+        let (count, stop) = self.interpret_block(id, retired as usize);
+        Some((retired as u64 + count, stop))
+    }
+
+    /// Re-run the first `retired` instructions of block `id` in the
+    /// interpreter, starting from the `regs` and `ram` the native code
+    /// started with, and panic if they end up anywhere different.
+    #[cfg(feature = "jit")]
+    fn check_native(&mut self, id: usize, regs: [u64; 32], ram: RAM, retired: u32, next_pc: u32) {
+        let start = self.pc;
+        let native_regs = mem::replace(&mut self.regs, regs);
+        let native_ram = mem::replace(&mut self.bus.ram, ram);
+
+        for i in 0..retired as usize {
+            let instr = self.decode_cache.block(id).instrs[i];
+            self.next_pc = self.pc.wrapping_add(instr.size());
+            if let Err(exception) = instr.execute(self) {
+                panic!("JIT retired {:?} at {:08x}, but the interpreter raised {:?}",
+                       instr,
+                       self.pc,
+                       exception);
+            }
+            self.pc = self.next_pc;
+        }
+
+        if self.regs != native_regs || self.pc != next_pc || self.bus.ram != native_ram {
+            let differing: Vec<_> = (0..32)
+                .filter(|&r| self.regs[r] != native_regs[r])
+                .map(|r| (r, self.regs[r], native_regs[r]))
+                .collect();
+            panic!("JIT and interpreter disagree after block at {:08x}: \
+                    pc {:08x} vs {:08x}, (register, interpreter, JIT): {:?}, \
+                    memory {}",
+                   start,
+                   self.pc,
+                   next_pc,
+                   differing,
+                   if self.bus.ram == native_ram { "matches" } else { "differs" });
+        }
+        self.pc = start;
+    }
+
+    /// The block starting at `pc`, forming it if it hasn't been seen before.
+    /// Blocks end at instructions that end blocks, page boundaries, anything
+    /// that isn't RAM or doesn't decode, and just before breakpoints and
+    /// halting addresses so that those are only ever at the start of a block.
+    /// An instruction that straddles two pages is left to single-stepping.
+    fn find_or_build_block(&mut self, pc: u32) -> Option<usize> {
+        if let Some(id) = self.decode_cache.find_block(pc) {
+            return Some(id);
+        }
+
+        let mut instrs = Vec::new();
+        let mut addr = pc;
+        loop {
+            if !self.bus.is_ram(addr, 2) {
+                break;
+            }
+            if addr != pc &&
+               (self.breakpoints.contains(&addr) ||
+                self.halt_conditions.contains(&HaltCondition::PcEquals(addr))) {
+                break;
+            }
+
+            let instr = match self.decode(addr) {
+                Ok(instr) => instr,
+                Err(_) => break,
+            };
+            if !self.bus.is_ram(addr, instr.size()) || (addr & 0xFFF) + instr.size() > 0x1000 {
                 break;
             }
+            instrs.push(instr);
+            addr = addr.wrapping_add(instr.size());
+
+            if instr.ends_block() || instrs.len() == decode_cache::MAX_BLOCK_LEN ||
+               addr & 0xFFF == 0 {
+                break;
+            }
+        }
+
+        if instrs.is_empty() {
+            return None;
         }
+
+        Some(self.decode_cache.insert_block(pc, instrs))
     }
 
-    fn get_instruction(&self) -> Option<Box<instruction::Instruction>> {
-        instruction::parse(self.ram.get_u32(self.pc))
+    // Checked after every instruction, or at the end of every block.
+    fn check_stop(&mut self) -> Option<StopReason> {
+        if let Some(reason) = self.pending_stop.take() {
+            return Some(reason);
+        }
+
+        for condition in &self.halt_conditions {
+            if let HaltCondition::PcEquals(addr) = *condition {
+                if self.pc == addr {
+                    return Some(StopReason::Halted(self.regs[10] as u32));
+                }
+            }
+        }
+
+        None
     }
 
-    pub fn get_register(&self, reg: u8) -> u32 {
-        self.regs[reg as usize]
+    fn decode(&mut self, pc: u32) -> Result<Decoded, Exception> {
+        if self.decode_cache_enabled {
+            if let Some(instr) = self.decode_cache.get(pc) {
+                return Ok(instr);
+            }
+        }
+
+        // With compressed instructions, 32-bit ones are fetched a half at a
+        // time, since the second half may be on a page that isn't there.
+        let fetch = |cpu: &mut CPU, addr: u32| {
+            cpu.bus.read(addr, 2).ok_or(Exception::InstructionAccessFault(addr as u64))
+        };
+        let raw = if !self.extension_enabled(Extension::C) {
+            self.bus.read(pc, 4).ok_or(Exception::InstructionAccessFault(pc as u64))?
+        } else {
+            let low = fetch(self, pc)?;
+            if low & 0b11 == 0b11 {
+                low | fetch(self, pc.wrapping_add(2))? << 16
+            } else {
+                low
+            }
+        };
+        let instr = self.parse(raw)?;
+
+        // Device registers can change under us, so only cache RAM.
+        if self.decode_cache_enabled && self.bus.is_ram(pc, instr.size()) {
+            self.decode_cache.insert(pc, instr);
+        }
+
+        Ok(instr)
     }
 
-    pub fn set_register(&mut self, reg: u8, value: u32) {
-        if reg == 0 {
+    // Decode `raw`, which is illegal unless an extension it's from is
+    // enabled and every register it names exists. Compressed instructions
+    // are the ones whose low two bits aren't both set, and only exist while
+    // C is enabled.
+    fn parse(&self, raw: u32) -> Result<Decoded, Exception> {
+        let instr = if raw & 0b11 == 0b11 {
+            instruction::parse(raw)
+        } else if self.extension_enabled(Extension::C) && raw >> 16 == 0 {
+            instruction::parse_compressed(raw as u16, self.xlen())
+        } else {
+            None
+        };
+        match instr {
+            Some(instr) if instr.extensions(self.xlen())
+                .iter()
+                .any(|&ext| self.extension_enabled(ext)) &&
+                           instr.requires().iter().all(|&ext| self.extension_enabled(ext)) &&
+                           (self.xlen() == 64 || !instr.is_rv64_only()) &&
+                           (self.xlen() == 32 || !instr.is_rv32_only()) &&
+                           instr.max_register() < self.isa.registers() => Ok(instr),
+            _ => Err(Exception::IllegalInstruction(raw)),
+        }
+    }
+
+    /// The alignment jump targets need: 2 bytes while C is enabled, and
+    /// otherwise 4.
+    pub fn instruction_alignment(&self) -> u32 {
+        if self.extension_enabled(Extension::C) { 2 } else { 4 }
+    }
+
+    /// Whether instructions from `ext` can execute: the ISA has to include
+    /// it, single-letter extensions can also be turned off in `misa`,
+    /// floating point in `mstatus.FS` and vectors in `mstatus.VS`.
+    pub fn extension_enabled(&self, ext: Extension) -> bool {
+        let ext = match ext {
+            Extension::I => self.isa.base(),
+            ext => ext,
+        };
+        match ext.misa_bit() {
+            Some(bit) => self.csr.misa & bit as u64 != 0,
+            None if matches!(ext, Extension::Zfa | Extension::Zfh | Extension::Zfhmin) => {
+                self.isa.has(ext) && self.csr.mstatus & MSTATUS_FS != 0
+            }
+            None if ext == Extension::Zve32x || ext == Extension::Zve64x => {
+                self.isa.has(ext) && self.csr.mstatus & MSTATUS_VS != 0
+            }
+            None => self.isa.has(ext),
+        }
+    }
+
+    pub fn isa(&self) -> Isa {
+        self.isa
+    }
+
+    /// The floating point registers and `fcsr`.
+    pub fn float(&self) -> &FloatState {
+        &self.float
+    }
+
+    /// The floating point registers and `fcsr`, for changing them, which
+    /// marks the floating point state dirty in `mstatus.FS`.
+    pub fn float_mut(&mut self) -> &mut FloatState {
+        self.csr.mstatus |= FS_DIRTY;
+        &mut self.float
+    }
+
+    /// The vector registers and CSRs.
+    pub fn vector(&self) -> &VectorState {
+        &self.vector
+    }
+
+    /// The vector registers and CSRs, for changing them, which marks the
+    /// vector state dirty in `mstatus.VS`.
+    pub fn vector_mut(&mut self) -> &mut VectorState {
+        self.csr.mstatus |= VS_DIRTY;
+        &mut self.vector
+    }
+
+    /// Fill tail and inactive vector elements with ones when `vtype` says
+    /// they're agnostic, rather than leaving them alone. Both are allowed,
+    /// and code that works with one but not the other is relying on
+    /// elements it doesn't own.
+    pub fn set_vector_agnostic_ones(&mut self, enabled: bool) {
+        self.vector_agnostic_ones = enabled;
+    }
+
+    pub(crate) fn vector_agnostic_ones(&self) -> bool {
+        self.vector_agnostic_ones
+    }
+
+    /// Set the size in bytes of the cache blocks that the Zicbom and Zicboz
+    /// instructions work on, and that CBO.ZERO clears. It has to be a power
+    /// of two from 4 bytes up to a 4 KiB page, and is 64 by default.
+    pub fn set_cache_block_size(&mut self, size: u32) {
+        assert!(size.is_power_of_two() && (4..=4096).contains(&size),
+                "cache blocks must be 4 to 4096 bytes and a power of two");
+        self.cache_block_size = size;
+    }
+
+    pub fn cache_block_size(&self) -> u32 {
+        self.cache_block_size
+    }
+
+    pub(crate) fn menvcfg(&self) -> u64 {
+        self.csr.menvcfg
+    }
+
+    pub(crate) fn jvt(&self) -> u64 {
+        self.csr.jvt
+    }
+
+    /// The width of the integer registers: 32 or 64.
+    pub fn xlen(&self) -> u32 {
+        self.isa.xlen()
+    }
+
+    /// Set the extensions this hart implements, and reset `misa`,
+    /// `mstatus.FS` and `mstatus.VS` to enable all of them. Switching to
+    /// RV32 truncates the registers, switching to RV32E clears x16-x31, and
+    /// changing XLEN or VLEN resets the vector unit.
+    pub fn set_isa(&mut self, isa: Isa) {
+        if isa.vlen() != self.isa.vlen() || isa.xlen() != self.isa.xlen() {
+            self.vector = VectorState::new(isa.vlen(), isa.xlen());
+        }
+        self.isa = isa;
+        self.csr.misa = isa.misa();
+        self.csr.mstatus = (self.csr.mstatus & !(MSTATUS_FS | MSTATUS_VS)) | initial_status(isa);
+        for reg in 0..32 {
+            let value = self.regs[reg as usize];
+            self.regs[reg as usize] = if reg < isa.registers() { self.truncate(value) } else { 0 };
+        }
+        self.decode_cache.flush();
+    }
+
+    /// Discard all cached decoded instructions. Stores made by the guest keep
+    /// the cache up to date, but embedders writing code directly to `bus`
+    /// after execution has started need to call this.
+    pub fn flush_decode_cache(&mut self) {
+        self.decode_cache.flush();
+    }
+
+    /// Turn decoded-instruction caching on or off (it's on by default).
+    /// Basic blocks are formed from cached instructions, so this turns block
+    /// dispatch off too.
+    pub fn set_decode_cache_enabled(&mut self, enabled: bool) {
+        self.decode_cache_enabled = enabled;
+        self.decode_cache.flush();
+    }
+
+    /// Turn basic-block dispatch on or off (it's on by default). When it's
+    /// off, every instruction goes through the single-step path.
+    pub fn set_block_dispatch_enabled(&mut self, enabled: bool) {
+        self.block_dispatch_enabled = enabled;
+        self.decode_cache.flush_blocks();
+    }
+
+    /// Turn the JIT on or off (it's on by default). Blocks are only compiled
+    /// when block dispatch is on.
+    #[cfg(feature = "jit")]
+    pub fn set_jit_enabled(&mut self, enabled: bool) {
+        self.jit_enabled = enabled;
+        self.decode_cache.flush_blocks();
+        self.jit.reset();
+    }
+
+    /// Check every compiled block against the interpreter: after native code
+    /// runs, re-run the same instructions from a copy of the registers and
+    /// RAM taken beforehand, and panic if the results differ. This copies
+    /// all of RAM for every block, so it's only useful for testing.
+    #[cfg(feature = "jit")]
+    pub fn set_jit_differential(&mut self, enabled: bool) {
+        self.jit_differential = enabled;
+    }
+
+    /// Continue at `target` once the current instruction completes, instead
+    /// of the next instruction.
+    pub fn jump(&mut self, target: u32) {
+        self.next_pc = target;
+    }
+
+    /// Enter the machine-mode trap handler for `exception`, raised by the
+    /// instruction at `pc`. If no handler has been installed (`mtvec` is
+    /// still 0) execution stops instead.
+    fn take_trap(&mut self, exception: Exception, pc: u32) -> Option<StopReason> {
+        if exception == Exception::EnvironmentCall && self.hooks.is_some() {
+            let mut hooks = self.hooks.take().unwrap();
+            let action = hooks.ecall(self);
+            self.hooks = Some(hooks);
+
+            match action {
+                HookAction::Stop => return Some(StopReason::Hook),
+                HookAction::Override(value) => {
+                    self.set_register(10, value);
+                    self.pc = self.pc.wrapping_add(4);
+                    self.csr.cycles = self.csr.cycles.wrapping_add(1);
+                    return None;
+                }
+                HookAction::Continue => {}
+            }
+        }
+
+        if exception == Exception::EnvironmentCall &&
+           self.halt_conditions.contains(&HaltCondition::ExitSyscall) &&
+           self.regs[17] == 93 {
+            return Some(StopReason::Halted(self.regs[10] as u32));
+        }
+
+        // EBREAK and triggers can enter debug mode instead, and EBREAK in
+        // debug mode is the end of what the debugger wanted run.
+        if let Exception::Breakpoint(_) = exception {
+            let fired = self.csr.debug.take_fired();
+            if self.csr.debug.mode {
+                return Some(StopReason::Debug(self.csr.debug.cause()));
+            }
+            if self.isa.has(Extension::Sdext) {
+                match fired {
+                    Some(Action::DebugMode) => {
+                        return Some(self.enter_debug_mode(DebugCause::Trigger, pc))
+                    }
+                    None if self.csr.debug.ebreak_enters(self.privilege) => {
+                        return Some(self.enter_debug_mode(DebugCause::Ebreak, pc))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let cause = exception.cause(self.privilege as u8);
+
+        if self.hooks.is_some() {
+            let event = TrapEvent {
+                pc,
+                cause,
+                tval: exception.tval(),
+            };
+            if self.with_hooks(|hooks, cpu| hooks.trap(cpu, &event)) == HookAction::Stop {
+                return Some(StopReason::Hook);
+            }
+        }
+
+        // Exceptions in debug mode don't trap either.
+        if self.csr.mtvec == 0 || self.csr.debug.mode {
+            self.count_event(Event::Exception);
+            return Some(match exception {
+                Exception::IllegalInstruction(raw) => {
+                    StopReason::IllegalInstruction { pc, raw }
+                }
+                Exception::InstructionAccessFault(addr) => {
+                    StopReason::MemoryFault { pc, addr, access: Access::Fetch }
+                }
+                Exception::LoadAccessFault(addr) => {
+                    StopReason::MemoryFault { pc, addr, access: Access::Load }
+                }
+                Exception::StoreAccessFault(addr) => {
+                    StopReason::MemoryFault { pc, addr, access: Access::Store }
+                }
+                Exception::Breakpoint(_) => StopReason::Breakpoint,
+                _ => StopReason::Trap { cause },
+            });
+        }
+
+        self.count_event(Event::Exception);
+        self.enter_trap(cause, exception.tval(), pc);
+        None
+    }
+
+    /// Take the highest-priority pending interrupt, if interrupts are
+    /// enabled. Like exceptions, an interrupt with no trap handler stops
+    /// execution instead.
+    fn take_interrupt(&mut self) -> Option<StopReason> {
+        if self.privilege == Privilege::Machine && self.csr.mstatus & MSTATUS_MIE == 0 {
+            return None;
+        }
+
+        let pending = self.csr.mip & self.csr.mie;
+        let interrupt = [Interrupt::External,
+                         Interrupt::Software,
+                         Interrupt::Timer,
+                         Interrupt::CounterOverflow]
+            .iter()
+            .cloned()
+            .find(|&i| pending & (1 << i as u32) != 0)?;
+        let cause = (1 << (self.xlen() - 1)) | interrupt as u64;
+
+        if self.hooks.is_some() {
+            let event = TrapEvent {
+                pc: self.pc,
+                cause,
+                tval: 0,
+            };
+            if self.with_hooks(|hooks, cpu| hooks.trap(cpu, &event)) == HookAction::Stop {
+                return Some(StopReason::Hook);
+            }
+        }
+
+        if self.csr.mtvec == 0 {
+            return Some(StopReason::Trap { cause });
+        }
+
+        let pc = self.pc;
+        self.count_event(Event::Interrupt);
+        self.enter_trap(cause, 0, pc);
+        None
+    }
+
+    fn enter_trap(&mut self, cause: u64, tval: u64, epc: u32) {
+        let mie = self.csr.mstatus & MSTATUS_MIE;
+        self.csr.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+        self.csr.mstatus |= (mie << 4) | ((self.privilege as u64) << MSTATUS_MPP_SHIFT);
+        self.csr.mepc = epc as u64;
+        self.csr.mcause = cause;
+        self.csr.mtval = tval;
+        self.csr.debug.trap_entered();
+        self.privilege = Privilege::Machine;
+        // Addresses are 32 bits wide; see `address`.
+        self.pc = (self.csr.mtvec & !0b11) as u32;
+    }
+
+    /// Return from a machine-mode trap handler (MRET).
+    pub fn return_from_trap(&mut self) {
+        let mpie = self.csr.mstatus & MSTATUS_MPIE;
+        self.privilege = match (self.csr.mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT {
+            0 => Privilege::User,
+            _ => Privilege::Machine,
+        };
+        self.csr.mstatus &= !(MSTATUS_MIE | MSTATUS_MPP);
+        self.csr.mstatus |= MSTATUS_MPIE | (mpie >> 4);
+        self.csr.debug.trap_returned();
+
+        self.next_pc = self.mepc() as u32;
+    }
+
+    /// Ask the hart to halt into debug mode before its next instruction, as
+    /// an external debugger would. Without Sdext there's no debug mode, and
+    /// nothing happens.
+    pub fn request_halt(&mut self) {
+        if self.isa.has(Extension::Sdext) {
+            self.csr.debug.halt_request = true;
+        }
+    }
+
+    pub fn in_debug_mode(&self) -> bool {
+        self.csr.debug.mode
+    }
+
+    /// Leave debug mode (DRET), continuing at `dpc` in the privilege level
+    /// `dcsr.prv` holds.
+    pub fn resume_from_debug_mode(&mut self) {
+        if !self.csr.debug.mode {
             return;
         }
+        self.csr.debug.mode = false;
+        self.privilege = self.csr.debug.privilege();
+        self.pc = self.csr.debug.dpc as u32;
+        self.next_pc = self.pc;
+    }
 
-        self.regs[reg as usize] = value
+    // Enter debug mode for `cause`, to resume at `pc`.
+    fn enter_debug_mode(&mut self, cause: DebugCause, pc: u32) -> StopReason {
+        self.csr.debug.enter(cause, pc, self.privilege);
+        self.privilege = Privilege::Machine;
+        self.pc = pc;
+        self.next_pc = pc;
+        self.pending_stop = None;
+        StopReason::Debug(cause)
     }
 
-    pub fn get_csr(&self, csr: u16) -> u32 {
-        match csr {
-            0x341 => self.csr.mepc,
-            0x700...0x702 => 0,
-            0x704...0x706 => 0,
-            0x708...0x70A => 0,
-            0xC00 => self.csr.cycles as u32,
-            0xC80 => (self.csr.cycles >> 32) as u32,
-            0xF10 => (1 << 30) | (1 << 8) | (1 << 12), // misa RV32IM
-            _ => panic!("read csr 0x{:03X} not implemented", csr),
+    // Without C, bit 1 of `mepc` reads as zero, though it's still there for
+    // when C is turned back on.
+    fn mepc(&self) -> u64 {
+        self.csr.mepc & !(self.instruction_alignment() as u64 - 1)
+    }
+
+    /// Stop execution once the current instruction completes (WFI), unless
+    /// an enabled interrupt is already pending. The embedder (or `Scheduler`)
+    /// decides what happens next.
+    pub fn wait_for_interrupt(&mut self) {
+        self.update_clint_interrupts();
+        // It's a NOP in debug mode and when single-stepping.
+        let debug = &self.csr.debug;
+        if !self.interrupt_pending() && !debug.mode && !debug.stepping() {
+            self.pending_stop = Some(StopReason::Wfi);
         }
     }
 
-    pub fn set_csr(&mut self, csr: u16, value: u32) {
-        match csr {
-            0x780 => {}
-            _ => panic!("write csr 0x{:03X} not implemented", csr),
+    /// Raise or clear an interrupt's pending bit in `mip`. The CLINT and
+    /// interrupt controllers drive these; software can't write them.
+    pub fn set_interrupt_pending(&mut self, interrupt: Interrupt, pending: bool) {
+        let bit = 1 << interrupt as u32;
+        if pending {
+            self.csr.mip |= bit;
+        } else {
+            self.csr.mip &= !bit;
         }
     }
-}
 
-impl fmt::Debug for CPU {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("CPU").field("regs", &self.regs).field("pc", &self.pc).finish()
+    /// Count `event` in the programmable counters listening for it. With
+    /// Sscofpmf, a counter overflowing raises a counter-overflow interrupt.
+    #[inline]
+    pub fn count_event(&mut self, event: Event) {
+        let wrapped = self.csr.counters.count(event, self.privilege);
+        if wrapped != 0 && self.isa.has(Extension::Sscofpmf) &&
+           self.csr.counters.overflow(wrapped) {
+            self.csr.mip |= MIP_LCOFIP;
+        }
+    }
+
+    /// Whether an interrupt is pending that `mie` enables, which is what wakes
+    /// a hart from WFI whether or not `mstatus.MIE` is set.
+    pub fn interrupt_pending(&self) -> bool {
+        self.csr.mip & self.csr.mie != 0
+    }
+
+    pub fn hart_id(&self) -> u32 {
+        self.hart_id
+    }
+
+    /// Set the value of `mhartid`. Harts sharing a bus must have distinct IDs.
+    pub fn set_hart_id(&mut self, hart_id: u32) {
+        self.hart_id = hart_id;
+    }
+
+    /// Read the `time` CSR from `clint`'s `mtime`, and drive `mip.MSIP` and
+    /// `mip.MTIP` from this hart's registers in it. Without a CLINT, `time`
+    /// counts instructions executed, which is how fast `mtime` goes when
+    /// there's one hart.
+    pub fn set_clint(&mut self, clint: Clint) {
+        self.clint = Some(clint);
+    }
+
+    // Refresh the CLINT's interrupt lines before they're looked at, so that
+    // a handler that clears `msip` or moves `mtimecmp` on returns to find
+    // its interrupt gone.
+    fn update_clint_interrupts(&mut self) {
+        let hart = self.hart_id as usize;
+        let (software, timer) = match self.clint {
+            Some(ref clint) if hart < clint.harts() => {
+                (clint.msip(hart), clint.timer_pending(hart))
+            }
+            _ => return,
+        };
+        self.set_interrupt_pending(Interrupt::Software, software);
+        self.set_interrupt_pending(Interrupt::Timer, timer);
+    }
+
+    fn time(&self) -> u64 {
+        match self.clint {
+            Some(ref clint) => clint.mtime(),
+            None => self.csr.cycles,
+        }
+    }
+
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    /// The number of instructions executed. `mcycle` and `minstret` count
+    /// the same until software writes or inhibits them.
+    pub fn cycles(&self) -> u64 {
+        self.csr.cycles
+    }
+
+    pub fn halt_conditions(&self) -> &[HaltCondition] {
+        &self.halt_conditions
+    }
+
+    pub fn add_halt_condition(&mut self, condition: HaltCondition) {
+        if !self.halt_conditions.contains(&condition) {
+            self.halt_conditions.push(condition);
+        }
+        // Blocks are formed around halting addresses.
+        self.decode_cache.flush_blocks();
+    }
+
+    pub fn remove_halt_condition(&mut self, condition: HaltCondition) {
+        self.halt_conditions.retain(|&c| c != condition);
+        self.decode_cache.flush_blocks();
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.insert(addr);
+        // Blocks are formed around breakpoints.
+        self.decode_cache.flush_blocks();
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.remove(&addr);
+        self.decode_cache.flush_blocks();
+    }
+
+    /// Don't stop on a breakpoint at the current pc when execution next
+    /// carries on, as if it had just stopped there.
+    pub fn skip_breakpoint(&mut self) {
+        self.resume_breakpoint = Some(self.pc);
+    }
+
+    /// Stop after any instruction whose `access` overlaps the `size` bytes
+    /// starting at `addr`.
+    pub fn add_watchpoint(&mut self, addr: u32, size: u32, access: Access) {
+        self.watchpoints.push(Watchpoint {
+            addr,
+            size,
+            access,
+        });
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u32, access: Access) {
+        self.watchpoints.retain(|w| w.addr != addr || w.access != access);
+    }
+
+    /// Register hooks to observe (and steer) execution.
+    pub fn hooks_mut(&mut self) -> &mut Hooks {
+        self.hooks.get_or_insert_with(Default::default)
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks = None;
+    }
+
+    // Hooks are moved out of the CPU while they run so they can be handed a
+    // reference to it.
+    fn with_hooks<F>(&mut self, f: F) -> HookAction
+        where F: FnOnce(&mut Hooks, &CPU) -> HookAction
+    {
+        match self.hooks.take() {
+            Some(mut hooks) => {
+                let action = f(&mut hooks, self);
+                self.hooks = Some(hooks);
+                action
+            }
+            None => HookAction::Continue,
+        }
+    }
+
+    fn access(&mut self, addr: u32, size: u32, access: Access, value: u32)
+              -> Result<u32, Exception> {
+        let fault = match access {
+            Access::Fetch => Exception::InstructionAccessFault(addr as u64),
+            Access::Load => Exception::LoadAccessFault(addr as u64),
+            Access::Store => Exception::StoreAccessFault(addr as u64),
+        };
+
+        let mut event = MemoryAccess {
+            addr,
+            size,
+            value,
+            access,
+        };
+        if access == Access::Store {
+            if self.csr.debug.armed() {
+                let xlen = self.xlen();
+                self.csr.debug.check_access(addr, size, access, value as u64, self.privilege,
+                                            xlen)?;
+            }
+            if let Some(ref mut hooks) = self.hooks {
+                if hooks.memory(&mut event) == HookAction::Stop {
+                    self.pending_stop = Some(StopReason::Hook);
+                }
+            }
+            self.bus.write(addr, size, event.value).ok_or(fault)?;
+            self.count_event(Event::Store);
+            if let Some(code) = self.bus.take_halt() {
+                self.pending_stop = Some(StopReason::Halted(code));
+            }
+            self.decode_cache.invalidate(addr, size);
+            if self.bus.has_reservations() {
+                self.bus.break_reservations(self.hart_id, addr, size);
+            }
+        } else {
+            event.value = self.bus.read(addr, size).ok_or(fault)?;
+            if self.csr.debug.armed() {
+                let xlen = self.xlen();
+                self.csr.debug.check_access(addr, size, access, event.value as u64,
+                                            self.privilege, xlen)?;
+            }
+            self.count_event(Event::Load);
+            if let Some(ref mut hooks) = self.hooks {
+                if hooks.memory(&mut event) == HookAction::Stop {
+                    self.pending_stop = Some(StopReason::Hook);
+                }
+            }
+        }
+        let result = event.value;
+
+        let end = addr as u64 + size as u64;
+        let hit = self.watchpoints.iter().any(|w| {
+            w.access == access && (addr as u64) < w.addr as u64 + w.size as u64 &&
+            (w.addr as u64) < end
+        });
+        if hit {
+            self.pending_stop = Some(StopReason::Watchpoint {
+                addr,
+                access,
+            });
+        }
+
+        Ok(result)
+    }
+
+    pub fn load_u8(&mut self, addr: u32) -> Result<u8, Exception> {
+        self.access(addr, 1, Access::Load, 0).map(|v| v as u8)
+    }
+
+    pub fn load_u16(&mut self, addr: u32) -> Result<u16, Exception> {
+        self.access(addr, 2, Access::Load, 0).map(|v| v as u16)
+    }
+
+    pub fn load_u32(&mut self, addr: u32) -> Result<u32, Exception> {
+        self.access(addr, 4, Access::Load, 0)
+    }
+
+    pub fn store_u8(&mut self, addr: u32, value: u8) -> Result<(), Exception> {
+        self.access(addr, 1, Access::Store, value as u32).map(|_| ())
+    }
+
+    pub fn store_u16(&mut self, addr: u32, value: u16) -> Result<(), Exception> {
+        self.access(addr, 2, Access::Store, value as u32).map(|_| ())
+    }
+
+    pub fn store_u32(&mut self, addr: u32, value: u32) -> Result<(), Exception> {
+        self.access(addr, 4, Access::Store, value).map(|_| ())
+    }
+
+    /// Doublewords reach the bus, and hooks, as two word accesses, low word
+    /// first.
+    pub fn load_u64(&mut self, addr: u32) -> Result<u64, Exception> {
+        let low = self.load_u32(addr)?;
+        let high = self.load_u32(addr.checked_add(4)
+            .ok_or(Exception::LoadAccessFault(addr as u64))?)?;
+        Ok((high as u64) << 32 | low as u64)
+    }
+
+    pub fn store_u64(&mut self, addr: u32, value: u64) -> Result<(), Exception> {
+        let high = addr.checked_add(4).ok_or(Exception::StoreAccessFault(addr as u64))?;
+        self.store_u32(addr, value as u32)?;
+        self.store_u32(high, (value >> 32) as u32)
+    }
+
+    /// The address `base + offset`, wrapping at XLEN bits. Physical
+    /// addresses are 32 bits wide, so on RV64 anything from 4 GiB up raises
+    /// `fault` instead.
+    pub fn address(&self, base: u64, offset: i32, fault: fn(u64) -> Exception)
+                   -> Result<u32, Exception> {
+        let addr = self.truncate(base.wrapping_add(offset as i64 as u64));
+        if addr > u32::MAX as u64 {
+            return Err(fault(addr));
+        }
+        Ok(addr as u32)
+    }
+
+    /// The number of integer registers: 16 on RV32E, otherwise 32.
+    pub fn registers(&self) -> u8 {
+        self.isa.registers()
+    }
+
+    /// Read a register. Panics if the hart doesn't have it.
+    pub fn get_register(&self, reg: u8) -> u64 {
+        assert!(reg < self.registers(), "x{} doesn't exist on {}", reg, self.isa);
+        self.regs[reg as usize]
+    }
+
+    /// Set a register, dropping any bits above XLEN. Panics if the hart
+    /// doesn't have it.
+    pub fn set_register(&mut self, reg: u8, value: u64) {
+        assert!(reg < self.registers(), "x{} doesn't exist on {}", reg, self.isa);
+        if reg == 0 {
+            return;
+        }
+
+        self.regs[reg as usize] = self.truncate(value)
+    }
+
+    /// `value` as a signed XLEN-bit number.
+    pub fn signed(&self, value: u64) -> i64 {
+        match self.xlen() {
+            32 => value as i32 as i64,
+            _ => value as i64,
+        }
+    }
+
+    /// `value` without the bits above XLEN.
+    pub fn truncate(&self, value: u64) -> u64 {
+        match self.xlen() {
+            32 => value as u32 as u64,
+            _ => value,
+        }
+    }
+
+    /// Read a CSR on behalf of a CSR instruction, running any CSR hooks.
+    pub fn read_csr(&mut self, csr: u16) -> Result<u64, Exception> {
+        let value = self.get_csr(csr)?;
+        Ok(self.csr_hooks(csr, value, false))
+    }
+
+    /// Write a CSR on behalf of a CSR instruction, running any CSR hooks.
+    pub fn write_csr(&mut self, csr: u16, value: u64) -> Result<(), Exception> {
+        let value = self.csr_hooks(csr, value, true);
+        self.set_csr(csr, value)
+    }
+
+    fn csr_hooks(&mut self, csr: u16, value: u64, write: bool) -> u64 {
+        let mut event = CsrAccess {
+            csr,
+            value,
+            write,
+        };
+        if let Some(ref mut hooks) = self.hooks {
+            if hooks.csr(&mut event) == HookAction::Stop {
+                self.pending_stop = Some(StopReason::Hook);
+            }
+        }
+        event.value
+    }
+
+    /// Read a CSR as the current privilege level. Accessing a CSR that doesn't
+    /// exist, or that needs a higher privilege level, is an illegal
+    /// instruction.
+    pub fn get_csr(&self, csr: u16) -> Result<u64, Exception> {
+        if ((csr >> 8) & 0b11) as u8 > self.privilege as u8 {
+            return Err(Exception::IllegalInstruction(0));
+        }
+
+        Ok(match csr {
+            0x300 => {
+                // SD summarises whether any extension state is dirty.
+                let dirty = if self.csr.mstatus & MSTATUS_FS == FS_DIRTY ||
+                               self.csr.mstatus & MSTATUS_VS == VS_DIRTY {
+                    1 << (self.xlen() - 1)
+                } else {
+                    0
+                };
+                let uxl = if self.xlen() == 64 { MSTATUS_UXL_64 } else { 0 };
+                self.csr.mstatus | dirty | uxl
+            }
+            0x301 => self.csr.misa,
+            0x304 => self.csr.mie,
+            0x305 => self.csr.mtvec,
+            0x340 => self.csr.mscratch,
+            0x341 => self.mepc(),
+            0x342 => self.csr.mcause,
+            0x343 => self.csr.mtval,
+            0x344 => self.csr.mip,
+            0x30A => self.truncate(self.csr.menvcfg),
+            0x31A if self.xlen() == 32 => self.csr.menvcfg >> 32,
+            0x017 if self.isa.has(Extension::Zcmt) => self.csr.jvt,
+            0x700..=0x702 => 0,
+            0x704..=0x706 => 0,
+            0x708..=0x70A => 0,
+            0x001..=0x003 => self.get_float_csr(csr)?,
+            0x008..=0x00A | 0x00F | 0xC20..=0xC22 => self.get_vector_csr(csr)?,
+            0x306 | 0x320 | 0x323..=0x33F | 0xB00..=0xB1F | 0xC00..=0xC1F => {
+                self.get_counter_csr(csr)?
+            }
+            0x723..=0x73F | 0xB80..=0xB9F | 0xC80..=0xC9F if self.xlen() == 32 => {
+                self.get_counter_csr(csr)?
+            }
+            0x7A0..=0x7A5 | 0x7B0..=0x7B3 => self.get_debug_csr(csr)?,
+            0xF11..=0xF13 => 0, // mvendorid, marchid, mimpid
+            0xF14 => self.hart_id as u64,
+            _ => return Err(Exception::IllegalInstruction(0)),
+        })
+    }
+
+    pub fn set_csr(&mut self, csr: u16, value: u64) -> Result<(), Exception> {
+        if ((csr >> 8) & 0b11) as u8 > self.privilege as u8 || csr >> 10 == 0b11 {
+            // Insufficient privilege, or a read-only CSR
+            return Err(Exception::IllegalInstruction(0));
+        }
+
+        match csr {
+            0x300 => {
+                let mpp = match (value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT {
+                    0 => Privilege::User,
+                    _ => Privilege::Machine,
+                };
+                let fs = if self.isa.has_float() { value & MSTATUS_FS } else { 0 };
+                let vs = if self.isa.vlen() != 0 { value & MSTATUS_VS } else { 0 };
+                // Turning floating point or vectors off or on changes which
+                // instructions decode.
+                if (fs == 0) != (self.csr.mstatus & MSTATUS_FS == 0) ||
+                   (vs == 0) != (self.csr.mstatus & MSTATUS_VS == 0) {
+                    self.decode_cache.flush();
+                }
+                self.csr.mstatus = (value & (MSTATUS_MIE | MSTATUS_MPIE)) |
+                                   ((mpp as u64) << MSTATUS_MPP_SHIFT) | fs | vs;
+            }
+            0x301 => {
+                // Extensions the ISA has can be turned off and on again,
+                // except for the base.
+                let writable = self.isa.misa() & !self.isa.mxl() &
+                               !(self.isa.base().misa_bit().unwrap_or(0) as u64);
+                let misa = (self.csr.misa & !writable) | (value & writable);
+                // Turning C off would leave the next instruction misaligned,
+                // so the write is ignored.
+                let c = Extension::C.misa_bit().unwrap() as u64;
+                if misa & c == 0 && self.next_pc & 0b10 != 0 {
+                    return Ok(());
+                }
+                if misa != self.csr.misa {
+                    self.csr.misa = misa;
+                    self.decode_cache.flush();
+                }
+            }
+            0x304 => {
+                let mut writable = MIP_MSIP | MIP_MTIP | MIP_MEIP;
+                if self.isa.has(Extension::Sscofpmf) {
+                    writable |= MIP_LCOFIP;
+                }
+                self.csr.mie = value & writable;
+            }
+            // Both only hold addresses, which are 32 bits wide.
+            0x305 => self.csr.mtvec = value & 0xFFFF_FFFC,
+            0x340 => self.csr.mscratch = self.truncate(value),
+            0x341 => self.csr.mepc = value & 0xFFFF_FFFE,
+            0x342 => self.csr.mcause = self.truncate(value),
+            0x343 => self.csr.mtval = self.truncate(value),
+            0x30A => {
+                let mut writable = 0;
+                if self.isa.has(Extension::Zicbom) {
+                    writable |= MENVCFG_CBIE | MENVCFG_CBCFE;
+                }
+                if self.isa.has(Extension::Zicboz) {
+                    writable |= MENVCFG_CBZE;
+                }
+                let mut menvcfg = value & writable;
+                // CBIE's 0b10 is reserved, and reads back as off.
+                if menvcfg & MENVCFG_CBIE == 0b10 << 4 {
+                    menvcfg &= !MENVCFG_CBIE;
+                }
+                self.csr.menvcfg = (self.csr.menvcfg & !0xFFFF_FFFF) | menvcfg;
+            }
+            // Nothing in the top half is writable yet.
+            0x31A if self.xlen() == 32 => {}
+            // Jump table mode is the only one there is.
+            0x017 if self.isa.has(Extension::Zcmt) => {
+                self.csr.jvt = self.truncate(value) & !0x3F
+            }
+            // The counter-overflow bit is software's to clear. The others are
+            // read-only.
+            0x344 if self.isa.has(Extension::Sscofpmf) => {
+                self.csr.mip = (self.csr.mip & !MIP_LCOFIP) | (value & MIP_LCOFIP);
+            }
+            0x344 => {}
+            0x780 => {}
+            0x001..=0x003 => self.set_float_csr(csr, value)?,
+            0x008..=0x00A | 0x00F => self.set_vector_csr(csr, value)?,
+            0x306 | 0x320 | 0x323..=0x33F | 0xB00..=0xB1F => self.set_counter_csr(csr, value)?,
+            0x723..=0x73F | 0xB80..=0xB9F if self.xlen() == 32 => {
+                self.set_counter_csr(csr, value)?
+            }
+            0x7A0..=0x7A5 | 0x7B0..=0x7B3 => self.set_debug_csr(csr, value)?,
+            _ => return Err(Exception::IllegalInstruction(0)),
+        }
+
+        Ok(())
+    }
+
+    // The counters and the CSRs that control them. On RV32 the counters and
+    // `mhpmevent` have their high halves in CSRs of their own.
+    fn get_counter_csr(&self, csr: u16) -> Result<u64, Exception> {
+        let counters = &self.csr.counters;
+        let n = (csr & 0x1F) as usize;
+        match csr {
+            0x306 => return Ok(counters.enable as u64),
+            0x320 => return Ok(counters.inhibit() as u64),
+            0x323..=0x33F => return Ok(self.truncate(counters.event(n))),
+            0x723..=0x73F => return Ok(counters.event(n) >> 32),
+            _ => {}
+        }
+
+        // `cycle`, `instret` and `hpmcounter<n>` are read-only views of the
+        // machine counters, which U-mode can only read if `mcounteren` says
+        // so.
+        if (csr >> 8) & 0xF == 0xC {
+            let ext = if n < 3 { Extension::Zicntr } else { Extension::Zihpm };
+            if !self.isa.has(ext) ||
+               (self.privilege == Privilege::User && counters.enable & (1 << n) == 0) {
+                return Err(Exception::IllegalInstruction(0));
+            }
+        }
+        let executed = self.csr.cycles;
+        let value = match n {
+            0 => counters.cycle(executed),
+            1 if csr >> 8 == 0xC => self.time(),
+            // `mtime` is memory-mapped, not a CSR.
+            1 => return Err(Exception::IllegalInstruction(0)),
+            2 => counters.instret(executed),
+            _ => counters.hpm(n),
+        };
+        Ok(if csr & 0x80 != 0 { value >> 32 } else { self.truncate(value) })
+    }
+
+    fn set_counter_csr(&mut self, csr: u16, value: u64) -> Result<(), Exception> {
+        // The write is what the next instruction sees, so it has to allow for
+        // this one retiring.
+        let executed = self.csr.cycles.wrapping_add(1);
+        let n = (csr & 0x1F) as usize;
+        let xlen = self.xlen();
+        let zihpm = self.isa.has(Extension::Zihpm);
+        let mut filters = 0;
+        if self.isa.has(Extension::Sscofpmf) {
+            filters = counters::MHPMEVENT_OF | counters::MHPMEVENT_MINH |
+                      counters::MHPMEVENT_UINH;
+        }
+        let mut enable = 0;
+        if self.isa.has(Extension::Zicntr) {
+            enable |= 0b111;
+        }
+        if zihpm {
+            enable |= !0b111;
+        }
+
+        let counters = &mut self.csr.counters;
+        match csr {
+            0x306 => counters.enable = value as u32 & enable,
+            0x320 => counters.set_inhibit(executed, value as u32),
+            0x323..=0x33F if zihpm => {
+                let event = write_half(counters.event(n), value, xlen, false);
+                counters.set_event(n, event, filters);
+            }
+            0x723..=0x73F if zihpm => {
+                let event = write_half(counters.event(n), value, xlen, true);
+                counters.set_event(n, event, filters);
+            }
+            // There's no `mtime` CSR, and the programmable counters are
+            // read-only zero without Zihpm.
+            _ => {
+                let high = csr & 0x80 != 0;
+                match n {
+                    0 => {
+                        let cycle = write_half(counters.cycle(executed), value, xlen, high);
+                        counters.set_cycle(executed, cycle);
+                    }
+                    1 => return Err(Exception::IllegalInstruction(0)),
+                    2 => {
+                        let instret = write_half(counters.instret(executed), value, xlen, high);
+                        counters.set_instret(executed, instret);
+                    }
+                    _ if zihpm => {
+                        let counter = write_half(counters.hpm(n), value, xlen, high);
+                        counters.set_hpm(n, counter);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Likewise `fflags`, `frm` and `fcsr` for floating point.
+    fn get_float_csr(&self, csr: u16) -> Result<u64, Exception> {
+        if !self.extension_enabled(Extension::Zfhmin) {
+            return Err(Exception::IllegalInstruction(0));
+        }
+
+        let float = &self.float;
+        Ok(match csr {
+            0x001 => float.fflags as u64,
+            0x002 => float.frm as u64,
+            _ => (float.frm as u64) << 5 | float.fflags as u64,
+        })
+    }
+
+    fn set_float_csr(&mut self, csr: u16, value: u64) -> Result<(), Exception> {
+        if !self.extension_enabled(Extension::Zfhmin) {
+            return Err(Exception::IllegalInstruction(0));
+        }
+
+        let float = self.float_mut();
+        match csr {
+            0x001 => float.fflags = value as u8 & 0x1F,
+            0x002 => float.frm = value as u8 & 0b111,
+            _ => {
+                float.fflags = value as u8 & 0x1F;
+                float.frm = (value >> 5) as u8 & 0b111;
+            }
+        }
+        Ok(())
+    }
+
+    // The vector CSRs only exist while vectors are turned on.
+    fn get_vector_csr(&self, csr: u16) -> Result<u64, Exception> {
+        if !self.extension_enabled(Extension::Zve32x) {
+            return Err(Exception::IllegalInstruction(0));
+        }
+
+        let vector = &self.vector;
+        Ok(match csr {
+            0x008 => vector.vstart,
+            0x009 => vector.vxsat as u64,
+            0x00A => vector.vxrm as u64,
+            0x00F => (vector.vxrm as u64) << 1 | vector.vxsat as u64,
+            0xC20 => vector.vl,
+            0xC21 => vector.vtype,
+            _ => vector.vlenb() as u64,
+        })
+    }
+
+    fn set_vector_csr(&mut self, csr: u16, value: u64) -> Result<(), Exception> {
+        if !self.extension_enabled(Extension::Zve32x) {
+            return Err(Exception::IllegalInstruction(0));
+        }
+
+        // vstart only needs to count up to the most elements a group can
+        // have, which is VLEN.
+        let vlen = self.isa.vlen() as u64;
+        let vector = self.vector_mut();
+        match csr {
+            0x008 => vector.vstart = value & (vlen - 1),
+            0x009 => vector.vxsat = value & 1 != 0,
+            0x00A => vector.vxrm = value as u8 & 0b11,
+            _ => {
+                vector.vxsat = value & 1 != 0;
+                vector.vxrm = (value >> 1) as u8 & 0b11;
+            }
+        }
+        Ok(())
+    }
+
+    // The trigger module's CSRs, and debug mode's, which only debug mode can
+    // access.
+    fn check_debug_csr(&self, csr: u16) -> Result<(), Exception> {
+        let allowed = if csr >= 0x7B0 {
+            self.isa.has(Extension::Sdext) && self.csr.debug.mode
+        } else {
+            self.isa.has(Extension::Sdtrig)
+        };
+        if allowed { Ok(()) } else { Err(Exception::IllegalInstruction(0)) }
+    }
+
+    fn get_debug_csr(&self, csr: u16) -> Result<u64, Exception> {
+        self.check_debug_csr(csr)?;
+        let debug = &self.csr.debug;
+        Ok(match csr {
+            0x7A0 => debug.tselect(),
+            0x7A1 => debug.tdata1(self.xlen()),
+            0x7A2 => debug.tdata2(),
+            // No trigger matches on anything tdata3 could hold.
+            0x7A3 => 0,
+            0x7A4 => debug.tinfo(),
+            0x7A5 => debug.tcontrol(),
+            0x7B0 => debug.dcsr(),
+            0x7B1 => debug.dpc,
+            _ => debug.dscratch[(csr & 1) as usize],
+        })
+    }
+
+    fn set_debug_csr(&mut self, csr: u16, value: u64) -> Result<(), Exception> {
+        self.check_debug_csr(csr)?;
+        let xlen = self.xlen();
+        let value = self.truncate(value);
+        let debug = &mut self.csr.debug;
+        match csr {
+            0x7A0 => debug.set_tselect(value),
+            0x7A1 => debug.set_tdata1(value, xlen),
+            0x7A2 => debug.set_tdata2(value),
+            // tdata3 has nothing to hold, and writes to tinfo are ignored.
+            0x7A3 | 0x7A4 => {}
+            0x7A5 => debug.set_tcontrol(value),
+            0x7B0 => debug.set_dcsr(value),
+            0x7B1 => debug.dpc = value & 0xFFFF_FFFE,
+            _ => debug.dscratch[(csr & 1) as usize] = value,
+        }
+        Ok(())
+    }
+}
+
+impl CPU {
+    /// Write this CPU's architectural state and everything on its bus to
+    /// `out`. See the `snapshot` module for what is and isn't included.
+    pub fn save_snapshot<W: Write>(&self, out: W) -> io::Result<()> {
+        snapshot::save(out, &[self], &[])
+    }
+
+    /// Restore a snapshot written by `save_snapshot`. The CPU's bus must
+    /// have the same RAM and devices mapped as the one the snapshot was
+    /// taken of.
+    pub fn load_snapshot<R: Read>(&mut self, input: R) -> Result<(), SnapshotError> {
+        snapshot::load(input, &mut [self], |_| Ok(()))
+    }
+
+    pub(crate) fn save_state(&self) -> Vec<u8> {
+        let mut out = Writer::new();
+        out.u32(self.hart_id);
+        out.u8(self.xlen() as u8);
+        out.u32(self.pc);
+        out.u8(self.privilege as u8);
+        for &reg in &self.regs[1..] {
+            out.u64(reg);
+        }
+
+        let csr = &self.csr;
+        out.u64(csr.cycles);
+        for &value in &[csr.misa, csr.mstatus, csr.mie, csr.mip, csr.mtvec, csr.mscratch, csr.mepc,
+                        csr.mcause, csr.mtval, csr.menvcfg, csr.jvt] {
+            out.u64(value);
+        }
+        csr.counters.save(&mut out);
+        csr.debug.save(&mut out);
+
+        let float = &self.float;
+        for reg in 0..32 {
+            out.u64(float.register(reg));
+        }
+        out.u8(float.frm);
+        out.u8(float.fflags);
+
+        let vector = &self.vector;
+        out.u32(vector.vlen());
+        for &value in &[vector.vl, vector.vtype, vector.vstart] {
+            out.u64(value);
+        }
+        out.u8(vector.vxrm);
+        out.u8(vector.vxsat as u8);
+        out.bytes(vector.as_bytes());
+        out.into_inner()
+    }
+
+    pub(crate) fn parse_state(data: &[u8]) -> Result<SavedState, SnapshotError> {
+        let mut input = Reader::new(data);
+        let hart_id = input.u32()?;
+        let xlen = match input.u8()? {
+            32 => 32,
+            64 => 64,
+            _ => return Err(SnapshotError::Corrupt("invalid XLEN")),
+        };
+        let pc = input.u32()?;
+        let privilege = match input.u8()? {
+            0 => Privilege::User,
+            3 => Privilege::Machine,
+            _ => return Err(SnapshotError::Corrupt("invalid privilege level")),
+        };
+        let mut regs = [0; 32];
+        for reg in &mut regs[1..] {
+            *reg = input.u64()?;
+        }
+
+        let csr = CSRs {
+            cycles: input.u64()?,
+            misa: input.u64()?,
+            mstatus: input.u64()?,
+            mie: input.u64()?,
+            mip: input.u64()?,
+            mtvec: input.u64()?,
+            mscratch: input.u64()?,
+            mepc: input.u64()?,
+            mcause: input.u64()?,
+            mtval: input.u64()?,
+            menvcfg: input.u64()?,
+            jvt: input.u64()?,
+            counters: Counters::load(&mut input)?,
+            debug: DebugState::load(&mut input)?,
+        };
+
+        let mut float = FloatState::new();
+        for reg in 0..32 {
+            float.set_register(reg, input.u64()?);
+        }
+        float.frm = input.u8()? & 0b111;
+        float.fflags = input.u8()? & 0x1F;
+
+        let vlen = input.u32()?;
+        if !(vlen == 0 || vlen.is_power_of_two()) {
+            return Err(SnapshotError::Corrupt("invalid VLEN"));
+        }
+        let mut vector = VectorState::new(vlen, xlen);
+        vector.vl = input.u64()?;
+        vector.vtype = input.u64()?;
+        vector.vstart = input.u64()?;
+        vector.vxrm = input.u8()? & 0b11;
+        vector.vxsat = input.u8()? != 0;
+        let vregs = input.bytes()?;
+        if vregs.len() != vector.as_bytes().len() {
+            return Err(SnapshotError::Corrupt("wrong size of vector registers"));
+        }
+        vector.as_mut_bytes().copy_from_slice(vregs);
+
+        if !input.is_empty() {
+            return Err(SnapshotError::Corrupt("unexpected hart state"));
+        }
+
+        Ok(SavedState {
+            xlen,
+            regs,
+            csr,
+            float,
+            vector,
+            pc,
+            hart_id,
+            privilege,
+        })
+    }
+
+    pub(crate) fn load_state(&mut self, state: SavedState) {
+        self.regs = state.regs;
+        for reg in self.isa.registers()..32 {
+            self.regs[reg as usize] = 0;
+        }
+        self.csr = state.csr;
+        // Only extensions this hart has can be enabled, and its base always
+        // is.
+        self.csr.misa = (self.csr.misa & self.isa.misa()) | self.isa.mxl() |
+                        self.isa.base().misa_bit().unwrap_or(0) as u64;
+        self.float = state.float;
+        self.vector = state.vector;
+        self.pc = state.pc;
+        self.next_pc = state.pc;
+        self.hart_id = state.hart_id;
+        self.privilege = state.privilege;
+        self.pending_stop = None;
+        self.resume_breakpoint = None;
+        // The code in memory has changed under the cache.
+        self.decode_cache.flush();
+    }
+}
+
+// What `mstatus.FS` and `mstatus.VS` start as: Initial for the units `isa`
+// has, and Off for the others.
+fn initial_status(isa: Isa) -> u64 {
+    let fs = if isa.has_float() { FS_INITIAL } else { 0 };
+    let vs = if isa.vlen() != 0 { VS_INITIAL } else { 0 };
+    fs | vs
+}
+
+// `old` with `value` written to the half of it a CSR holds on RV32, the high
+// half or the low one, or all of it on RV64.
+fn write_half(old: u64, value: u64, xlen: u32, high: bool) -> u64 {
+    match (xlen, high) {
+        (32, true) => (old & 0xFFFF_FFFF) | value << 32,
+        (32, false) => (old & !0xFFFF_FFFF) | (value & 0xFFFF_FFFF),
+        _ => value,
+    }
+}
+
+impl fmt::Debug for CPU {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CPU")
+            .field("regs", &&self.regs[..self.registers() as usize])
+            .field("pc", &self.pc)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use trap::{Access, StopReason};
+
+    fn cpu_with_program(program: &[u32]) -> CPU {
+        let mut cpu = CPU::new(RAM::new(1024));
+        for (i, &word) in program.iter().enumerate() {
+            cpu.bus.ram.set_u32(0x200 + 4 * i as u32, word);
+        }
+        cpu.pc = 0x200;
+        cpu
+    }
+
+    #[test]
+    fn test_step() {
+        let mut cpu = cpu_with_program(&[0x02a00513]); // li a0, 42
+
+        assert_eq!(cpu.step(), StopReason::InstructionLimit);
+        assert_eq!(cpu.pc, 0x204);
+        assert_eq!(cpu.get_register(10), 42);
+        assert_eq!(cpu.cycles(), 1);
+    }
+
+    #[test]
+    fn test_run_halts_on_return() {
+        let mut cpu = cpu_with_program(&[0x02a00513, 0x00008067]); // li a0, 42; ret
+
+        assert_eq!(cpu.run(0x200), StopReason::Halted(42));
+        assert_eq!(cpu.pc, 0);
+    }
+
+    #[test]
+    fn test_run_for_limit() {
+        let mut cpu = cpu_with_program(&[0x02a00513, 0x02a00513, 0x00008067]);
+
+        assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+        assert_eq!(cpu.pc, 0x208);
+        assert_eq!(cpu.run_for(2), StopReason::Halted(42));
+    }
+
+    #[test]
+    fn test_exit_syscall() {
+        // li a0, 42; li a7, 93; ecall
+        let mut cpu = cpu_with_program(&[0x02a00513, 0x05d00893, 0x00000073]);
+
+        assert_eq!(cpu.run_for(3), StopReason::Trap { cause: 11 });
+
+        cpu.pc = 0x200;
+        cpu.add_halt_condition(HaltCondition::ExitSyscall);
+        assert_eq!(cpu.run_for(3), StopReason::Halted(42));
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut cpu = cpu_with_program(&[0x02a00513, 0x02a00513, 0x00008067]);
+        cpu.add_breakpoint(0x204);
+
+        assert_eq!(cpu.run_for(10), StopReason::Breakpoint);
+        assert_eq!(cpu.pc, 0x204);
+        assert_eq!(cpu.step(), StopReason::InstructionLimit);
+        assert_eq!(cpu.pc, 0x208);
+        assert_eq!(cpu.run_for(10), StopReason::Halted(42));
+    }
+
+    #[test]
+    fn test_ebreak() {
+        let mut cpu = cpu_with_program(&[0x00100073]);
+
+        assert_eq!(cpu.step(), StopReason::Breakpoint);
+        assert_eq!(cpu.pc, 0x200);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        // li a0, 42; sw a0, 256(zero); ret
+        let mut cpu = cpu_with_program(&[0x02a00513, 0x10a02023, 0x00008067]);
+        cpu.add_watchpoint(0x102, 1, Access::Store);
+
+        assert_eq!(cpu.run_for(10),
+                   StopReason::Watchpoint { addr: 0x100, access: Access::Store });
+        assert_eq!(cpu.pc, 0x208);
+        assert_eq!(cpu.bus.ram.get_u32(0x100), 42);
+    }
+
+    #[test]
+    fn test_illegal_instruction() {
+        let mut cpu = cpu_with_program(&[0xFFFFFFFF]);
+
+        assert_eq!(cpu.step(),
+                   StopReason::IllegalInstruction {
+                       pc: 0x200,
+                       raw: 0xFFFFFFFF,
+                   });
+    }
+
+    #[test]
+    fn test_disabled_extension() {
+        // mul a0, a0, a1; csrw misa, a2; mul a0, a0, a1
+        let mut cpu = cpu_with_program(&[0x02b50533, 0x30161073, 0x02b50533]);
+        cpu.set_isa(Isa::parse("rv32i").unwrap());
+        assert_eq!(cpu.get_csr(0x301), Ok(0x40000100));
+        assert_eq!(cpu.step(),
+                   StopReason::IllegalInstruction {
+                       pc: 0x200,
+                       raw: 0x02b50533,
+                   });
+
+        // M turned off at run time, after it's been cached
+        cpu.set_isa(Isa::default());
+        cpu.set_register(10, 6);
+        cpu.set_register(11, 7);
+        cpu.set_register(12, 0x40000100);
+        assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+        assert_eq!(cpu.get_register(10), 42);
+        assert_eq!(cpu.get_csr(0x301), Ok(0x40000100));
+        assert_eq!(cpu.step(),
+                   StopReason::IllegalInstruction {
+                       pc: 0x208,
+                       raw: 0x02b50533,
+                   });
+
+        // Only extensions the ISA has can be turned back on.
+        cpu.set_isa(Isa::parse("rv32im_zicsr").unwrap());
+        cpu.set_csr(0x301, 0xFFFFFFFF).unwrap();
+        assert_eq!(cpu.get_csr(0x301), Ok(0x40001100));
+    }
+
+    #[test]
+    fn test_rv32e() {
+        // li a5, 42; li a6, 42; csrw misa, zero
+        let mut cpu = cpu_with_program(&[0x02a00793, 0x02a00813, 0x30101073]);
+        cpu.set_isa(Isa::parse("rv32em_zicsr").unwrap());
+        assert_eq!(cpu.get_csr(0x301), Ok(0x40001010));
+        assert_eq!(cpu.step(), StopReason::InstructionLimit);
+        assert_eq!(cpu.get_register(15), 42);
+        assert_eq!(cpu.step(),
+                   StopReason::IllegalInstruction {
+                       pc: 0x204,
+                       raw: 0x02a00813,
+                   });
+
+        // The base can't be turned off.
+        cpu.pc = 0x208;
+        assert_eq!(cpu.step(), StopReason::InstructionLimit);
+        assert_eq!(cpu.get_csr(0x301), Ok(0x40000010));
+    }
+
+    #[test]
+    fn test_rv64() {
+        // addiw a0, a0, 1; sd a0, 0x100(zero); lwu a1, 0x104(zero); ld a2, 0x100(zero);
+        // mulw a3, a0, a0; divw a4, a0, a5; sll a5, a5, a1
+        let program = [0x0015051b, 0x10a03023, 0x10406583, 0x10003603, 0x02a506bb, 0x02f5473b,
+                       0x00b797b3];
+        let mut cpu = cpu_with_program(&program);
+        assert_eq!(cpu.step(),
+                   StopReason::IllegalInstruction {
+                       pc: 0x200,
+                       raw: 0x0015051b,
+                   });
+
+        cpu.set_isa(Isa::parse("rv64im").unwrap());
+        assert_eq!(cpu.xlen(), 64);
+        cpu.set_register(10, 0x7FFFFFFF);
+        cpu.set_register(15, !0);
+        assert_eq!(cpu.run_for(7), StopReason::InstructionLimit);
+        assert_eq!(cpu.get_register(10), 0xFFFFFFFF_80000000);
+        assert_eq!(cpu.get_register(11), 0xFFFFFFFF);
+        assert_eq!(cpu.get_register(12), 0xFFFFFFFF_80000000);
+        assert_eq!(cpu.get_register(13), 0);
+        assert_eq!(cpu.get_register(14), 0xFFFFFFFF_80000000);
+        assert_eq!(cpu.get_register(15), 0x80000000_00000000);
+
+        // Going back to RV32 drops the upper halves.
+        cpu.set_isa(Isa::default());
+        assert_eq!(cpu.get_register(10), 0x80000000);
+    }
+
+    #[test]
+    fn test_memory_fault() {
+        let mut cpu = cpu_with_program(&[0x00062583]); // lw a1, 0(a2)
+        cpu.set_register(12, 0x10000);
+
+        assert_eq!(cpu.step(),
+                   StopReason::MemoryFault {
+                       pc: 0x200,
+                       addr: 0x10000,
+                       access: Access::Load,
+                   });
+
+        cpu.pc = 0x10000;
+        assert_eq!(cpu.step(),
+                   StopReason::MemoryFault {
+                       pc: 0x10000,
+                       addr: 0x10000,
+                       access: Access::Fetch,
+                   });
+    }
+
+    #[test]
+    fn test_wfi() {
+        let mut cpu = cpu_with_program(&[0x10500073]);
+
+        assert_eq!(cpu.run_for(10), StopReason::Wfi);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut cpu = cpu_with_program(&[0x00100513, // li a0, 1
+                                         0x00029863, // bnez t0, 16
+                                         0x20602023, // sw t1, 0x200(zero)
+                                         0x00100293, // li t0, 1
+                                         0xff1ff06f, // j -16
+                                         0x00008067 /* ret */]);
+        cpu.set_register(6, 0x00200513); // li a0, 2
+
+        assert_eq!(cpu.run(0x200), StopReason::Halted(2));
+    }
+
+    #[test]
+    fn test_fence_i() {
+        let mut cpu = cpu_with_program(&[0x00100513, // li a0, 1
+                                         0x0000100f, // fence.i
+                                         0x00008067 /* ret */]);
+
+        assert_eq!(cpu.step(), StopReason::InstructionLimit);
+        // Bypass the CPU, so only FENCE.I makes this visible.
+        cpu.bus.write(0x200, 4, 0x00200513).unwrap(); // li a0, 2
+        assert_eq!(cpu.run(0x200), StopReason::Halted(1));
+        assert_eq!(cpu.run(0x200), StopReason::Halted(2));
+    }
+
+    #[test]
+    fn test_store_into_running_block() {
+        let mut cpu = cpu_with_program(&[0x20602623, // sw t1, 0x20c(zero)
+                                         0x00100513, // li a0, 1
+                                         0x00100513, // li a0, 1
+                                         0x00100513, // li a0, 1
+                                         0x00008067 /* ret */]);
+        cpu.set_register(6, 0x00200513); // li a0, 2
+
+        assert_eq!(cpu.run(0x200), StopReason::Halted(2));
+        assert_eq!(cpu.cycles(), 5);
+    }
+
+    #[test]
+    fn test_precise_trap_in_block() {
+        let program = [0x30559073, // csrw mtvec, a1
+                       0x00100513, // li a0, 1
+                       0x00062583, // lw a1, 0(a2)
+                       0x00300513, // li a0, 3
+                       0x00008067, // ret
+                       0x00000000,
+                       0x00000000,
+                       0x00000000,
+                       0x341026f3, // handler: csrr a3, mepc
+                       0x10500073 /* wfi */];
+
+        for &blocks in &[true, false] {
+            let mut cpu = cpu_with_program(&program);
+            cpu.set_block_dispatch_enabled(blocks);
+            cpu.set_register(11, 0x220);
+            cpu.set_register(12, 0x10000);
+
+            assert_eq!(cpu.run_for(100), StopReason::Wfi);
+            assert_eq!(cpu.get_register(10), 1);
+            assert_eq!(cpu.get_register(13), 0x208);
+            assert_eq!(cpu.get_csr(0x342), Ok(5));
+            assert_eq!(cpu.cycles(), 4);
+        }
+    }
+
+    #[test]
+    fn test_trap_handler() {
+        let mut cpu = cpu_with_program(&[0x30559073, // csrw mtvec, a1
+                                         0xFFFFFFFF,
+                                         0x00000000,
+                                         0x00000000,
+                                         0x34202673, // handler: csrr a2, mcause
+                                         0x341026f3, // csrr a3, mepc
+                                         0x30200073 /* mret */]);
+        cpu.set_register(11, 0x210);
+
+        assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+        assert_eq!(cpu.pc, 0x210);
+        assert_eq!(cpu.get_csr(0x343), Ok(0xFFFFFFFF));
+
+        assert_eq!(cpu.run_for(3), StopReason::InstructionLimit);
+        assert_eq!(cpu.get_register(12), 2);
+        assert_eq!(cpu.get_register(13), 0x204);
+        assert_eq!(cpu.pc, 0x204);
+        assert_eq!(cpu.privilege(), Privilege::Machine);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut cpu = cpu_with_program(&[0x30559073, // csrw mtvec, a1
+                                         0x30461073, // csrw mie, a2
+                                         0x30046073, // csrsi mstatus, MIE
+                                         0x00000013, // nop
+                                         0x342026f3, // handler: csrr a3, mcause
+                                         0x10500073 /* wfi */]);
+        cpu.set_register(11, 0x210);
+        cpu.set_register(12, 1 << 3);
+
+        // Pending and enabled in mie, but not globally
+        assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+        cpu.set_interrupt_pending(Interrupt::Software, true);
+        cpu.set_interrupt_pending(Interrupt::Timer, true);
+        assert!(cpu.interrupt_pending());
+        assert_eq!(cpu.run_for(1), StopReason::InstructionLimit);
+        assert_eq!(cpu.pc, 0x20C);
+
+        // Taken before the next instruction. The timer interrupt isn't enabled
+        // in mie, and the pending software interrupt stops WFI from waiting.
+        assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+        assert_eq!(cpu.get_register(13), 0x80000003);
+        assert_eq!(cpu.get_csr(0x341), Ok(0x20C));
+        assert_eq!(cpu.get_csr(0x344), Ok(0x88));
+        assert_eq!(cpu.get_csr(0x300).map(|mstatus| mstatus & 0x88), Ok(0x80));
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut cpu = cpu_with_program(&[0xb0259073, // csrw minstret, a1
+                                         0xc0202673, // rdinstret a2
+                                         0xc00026f3, // rdcycle a3
+                                         0x32071073, // csrw mcountinhibit, a4
+                                         0x00000013, // nop
+                                         0xb00027f3 /* csrr a5, mcycle */]);
+        cpu.set_register(11, 100);
+        cpu.set_register(14, 1);
+
+        // The write takes the place of its own instruction retiring, and
+        // an inhibited counter stops where it is.
+        assert_eq!(cpu.run_for(6), StopReason::InstructionLimit);
+        assert_eq!((cpu.get_register(12), cpu.get_register(13)), (100, 2));
+        assert_eq!(cpu.get_register(15), 4);
+        assert_eq!(cpu.get_csr(0xB02), Ok(105));
+        assert_eq!(cpu.cycles(), 6);
+
+        // U-mode can only read what mcounteren lets it.
+        cpu.set_csr(0x306, 0b1).unwrap();
+        cpu.privilege = Privilege::User;
+        assert_eq!(cpu.get_csr(0xC00), Ok(4));
+        assert_eq!(cpu.get_csr(0xC02), Err(Exception::IllegalInstruction(0)));
+        assert_eq!(cpu.get_csr(0xB00), Err(Exception::IllegalInstruction(0)));
+    }
+
+    #[test]
+    fn test_time() {
+        let mut cpu = cpu_with_program(&[0x00000013; 3]);
+        assert_eq!(cpu.run_for(3), StopReason::InstructionLimit);
+        assert_eq!((cpu.get_csr(0xC01), cpu.get_csr(0xC81)), (Ok(3), Ok(0)));
+        assert_eq!(cpu.get_csr(0xB01), Err(Exception::IllegalInstruction(0)));
+
+        let clint = Clint::new(1);
+        clint.set_mtime(0x1_0000_0005);
+        cpu.set_clint(clint);
+        cpu.set_csr(0x306, 0b10).unwrap();
+        cpu.privilege = Privilege::User;
+        assert_eq!((cpu.get_csr(0xC01), cpu.get_csr(0xC81)), (Ok(5), Ok(1)));
+        assert_eq!(cpu.get_csr(0xC00), Err(Exception::IllegalInstruction(0)));
+    }
+
+    #[test]
+    fn test_counter_overflow() {
+        let mut cpu = cpu_with_program(&[0x00002503, // lw a0, 0(zero)
+                                         0x00000013 /* nop */]);
+        // Count loads in mhpmcounter3, one short of overflowing.
+        cpu.set_csr(0x323, Event::Load as u64).unwrap();
+        cpu.set_csr(0xB03, 0xFFFF_FFFF).unwrap();
+        cpu.set_csr(0xB83, 0xFFFF_FFFF).unwrap();
+        cpu.set_csr(0x304, MIP_LCOFIP).unwrap();
+        cpu.set_csr(0x300, MSTATUS_MIE).unwrap();
+
+        assert_eq!(cpu.run_for(3), StopReason::Trap { cause: 0x8000000D });
+        assert_eq!((cpu.get_csr(0xB03), cpu.get_csr(0xB83)), (Ok(0), Ok(0)));
+        assert_eq!(cpu.get_csr(0x723), Ok(0x8000_0000));
+        assert_eq!(cpu.get_csr(0x344), Ok(MIP_LCOFIP));
+        cpu.set_csr(0x344, 0).unwrap();
+        assert!(!cpu.interrupt_pending());
+
+        // Without Sscofpmf counters still wrap, but quietly.
+        cpu.set_isa(Isa::parse("rv32i_zicsr_zihpm").unwrap());
+        cpu.set_csr(0x323, Event::Load as u64).unwrap();
+        cpu.set_csr(0xB03, 0xFFFF_FFFF).unwrap();
+        cpu.set_csr(0xB83, 0xFFFF_FFFF).unwrap();
+        cpu.pc = 0x200;
+        assert_eq!(cpu.step(), StopReason::InstructionLimit);
+        assert_eq!((cpu.get_csr(0xB03), cpu.get_csr(0x344)), (Ok(0), Ok(0)));
+        assert_eq!(cpu.get_csr(0x723), Ok(0));
+    }
+
+    #[test]
+    fn test_debug_mode() {
+        let mut cpu = cpu_with_program(&[0x00100073, // ebreak
+                                         0x00150513, // addi a0, a0, 1
+                                         0x00000013, // nop
+                                         0x7b200073 /* dret */]);
+        assert_eq!(cpu.get_csr(0x7B0), Err(Exception::IllegalInstruction(0)));
+
+        cpu.request_halt();
+        assert_eq!(cpu.step(), StopReason::Debug(DebugCause::HaltRequest));
+        assert!(cpu.in_debug_mode());
+        let dcsr = cpu.get_csr(0x7B0).unwrap();
+        assert_eq!((dcsr >> 6 & 0b111, dcsr & 0b11), (3, 3));
+        assert_eq!(cpu.get_csr(0x7B1), Ok(0x200));
+
+        // EBREAK goes to debug mode rather than stopping, and so does each
+        // single step.
+        cpu.set_csr(0x7B0, dcsr | 1 << 15 | 1 << 2).unwrap();
+        cpu.resume_from_debug_mode();
+        assert_eq!(cpu.run_for(10), StopReason::Debug(DebugCause::Ebreak));
+        assert_eq!((cpu.pc, cpu.get_csr(0x7B1)), (0x200, Ok(0x200)));
+        cpu.set_csr(0x7B1, 0x204).unwrap();
+        cpu.resume_from_debug_mode();
+        assert_eq!(cpu.run_for(10), StopReason::Debug(DebugCause::Step));
+        assert_eq!((cpu.pc, cpu.get_register(10)), (0x208, 1));
+
+        // Instructions run in debug mode one at a time, until DRET.
+        cpu.set_csr(0x7B0, 0).unwrap();
+        cpu.pc = 0x20C;
+        assert_eq!(cpu.step(), StopReason::InstructionLimit);
+        assert_eq!(cpu.pc, 0x208);
+        assert!(!cpu.in_debug_mode());
+        cpu.pc = 0x20C;
+        assert_eq!(cpu.step(),
+                   StopReason::IllegalInstruction { pc: 0x20C, raw: 0x7b200073 });
+
+        // Without Sdext, there's no debug mode to halt into.
+        cpu.set_isa(Isa::parse("rv32i_zicsr").unwrap());
+        cpu.request_halt();
+        cpu.pc = 0x204;
+        assert_eq!(cpu.step(), StopReason::InstructionLimit);
+    }
+
+    #[test]
+    fn test_triggers() {
+        let mut cpu = cpu_with_program(&[0x00150513, // addi a0, a0, 1
+                                         0x10a02023, // sw a0, 256(zero)
+                                         0x00000013 /* nop */]);
+        // A store trigger on 0x100 in M-mode, which needs tcontrol.mte
+        cpu.set_csr(0x7A5, 1 << 3).unwrap();
+        cpu.set_csr(0x7A1, 6 << 28 | 1 << 6 | 1 << 1).unwrap();
+        cpu.set_csr(0x7A2, 0x100).unwrap();
+        assert_eq!(cpu.run_for(10), StopReason::Breakpoint);
+        assert_eq!((cpu.pc, cpu.bus.ram.get_u32(0x100)), (0x204, 0));
+        assert_eq!(cpu.get_csr(0x7A1), Ok(6 << 28 | 1 << 22 | 1 << 6 | 1 << 1));
+
+        // Only debug mode can set dmode, which an execute trigger on 0x208
+        // that enters debug mode needs.
+        cpu.set_csr(0x7A1, 0).unwrap();
+        cpu.request_halt();
+        assert_eq!(cpu.step(), StopReason::Debug(DebugCause::HaltRequest));
+        cpu.set_csr(0x7A0, 1).unwrap();
+        cpu.set_csr(0x7A1, 6 << 28 | 1 << 27 | 1 << 12 | 1 << 6 | 1 << 2).unwrap();
+        cpu.set_csr(0x7A2, 0x208).unwrap();
+        cpu.resume_from_debug_mode();
+        assert_eq!(cpu.run_for(10), StopReason::Debug(DebugCause::Trigger));
+        assert_eq!((cpu.pc, cpu.bus.ram.get_u32(0x100)), (0x208, 1));
+        cpu.resume_from_debug_mode();
+
+        let tdata1 = cpu.get_csr(0x7A1).unwrap();
+        assert_eq!(tdata1 >> 27, 6 << 1 | 1);
+        cpu.set_csr(0x7A1, 0).unwrap();
+        assert_eq!(cpu.get_csr(0x7A1), Ok(tdata1));
+    }
+
+    #[test]
+    fn test_instruction_count_trigger() {
+        // addi a0, a0, 1, three times
+        let mut cpu = cpu_with_program(&[0x00150513, 0x00150513, 0x00150513]);
+        cpu.set_csr(0x305, 0x300).unwrap();
+        // Breakpoint after two M-mode instructions
+        cpu.set_csr(0x7A5, 1 << 3).unwrap();
+        cpu.set_csr(0x7A1, 3 << 28 | 2 << 10 | 1 << 9).unwrap();
+
+        assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+        assert_eq!((cpu.pc, cpu.get_register(10)), (0x300, 2));
+        assert_eq!((cpu.get_csr(0x341), cpu.get_csr(0x342)), (Ok(0x208), Ok(3)));
+        assert_eq!(cpu.get_csr(0x7A1), Ok(3 << 28 | 1 << 24 | 1 << 9));
+        // The handler can't hit M-mode breakpoints until it returns.
+        assert_eq!(cpu.get_csr(0x7A5), Ok(1 << 7));
     }
 }
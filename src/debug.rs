@@ -0,0 +1,584 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Debug mode (Sdext) and the trigger module (Sdtrig).
+//!
+//! A hart enters debug mode on EBREAK when `dcsr.ebreakm` or `dcsr.ebreaku`
+//! says so, when a trigger with action 1 fires, when the embedder asks it to
+//! halt, or after a single step. It then stops, and anything run before
+//! DRET (or `CPU::resume_from_debug_mode`) runs like a debugger's program
+//! buffer: in M-mode, with no interrupts or triggers, and stopping again at
+//! EBREAK or any exception rather than trapping.
+//!
+//! There are four triggers, each either an `mcontrol6` address or data
+//! match on execution, loads and stores, or an `icount` that fires once a
+//! number of instructions have retired. Loads match after reading memory
+//! but before writing the register. Doublewords match as the two words
+//! they reach the bus as.
+
+use cpu::Privilege;
+use snapshot::{Reader, SnapshotError, Writer};
+use trap::{Access, Exception};
+
+pub const TRIGGERS: usize = 4;
+
+// dcsr fields. debugver 4 is the 1.0 spec.
+const DCSR_DEBUGVER: u32 = 4 << 28;
+const DCSR_EBREAKM: u32 = 1 << 15;
+const DCSR_EBREAKU: u32 = 1 << 12;
+const DCSR_STEPIE: u32 = 1 << 11;
+const DCSR_CAUSE_SHIFT: u32 = 6;
+const DCSR_CAUSE: u32 = 0b111 << DCSR_CAUSE_SHIFT;
+const DCSR_STEP: u32 = 1 << 2;
+const DCSR_PRV: u32 = 0b11;
+
+// tcontrol: whether M-mode triggers can raise breakpoints, and what that
+// was before the last trap.
+const TCONTROL_MTE: u64 = 1 << 3;
+const TCONTROL_MPTE: u64 = 1 << 7;
+
+// Trigger types, in the top four bits of tdata1
+const TYPE_ICOUNT: u8 = 3;
+const TYPE_MCONTROL6: u8 = 6;
+const TYPE_DISABLED: u8 = 15;
+
+// mcontrol6 fields below the type and dmode
+const MCONTROL6_HIT0: u64 = 1 << 22;
+const MCONTROL6_SELECT: u64 = 1 << 21;
+const MCONTROL6_SIZE_SHIFT: u32 = 16;
+const MCONTROL6_ACTION_SHIFT: u32 = 12;
+const MCONTROL6_MATCH_SHIFT: u32 = 7;
+const MCONTROL6_M: u64 = 1 << 6;
+const MCONTROL6_U: u64 = 1 << 3;
+const MCONTROL6_EXECUTE: u64 = 1 << 2;
+const MCONTROL6_STORE: u64 = 1 << 1;
+const MCONTROL6_LOAD: u64 = 1 << 0;
+
+// icount fields below the type and dmode
+const ICOUNT_HIT: u64 = 1 << 24;
+const ICOUNT_COUNT_SHIFT: u32 = 10;
+const ICOUNT_COUNT: u64 = 0x3FFF << ICOUNT_COUNT_SHIFT;
+const ICOUNT_M: u64 = 1 << 9;
+const ICOUNT_U: u64 = 1 << 6;
+
+/// Why the hart entered debug mode, as `dcsr.cause` has it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugCause {
+    Ebreak = 1,
+    Trigger = 2,
+    HaltRequest = 3,
+    Step = 4,
+}
+
+impl DebugCause {
+    pub(crate) fn from_code(code: u8) -> Option<DebugCause> {
+        [DebugCause::Ebreak, DebugCause::Trigger, DebugCause::HaltRequest, DebugCause::Step]
+            .iter()
+            .cloned()
+            .find(|&cause| cause as u8 == code)
+    }
+}
+
+// What a trigger does when it fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    Breakpoint,
+    DebugMode,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Trigger {
+    typ: u8,
+    // Only debug mode can change the trigger.
+    dmode: bool,
+    // The rest of tdata1
+    control: u64,
+    tdata2: u64,
+}
+
+impl Trigger {
+    fn disabled() -> Trigger {
+        Trigger {
+            typ: TYPE_DISABLED,
+            dmode: false,
+            control: 0,
+            tdata2: 0,
+        }
+    }
+
+    fn action(&self) -> Action {
+        let action = match self.typ {
+            TYPE_MCONTROL6 => (self.control >> MCONTROL6_ACTION_SHIFT) & 0xF,
+            _ => self.control & 0x3F,
+        };
+        if action == 1 { Action::DebugMode } else { Action::Breakpoint }
+    }
+
+    // Whether the trigger is on in `privilege`.
+    fn enabled(&self, privilege: Privilege) -> bool {
+        let (m, u) = match self.typ {
+            TYPE_MCONTROL6 => (MCONTROL6_M, MCONTROL6_U),
+            TYPE_ICOUNT => (ICOUNT_M, ICOUNT_U),
+            _ => return false,
+        };
+        match privilege {
+            Privilege::Machine => self.control & m != 0,
+            Privilege::User => self.control & u != 0,
+        }
+    }
+
+    // Whether an mcontrol6 trigger matches `value`, an address or data.
+    fn matches(&self, value: u64, xlen: u32) -> bool {
+        let tdata2 = self.tdata2;
+        let kind = (self.control >> MCONTROL6_MATCH_SHIFT) & 0xF;
+        let matched = match kind & 0b111 {
+            0 => value == tdata2,
+            // Everything above the lowest clear bit of tdata2
+            1 => {
+                let bits = (tdata2.trailing_ones() + 1).min(xlen);
+                let mask = if bits == 64 { 0 } else { !0 << bits };
+                value & mask == tdata2 & mask
+            }
+            2 => value >= tdata2,
+            _ => value < tdata2,
+        };
+        matched != (kind & 0b1000 != 0)
+    }
+
+    // Whether an mcontrol6 trigger's size matches an access of `size`
+    // bytes.
+    fn size_matches(&self, size: u32) -> bool {
+        match (self.control >> MCONTROL6_SIZE_SHIFT) & 0b111 {
+            0 => true,
+            field => size == 1 << (field - 1).min(3),
+        }
+    }
+
+    fn hit(&mut self) {
+        self.control |= match self.typ {
+            TYPE_MCONTROL6 => MCONTROL6_HIT0,
+            _ => ICOUNT_HIT,
+        };
+    }
+}
+
+/// The debug-mode state and triggers of a hart.
+#[derive(Clone)]
+pub(crate) struct DebugState {
+    /// Whether the hart is in debug mode.
+    pub(crate) mode: bool,
+    /// Whether the embedder asked the hart to halt.
+    pub(crate) halt_request: bool,
+    dcsr: u32,
+    pub(crate) dpc: u64,
+    pub(crate) dscratch: [u64; 2],
+    tselect: usize,
+    tcontrol: u64,
+    triggers: [Trigger; TRIGGERS],
+    // Whether any trigger can fire
+    armed: bool,
+    // The action of the trigger that raised the breakpoint being taken
+    fired: Option<Action>,
+}
+
+impl DebugState {
+    pub(crate) fn new() -> DebugState {
+        DebugState {
+            mode: false,
+            halt_request: false,
+            dcsr: DCSR_DEBUGVER | Privilege::Machine as u32,
+            dpc: 0,
+            dscratch: [0; 2],
+            tselect: 0,
+            tcontrol: 0,
+            triggers: [Trigger::disabled(); TRIGGERS],
+            armed: false,
+            fired: None,
+        }
+    }
+
+    /// Whether instructions have to be run one at a time, for the triggers
+    /// or single-stepping.
+    pub(crate) fn single_step(&self) -> bool {
+        self.mode || self.armed || self.stepping()
+    }
+
+    /// Whether any trigger can fire.
+    pub(crate) fn armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Why the hart last entered debug mode.
+    pub(crate) fn cause(&self) -> DebugCause {
+        let code = (self.dcsr & DCSR_CAUSE) >> DCSR_CAUSE_SHIFT;
+        DebugCause::from_code(code as u8).unwrap_or(DebugCause::HaltRequest)
+    }
+
+    /// Whether the next instruction is a single step, after which the hart
+    /// enters debug mode.
+    pub(crate) fn stepping(&self) -> bool {
+        self.dcsr & DCSR_STEP != 0 && !self.mode
+    }
+
+    /// Whether interrupts can be taken while single-stepping.
+    pub(crate) fn step_interrupts(&self) -> bool {
+        self.dcsr & DCSR_STEPIE != 0
+    }
+
+    /// Whether EBREAK in `privilege` enters debug mode.
+    pub(crate) fn ebreak_enters(&self, privilege: Privilege) -> bool {
+        let bit = match privilege {
+            Privilege::Machine => DCSR_EBREAKM,
+            Privilege::User => DCSR_EBREAKU,
+        };
+        self.dcsr & bit != 0
+    }
+
+    /// Enter debug mode for `cause` from `privilege`, to resume at `pc`.
+    pub(crate) fn enter(&mut self, cause: DebugCause, pc: u32, privilege: Privilege) {
+        self.mode = true;
+        self.halt_request = false;
+        self.dpc = pc as u64;
+        self.dcsr = (self.dcsr & !(DCSR_CAUSE | DCSR_PRV)) |
+                    (cause as u32) << DCSR_CAUSE_SHIFT | privilege as u32;
+    }
+
+    /// The privilege level DRET returns to.
+    pub(crate) fn privilege(&self) -> Privilege {
+        match self.dcsr & DCSR_PRV {
+            0 => Privilege::User,
+            _ => Privilege::Machine,
+        }
+    }
+
+    pub(crate) fn dcsr(&self) -> u64 {
+        self.dcsr as u64
+    }
+
+    pub(crate) fn set_dcsr(&mut self, value: u64) {
+        let writable = DCSR_EBREAKM | DCSR_EBREAKU | DCSR_STEPIE | DCSR_STEP;
+        let mut dcsr = (self.dcsr & !writable) | (value as u32 & writable);
+        // Only M and U exist.
+        match value as u32 & DCSR_PRV {
+            0 => dcsr &= !DCSR_PRV,
+            3 => dcsr |= DCSR_PRV,
+            _ => {}
+        }
+        self.dcsr = dcsr;
+    }
+
+    pub(crate) fn tselect(&self) -> u64 {
+        self.tselect as u64
+    }
+
+    pub(crate) fn set_tselect(&mut self, value: u64) {
+        if value < TRIGGERS as u64 {
+            self.tselect = value as usize;
+        }
+    }
+
+    pub(crate) fn tcontrol(&self) -> u64 {
+        self.tcontrol
+    }
+
+    pub(crate) fn set_tcontrol(&mut self, value: u64) {
+        self.tcontrol = value & (TCONTROL_MTE | TCONTROL_MPTE);
+    }
+
+    /// Taking a trap into M-mode turns M-mode breakpoints off, so that a
+    /// handler can't trigger itself.
+    pub(crate) fn trap_entered(&mut self) {
+        let mte = self.tcontrol & TCONTROL_MTE;
+        self.tcontrol = mte << 4;
+    }
+
+    pub(crate) fn trap_returned(&mut self) {
+        let mpte = self.tcontrol & TCONTROL_MPTE;
+        self.tcontrol = mpte | mpte >> 4;
+    }
+
+    pub(crate) fn tdata1(&self, xlen: u32) -> u64 {
+        let trigger = &self.triggers[self.tselect];
+        (trigger.typ as u64) << (xlen - 4) | (trigger.dmode as u64) << (xlen - 5) |
+        trigger.control
+    }
+
+    /// Write tdata1 of the selected trigger. Unsupported types and fields
+    /// leave it disabled, and action 1 needs dmode, which only debug mode
+    /// can set.
+    pub(crate) fn set_tdata1(&mut self, value: u64, xlen: u32) {
+        let mode = self.mode;
+        let trigger = &mut self.triggers[self.tselect];
+        if trigger.dmode && !mode {
+            return;
+        }
+
+        let typ = (value >> (xlen - 4)) as u8;
+        let dmode = mode && value & (1 << (xlen - 5)) != 0;
+        let (mut control, action) = match typ {
+            TYPE_MCONTROL6 => {
+                let mut control = value &
+                                  (MCONTROL6_HIT0 | MCONTROL6_SELECT | MCONTROL6_M |
+                                   MCONTROL6_U | MCONTROL6_EXECUTE |
+                                   MCONTROL6_STORE | MCONTROL6_LOAD);
+                let size = (value >> MCONTROL6_SIZE_SHIFT) & 0b111;
+                if matches!(size, 1 | 2 | 3 | 5) {
+                    control |= size << MCONTROL6_SIZE_SHIFT;
+                }
+                let kind = (value >> MCONTROL6_MATCH_SHIFT) & 0xF;
+                if matches!(kind, 0 | 1 | 2 | 3 | 8 | 9) {
+                    control |= kind << MCONTROL6_MATCH_SHIFT;
+                }
+                (control, (value >> MCONTROL6_ACTION_SHIFT) & 0xF)
+            }
+            TYPE_ICOUNT => {
+                (value & (ICOUNT_HIT | ICOUNT_COUNT | ICOUNT_M | ICOUNT_U), value & 0x3F)
+            }
+            _ => {
+                *trigger = Trigger {
+                    tdata2: trigger.tdata2,
+                    ..Trigger::disabled()
+                };
+                self.update_armed();
+                return;
+            }
+        };
+        if action == 1 && dmode {
+            control |= match typ {
+                TYPE_MCONTROL6 => 1 << MCONTROL6_ACTION_SHIFT,
+                _ => 1,
+            };
+        }
+
+        trigger.typ = typ;
+        trigger.dmode = dmode;
+        trigger.control = control;
+        self.update_armed();
+    }
+
+    pub(crate) fn tdata2(&self) -> u64 {
+        self.triggers[self.tselect].tdata2
+    }
+
+    pub(crate) fn set_tdata2(&mut self, value: u64) {
+        let trigger = &mut self.triggers[self.tselect];
+        if !trigger.dmode || self.mode {
+            trigger.tdata2 = value;
+        }
+    }
+
+    /// The trigger types the selected trigger supports, and the version of
+    /// the spec.
+    pub(crate) fn tinfo(&self) -> u64 {
+        1 << 24 | 1 << TYPE_DISABLED | 1 << TYPE_MCONTROL6 | 1 << TYPE_ICOUNT
+    }
+
+    fn update_armed(&mut self) {
+        self.armed = self.triggers.iter().any(|trigger| trigger.typ != TYPE_DISABLED);
+    }
+
+    // Whether a trigger with `action` can fire in `privilege`. In M-mode,
+    // breakpoints need tcontrol.mte.
+    fn can_fire(&self, action: Action, privilege: Privilege) -> bool {
+        !self.mode &&
+        (action == Action::DebugMode || privilege == Privilege::User ||
+         self.tcontrol & TCONTROL_MTE != 0)
+    }
+
+    /// Check the execute triggers against the instruction `raw` of `size`
+    /// bytes at `pc`, before it runs.
+    pub(crate) fn check_execute(&mut self, pc: u32, raw: u32, size: u32, privilege: Privilege,
+                                xlen: u32)
+                                -> Result<(), Exception> {
+        if self.check(MCONTROL6_EXECUTE, pc as u64, raw as u64, size, privilege, xlen) {
+            return Err(Exception::Breakpoint(pc as u64));
+        }
+        Ok(())
+    }
+
+    /// Check the load or store triggers against an access of `size` bytes
+    /// of `value` at `addr`.
+    pub(crate) fn check_access(&mut self, addr: u32, size: u32, access: Access, value: u64,
+                               privilege: Privilege, xlen: u32)
+                               -> Result<(), Exception> {
+        let bit = if access == Access::Store { MCONTROL6_STORE } else { MCONTROL6_LOAD };
+        if self.check(bit, addr as u64, value, size, privilege, xlen) {
+            return Err(Exception::Breakpoint(addr as u64));
+        }
+        Ok(())
+    }
+
+    // Whether an mcontrol6 trigger on `bit` fires.
+    fn check(&mut self, bit: u64, addr: u64, data: u64, size: u32, privilege: Privilege,
+             xlen: u32)
+             -> bool {
+        for i in 0..TRIGGERS {
+            let trigger = self.triggers[i];
+            if trigger.typ != TYPE_MCONTROL6 || trigger.control & bit == 0 ||
+               !trigger.enabled(privilege) || !trigger.size_matches(size) {
+                continue;
+            }
+            let value = if trigger.control & MCONTROL6_SELECT != 0 { data } else { addr };
+            let action = trigger.action();
+            if trigger.matches(value, xlen) && self.can_fire(action, privilege) {
+                self.triggers[i].hit();
+                self.fired = Some(action);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Count an instruction that retired in `privilege` in the icount
+    /// triggers. Returns whether one fired.
+    pub(crate) fn count_instruction(&mut self, privilege: Privilege) -> bool {
+        if self.mode {
+            return false;
+        }
+        let mut fired = None;
+        for i in 0..TRIGGERS {
+            let trigger = self.triggers[i];
+            let count = (trigger.control & ICOUNT_COUNT) >> ICOUNT_COUNT_SHIFT;
+            if trigger.typ != TYPE_ICOUNT || count == 0 || !trigger.enabled(privilege) {
+                continue;
+            }
+            let trigger = &mut self.triggers[i];
+            trigger.control = (trigger.control & !ICOUNT_COUNT) |
+                              (count - 1) << ICOUNT_COUNT_SHIFT;
+            let action = trigger.action();
+            if count == 1 && fired.is_none() && self.can_fire(action, privilege) {
+                self.triggers[i].hit();
+                fired = Some(action);
+            }
+        }
+        self.fired = fired;
+        fired.is_some()
+    }
+
+    /// The action of the trigger behind the breakpoint being taken, if
+    /// it came from one.
+    pub(crate) fn take_fired(&mut self) -> Option<Action> {
+        self.fired.take()
+    }
+
+    pub(crate) fn save(&self, out: &mut Writer) {
+        out.u8(self.mode as u8);
+        out.u8(self.halt_request as u8);
+        out.u32(self.dcsr);
+        for &value in &[self.dpc, self.dscratch[0], self.dscratch[1], self.tcontrol] {
+            out.u64(value);
+        }
+        out.u8(self.tselect as u8);
+        for trigger in &self.triggers {
+            out.u8(trigger.typ);
+            out.u8(trigger.dmode as u8);
+            out.u64(trigger.control);
+            out.u64(trigger.tdata2);
+        }
+    }
+
+    pub(crate) fn load(input: &mut Reader) -> Result<DebugState, SnapshotError> {
+        let mut debug = DebugState::new();
+        debug.mode = input.u8()? != 0;
+        debug.halt_request = input.u8()? != 0;
+        debug.dcsr = input.u32()?;
+        debug.dpc = input.u64()?;
+        debug.dscratch = [input.u64()?, input.u64()?];
+        debug.tcontrol = input.u64()? & (TCONTROL_MTE | TCONTROL_MPTE);
+        debug.tselect = input.u8()? as usize;
+        if debug.tselect >= TRIGGERS {
+            return Err(SnapshotError::Corrupt("invalid trigger selected"));
+        }
+        for trigger in &mut debug.triggers {
+            trigger.typ = input.u8()?;
+            trigger.dmode = input.u8()? != 0;
+            trigger.control = input.u64()?;
+            trigger.tdata2 = input.u64()?;
+            if !matches!(trigger.typ, TYPE_ICOUNT | TYPE_MCONTROL6 | TYPE_DISABLED) {
+                return Err(SnapshotError::Corrupt("invalid trigger type"));
+            }
+        }
+        debug.update_armed();
+        Ok(debug)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An mcontrol6 trigger in M-mode and U-mode on `bits`, with `kind` of
+    // match and action 0.
+    fn mcontrol6(bits: u64, kind: u64) -> u64 {
+        (TYPE_MCONTROL6 as u64) << 28 | kind << MCONTROL6_MATCH_SHIFT | MCONTROL6_M | MCONTROL6_U |
+        bits
+    }
+
+    #[test]
+    fn test_address_match() {
+        let mut debug = DebugState::new();
+        debug.set_tcontrol(TCONTROL_MTE);
+        debug.set_tdata1(mcontrol6(MCONTROL6_STORE, 1), 32);
+        debug.set_tdata2(0x1007);
+
+        // Napot: 0x1000-0x100F
+        let store = |debug: &mut DebugState, addr| {
+            debug.check_access(addr, 4, Access::Store, 0, Privilege::Machine, 32)
+        };
+        assert_eq!(store(&mut debug, 0x0FFC), Ok(()));
+        assert_eq!(store(&mut debug, 0x100C), Err(Exception::Breakpoint(0x100C)));
+        assert_eq!(debug.take_fired(), Some(Action::Breakpoint));
+        assert_eq!(debug.tdata1(32) & MCONTROL6_HIT0, MCONTROL6_HIT0);
+        assert_eq!(debug.check_access(0x1000, 4, Access::Load, 0, Privilege::Machine, 32),
+                   Ok(()));
+
+        // Not in M-mode handlers, until they return.
+        debug.trap_entered();
+        assert_eq!(store(&mut debug, 0x1000), Ok(()));
+        debug.trap_returned();
+        assert_eq!(debug.tcontrol(), TCONTROL_MTE | TCONTROL_MPTE);
+        assert!(store(&mut debug, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_tdata1() {
+        let mut debug = DebugState::new();
+        assert_eq!(debug.tdata1(64), 15 << 60);
+
+        // Action 1 needs dmode, and an unknown match kind leaves it equal.
+        let value = mcontrol6(MCONTROL6_EXECUTE, 4) | 1 << MCONTROL6_ACTION_SHIFT;
+        debug.set_tdata1(value, 32);
+        assert_eq!(debug.tdata1(32), mcontrol6(MCONTROL6_EXECUTE, 0));
+        debug.mode = true;
+        debug.set_tdata1(value | 1 << 27, 32);
+        assert_eq!(debug.triggers[0].action(), Action::DebugMode);
+        debug.mode = false;
+        debug.set_tdata1(0, 32);
+        // Only debug mode can change it now.
+        assert_eq!(debug.tdata1(32) >> 28, TYPE_MCONTROL6 as u64);
+
+        debug.set_tselect(7);
+        assert_eq!(debug.tselect(), 0);
+        debug.set_tselect(1);
+        debug.set_tdata1(0x1234_5678, 32);
+        assert_eq!(debug.tdata1(32), 15 << 28);
+    }
+
+    #[test]
+    fn test_icount() {
+        let mut debug = DebugState::new();
+        let icount = (TYPE_ICOUNT as u64) << 28 | 2 << ICOUNT_COUNT_SHIFT | ICOUNT_U;
+        debug.set_tdata1(icount, 32);
+
+        assert!(!debug.count_instruction(Privilege::Machine));
+        assert!(!debug.count_instruction(Privilege::User));
+        assert!(debug.count_instruction(Privilege::User));
+        assert_eq!(debug.take_fired(), Some(Action::Breakpoint));
+        assert!(!debug.count_instruction(Privilege::User));
+        assert_eq!(debug.tdata1(32) & (ICOUNT_HIT | ICOUNT_COUNT), ICOUNT_HIT);
+    }
+}
@@ -0,0 +1,307 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+
+use instruction::Decoded;
+#[cfg(feature = "jit")]
+use jit::Native;
+
+const PAGE_SHIFT: u32 = 12;
+// Instructions can start at any halfword.
+const SLOTS_PER_PAGE: usize = 1 << (PAGE_SHIFT - 1);
+/// Blocks are never longer than this, so `run_for` budgets that are smaller
+/// than a block don't force the slow path too often.
+pub const MAX_BLOCK_LEN: usize = 64;
+// Start over rather than let invalidated blocks pile up forever.
+const MAX_BLOCKS: usize = 1 << 16;
+
+type Page = [Option<Decoded>; SLOTS_PER_PAGE];
+
+/// A basic block: straight-line instructions starting at `start`, none of
+/// which ends a block except possibly the last. Blocks never cross a page.
+pub struct Block {
+    pub start: u32,
+    // The address of the block's last byte
+    end: u32,
+    pub instrs: Vec<Decoded>,
+    valid: bool,
+    // The block that ran after this one last time, and the pc it started at.
+    next: Option<(u32, usize)>,
+    #[cfg(feature = "jit")]
+    pub native: Native,
+}
+
+/// Already-decoded instructions, keyed by physical address and grouped into
+/// 4 KiB pages, along with the basic blocks formed from them.
+///
+/// Stores made through the CPU invalidate the instructions and blocks they
+/// touch, and FENCE.I flushes everything, so self-modifying code sees its
+/// own writes.
+pub struct DecodeCache {
+    pages: Vec<Box<Page>>,
+    index: HashMap<u32, usize>,
+    // One bit per page of the address space, set if the page has an entry in
+    // `pages`. Lets stores to data pages skip the hash lookup.
+    code_pages: Vec<u64>,
+    // The most recently used page, since execution rarely leaves one.
+    last_page: u32,
+    last_index: usize,
+    blocks: Vec<Block>,
+    block_index: HashMap<u32, usize>,
+    page_blocks: HashMap<u32, Vec<usize>>,
+    // Bumped whenever a block is invalidated or the blocks are flushed, so
+    // the CPU can tell that the block it's running has gone stale.
+    generation: u64,
+}
+
+impl Default for DecodeCache {
+    fn default() -> DecodeCache {
+        DecodeCache::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache {
+            pages: Vec::new(),
+            index: HashMap::new(),
+            code_pages: vec![0; 1 << (32 - PAGE_SHIFT - 6)],
+            last_page: u32::MAX,
+            last_index: 0,
+            blocks: Vec::new(),
+            block_index: HashMap::new(),
+            page_blocks: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    fn is_code_page(&self, page: u32) -> bool {
+        self.code_pages[(page >> 6) as usize] & (1 << (page & 63)) != 0
+    }
+
+    fn page_index(&mut self, page: u32) -> Option<usize> {
+        if page == self.last_page {
+            return Some(self.last_index);
+        }
+
+        let index = *self.index.get(&page)?;
+        self.last_page = page;
+        self.last_index = index;
+        Some(index)
+    }
+
+    #[inline]
+    pub fn get(&mut self, addr: u32) -> Option<Decoded> {
+        let index = self.page_index(addr >> PAGE_SHIFT)?;
+        self.pages[index][((addr >> 1) as usize) & (SLOTS_PER_PAGE - 1)]
+    }
+
+    pub fn insert(&mut self, addr: u32, instr: Decoded) {
+        let page = addr >> PAGE_SHIFT;
+        let index = match self.page_index(page) {
+            Some(index) => index,
+            None => {
+                self.pages.push(Box::new([None; SLOTS_PER_PAGE]));
+                let index = self.pages.len() - 1;
+                self.index.insert(page, index);
+                self.code_pages[(page >> 6) as usize] |= 1 << (page & 63);
+                index
+            }
+        };
+
+        self.pages[index][((addr >> 1) as usize) & (SLOTS_PER_PAGE - 1)] = Some(instr);
+    }
+
+    /// Forget any instructions overlapping the `size` bytes written at `addr`,
+    /// including a 32-bit one that starts in the halfword before.
+    #[inline]
+    pub fn invalidate(&mut self, addr: u32, size: u32) {
+        let first = addr.wrapping_sub(2) & !1;
+        let last = addr.wrapping_add(size - 1);
+        if !self.is_code_page(first >> PAGE_SHIFT) && !self.is_code_page(last >> PAGE_SHIFT) {
+            return;
+        }
+
+        let mut half = first;
+        loop {
+            if let Some(index) = self.page_index(half >> PAGE_SHIFT) {
+                self.pages[index][((half >> 1) as usize) & (SLOTS_PER_PAGE - 1)] = None;
+            }
+
+            if half == last & !1 {
+                break;
+            }
+            half = half.wrapping_add(2);
+        }
+
+        self.invalidate_blocks(addr >> PAGE_SHIFT, addr, last);
+        if last >> PAGE_SHIFT != addr >> PAGE_SHIFT {
+            self.invalidate_blocks(last >> PAGE_SHIFT, addr, last);
+        }
+    }
+
+    fn invalidate_blocks(&mut self, page: u32, first: u32, last: u32) {
+        let blocks = &mut self.blocks;
+        let block_index = &mut self.block_index;
+        let generation = &mut self.generation;
+        if let Some(ids) = self.page_blocks.get_mut(&page) {
+            ids.retain(|&id| {
+                let block = &mut blocks[id];
+                if last < block.start || block.end < first {
+                    return true;
+                }
+
+                block.valid = false;
+                block_index.remove(&block.start);
+                *generation += 1;
+                false
+            });
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.pages.clear();
+        self.index.clear();
+        for bits in &mut self.code_pages {
+            *bits = 0;
+        }
+        self.last_page = u32::MAX;
+        self.flush_blocks();
+    }
+
+    /// Forget all basic blocks, but keep the decoded instructions.
+    pub fn flush_blocks(&mut self) {
+        self.blocks.clear();
+        self.block_index.clear();
+        self.page_blocks.clear();
+        self.generation += 1;
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    #[inline]
+    pub fn block(&self, id: usize) -> &Block {
+        &self.blocks[id]
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn block_mut(&mut self, id: usize) -> &mut Block {
+        &mut self.blocks[id]
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn code_pages(&self) -> *const u64 {
+        self.code_pages.as_ptr()
+    }
+
+    pub fn find_block(&self, start: u32) -> Option<usize> {
+        self.block_index.get(&start).cloned()
+    }
+
+    /// Add a block. Its instructions must already be in the cache, so that
+    /// stores to them invalidate it.
+    pub fn insert_block(&mut self, start: u32, instrs: Vec<Decoded>) -> usize {
+        if self.blocks.len() >= MAX_BLOCKS {
+            self.flush_blocks();
+        }
+
+        let size = instrs.iter().map(|instr| instr.size()).sum::<u32>();
+        let id = self.blocks.len();
+        self.blocks.push(Block {
+            start,
+            end: start.wrapping_add(size - 1),
+            instrs,
+            valid: true,
+            next: None,
+            #[cfg(feature = "jit")]
+            native: Native::Cold(0),
+        });
+        self.block_index.insert(start, id);
+        self.page_blocks.entry(start >> PAGE_SHIFT).or_default().push(id);
+        id
+    }
+
+    /// The block that followed `from` last time, if it started at `pc` and is
+    /// still valid.
+    #[inline]
+    pub fn chained(&self, from: usize, pc: u32) -> Option<usize> {
+        match self.blocks[from].next {
+            Some((start, id)) if start == pc && self.blocks[id].valid => Some(id),
+            _ => None,
+        }
+    }
+
+    pub fn chain(&mut self, from: usize, pc: u32, to: usize) {
+        self.blocks[from].next = Some((pc, to));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instruction;
+
+    #[test]
+    fn test_insert_and_invalidate() {
+        let mut cache = DecodeCache::new();
+        let addi = instruction::parse(0x02a00513).unwrap();
+
+        assert!(cache.get(0x1000).is_none());
+        cache.insert(0x1000, addi);
+        cache.insert(0x1ffc, addi);
+        cache.insert(0x2000, addi);
+        assert!(cache.get(0x1000).is_some());
+        assert!(cache.get(0x1004).is_none());
+
+        cache.invalidate(0x1002, 1);
+        assert!(cache.get(0x1000).is_none());
+
+        // Instructions can start at any halfword, and a store to the one
+        // after still hits them.
+        cache.insert(0x1006, addi);
+        assert!(cache.get(0x1004).is_none());
+        cache.invalidate(0x1008, 2);
+        assert!(cache.get(0x1006).is_none());
+
+        // A misaligned store straddling two pages hits both words.
+        cache.invalidate(0x1ffe, 4);
+        assert!(cache.get(0x1ffc).is_none());
+        assert!(cache.get(0x2000).is_none());
+
+        cache.insert(0x3000, addi);
+        cache.flush();
+        assert!(cache.get(0x3000).is_none());
+    }
+
+    #[test]
+    fn test_block_invalidation() {
+        let mut cache = DecodeCache::new();
+        let addi = instruction::parse(0x02a00513).unwrap();
+
+        for addr in (0x1000..0x1010).step_by(4) {
+            cache.insert(addr, addi);
+        }
+        let first = cache.insert_block(0x1000, vec![addi; 2]);
+        let second = cache.insert_block(0x1008, vec![addi; 2]);
+        cache.chain(first, 0x1008, second);
+        assert_eq!(cache.chained(first, 0x1008), Some(second));
+        assert_eq!(cache.chained(first, 0x100c), None);
+
+        let generation = cache.generation();
+        cache.invalidate(0x1010, 4);
+        assert_eq!(cache.generation(), generation);
+
+        cache.invalidate(0x100c, 1);
+        assert_ne!(cache.generation(), generation);
+        assert_eq!(cache.find_block(0x1000), Some(first));
+        assert_eq!(cache.find_block(0x1008), None);
+        assert_eq!(cache.chained(first, 0x1008), None);
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The floating point register file, and `fcsr`.
+//!
+//! Registers are 64 bits wide, and narrower values are NaN-boxed in them:
+//! every bit above the value is set. Reading a register as a width it
+//! doesn't hold a properly boxed value of gives the canonical NaN.
+
+use softfloat::HALF;
+
+/// The floating point unit's architectural state.
+#[derive(Clone, PartialEq, Eq)]
+pub struct FloatState {
+    regs: [u64; 32],
+    /// The dynamic rounding mode.
+    pub(crate) frm: u8,
+    /// The exception flags accrued since software last cleared them.
+    pub(crate) fflags: u8,
+}
+
+impl FloatState {
+    pub fn new() -> FloatState {
+        FloatState {
+            regs: [0; 32],
+            frm: 0,
+            fflags: 0,
+        }
+    }
+
+    /// The raw contents of register `reg`.
+    pub fn register(&self, reg: u8) -> u64 {
+        self.regs[reg as usize]
+    }
+
+    pub fn set_register(&mut self, reg: u8, value: u64) {
+        self.regs[reg as usize] = value;
+    }
+
+    /// Register `reg` as a half-precision number.
+    pub fn half(&self, reg: u8) -> u16 {
+        let value = self.regs[reg as usize];
+        if value >> 16 == !0 >> 16 {
+            value as u16
+        } else {
+            HALF.canonical_nan() as u16
+        }
+    }
+
+    /// Write a half-precision number to register `reg`, NaN-boxed.
+    pub fn set_half(&mut self, reg: u8, value: u16) {
+        self.regs[reg as usize] = !0xFFFF | value as u64;
+    }
+
+    pub fn frm(&self) -> u8 {
+        self.frm
+    }
+
+    pub fn fflags(&self) -> u8 {
+        self.fflags
+    }
+}
+
+impl Default for FloatState {
+    fn default() -> FloatState {
+        FloatState::new()
+    }
+}
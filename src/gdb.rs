@@ -0,0 +1,323 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A GDB remote serial protocol server over a replay, so that GDB's
+//! `reverse-stepi` and `reverse-continue` (the `bs` and `bc` packets) work as
+//! well as going forwards.
+//!
+//! It speaks just enough of the protocol for that: reading registers and RAM,
+//! software breakpoints, and stepping and continuing either way. Nothing can
+//! be written, since changing the machine would make the replay diverge.
+//! Each hart is a thread, numbered from 1. GDB takes the XLEN from the ELF
+//! it's debugging, and expects x0-x31 and then the pc in a `g` reply.
+
+use std::io::{self, Read, Write};
+
+use replay::Replayer;
+use trap::StopReason;
+
+const FEATURES: &str = "PacketSize=1000;QStartNoAckMode+;ReverseStep+;ReverseContinue+;\
+                        swbreak+";
+// Half the packet size, since each byte read takes two hex digits.
+const MAX_READ: usize = 0x800;
+const ERROR: &str = "E01";
+
+struct Session<'a, S> {
+    replayer: &'a mut Replayer,
+    stream: S,
+    // The hart register reads come from.
+    hart: usize,
+    ack: bool,
+    stop: String,
+}
+
+/// Serve one GDB connection on `stream`, until GDB detaches or kills the
+/// target or closes the connection.
+pub fn serve<S: Read + Write>(replayer: &mut Replayer, stream: S) -> io::Result<()> {
+    let mut session = Session {
+        replayer,
+        stream,
+        hart: 0,
+        ack: true,
+        stop: "S05".to_string(),
+    };
+    while let Some(packet) = session.read_packet()? {
+        let packet = String::from_utf8_lossy(&packet).into_owned();
+        match session.handle(&packet) {
+            Some(reply) => session.write_packet(&reply)?,
+            None => break,
+        }
+        // Detaching gets an OK before the session ends, unlike killing.
+        if packet == "D" {
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl<'a, S: Read + Write> Session<'a, S> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // The next packet with a good checksum, or `None` once the connection
+    // closes.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // Skip acks, and the interrupt byte: nothing runs for long enough
+            // to need interrupting.
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => {}
+                    None => return Ok(None),
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut digits = [0; 2];
+            for digit in &mut digits {
+                *digit = match self.read_byte()? {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+            }
+
+            let expected = String::from_utf8_lossy(&digits);
+            let good = u8::from_str_radix(&expected, 16).ok() == Some(checksum(&data));
+            if self.ack {
+                self.stream.write_all(if good { b"+" } else { b"-" })?;
+            }
+            if good {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+
+    // The reply to `packet`, or `None` to end the session.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        Some(match command {
+            "?" => self.stop.clone(),
+            "q" | "Q" => self.query(packet),
+            "H" => {
+                // Hg picks the hart registers are read from. Continuing and
+                // stepping always run every hart.
+                match (args.get(..1), args.get(1..).and_then(parse_thread)) {
+                    (Some("g"), Some(Some(hart))) if hart < self.harts() => {
+                        self.hart = hart;
+                        "OK".to_string()
+                    }
+                    (Some(_), Some(None)) | (Some("c"), Some(_)) => "OK".to_string(),
+                    _ => ERROR.to_string(),
+                }
+            }
+            "T" => {
+                match parse_thread(args) {
+                    Some(Some(hart)) if hart < self.harts() => "OK".to_string(),
+                    _ => ERROR.to_string(),
+                }
+            }
+            "g" => {
+                let registers = (0..33).map(|reg| self.register(reg).unwrap());
+                registers.collect()
+            }
+            "p" => {
+                match usize::from_str_radix(args, 16).ok().and_then(|reg| self.register(reg)) {
+                    Some(value) => value,
+                    None => ERROR.to_string(),
+                }
+            }
+            "m" => self.read_memory(args).unwrap_or_else(|| ERROR.to_string()),
+            // Writing anything would make the replay diverge.
+            "G" | "P" | "M" | "X" => ERROR.to_string(),
+            "c" => {
+                let result = self.replayer.resume();
+                self.forward_stop(result)
+            }
+            "s" => self.step(),
+            "b" if args == "c" => {
+                match self.replayer.reverse_continue() {
+                    Ok(Some(hart)) => self.stopped(hart, "swbreak:;"),
+                    Ok(None) => self.stopped(0, "replaylog:begin;"),
+                    Err(_) => ERROR.to_string(),
+                }
+            }
+            "b" if args == "s" => {
+                let hart = self.hart;
+                match self.replayer.reverse_step() {
+                    Ok(true) => self.stopped(hart, ""),
+                    Ok(false) => self.stopped(hart, "replaylog:begin;"),
+                    Err(_) => ERROR.to_string(),
+                }
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args).unwrap_or_default(),
+            "D" => "OK".to_string(),
+            "k" => return None,
+            _ => String::new(),
+        })
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        let name = packet.split([':', ',']).next().unwrap_or("");
+        match name {
+            "qSupported" => FEATURES.to_string(),
+            "QStartNoAckMode" => {
+                // The ack for this packet has already gone.
+                self.ack = false;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => format!("QC{:x}", self.hart + 1),
+            "qfThreadInfo" => {
+                let threads: Vec<String> =
+                    (1..self.harts() + 1).map(|thread| format!("{:x}", thread)).collect();
+                format!("m{}", threads.join(","))
+            }
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn harts(&self) -> usize {
+        self.replayer.machine().harts.len() + 1
+    }
+
+    // The selected hart's x0-x31 (numbers 0-31) or pc (32), in target byte
+    // order and XLEN wide. Registers an RV32E hart doesn't have are
+    // unavailable.
+    fn register(&self, reg: usize) -> Option<String> {
+        let machine = self.replayer.machine();
+        let cpu = if self.hart == 0 { &machine.cpu } else { &machine.harts[self.hart - 1] };
+        let bytes = cpu.xlen() as usize / 8;
+        let value = match reg {
+            _ if reg < cpu.registers() as usize => cpu.get_register(reg as u8),
+            _ if reg < 32 => return Some("xx".repeat(bytes)),
+            32 => cpu.pc as u64,
+            _ => return None,
+        };
+        Some((0..bytes).map(|byte| format!("{:02x}", (value >> (8 * byte)) as u8)).collect())
+    }
+
+    // `m addr,length`. Only RAM can be read: reading a device would take the
+    // next value out of the log.
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let mut fields = args.split(',');
+        let addr = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let length = usize::from_str_radix(fields.next()?, 16).ok()?.min(MAX_READ);
+        let bus = &self.replayer.machine().cpu.bus;
+        if !bus.is_ram(addr, length as u32) {
+            return None;
+        }
+
+        let offset = addr.wrapping_sub(bus.ram_base()) as usize;
+        let bytes = &bus.ram.as_slice()[offset..offset + length];
+        Some(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    // `Z0,addr,kind` or `z0,addr,kind`. Only software breakpoints are
+    // supported, and they're set on every hart.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Option<String> {
+        let mut fields = args.split(',');
+        if fields.next()? != "0" {
+            return None;
+        }
+        let addr = u32::from_str_radix(fields.next()?, 16).ok()?;
+
+        let machine = self.replayer.machine_mut();
+        for hart in Some(&mut machine.cpu).into_iter().chain(&mut machine.harts) {
+            if insert {
+                hart.add_breakpoint(addr);
+            } else {
+                hart.remove_breakpoint(addr);
+            }
+        }
+        Some("OK".to_string())
+    }
+
+    // A step always runs an instruction, even when it starts on a
+    // breakpoint.
+    fn step(&mut self) -> String {
+        let instret = self.replayer.instret();
+        let result = match self.replayer.run_for(1) {
+            Ok((_, StopReason::Breakpoint)) if self.replayer.instret() == instret => {
+                self.replayer.run_for(1)
+            }
+            result => result,
+        };
+        self.forward_stop(result)
+    }
+
+    fn forward_stop<E>(&mut self, result: Result<(usize, StopReason), E>) -> String {
+        match result {
+            Ok((hart, _)) if self.replayer.instret() >= self.replayer.end_instret() => {
+                self.stopped(hart, "replaylog:end;")
+            }
+            Ok((hart, StopReason::Breakpoint)) => self.stopped(hart, "swbreak:;"),
+            Ok((hart, _)) => self.stopped(hart, ""),
+            Err(_) => ERROR.to_string(),
+        }
+    }
+
+    // A stop reply for a SIGTRAP on `hart`, which also becomes the hart
+    // registers are read from.
+    fn stopped(&mut self, hart: usize, why: &str) -> String {
+        self.hart = hart;
+        self.stop = format!("T05{}thread:{:x};", why, hart + 1);
+        self.stop.clone()
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+// A thread ID, which is a hart plus one, or `Some(None)` for 0 (any thread)
+// and -1 (all of them).
+fn parse_thread(id: &str) -> Option<Option<usize>> {
+    match id {
+        "0" | "-1" => Some(None),
+        _ => {
+            match usize::from_str_radix(id, 16) {
+                Ok(thread) if thread > 0 => Some(Some(thread - 1)),
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_thread() {
+        assert_eq!(parse_thread("1"), Some(Some(0)));
+        assert_eq!(parse_thread("a"), Some(Some(9)));
+        assert_eq!(parse_thread("0"), Some(None));
+        assert_eq!(parse_thread("-1"), Some(None));
+        assert_eq!(parse_thread("-2"), None);
+        assert_eq!(checksum(b"OK"), 0x9a);
+    }
+}
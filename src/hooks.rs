@@ -0,0 +1,183 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use cpu::CPU;
+use instruction::Instruction;
+use trap::Access;
+
+/// What a hook wants to happen after it has run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    Continue,
+    /// Stop execution with `StopReason::Hook`.
+    Stop,
+    /// Replace a value. What that means depends on the hook:
+    ///
+    /// * before an instruction: execute this raw instruction word instead.
+    /// * memory and CSR accesses: the value read or written.
+    /// * ECALL: the call has been handled; set a0 to this value and resume
+    ///   after the ECALL instead of trapping.
+    ///
+    /// Other hooks treat it like `Continue`.
+    Override(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub addr: u32,
+    pub size: u32,
+    /// The value read or about to be written, including any overrides made by
+    /// earlier hooks.
+    pub value: u32,
+    pub access: Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsrAccess {
+    pub csr: u16,
+    pub value: u64,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapEvent {
+    /// The address of the instruction that raised the exception.
+    pub pc: u32,
+    pub cause: u64,
+    pub tval: u64,
+}
+
+pub type InstructionHook = Box<dyn FnMut(&CPU, u32, &dyn Instruction) -> HookAction>;
+pub type MemoryHook = Box<dyn FnMut(&MemoryAccess) -> HookAction>;
+pub type CsrHook = Box<dyn FnMut(&CsrAccess) -> HookAction>;
+pub type TrapHook = Box<dyn FnMut(&CPU, &TrapEvent) -> HookAction>;
+pub type EcallHook = Box<dyn FnMut(&mut CPU) -> HookAction>;
+
+/// Callbacks run by the CPU as it executes. Register them through
+/// `CPU::hooks_mut`; hooks of the same kind run in the order they were added.
+#[derive(Default)]
+pub struct Hooks {
+    before_instruction: Vec<InstructionHook>,
+    after_instruction: Vec<InstructionHook>,
+    memory: Vec<MemoryHook>,
+    csr: Vec<CsrHook>,
+    trap: Vec<TrapHook>,
+    ecall: Vec<EcallHook>,
+}
+
+/// Combine the actions of several hooks: any `Stop` wins, otherwise the last
+/// `Override` does.
+fn combine(current: HookAction, next: HookAction) -> HookAction {
+    match (current, next) {
+        (HookAction::Stop, _) | (_, HookAction::Stop) => HookAction::Stop,
+        (_, HookAction::Override(value)) => HookAction::Override(value),
+        (current, HookAction::Continue) => current,
+    }
+}
+
+impl Hooks {
+    /// Called with the pc and decoded instruction before it executes.
+    pub fn on_before_instruction<F>(&mut self, hook: F)
+        where F: FnMut(&CPU, u32, &dyn Instruction) -> HookAction + 'static
+    {
+        self.before_instruction.push(Box::new(hook));
+    }
+
+    /// Called with the instruction's pc once it has executed without raising
+    /// an exception.
+    pub fn on_after_instruction<F>(&mut self, hook: F)
+        where F: FnMut(&CPU, u32, &dyn Instruction) -> HookAction + 'static
+    {
+        self.after_instruction.push(Box::new(hook));
+    }
+
+    /// Called for every successful data load and store.
+    pub fn on_memory_access<F>(&mut self, hook: F)
+        where F: FnMut(&MemoryAccess) -> HookAction + 'static
+    {
+        self.memory.push(Box::new(hook));
+    }
+
+    /// Called for every CSR read and write made by a CSR instruction.
+    pub fn on_csr_access<F>(&mut self, hook: F)
+        where F: FnMut(&CsrAccess) -> HookAction + 'static
+    {
+        self.csr.push(Box::new(hook));
+    }
+
+    /// Called before an exception is delivered to the guest's trap handler,
+    /// or stops execution for lack of one.
+    pub fn on_trap<F>(&mut self, hook: F)
+        where F: FnMut(&CPU, &TrapEvent) -> HookAction + 'static
+    {
+        self.trap.push(Box::new(hook));
+    }
+
+    /// Called on ECALL before anything else happens, with the pc still
+    /// pointing at the ECALL.
+    pub fn on_ecall<F>(&mut self, hook: F)
+        where F: FnMut(&mut CPU) -> HookAction + 'static
+    {
+        self.ecall.push(Box::new(hook));
+    }
+
+    pub(crate) fn before_instruction(&mut self,
+                                     cpu: &CPU,
+                                     pc: u32,
+                                     instr: &dyn Instruction)
+                                     -> HookAction {
+        self.before_instruction
+            .iter_mut()
+            .fold(HookAction::Continue, |action, hook| combine(action, hook(cpu, pc, instr)))
+    }
+
+    pub(crate) fn after_instruction(&mut self,
+                                    cpu: &CPU,
+                                    pc: u32,
+                                    instr: &dyn Instruction)
+                                    -> HookAction {
+        self.after_instruction
+            .iter_mut()
+            .fold(HookAction::Continue, |action, hook| combine(action, hook(cpu, pc, instr)))
+    }
+
+    pub(crate) fn memory(&mut self, access: &mut MemoryAccess) -> HookAction {
+        let mut action = HookAction::Continue;
+        for hook in &mut self.memory {
+            let next = hook(access);
+            if let HookAction::Override(value) = next {
+                access.value = value as u32;
+            }
+            action = combine(action, next);
+        }
+        action
+    }
+
+    pub(crate) fn csr(&mut self, access: &mut CsrAccess) -> HookAction {
+        let mut action = HookAction::Continue;
+        for hook in &mut self.csr {
+            let next = hook(access);
+            if let HookAction::Override(value) = next {
+                access.value = value;
+            }
+            action = combine(action, next);
+        }
+        action
+    }
+
+    pub(crate) fn trap(&mut self, cpu: &CPU, event: &TrapEvent) -> HookAction {
+        self.trap
+            .iter_mut()
+            .fold(HookAction::Continue, |action, hook| combine(action, hook(cpu, event)))
+    }
+
+    pub(crate) fn ecall(&mut self, cpu: &mut CPU) -> HookAction {
+        self.ecall
+            .iter_mut()
+            .fold(HookAction::Continue, |action, hook| combine(action, hook(cpu)))
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The host-target interface (HTIF) used by Spike, riscv-tests and
+//! proxy-kernel programs to talk to the host.
+//!
+//! A program writes a command to the 64-bit `tohost` word: a device number in
+//! bits 56 to 63, a command in bits 48 to 55 and a payload below that. The
+//! host clears `tohost` once it has taken the command, and answers in
+//! `fromhost` in the same format when there's an answer to give.
+//!
+//! * Device 0, command 0 with an odd payload exits with the payload shifted
+//!   right by one. With an even payload, the payload points at eight 64-bit
+//!   words holding a system call number and its arguments; the result
+//!   replaces the number, and `fromhost` is set to 1.
+//! * Device 1 is the console: command 1 writes the low byte of the payload,
+//!   and command 0 asks for a character, which comes back in `fromhost`.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use bus::{half, Device, Memory};
+use snapshot::{Reader, Writer};
+
+const SYS_CLOSE: u32 = 57;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+
+const EBADF: u32 = 9;
+const ENOSYS: u32 = 38;
+
+struct State {
+    tohost: u64,
+    fromhost: u64,
+    // Set between a write to the low half of `tohost` and one to the high
+    // half.
+    low_written: bool,
+    // Set when `tohost` holds a complete command.
+    ready: bool,
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+}
+
+/// The `tohost` and `fromhost` registers and the host side of the protocol.
+///
+/// Like the `Clint`, cloning an `Htif` gives another handle to the same
+/// state, and `tohost_device` and `fromhost_device` give the registers to map
+/// on the bus, usually over the program's `tohost` and `fromhost` symbols.
+#[derive(Clone)]
+pub struct Htif {
+    state: Rc<RefCell<State>>,
+}
+
+impl Default for Htif {
+    fn default() -> Htif {
+        Htif::new()
+    }
+}
+
+impl Htif {
+    /// An HTIF whose console is the host's standard input and output.
+    pub fn new() -> Htif {
+        Htif {
+            state: Rc::new(RefCell::new(State {
+                tohost: 0,
+                fromhost: 0,
+                low_written: false,
+                ready: false,
+                input: Box::new(io::stdin()),
+                output: Box::new(io::stdout()),
+            })),
+        }
+    }
+
+    /// Read console input and system call `read`s on standard input from
+    /// `input`, and send console output and `write`s to standard output or
+    /// error to `output`.
+    pub fn set_console(&self, input: Box<dyn Read>, output: Box<dyn Write>) {
+        let mut state = self.state.borrow_mut();
+        state.input = input;
+        state.output = output;
+    }
+
+    pub fn tohost_device(&self) -> Box<dyn Device> {
+        Box::new(Register {
+            htif: self.clone(),
+            fromhost: false,
+        })
+    }
+
+    pub fn fromhost_device(&self) -> Box<dyn Device> {
+        Box::new(Register {
+            htif: self.clone(),
+            fromhost: true,
+        })
+    }
+}
+
+impl State {
+    // Carry out the command in `tohost`, returning an exit code if it was
+    // one to exit.
+    fn command(&mut self, memory: &mut Memory) -> Option<u32> {
+        let command = self.tohost;
+        if command == 0 {
+            return None;
+        }
+        self.tohost = 0;
+
+        let device = (command >> 56) as u8;
+        let cmd = (command >> 48) as u8;
+        let payload = command & 0xFFFF_FFFF_FFFF;
+        match (device, cmd) {
+            (0, 0) if payload & 1 == 1 => return Some((payload >> 1) as u32),
+            (0, 0) => {
+                if let Some(code) = self.syscall(payload as u32, memory) {
+                    return Some(code);
+                }
+                self.fromhost = 1;
+            }
+            (1, 0) => {
+                let mut byte = [0];
+                if let Ok(1) = self.input.read(&mut byte) {
+                    self.fromhost = 1 << 56 | 0x100 | byte[0] as u64;
+                }
+            }
+            (1, 1) => {
+                let _ = self.output.write_all(&[payload as u8]);
+                let _ = self.output.flush();
+            }
+            _ => {}
+        }
+        None
+    }
+
+    // Proxy the system call described at `addr`, putting the result in its
+    // first word, unless it exits.
+    fn syscall(&mut self, addr: u32, memory: &mut Memory) -> Option<u32> {
+        let arg = |n: u32| memory.read(addr.wrapping_add(8 * n), 4).unwrap_or(0);
+        let (number, fd, buf, len) = (arg(0), arg(1), arg(2), arg(3));
+
+        let result = match number {
+            SYS_EXIT | SYS_EXIT_GROUP => return Some(fd),
+            SYS_WRITE if fd == 1 || fd == 2 => {
+                let bytes: Vec<u8> = (0..len)
+                    .map_while(|i| memory.read(buf.wrapping_add(i), 1).map(|byte| byte as u8))
+                    .collect();
+                let _ = self.output.write_all(&bytes);
+                let _ = self.output.flush();
+                bytes.len() as u32
+            }
+            SYS_READ if fd == 0 => {
+                // A short read is fine, and never takes input there's no RAM
+                // left to put in.
+                let mut bytes = vec![0; len.min(memory.remaining(buf)) as usize];
+                let count = self.input.read(&mut bytes).unwrap_or(0);
+                for (i, &byte) in bytes[..count].iter().enumerate() {
+                    memory.write(buf.wrapping_add(i as u32), 1, byte as u32);
+                }
+                count as u32
+            }
+            SYS_CLOSE if fd <= 2 => 0,
+            SYS_WRITE | SYS_READ | SYS_CLOSE => EBADF.wrapping_neg(),
+            _ => ENOSYS.wrapping_neg(),
+        };
+
+        // The result is a sign-extended 64-bit word.
+        memory.write(addr, 4, result);
+        memory.write(addr.wrapping_add(4), 4, ((result as i32) >> 31) as u32);
+        None
+    }
+}
+
+struct Register {
+    htif: Htif,
+    fromhost: bool,
+}
+
+impl Device for Register {
+    fn read(&mut self, offset: u32, size: u32) -> Option<u32> {
+        if size != 4 || offset & 0b11 != 0 {
+            return None;
+        }
+        let state = self.htif.state.borrow();
+        let value = if self.fromhost { state.fromhost } else { state.tohost };
+        Some((value >> half(offset)) as u32)
+    }
+
+    // A 32-bit program writes the low half of `tohost` and then the high
+    // half, so the command is only complete after the second write. Older
+    // riscv-tests only ever write the low half, over and over, so a second
+    // write to it in a row also completes one.
+    fn write(&mut self, offset: u32, size: u32, value: u32) -> Option<()> {
+        if size != 4 || offset & 0b11 != 0 {
+            return None;
+        }
+        let mut state = self.htif.state.borrow_mut();
+        let shift = half(offset);
+        if self.fromhost {
+            state.fromhost = (state.fromhost & !(0xFFFF_FFFF << shift)) | ((value as u64) << shift);
+            return Some(());
+        }
+
+        state.tohost = (state.tohost & !(0xFFFF_FFFF << shift)) | ((value as u64) << shift);
+        if shift == 0 && !state.low_written {
+            state.low_written = true;
+        } else {
+            state.low_written = false;
+            state.ready = true;
+        }
+        Some(())
+    }
+
+    fn after_write(&mut self, memory: &mut Memory) -> Option<u32> {
+        let mut state = self.htif.state.borrow_mut();
+        if !state.ready {
+            return None;
+        }
+        state.ready = false;
+        state.command(memory)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        if self.fromhost {
+            return Vec::new();
+        }
+        let state = self.htif.state.borrow();
+        let mut out = Writer::new();
+        out.u64(state.tohost);
+        out.u64(state.fromhost);
+        out.u8(state.low_written as u8);
+        out.into_inner()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Option<()> {
+        if self.fromhost {
+            return if data.is_empty() { Some(()) } else { None };
+        }
+        let mut input = Reader::new(data);
+        let mut state = self.htif.state.borrow_mut();
+        state.tohost = input.u64().ok()?;
+        state.fromhost = input.u64().ok()?;
+        state.low_written = input.u8().ok()? != 0;
+        if !input.is_empty() {
+            return None;
+        }
+        Some(())
+    }
+}
@@ -0,0 +1,420 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zkne and Zknd: AES encryption and decryption. The RV32 instructions do a
+//! byte of a round at a time, and RV64's half of the state; the two
+//! extensions share RV64's key schedule instructions.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use isa::Extension;
+use trap::Exception;
+
+// The round constants for AES64KS1I's round numbers. Round number 10 is only
+// used by AES-256, which doesn't want one.
+const ROUND_CONSTANTS: [u8; 11] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36,
+                                   0x00];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+    // The byte of src2 for the RV32 instructions, or AES64KS1I's round number
+    select: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    EncryptFinal32,
+    EncryptMiddle32,
+    DecryptFinal32,
+    DecryptMiddle32,
+    EncryptFinal64,
+    EncryptMiddle64,
+    DecryptFinal64,
+    DecryptMiddle64,
+    InverseMixColumns64,
+    KeySchedule1,
+    KeySchedule2,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        let typ = match (decoded.opcode, decoded.funct7, decoded.funct3) {
+            (0x33, funct7, 0b000) if funct7 & 0x1F == 0x11 => OperationType::EncryptFinal32,
+            (0x33, funct7, 0b000) if funct7 & 0x1F == 0x13 => OperationType::EncryptMiddle32,
+            (0x33, funct7, 0b000) if funct7 & 0x1F == 0x15 => OperationType::DecryptFinal32,
+            (0x33, funct7, 0b000) if funct7 & 0x1F == 0x17 => OperationType::DecryptMiddle32,
+            (0x33, 0x19, 0b000) => OperationType::EncryptFinal64,
+            (0x33, 0x1B, 0b000) => OperationType::EncryptMiddle64,
+            (0x33, 0x1D, 0b000) => OperationType::DecryptFinal64,
+            (0x33, 0x1F, 0b000) => OperationType::DecryptMiddle64,
+            (0x33, 0x3F, 0b000) => OperationType::KeySchedule2,
+            (0x13, 0x18, 0b001) if decoded.rs2 == 0 => OperationType::InverseMixColumns64,
+            (0x13, 0x18, 0b001) if decoded.rs2 & 0x10 != 0 && decoded.rs2 & 0xF <= 0xA => {
+                OperationType::KeySchedule1
+            }
+            _ => return None,
+        };
+
+        let immediate = decoded.opcode == 0x13;
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: if immediate { 0 } else { decoded.rs2 },
+            select: if immediate { decoded.rs2 & 0xF } else { decoded.funct7 >> 5 },
+        })
+    }
+
+    /// Zkne for encryption, Zknd for decryption, and either for the key
+    /// schedule.
+    pub fn extensions(&self) -> &'static [Extension] {
+        match self.typ {
+            OperationType::EncryptFinal32 | OperationType::EncryptMiddle32 |
+            OperationType::EncryptFinal64 | OperationType::EncryptMiddle64 => &[Extension::Zkne],
+            OperationType::KeySchedule1 | OperationType::KeySchedule2 => {
+                &[Extension::Zknd, Extension::Zkne]
+            }
+            _ => &[Extension::Zknd],
+        }
+    }
+
+    /// Whether this is one of the AES32 instructions, which RV64 doesn't
+    /// have.
+    pub fn is_rv32_only(&self) -> bool {
+        matches!(self.typ,
+                 OperationType::EncryptFinal32 | OperationType::EncryptMiddle32 |
+                 OperationType::DecryptFinal32 | OperationType::DecryptMiddle32)
+    }
+
+    pub fn is_rv64_only(&self) -> bool {
+        !self.is_rv32_only()
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.src1, self.src2))
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let src1 = cpu.get_register(self.src1);
+        let src2 = cpu.get_register(self.src2);
+
+        let result = match self.typ {
+            OperationType::EncryptFinal32 | OperationType::EncryptMiddle32 |
+            OperationType::DecryptFinal32 | OperationType::DecryptMiddle32 => {
+                let shamt = 8 * self.select as u32;
+                let byte = (src2 >> shamt) as u8 as usize;
+                let mixed = match self.typ {
+                    OperationType::EncryptFinal32 => SBOX[byte] as u32,
+                    OperationType::EncryptMiddle32 => mix_byte_forward(SBOX[byte]),
+                    OperationType::DecryptFinal32 => INVERSE_SBOX[byte] as u32,
+                    _ => mix_byte_inverse(INVERSE_SBOX[byte]),
+                };
+                ((src1 as u32) ^ mixed.rotate_left(shamt)) as i32 as i64 as u64
+            }
+            OperationType::EncryptFinal64 => sub_bytes(shift_rows(src1, src2, false), &SBOX),
+            OperationType::EncryptMiddle64 => {
+                mix_columns(sub_bytes(shift_rows(src1, src2, false), &SBOX),
+                            mix_column_forward)
+            }
+            OperationType::DecryptFinal64 => {
+                sub_bytes(shift_rows(src1, src2, true), &INVERSE_SBOX)
+            }
+            OperationType::DecryptMiddle64 => {
+                mix_columns(sub_bytes(shift_rows(src1, src2, true), &INVERSE_SBOX),
+                            mix_column_inverse)
+            }
+            OperationType::InverseMixColumns64 => mix_columns(src1, mix_column_inverse),
+            OperationType::KeySchedule1 => {
+                // SubWord(RotWord(w)) ^ Rcon, for the last word of the
+                // previous round key
+                let word = (src1 >> 32) as u32;
+                let word = if self.select == 0xA { word } else { word.rotate_right(8) };
+                let word = sub_bytes(word as u64, &SBOX) as u32 ^
+                           ROUND_CONSTANTS[self.select as usize] as u32;
+                (word as u64) << 32 | word as u64
+            }
+            OperationType::KeySchedule2 => {
+                let low = (src1 >> 32) as u32 ^ src2 as u32;
+                let high = low ^ (src2 >> 32) as u32;
+                (high as u64) << 32 | low as u64
+            }
+        };
+
+        cpu.set_register(self.dest, result);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (opcode, funct7, funct3, rs2) = match self.typ {
+            OperationType::EncryptFinal32 => (0x33, self.select << 5 | 0x11, 0b000, self.src2),
+            OperationType::EncryptMiddle32 => (0x33, self.select << 5 | 0x13, 0b000, self.src2),
+            OperationType::DecryptFinal32 => (0x33, self.select << 5 | 0x15, 0b000, self.src2),
+            OperationType::DecryptMiddle32 => (0x33, self.select << 5 | 0x17, 0b000, self.src2),
+            OperationType::EncryptFinal64 => (0x33, 0x19, 0b000, self.src2),
+            OperationType::EncryptMiddle64 => (0x33, 0x1B, 0b000, self.src2),
+            OperationType::DecryptFinal64 => (0x33, 0x1D, 0b000, self.src2),
+            OperationType::DecryptMiddle64 => (0x33, 0x1F, 0b000, self.src2),
+            OperationType::KeySchedule2 => (0x33, 0x3F, 0b000, self.src2),
+            OperationType::InverseMixColumns64 => (0x13, 0x18, 0b001, 0),
+            OperationType::KeySchedule1 => (0x13, 0x18, 0b001, 0x10 | self.select),
+        };
+
+        encoding::R {
+            opcode,
+            funct7,
+            funct3,
+            rd: self.dest,
+            rs1: self.src1,
+            rs2,
+        }.to_raw()
+    }
+}
+
+// Multiplication in AES's GF(2^8).
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1B } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+// A column's worth of MixColumns for the byte in row 0 of the input.
+fn mix_byte_forward(byte: u8) -> u32 {
+    u32::from_le_bytes([gf_mul(byte, 2), byte, byte, gf_mul(byte, 3)])
+}
+
+fn mix_byte_inverse(byte: u8) -> u32 {
+    u32::from_le_bytes([gf_mul(byte, 0xE),
+                        gf_mul(byte, 0x9),
+                        gf_mul(byte, 0xD),
+                        gf_mul(byte, 0xB)])
+}
+
+// MixColumns is linear, so a column is the sum of its bytes' columns.
+fn mix_column_forward(column: u32) -> u32 {
+    (0..4).fold(0, |mixed, row| {
+        mixed ^ mix_byte_forward((column >> (8 * row)) as u8).rotate_left(8 * row)
+    })
+}
+
+fn mix_column_inverse(column: u32) -> u32 {
+    (0..4).fold(0, |mixed, row| {
+        mixed ^ mix_byte_inverse((column >> (8 * row)) as u8).rotate_left(8 * row)
+    })
+}
+
+fn mix_columns(columns: u64, mix: fn(u32) -> u32) -> u64 {
+    (mix((columns >> 32) as u32) as u64) << 32 | mix(columns as u32) as u64
+}
+
+// The first two columns of (Inv)ShiftRows of the state `high:low`, with row
+// 0 in the low byte of each column.
+fn shift_rows(low: u64, high: u64, inverse: bool) -> u64 {
+    let state = (high as u128) << 64 | low as u128;
+    (0..8).fold(0, |result, i| {
+        let (column, row) = (i / 4, i % 4);
+        let from = if inverse { (column + 4 - row) % 4 } else { (column + row) % 4 };
+        let byte = (state >> (8 * (4 * from + row))) as u8;
+        result | (byte as u64) << (8 * i)
+    })
+}
+
+fn sub_bytes(bytes: u64, sbox: &[u8; 256]) -> u64 {
+    (0..8).fold(0, |result, i| {
+        result | (sbox[(bytes >> (8 * i)) as u8 as usize] as u64) << (8 * i)
+    })
+}
+
+// The AES S-box, SubBytes.
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+// Its inverse, for InvSubBytes.
+const INVERSE_SBOX: [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::Isa;
+    use instruction::test_helpers::run_op;
+
+    // The AES-128 example from FIPS 197, appendix C.1, as little-endian
+    // columns.
+    const KEY: [u32; 4] = [0x03020100, 0x07060504, 0x0b0a0908, 0x0f0e0d0c];
+    const PLAINTEXT: [u32; 4] = [0x33221100, 0x77665544, 0xbbaa9988, 0xffeeddcc];
+    const CIPHERTEXT: [u32; 4] = [0xd8e0c469, 0x30047b6a, 0x80b7cdd8, 0x5ac5b470];
+
+    fn round_keys() -> [[u32; 4]; 11] {
+        let mut keys = [KEY; 11];
+        for round in 1..11 {
+            let last = keys[round - 1][3].rotate_right(8);
+            let mut word = sub_bytes(last as u64, &SBOX) as u32 ^
+                           ROUND_CONSTANTS[round - 1] as u32;
+            let previous = keys[round - 1];
+            for (column, previous) in keys[round].iter_mut().zip(&previous) {
+                word ^= previous;
+                *column = word;
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn test_aes32() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.set_isa(Isa::parse("rv32i_zknd_zkne").unwrap());
+        let keys = round_keys();
+
+        // aes32esmi, aes32esi, aes32dsmi, aes32dsi x1, x2, x3, bs, with a
+        // round's worth of them for each column
+        let round = |cpu: &mut CPU, funct5: u32, state: [u32; 4], key: [u32; 4], inverse: bool| {
+            let mut next = key;
+            for (column, word) in next.iter_mut().enumerate() {
+                for bs in 0..4 {
+                    let from = if inverse { (column + 4 - bs) % 4 } else { (column + bs) % 4 };
+                    let raw = (bs as u32) << 30 | funct5 << 25 | 0x003100b3;
+                    *word = run_op(cpu, Op::parse, raw, *word as u64, state[from] as u64) as u32;
+                }
+            }
+            next
+        };
+
+        let mut state = PLAINTEXT;
+        for (column, key) in state.iter_mut().zip(&keys[0]) {
+            *column ^= key;
+        }
+        for &key in &keys[1..10] {
+            state = round(&mut cpu, 0b10011, state, key, false);
+        }
+        state = round(&mut cpu, 0b10001, state, keys[10], false);
+        assert_eq!(state, CIPHERTEXT);
+
+        // The equivalent inverse cipher wants InvMixColumns applied to the
+        // middle round keys.
+        for (column, key) in state.iter_mut().zip(&keys[10]) {
+            *column ^= key;
+        }
+        for &key in keys[1..10].iter().rev() {
+            let mut key = key;
+            for column in key.iter_mut() {
+                *column = mix_column_inverse(*column);
+            }
+            state = round(&mut cpu, 0b10111, state, key, true);
+        }
+        state = round(&mut cpu, 0b10101, state, keys[0], true);
+        assert_eq!(state, PLAINTEXT);
+    }
+
+    #[test]
+    fn test_aes64() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.set_isa(Isa::parse("rv64i_zknd_zkne").unwrap());
+        let pair = |columns: [u32; 4], half: usize| {
+            (columns[2 * half + 1] as u64) << 32 | columns[2 * half] as u64
+        };
+
+        // aes64ks1i x1, x2, round; aes64ks2 x1, x2, x3
+        let mut keys = [(pair(KEY, 0), pair(KEY, 1)); 11];
+        for round in 1..11 {
+            let (low, high) = keys[round - 1];
+            let word = run_op(&mut cpu, Op::parse, 0x31011093 | (round as u32 - 1) << 20, high, 0);
+            let low = run_op(&mut cpu, Op::parse, 0x7e3100b3, word, low);
+            let high = run_op(&mut cpu, Op::parse, 0x7e3100b3, low, high);
+            keys[round] = (low, high);
+        }
+        let expected = round_keys();
+        for (key, expected) in keys.iter().zip(&expected) {
+            assert_eq!(*key, (pair(*expected, 0), pair(*expected, 1)));
+        }
+
+        // aes64esm, aes64es x1, x2, x3
+        let mut state = (pair(PLAINTEXT, 0) ^ keys[0].0, pair(PLAINTEXT, 1) ^ keys[0].1);
+        for (round, key) in keys.iter().enumerate().skip(1) {
+            let raw = if round == 10 { 0x323100b3 } else { 0x363100b3 };
+            state = (run_op(&mut cpu, Op::parse, raw, state.0, state.1) ^ key.0,
+                     run_op(&mut cpu, Op::parse, raw, state.1, state.0) ^ key.1);
+        }
+        assert_eq!(state, (pair(CIPHERTEXT, 0), pair(CIPHERTEXT, 1)));
+
+        // aes64dsm, aes64ds x1, x2, x3; aes64im x1, x2
+        state = (state.0 ^ keys[10].0, state.1 ^ keys[10].1);
+        for round in (0..10).rev() {
+            let (low, high) = keys[round];
+            let (raw, key) = if round == 0 {
+                (0x3a3100b3, (low, high))
+            } else {
+                let low = run_op(&mut cpu, Op::parse, 0x30011093, low, 0);
+                (0x3e3100b3, (low, run_op(&mut cpu, Op::parse, 0x30011093, high, 0)))
+            };
+            state = (run_op(&mut cpu, Op::parse, raw, state.0, state.1) ^ key.0,
+                     run_op(&mut cpu, Op::parse, raw, state.1, state.0) ^ key.1);
+        }
+        assert_eq!(state, (pair(PLAINTEXT, 0), pair(PLAINTEXT, 1)));
+    }
+
+    #[test]
+    fn test_xlen() {
+        let aes32esi = Op::parse(0x223100b3).unwrap();
+        assert!(aes32esi.is_rv32_only());
+        assert_eq!(aes32esi.extensions(), &[Extension::Zkne]);
+        let aes64ks2 = Op::parse(0x7e3100b3).unwrap();
+        assert!(aes64ks2.is_rv64_only());
+        assert_eq!(aes64ks2.extensions(), &[Extension::Zknd, Extension::Zkne]);
+        // aes64ks1i with a round number above 10
+        assert!(Op::parse(0x31b11093).is_none());
+    }
+}
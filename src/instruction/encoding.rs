@@ -124,7 +124,7 @@ impl I {
             rd: get_rd(instruction),
             funct3: get_funct3(instruction),
             rs1: get_rs1(instruction),
-            immediate: immediate,
+            immediate,
         }
     }
 
@@ -148,7 +148,7 @@ impl S {
             funct3: get_funct3(instruction),
             rs1: get_rs1(instruction),
             rs2: get_rs2(instruction),
-            immediate: immediate,
+            immediate,
         }
     }
 
@@ -174,7 +174,7 @@ impl SB {
             funct3: get_funct3(instruction),
             rs1: get_rs1(instruction),
             rs2: get_rs2(instruction),
-            immediate: immediate,
+            immediate,
         }
     }
 
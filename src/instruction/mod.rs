@@ -5,50 +5,372 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+// Declared first, so that the others can use its macros.
+#[cfg(test)]
+#[macro_use]
+mod test_helpers;
+
+pub mod aes;
 pub mod encoding;
+pub mod rv32a;
 pub mod rv32i;
 pub mod rv32m;
+pub mod rvc;
+pub mod rvv;
+pub mod zba;
+pub mod zbb;
+pub mod zbc;
+pub mod zbkb;
+pub mod zbkx;
+pub mod zbs;
+pub mod zcmp;
+pub mod zcmt;
+pub mod zfa;
+pub mod zfh;
+pub mod zicbo;
+pub mod zicond;
+pub mod zimop;
+pub mod zknh;
 
 use std::fmt::Debug;
 use cpu::CPU;
+use isa::Extension;
+use trap::Exception;
 
 pub trait Instruction: Debug {
-    fn execute(&self, cpu: &mut CPU);
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception>;
     fn to_raw(&self) -> u32;
 }
 
-pub fn parse(instruction: u32) -> Option<Box<Instruction>> {
+macro_rules! decoded {
+    ($($variant:ident($typ:ty),)*) => {
+        /// Any decoded instruction. This is what `parse` returns and what the
+        /// CPU caches, so it's kept small and `Copy`, and dispatches with a
+        /// `match` rather than through a vtable.
+        #[derive(Debug, Clone, Copy)]
+        pub enum Decoded {
+            $($variant($typ),)*
+        }
+
+        impl Instruction for Decoded {
+            #[inline]
+            fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+                match *self {
+                    $(Decoded::$variant(ref instr) => instr.execute(cpu),)*
+                }
+            }
+
+            fn to_raw(&self) -> u32 {
+                match *self {
+                    $(Decoded::$variant(ref instr) => instr.to_raw(),)*
+                }
+            }
+        }
+    }
+}
+
+decoded! {
+    Load(rv32i::Load),
+    MiscMem(rv32i::MiscMem),
+    OpImm(rv32i::OpImm),
+    Auipc(rv32i::Auipc),
+    Store(rv32i::Store),
+    Op(rv32i::Op),
+    MulDiv(rv32m::Op),
+    Amo(rv32a::Amo),
+    Lui(rv32i::Lui),
+    Branch(rv32i::Branch),
+    Jalr(rv32i::Jalr),
+    Jal(rv32i::Jal),
+    System(rv32i::System),
+    Csr(rv32i::Csr),
+    Zba(zba::Op),
+    Zbb(zbb::Op),
+    Zbc(zbc::Op),
+    Zbs(zbs::Op),
+    Zbkb(zbkb::Op),
+    Zbkx(zbkx::Op),
+    Aes(aes::Op),
+    Zknh(zknh::Op),
+    SetVl(rvv::config::SetVl),
+    VectorLoadStore(rvv::memory::LoadStore),
+    VectorInteger(rvv::integer::Op),
+    VectorMask(rvv::mask::Op),
+    VectorReduction(rvv::reduction::Op),
+    VectorPermute(rvv::permute::Op),
+    FloatLoad(zfh::Load),
+    FloatStore(zfh::Store),
+    FusedMultiplyAdd(zfh::FusedMultiplyAdd),
+    Zfh(zfh::Op),
+    Zfa(zfa::Op),
+    Zicond(zicond::Op),
+    Zimop(zimop::Op),
+    CacheBlock(zicbo::Op),
+    Compressed(rvc::Op),
+    PushPop(zcmp::PushPop),
+    MovePair(zcmp::MovePair),
+    TableJump(zcmt::TableJump),
+}
+
+impl Decoded {
+    /// Whether the CPU has to stop and look around after this instruction:
+    /// it may jump, trap, change privilege or CSR state, or flush the decode
+    /// cache. Basic blocks end with one of these.
+    pub fn ends_block(&self) -> bool {
+        match *self {
+            Decoded::Compressed(ref instr) => instr.ends_block(),
+            Decoded::PushPop(ref instr) => instr.returns(),
+            _ => {
+                matches!(*self,
+                         Decoded::MiscMem(_) | Decoded::Branch(_) | Decoded::Jalr(_) |
+                         Decoded::Jal(_) | Decoded::System(_) | Decoded::Csr(_) |
+                         Decoded::TableJump(_))
+            }
+        }
+    }
+
+    /// The instruction's length in bytes: 2 for compressed instructions,
+    /// otherwise 4.
+    pub fn size(&self) -> u32 {
+        match *self {
+            Decoded::Compressed(_) | Decoded::PushPop(_) | Decoded::MovePair(_) |
+            Decoded::TableJump(_) => 2,
+            _ => 4,
+        }
+    }
+
+    /// The extensions that define this instruction, one of which has to be
+    /// enabled for it to execute. `I` stands for whichever base the ISA has.
+    pub fn extensions(&self, xlen: u32) -> &'static [Extension] {
+        match *self {
+            Decoded::MulDiv(_) => &[Extension::M],
+            Decoded::Amo(_) => &[Extension::A],
+            Decoded::Csr(_) => &[Extension::Zicsr],
+            Decoded::Zba(_) => &[Extension::Zba],
+            Decoded::Zbb(ref instr) => instr.extensions(),
+            Decoded::Zbc(ref instr) => instr.extensions(),
+            Decoded::Zbs(_) => &[Extension::Zbs],
+            Decoded::Zbkb(ref instr) => instr.extensions(xlen),
+            Decoded::Zbkx(_) => &[Extension::Zbkx],
+            Decoded::Aes(ref instr) => instr.extensions(),
+            Decoded::Zknh(_) => &[Extension::Zknh],
+            Decoded::SetVl(_) | Decoded::VectorLoadStore(_) | Decoded::VectorInteger(_) |
+            Decoded::VectorMask(_) | Decoded::VectorReduction(_) | Decoded::VectorPermute(_) => {
+                &[Extension::Zve32x]
+            }
+            Decoded::FloatLoad(_) | Decoded::FloatStore(_) => &[Extension::Zfhmin],
+            Decoded::FusedMultiplyAdd(_) => &[Extension::Zfh],
+            Decoded::Zfh(ref instr) => instr.extensions(),
+            Decoded::Zfa(_) => &[Extension::Zfa],
+            Decoded::Zicond(_) => &[Extension::Zicond],
+            Decoded::Zimop(_) => &[Extension::Zimop],
+            Decoded::CacheBlock(ref instr) => instr.extensions(),
+            Decoded::Compressed(ref instr) => instr.extensions(),
+            Decoded::PushPop(_) | Decoded::MovePair(_) => &[Extension::Zcmp],
+            Decoded::TableJump(_) => &[Extension::Zcmt],
+            Decoded::MiscMem(ref instr) if instr.is_fence_i() => &[Extension::Zifencei],
+            _ => &[Extension::I],
+        }
+    }
+
+    /// Further extensions that all have to be enabled as well: the ones
+    /// whose instructions Zcb has short forms of.
+    pub fn requires(&self) -> &'static [Extension] {
+        match *self {
+            Decoded::Compressed(ref instr) => instr.requires(),
+            _ => &[],
+        }
+    }
+
+    /// Whether this instruction is illegal on RV32.
+    pub fn is_rv64_only(&self) -> bool {
+        match *self {
+            Decoded::Load(ref instr) => instr.is_rv64_only(),
+            Decoded::Store(ref instr) => instr.is_rv64_only(),
+            Decoded::OpImm(ref instr) => instr.is_rv64_only(),
+            Decoded::Op(ref instr) => instr.is_rv64_only(),
+            Decoded::MulDiv(ref instr) => instr.is_rv64_only(),
+            Decoded::Amo(ref instr) => instr.is_rv64_only(),
+            Decoded::Zba(ref instr) => instr.is_rv64_only(),
+            Decoded::Zbb(ref instr) => instr.is_rv64_only(),
+            Decoded::Zbs(ref instr) => instr.is_rv64_only(),
+            Decoded::Zbkb(ref instr) => instr.is_rv64_only(),
+            Decoded::Aes(ref instr) => instr.is_rv64_only(),
+            Decoded::Zknh(ref instr) => instr.is_rv64_only(),
+            Decoded::Zfh(ref instr) => instr.is_rv64_only(),
+            Decoded::Compressed(ref instr) => instr.is_rv64_only(),
+            _ => false,
+        }
+    }
+
+    /// Whether this instruction is illegal on RV64, because RV64 encodes it
+    /// differently.
+    pub fn is_rv32_only(&self) -> bool {
+        match *self {
+            Decoded::Zbb(ref instr) => instr.is_rv32_only(),
+            Decoded::Zbkb(ref instr) => instr.is_rv32_only(),
+            Decoded::Aes(ref instr) => instr.is_rv32_only(),
+            Decoded::Zknh(ref instr) => instr.is_rv32_only(),
+            _ => false,
+        }
+    }
+
+    /// The highest-numbered integer register this reads or writes, which
+    /// has to be below 16 on RV32E.
+    pub fn max_register(&self) -> u8 {
+        match *self {
+            Decoded::Load(ref instr) => instr.max_register(),
+            Decoded::OpImm(ref instr) => instr.max_register(),
+            Decoded::Auipc(ref instr) => instr.max_register(),
+            Decoded::Store(ref instr) => instr.max_register(),
+            Decoded::Op(ref instr) => instr.max_register(),
+            Decoded::MulDiv(ref instr) => instr.max_register(),
+            Decoded::Amo(ref instr) => instr.max_register(),
+            Decoded::Lui(ref instr) => instr.max_register(),
+            Decoded::Branch(ref instr) => instr.max_register(),
+            Decoded::Jalr(ref instr) => instr.max_register(),
+            Decoded::Jal(ref instr) => instr.max_register(),
+            Decoded::Csr(ref instr) => instr.max_register(),
+            Decoded::Zba(ref instr) => instr.max_register(),
+            Decoded::Zbb(ref instr) => instr.max_register(),
+            Decoded::Zbc(ref instr) => instr.max_register(),
+            Decoded::Zbs(ref instr) => instr.max_register(),
+            Decoded::Zbkb(ref instr) => instr.max_register(),
+            Decoded::Zbkx(ref instr) => instr.max_register(),
+            Decoded::Aes(ref instr) => instr.max_register(),
+            Decoded::Zknh(ref instr) => instr.max_register(),
+            Decoded::SetVl(ref instr) => instr.max_register(),
+            Decoded::VectorLoadStore(ref instr) => instr.max_register(),
+            Decoded::VectorInteger(ref instr) => instr.max_register(),
+            Decoded::VectorMask(ref instr) => instr.max_register(),
+            Decoded::VectorPermute(ref instr) => instr.max_register(),
+            Decoded::FloatLoad(ref instr) => instr.max_register(),
+            Decoded::FloatStore(ref instr) => instr.max_register(),
+            Decoded::Zfh(ref instr) => instr.max_register(),
+            Decoded::Zfa(ref instr) => instr.max_register(),
+            Decoded::Zicond(ref instr) => instr.max_register(),
+            Decoded::Zimop(ref instr) => instr.max_register(),
+            Decoded::CacheBlock(ref instr) => instr.max_register(),
+            Decoded::Compressed(ref instr) => instr.max_register(),
+            Decoded::PushPop(ref instr) => instr.max_register(),
+            Decoded::MovePair(ref instr) => instr.max_register(),
+            Decoded::TableJump(ref instr) => instr.max_register(),
+            // FENCE's register fields are reserved and ignored.
+            Decoded::MiscMem(_) | Decoded::System(_) | Decoded::VectorReduction(_) |
+            Decoded::FusedMultiplyAdd(_) => 0,
+        }
+    }
+}
+
+pub fn parse(instruction: u32) -> Option<Decoded> {
     match encoding::get_opcode(instruction) {
-        0x03 => rv32i::Load::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        // 0x07 => load_fp::LoadFp::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        // 0x0F => misc_mem::MiscMem::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        0x13 => rv32i::OpImm::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        0x17 => rv32i::Auipc::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        0x23 => rv32i::Store::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        // 0x27 => store_fp::StoreFp::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        // 0x2F => amo::Amo::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        0x33 => {
+        0x03 => rv32i::Load::parse(instruction).map(Decoded::Load),
+        0x07 => {
+            zfh::Load::parse(instruction).map(Decoded::FloatLoad).or_else(|| {
+                rvv::memory::LoadStore::parse(instruction).map(Decoded::VectorLoadStore)
+            })
+        }
+        0x0F => {
+            rv32i::MiscMem::parse(instruction).map(Decoded::MiscMem)
+                .or_else(|| zicbo::Op::parse(instruction).map(Decoded::CacheBlock))
+        }
+        0x13 => {
+            rv32i::OpImm::parse(instruction).map(Decoded::OpImm)
+                .or_else(|| zbb::Op::parse(instruction).map(Decoded::Zbb))
+                .or_else(|| zbs::Op::parse(instruction).map(Decoded::Zbs))
+                .or_else(|| zbkb::Op::parse(instruction).map(Decoded::Zbkb))
+                .or_else(|| aes::Op::parse(instruction).map(Decoded::Aes))
+                .or_else(|| zknh::Op::parse(instruction).map(Decoded::Zknh))
+        }
+        0x17 => rv32i::Auipc::parse(instruction).map(Decoded::Auipc),
+        0x1B => {
+            rv32i::OpImm::parse(instruction).map(Decoded::OpImm)
+                .or_else(|| zba::Op::parse(instruction).map(Decoded::Zba))
+                .or_else(|| zbb::Op::parse(instruction).map(Decoded::Zbb))
+        }
+        0x23 => rv32i::Store::parse(instruction).map(Decoded::Store),
+        0x27 => {
+            zfh::Store::parse(instruction).map(Decoded::FloatStore).or_else(|| {
+                rvv::memory::LoadStore::parse(instruction).map(Decoded::VectorLoadStore)
+            })
+        }
+        0x2F => rv32a::Amo::parse(instruction).map(Decoded::Amo),
+        0x33 | 0x3B => {
             match encoding::get_funct7(instruction) {
-                0x00 | 0x20 => {
-                    rv32i::Op::parse(instruction).map(|i| Box::new(i) as Box<Instruction>)
+                0x00 => rv32i::Op::parse(instruction).map(Decoded::Op),
+                0x20 => {
+                    rv32i::Op::parse(instruction).map(Decoded::Op)
+                        .or_else(|| zbb::Op::parse(instruction).map(Decoded::Zbb))
+                }
+                0x01 => rv32m::Op::parse(instruction).map(Decoded::MulDiv),
+                0x07 => zicond::Op::parse(instruction).map(Decoded::Zicond),
+                0x04 => {
+                    zba::Op::parse(instruction).map(Decoded::Zba)
+                        .or_else(|| zbkb::Op::parse(instruction).map(Decoded::Zbkb))
+                }
+                0x05 => {
+                    zbb::Op::parse(instruction).map(Decoded::Zbb)
+                        .or_else(|| zbc::Op::parse(instruction).map(Decoded::Zbc))
+                }
+                0x10 => zba::Op::parse(instruction).map(Decoded::Zba),
+                0x14 => {
+                    zbs::Op::parse(instruction).map(Decoded::Zbs)
+                        .or_else(|| zbkx::Op::parse(instruction).map(Decoded::Zbkx))
+                }
+                0x24 | 0x34 => zbs::Op::parse(instruction).map(Decoded::Zbs),
+                0x30 => zbb::Op::parse(instruction).map(Decoded::Zbb),
+                _ => {
+                    aes::Op::parse(instruction).map(Decoded::Aes)
+                        .or_else(|| zknh::Op::parse(instruction).map(Decoded::Zknh))
+                }
+            }
+        }
+        0x37 => rv32i::Lui::parse(instruction).map(Decoded::Lui),
+        0x43 | 0x47 | 0x4B | 0x4F => {
+            zfh::FusedMultiplyAdd::parse(instruction).map(Decoded::FusedMultiplyAdd)
+        }
+        0x53 => {
+            zfh::Op::parse(instruction).map(Decoded::Zfh)
+                .or_else(|| zfa::Op::parse(instruction).map(Decoded::Zfa))
+        }
+        0x57 => {
+            match encoding::get_funct3(instruction) {
+                0b111 => rvv::config::SetVl::parse(instruction).map(Decoded::SetVl),
+                _ => {
+                    rvv::integer::Op::parse(instruction).map(Decoded::VectorInteger)
+                        .or_else(|| rvv::mask::Op::parse(instruction).map(Decoded::VectorMask))
+                        .or_else(|| {
+                            rvv::reduction::Op::parse(instruction).map(Decoded::VectorReduction)
+                        })
+                        .or_else(|| {
+                            rvv::permute::Op::parse(instruction).map(Decoded::VectorPermute)
+                        })
                 }
-                0x01 => rv32m::Op::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-                _ => None,
             }
         }
-        0x37 => rv32i::Lui::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        // 0x43 => madd::Madd::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        // 0x47 => msub::Msub::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        // 0x4B => nmsub::Nmsub::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        // 0x4F => nmadd::Nmadd::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        // 0x53 => op_fp::OpFp::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        0x63 => rv32i::Branch::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        0x67 => rv32i::Jalr::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        0x6F => rv32i::Jal::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        // 0x73 => system::System::parse(instruction).map(|i| Box::new(i) as Box<Instruction>),
-        op => {
-            println!("unknown opcode: {:02x}", op);
-            None
+        0x63 => rv32i::Branch::parse(instruction).map(Decoded::Branch),
+        0x67 => rv32i::Jalr::parse(instruction).map(Decoded::Jalr),
+        0x6F => rv32i::Jal::parse(instruction).map(Decoded::Jal),
+        0x73 => {
+            match encoding::get_funct3(instruction) {
+                0b000 => rv32i::System::parse(instruction).map(Decoded::System),
+                0b100 => zimop::Op::parse(instruction).map(Decoded::Zimop),
+                _ => rv32i::Csr::parse(instruction).map(Decoded::Csr),
+            }
         }
+        _ => None,
     }
 }
+
+/// Decode a 16-bit instruction, one whose low two bits aren't both set. Some
+/// mean different things on RV32 and RV64, so this needs to know XLEN.
+pub fn parse_compressed(instruction: u16, xlen: u32) -> Option<Decoded> {
+    // Zcmp and Zcmt take over C.FSDSP, which needs D.
+    if instruction & 0xE003 == 0xA002 {
+        return zcmp::PushPop::parse(instruction).map(Decoded::PushPop)
+            .or_else(|| zcmp::MovePair::parse(instruction).map(Decoded::MovePair))
+            .or_else(|| zcmt::TableJump::parse(instruction).map(Decoded::TableJump));
+    }
+
+    rvc::Op::parse(instruction, xlen).map(Decoded::Compressed)
+}
@@ -0,0 +1,296 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Amo {
+    typ: AmoType,
+    dest: u8,
+    addr: u8,
+    src: u8,
+    acquire: bool,
+    release: bool,
+    // RV64's .D forms, on doublewords
+    double: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmoType {
+    LoadReserved,
+    StoreConditional,
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    MinUnsigned,
+    MaxUnsigned,
+}
+
+impl Amo {
+    pub fn parse(instruction: u32) -> Option<Amo> {
+        let decoded = encoding::R::parse(instruction);
+
+        if decoded.opcode != 0x2F {
+            // Not an AMO opcode
+            return None;
+        }
+
+        let double = match decoded.funct3 {
+            0b010 => false,
+            0b011 => true,
+            _ => return None,
+        };
+
+        let typ = match decoded.funct7 >> 2 {
+            0x02 if decoded.rs2 == 0 => AmoType::LoadReserved,
+            0x03 => AmoType::StoreConditional,
+            0x01 => AmoType::Swap,
+            0x00 => AmoType::Add,
+            0x04 => AmoType::Xor,
+            0x0C => AmoType::And,
+            0x08 => AmoType::Or,
+            0x10 => AmoType::Min,
+            0x14 => AmoType::Max,
+            0x18 => AmoType::MinUnsigned,
+            0x1C => AmoType::MaxUnsigned,
+            _ => return None,
+        };
+
+        Some(Amo {
+            typ,
+            dest: decoded.rd,
+            addr: decoded.rs1,
+            src: decoded.rs2,
+            acquire: decoded.funct7 & 0b10 != 0,
+            release: decoded.funct7 & 0b01 != 0,
+            double,
+        })
+    }
+
+    /// Whether this is one of the .D forms, which only exist on RV64.
+    pub fn is_rv64_only(&self) -> bool {
+        self.double
+    }
+
+    fn load(&self, cpu: &mut CPU, addr: u32) -> Result<u64, Exception> {
+        if self.double {
+            cpu.load_u64(addr)
+        } else {
+            cpu.load_u32(addr).map(|value| value as i32 as i64 as u64)
+        }
+    }
+
+    fn store(&self, cpu: &mut CPU, addr: u32, value: u64) -> Result<(), Exception> {
+        if self.double {
+            cpu.store_u64(addr, value)
+        } else {
+            cpu.store_u32(addr, value as u32)
+        }
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.addr, self.src))
+    }
+}
+
+impl Instruction for Amo {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        // There's only ever one hart running at a time, so every access is
+        // already sequentially consistent and aq/rl need no handling.
+        let base = cpu.get_register(self.addr);
+        let hart = cpu.hart_id();
+
+        let alignment = if self.double { 0b111 } else { 0b11 };
+        if base & alignment != 0 {
+            return Err(match self.typ {
+                AmoType::LoadReserved => Exception::LoadAddressMisaligned(base),
+                _ => Exception::StoreAddressMisaligned(base),
+            });
+        }
+        let addr = cpu.address(base, 0, match self.typ {
+            AmoType::LoadReserved => Exception::LoadAccessFault,
+            _ => Exception::StoreAccessFault,
+        })?;
+        // Word operations work on the low half of src, and compare and
+        // return sign-extended words.
+        let src = if self.double {
+            cpu.get_register(self.src)
+        } else {
+            cpu.get_register(self.src) as i32 as i64 as u64
+        };
+
+        match self.typ {
+            AmoType::LoadReserved => {
+                let value = self.load(cpu, addr)?;
+                cpu.bus.reserve(hart, addr);
+                cpu.set_register(self.dest, value);
+            }
+            AmoType::StoreConditional => {
+                if cpu.bus.take_reservation(hart, addr) {
+                    self.store(cpu, addr, src)?;
+                    cpu.set_register(self.dest, 0);
+                } else {
+                    cpu.set_register(self.dest, 1);
+                }
+            }
+            _ => {
+                // AMOs report faults as stores, even on the load half.
+                let value = self.load(cpu, addr).map_err(|e| match e {
+                    Exception::LoadAccessFault(addr) => Exception::StoreAccessFault(addr),
+                    e => e,
+                })?;
+                let result = match self.typ {
+                    AmoType::Swap => src,
+                    AmoType::Add => value.wrapping_add(src),
+                    AmoType::Xor => value ^ src,
+                    AmoType::And => value & src,
+                    AmoType::Or => value | src,
+                    AmoType::Min => (value as i64).min(src as i64) as u64,
+                    AmoType::Max => (value as i64).max(src as i64) as u64,
+                    AmoType::MinUnsigned => value.min(src),
+                    AmoType::MaxUnsigned => value.max(src),
+                    AmoType::LoadReserved | AmoType::StoreConditional => unreachable!(),
+                };
+                self.store(cpu, addr, result)?;
+                cpu.set_register(self.dest, value);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let funct5 = match self.typ {
+            AmoType::LoadReserved => 0x02,
+            AmoType::StoreConditional => 0x03,
+            AmoType::Swap => 0x01,
+            AmoType::Add => 0x00,
+            AmoType::Xor => 0x04,
+            AmoType::And => 0x0C,
+            AmoType::Or => 0x08,
+            AmoType::Min => 0x10,
+            AmoType::Max => 0x14,
+            AmoType::MinUnsigned => 0x18,
+            AmoType::MaxUnsigned => 0x1C,
+        };
+
+        encoding::R {
+            opcode: 0x2F,
+            funct7: (funct5 << 2) | ((self.acquire as u8) << 1) | self.release as u8,
+            funct3: if self.double { 0b011 } else { 0b010 },
+            rd: self.dest,
+            rs1: self.addr,
+            rs2: self.src,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    #[test]
+    fn test_amo_ops() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        macro_rules! test_amo {
+            ($funct5:expr, $result:expr, $mem:expr, $src:expr) => {
+                let instr = Amo::parse(($funct5 << 27) | 0b00011_00010_010_00001_0101111)
+                    .expect("couldn't parse AMO x1, x3, (x2)");
+                assert_eq!(instr.to_raw() >> 27, $funct5);
+
+                cpu.bus.ram.set_u32(0x100, $mem as u32);
+                cpu.set_register(2, 0x100);
+                cpu.set_register(3, $src as u32 as u64);
+                instr.execute(&mut cpu).expect("couldn't execute instruction");
+                assert_eq!(cpu.get_register(1), $mem as u32 as u64);
+                assert_eq!(cpu.bus.ram.get_u32(0x100), $result as u32);
+            }
+        }
+
+        test_amo!(0x01, 7, 5, 7);
+        test_amo!(0x00, 0, -1i32, 1);
+        test_amo!(0x04, 0b0110, 0b0101, 0b0011);
+        test_amo!(0x0C, 0b0001, 0b0101, 0b0011);
+        test_amo!(0x08, 0b0111, 0b0101, 0b0011);
+        test_amo!(0x10, -5i32, -5i32, 3);
+        test_amo!(0x14, 3, -5i32, 3);
+        test_amo!(0x18, 3, -5i32, 3);
+        test_amo!(0x1C, -5i32, -5i32, 3);
+    }
+
+    #[test]
+    fn test_lr_sc() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        let lr = Amo::parse(0b00010_1_0_00000_00010_010_00001_0101111)
+            .expect("couldn't parse LR.W.AQ x1, (x2)");
+        let sc = Amo::parse(0b00011_0_1_00011_00010_010_00100_0101111)
+            .expect("couldn't parse SC.W.RL x4, x3, (x2)");
+
+        cpu.bus.ram.set_u32(0x100, 42);
+        cpu.set_register(2, 0x100);
+        cpu.set_register(3, 43);
+
+        // No reservation yet
+        sc.execute(&mut cpu).expect("couldn't execute SC");
+        assert_eq!(cpu.get_register(4), 1);
+        assert_eq!(cpu.bus.ram.get_u32(0x100), 42);
+
+        lr.execute(&mut cpu).expect("couldn't execute LR");
+        assert_eq!(cpu.get_register(1), 42);
+        sc.execute(&mut cpu).expect("couldn't execute SC");
+        assert_eq!(cpu.get_register(4), 0);
+        assert_eq!(cpu.bus.ram.get_u32(0x100), 43);
+
+        // The reservation is used up
+        sc.execute(&mut cpu).expect("couldn't execute SC");
+        assert_eq!(cpu.get_register(4), 1);
+
+        // Another hart's store breaks it
+        lr.execute(&mut cpu).expect("couldn't execute LR");
+        cpu.set_hart_id(1);
+        cpu.store_u32(0x100, 0).expect("couldn't store");
+        cpu.set_hart_id(0);
+        sc.execute(&mut cpu).expect("couldn't execute SC");
+        assert_eq!(cpu.get_register(4), 1);
+
+        // But its own doesn't
+        lr.execute(&mut cpu).expect("couldn't execute LR");
+        cpu.store_u32(0x100, 0).expect("couldn't store");
+        sc.execute(&mut cpu).expect("couldn't execute SC");
+        assert_eq!(cpu.get_register(4), 0);
+
+        assert_eq!(sc.to_raw(), 0b00011_0_1_00011_00010_010_00100_0101111);
+        assert!(Amo::parse(0b00010_0_0_00011_00010_010_00001_0101111).is_none());
+    }
+
+    #[test]
+    fn test_misaligned() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        let lr = Amo::parse(0b00010_0_0_00000_00010_010_00001_0101111).unwrap();
+        let add = Amo::parse(0b00000_0_0_00011_00010_010_00001_0101111).unwrap();
+
+        cpu.set_register(2, 0x102);
+        assert_eq!(lr.execute(&mut cpu).unwrap_err(), Exception::LoadAddressMisaligned(0x102));
+        assert_eq!(add.execute(&mut cpu).unwrap_err(), Exception::StoreAddressMisaligned(0x102));
+
+        cpu.set_register(2, 0x1000);
+        assert_eq!(add.execute(&mut cpu).unwrap_err(), Exception::StoreAccessFault(0x1000));
+    }
+}
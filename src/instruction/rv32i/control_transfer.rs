@@ -5,10 +5,14 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::cmp;
+
 use instruction::{encoding, Instruction};
+use counters::Event;
 use cpu::CPU;
+use trap::Exception;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Jal {
     dest: u8,
     offset: i32,
@@ -28,17 +32,23 @@ impl Jal {
             offset: decoded.immediate,
         })
     }
+
+    pub fn max_register(&self) -> u8 {
+        self.dest
+    }
 }
 
 impl Instruction for Jal {
-    fn execute(&self, cpu: &mut CPU) {
-        let jump_back_target = cpu.pc.wrapping_add(4);
-        cpu.set_register(self.dest, jump_back_target);
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let target = cpu.address(cpu.pc as u64, self.offset, Exception::InstructionAccessFault)?;
+        if target & (cpu.instruction_alignment() - 1) != 0 {
+            return Err(Exception::InstructionAddressMisaligned(target as u64));
+        }
 
-        let target = cpu.pc.wrapping_add(self.offset as u32);
-        // Subtract 4 since the CPU will increment by 4 after the instruction is
-        // run.
-        cpu.pc = target.wrapping_sub(4);
+        let jump_back_target = cpu.pc as u64 + 4;
+        cpu.set_register(self.dest, jump_back_target);
+        cpu.jump(target);
+        Ok(())
     }
 
     fn to_raw(&self) -> u32 {
@@ -50,7 +60,7 @@ impl Instruction for Jal {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Jalr {
     dest: u8,
     base: u8,
@@ -76,18 +86,25 @@ impl Jalr {
             offset: decoded.immediate,
         })
     }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, self.base)
+    }
 }
 
 impl Instruction for Jalr {
-    fn execute(&self, cpu: &mut CPU) {
-        let jump_back_target = cpu.pc.wrapping_add(4);
-        cpu.set_register(self.dest, jump_back_target);
-
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
         let base = cpu.get_register(self.base);
-        let target = base.wrapping_add(self.offset as u32) & 0xFFFFFFFE;
-        // Subtract 4 since the CPU will increment by 4 after the instruction is
-        // run.
-        cpu.pc = target.wrapping_sub(4);
+        let target = cpu.address(base, self.offset, Exception::InstructionAccessFault)? &
+                     0xFFFFFFFE;
+        if target & (cpu.instruction_alignment() - 1) != 0 {
+            return Err(Exception::InstructionAddressMisaligned(target as u64));
+        }
+
+        let jump_back_target = cpu.pc as u64 + 4;
+        cpu.set_register(self.dest, jump_back_target);
+        cpu.jump(target);
+        Ok(())
     }
 
     fn to_raw(&self) -> u32 {
@@ -101,7 +118,7 @@ impl Instruction for Jalr {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum BranchType {
     Equals,
     NotEquals,
@@ -111,7 +128,7 @@ pub enum BranchType {
     GreaterOrEqualUnsigned,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Branch {
     typ: BranchType,
     src1: u8,
@@ -139,31 +156,48 @@ impl Branch {
         };
 
         Some(Branch {
-            typ: typ,
+            typ,
             src1: decoded.rs1,
             src2: decoded.rs2,
             offset: decoded.immediate,
         })
     }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.src1, self.src2)
+    }
 }
 
 impl Instruction for Branch {
-    fn execute(&self, cpu: &mut CPU) {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
         let src1 = cpu.get_register(self.src1);
         let src2 = cpu.get_register(self.src2);
 
         let result = match self.typ {
             BranchType::Equals => src1 == src2,
             BranchType::NotEquals => src1 != src2,
-            BranchType::LessThan => (src1 as i32) < (src2 as i32),
-            BranchType::GreaterOrEqual => (src1 as i32) >= (src2 as i32),
+            BranchType::LessThan => cpu.signed(src1) < cpu.signed(src2),
+            BranchType::GreaterOrEqual => cpu.signed(src1) >= cpu.signed(src2),
             BranchType::LessThanUnsigned => src1 < src2,
             BranchType::GreaterOrEqualUnsigned => src1 >= src2,
         };
 
         if result {
-            cpu.pc = cpu.pc.wrapping_add(self.offset as u32).wrapping_sub(4);
+            let target =
+                cpu.address(cpu.pc as u64, self.offset, Exception::InstructionAccessFault)?;
+            if target & (cpu.instruction_alignment() - 1) != 0 {
+                return Err(Exception::InstructionAddressMisaligned(target as u64));
+            }
+
+            cpu.jump(target);
+            cpu.count_event(Event::BranchTaken);
+        }
+        // A static predictor would guess that backward branches are taken.
+        if result != (self.offset < 0) {
+            cpu.count_event(Event::BranchMispredicted);
         }
+
+        Ok(())
     }
 
     fn to_raw(&self) -> u32 {
@@ -187,32 +221,36 @@ impl Instruction for Branch {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use counters::Event;
     use cpu::CPU;
     use ram::RAM;
     use instruction::Instruction;
+    use trap::StopReason;
 
     macro_rules! test_br2_op_taken {
         ($cpu:expr, $op:expr, $val1:expr, $val2:expr) => {
-            $cpu.set_register(1, $val1 as u32);
-            $cpu.set_register(2, $val2 as u32);
+            $cpu.set_register(1, $val1 as u32 as u64);
+            $cpu.set_register(2, $val2 as u32 as u64);
             let raw_instruction = (4 << 8) | (2 << 20) | (1 << 15) | $op << 12 | 0x63;
             let instr = Branch::parse(raw_instruction).expect("couldn't parse instruction");
+            $cpu.bus.ram.set_u32(100, instr.to_raw());
+            $cpu.flush_decode_cache();
             $cpu.pc = 100;
-            instr.execute(&mut $cpu);
-            $cpu.pc = $cpu.pc.wrapping_add(4);
+            assert_eq!($cpu.step(), StopReason::InstructionLimit);
             assert_eq!($cpu.pc, 108);
         }
     }
 
     macro_rules! test_br2_op_not_taken {
         ($cpu:expr, $op:expr, $val1:expr, $val2:expr) => {
-            $cpu.set_register(1, $val1 as u32);
-            $cpu.set_register(2, $val2 as u32);
+            $cpu.set_register(1, $val1 as u32 as u64);
+            $cpu.set_register(2, $val2 as u32 as u64);
             let raw_instruction = (4 << 8) | (2 << 20) | (1 << 15) | $op << 12 | 0x63;
             let instr = Branch::parse(raw_instruction).expect("couldn't parse instruction");
+            $cpu.bus.ram.set_u32(100, instr.to_raw());
+            $cpu.flush_decode_cache();
             $cpu.pc = 100;
-            instr.execute(&mut $cpu);
-            $cpu.pc = $cpu.pc.wrapping_add(4);
+            assert_eq!($cpu.step(), StopReason::InstructionLimit);
             assert_eq!($cpu.pc, 104);
         }
     }
@@ -306,4 +344,21 @@ mod tests {
         test_br2_op_not_taken!(cpu, 0b111, 0x00000000, 0xffffffff);
         test_br2_op_not_taken!(cpu, 0b111, 0x7fffffff, 0x80000000);
     }
+
+    #[test]
+    fn test_branch_events() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        // loop: addi a0, a0, -1; bnez a0, loop
+        cpu.bus.ram.set_u32(0x100, 0xfff50513);
+        cpu.bus.ram.set_u32(0x104, 0xfe051ee3);
+        cpu.set_csr(0x323, Event::BranchTaken as u64).unwrap();
+        cpu.set_csr(0x324, Event::BranchMispredicted as u64).unwrap();
+        cpu.set_register(10, 5);
+        cpu.pc = 0x100;
+
+        // Only falling out of the loop goes against the backward branch.
+        assert_eq!(cpu.run_for(10), StopReason::InstructionLimit);
+        assert_eq!(cpu.pc, 0x108);
+        assert_eq!((cpu.get_csr(0xC03), cpu.get_csr(0xC04)), (Ok(4), Ok(1)));
+    }
 }
@@ -5,18 +5,24 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::cmp;
+
 use instruction::{encoding, Instruction};
 use cpu::CPU;
+use trap::Exception;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct OpImm {
     typ: ImmediateOperationType,
     dest: u8,
     src: u8,
     immediate: i32,
+    // RV64's OP-IMM-32 (ADDIW and friends): operates on the low 32 bits and
+    // sign-extends the result.
+    word: bool,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ImmediateOperationType {
     Add,
     SetLessThan,
@@ -33,10 +39,12 @@ impl OpImm {
     pub fn parse(instruction: u32) -> Option<OpImm> {
         let decoded = encoding::I::parse(instruction);
 
-        if decoded.opcode != 0x13 {
-            // Not a OP-IMM opcode
-            return None;
-        }
+        let word = match decoded.opcode {
+            0x13 => false,
+            0x1B => true,
+            // Not a OP-IMM or OP-IMM-32 opcode
+            _ => return None,
+        };
 
         let typ = match decoded.funct3 {
             0b000 => ImmediateOperationType::Add,
@@ -56,52 +64,102 @@ impl OpImm {
             _ => return None,
         };
 
+        // Above the shift amount, shifts' immediates have to be zero (apart
+        // from SRAI's bit 10). Other values are other extensions'
+        // instructions.
+        let shift_funct = decoded.immediate & 0xFC0;
+        match typ {
+            ImmediateOperationType::ShiftLeftLogical |
+            ImmediateOperationType::ShiftRightLogical if shift_funct != 0 => return None,
+            ImmediateOperationType::ShiftRightArithmetic if shift_funct != 0x400 => return None,
+            _ => {}
+        }
+
+        if word {
+            match typ {
+                ImmediateOperationType::Add => {}
+                // Shift amounts only go up to 31.
+                ImmediateOperationType::ShiftLeftLogical |
+                ImmediateOperationType::ShiftRightLogical |
+                ImmediateOperationType::ShiftRightArithmetic
+                    if decoded.immediate & 0x20 == 0 => {}
+                _ => return None,
+            }
+        }
+
         Some(OpImm {
-            typ: typ,
+            typ,
             dest: decoded.rd,
             src: decoded.rs1,
             immediate: decoded.immediate,
+            word,
         })
     }
+
+    /// Whether this only exists on RV64: the OP-IMM-32 instructions, and
+    /// shifts by 32 or more.
+    pub fn is_rv64_only(&self) -> bool {
+        self.word || (self.is_shift() && self.immediate & 0x20 != 0)
+    }
+
+    fn is_shift(&self) -> bool {
+        matches!(self.typ,
+                 ImmediateOperationType::ShiftLeftLogical |
+                 ImmediateOperationType::ShiftRightLogical |
+                 ImmediateOperationType::ShiftRightArithmetic)
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, self.src)
+    }
 }
 
 impl Instruction for OpImm {
-    fn execute(&self, cpu: &mut CPU) {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
         if self.typ == ImmediateOperationType::Add && self.src == 0 && self.dest == 0 &&
            self.immediate == 0 {
             // NOP
-            return;
+            return Ok(());
         }
 
         let src = cpu.get_register(self.src);
+        // The immediate is sign-extended to XLEN.
+        let immediate = cpu.truncate(self.immediate as i64 as u64);
+        let shamt = self.immediate & 0x3F;
+
+        if self.word {
+            let src = src as u32;
+            let result = match self.typ {
+                ImmediateOperationType::Add => src.wrapping_add(immediate as u32),
+                ImmediateOperationType::ShiftLeftLogical => src << shamt,
+                ImmediateOperationType::ShiftRightLogical => src >> shamt,
+                ImmediateOperationType::ShiftRightArithmetic => ((src as i32) >> shamt) as u32,
+                _ => unreachable!(),
+            };
+            cpu.set_register(self.dest, result as i32 as i64 as u64);
+            return Ok(());
+        }
 
-        cpu.set_register(self.dest,
-                         match self.typ {
-                             ImmediateOperationType::Add => src.wrapping_add(self.immediate as u32),
-                             ImmediateOperationType::SetLessThan => {
-                                 if (src as i32) < self.immediate { 1 } else { 0 }
-                             }
-                             ImmediateOperationType::SetLessThanUnsigned => {
-                                 if src < (self.immediate as u32) { 1 } else { 0 }
-                             }
-                             ImmediateOperationType::And => src & (self.immediate as u32),
-                             ImmediateOperationType::Or => src | (self.immediate as u32),
-                             ImmediateOperationType::Xor => src ^ (self.immediate as u32),
-                             ImmediateOperationType::ShiftLeftLogical => {
-                                 src << (self.immediate & 0x1F)
-                             }
-                             ImmediateOperationType::ShiftRightLogical => {
-                                 src >> (self.immediate & 0x1F)
-                             }
-                             ImmediateOperationType::ShiftRightArithmetic => {
-                                 ((src as i32) >> (self.immediate & 0x1F)) as u32
-                             }
-                         });
+        let result = match self.typ {
+            ImmediateOperationType::Add => src.wrapping_add(immediate),
+            ImmediateOperationType::SetLessThan => {
+                if cpu.signed(src) < self.immediate as i64 { 1 } else { 0 }
+            }
+            ImmediateOperationType::SetLessThanUnsigned => if src < immediate { 1 } else { 0 },
+            ImmediateOperationType::And => src & immediate,
+            ImmediateOperationType::Or => src | immediate,
+            ImmediateOperationType::Xor => src ^ immediate,
+            ImmediateOperationType::ShiftLeftLogical => src << shamt,
+            ImmediateOperationType::ShiftRightLogical => src >> shamt,
+            ImmediateOperationType::ShiftRightArithmetic => (cpu.signed(src) >> shamt) as u64,
+        };
+        cpu.set_register(self.dest, result);
+        Ok(())
     }
 
     fn to_raw(&self) -> u32 {
         encoding::I {
-            opcode: 0x13,
+            opcode: if self.word { 0x1B } else { 0x13 },
             funct3: match self.typ {
                 ImmediateOperationType::Add => 0b000,
                 ImmediateOperationType::ShiftLeftLogical => 0b001,
@@ -120,15 +178,17 @@ impl Instruction for OpImm {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Op {
     typ: OperationType,
     dest: u8,
     src1: u8,
     src2: u8,
+    // RV64's OP-32 (ADDW and friends)
+    word: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum OperationType {
     Add,
     Sub,
@@ -146,10 +206,12 @@ impl Op {
     pub fn parse(instruction: u32) -> Option<Op> {
         let decoded = encoding::R::parse(instruction);
 
-        if decoded.opcode != 0x33 {
-            // Not a OP-IMM opcode
-            return None;
-        }
+        let word = match decoded.opcode {
+            0x33 => false,
+            0x3B => true,
+            // Not a OP or OP-32 opcode
+            _ => return None,
+        };
 
         let typ = match (decoded.funct7, decoded.funct3) {
             (0, 0b000) => OperationType::Add,
@@ -165,42 +227,76 @@ impl Op {
             _ => return None,
         };
 
+        if word {
+            match typ {
+                OperationType::Add | OperationType::Sub | OperationType::ShiftLeftLogical |
+                OperationType::ShiftRightLogical | OperationType::ShiftRightArithmetic => {}
+                _ => return None,
+            }
+        }
+
         Some(Op {
-            typ: typ,
+            typ,
             dest: decoded.rd,
             src1: decoded.rs1,
             src2: decoded.rs2,
+            word,
         })
     }
+
+    /// Whether this is one of the OP-32 instructions, which only exist on
+    /// RV64.
+    pub fn is_rv64_only(&self) -> bool {
+        self.word
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.src1, self.src2))
+    }
 }
 
 impl Instruction for Op {
-    fn execute(&self, cpu: &mut CPU) {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
         let src1 = cpu.get_register(self.src1);
         let src2 = cpu.get_register(self.src2);
 
-        cpu.set_register(self.dest,
-                         match self.typ {
-                             OperationType::Add => src1.wrapping_add(src2),
-                             OperationType::Sub => src1.wrapping_sub(src2),
-                             OperationType::SetLessThan => {
-                                 if (src1 as i32) < (src2 as i32) { 1 } else { 0 }
-                             }
-                             OperationType::SetLessThanUnsigned => if src1 < src2 { 1 } else { 0 },
-                             OperationType::And => src1 & src2,
-                             OperationType::Or => src1 | src2,
-                             OperationType::Xor => src1 ^ src2,
-                             OperationType::ShiftLeftLogical => src1 << (src2 & 0x1F),
-                             OperationType::ShiftRightLogical => src1 >> (src2 & 0x1F),
-                             OperationType::ShiftRightArithmetic => {
-                                 ((src1 as i32) >> (src2 & 0x1F)) as u32
-                             }
-                         });
+        if self.word {
+            let (src1, src2) = (src1 as u32, src2 as u32);
+            let result = match self.typ {
+                OperationType::Add => src1.wrapping_add(src2),
+                OperationType::Sub => src1.wrapping_sub(src2),
+                OperationType::ShiftLeftLogical => src1 << (src2 & 0x1F),
+                OperationType::ShiftRightLogical => src1 >> (src2 & 0x1F),
+                OperationType::ShiftRightArithmetic => ((src1 as i32) >> (src2 & 0x1F)) as u32,
+                _ => unreachable!(),
+            };
+            cpu.set_register(self.dest, result as i32 as i64 as u64);
+            return Ok(());
+        }
+
+        // Shifts use the low 5 bits of src2 on RV32, and 6 on RV64.
+        let shamt = src2 & (cpu.xlen() as u64 - 1);
+        let result = match self.typ {
+            OperationType::Add => src1.wrapping_add(src2),
+            OperationType::Sub => src1.wrapping_sub(src2),
+            OperationType::SetLessThan => {
+                if cpu.signed(src1) < cpu.signed(src2) { 1 } else { 0 }
+            }
+            OperationType::SetLessThanUnsigned => if src1 < src2 { 1 } else { 0 },
+            OperationType::And => src1 & src2,
+            OperationType::Or => src1 | src2,
+            OperationType::Xor => src1 ^ src2,
+            OperationType::ShiftLeftLogical => src1 << shamt,
+            OperationType::ShiftRightLogical => src1 >> shamt,
+            OperationType::ShiftRightArithmetic => (cpu.signed(src1) >> shamt) as u64,
+        };
+        cpu.set_register(self.dest, result);
+        Ok(())
     }
 
     fn to_raw(&self) -> u32 {
         encoding::R {
-            opcode: 0x33,
+            opcode: if self.word { 0x3B } else { 0x33 },
             funct3: match self.typ {
                 OperationType::Add => 0b000,
                 OperationType::Sub => 0b000,
@@ -224,7 +320,7 @@ impl Instruction for Op {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Lui {
     dest: u8,
     immediate: u32,
@@ -244,11 +340,16 @@ impl Lui {
             immediate: decoded.immediate as u32,
         })
     }
+
+    pub fn max_register(&self) -> u8 {
+        self.dest
+    }
 }
 
 impl Instruction for Lui {
-    fn execute(&self, cpu: &mut CPU) {
-        cpu.set_register(self.dest, self.immediate);
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        cpu.set_register(self.dest, self.immediate as i32 as i64 as u64);
+        Ok(())
     }
 
     fn to_raw(&self) -> u32 {
@@ -260,7 +361,7 @@ impl Instruction for Lui {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Auipc {
     dest: u8,
     immediate: u32,
@@ -280,12 +381,17 @@ impl Auipc {
             immediate: decoded.immediate as u32,
         })
     }
+
+    pub fn max_register(&self) -> u8 {
+        self.dest
+    }
 }
 
 impl Instruction for Auipc {
-    fn execute(&self, cpu: &mut CPU) {
-        let result = cpu.pc.wrapping_add(self.immediate);
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let result = (cpu.pc as u64).wrapping_add(self.immediate as i32 as i64 as u64);
         cpu.set_register(self.dest, result);
+        Ok(())
     }
 
     fn to_raw(&self) -> u32 {
@@ -298,6 +404,7 @@ impl Instruction for Auipc {
 }
 
 #[cfg(test)]
+#[allow(clippy::identity_op)]
 mod tests {
     use super::*;
     use cpu::CPU;
@@ -309,7 +416,7 @@ mod tests {
             $cpu.set_register(1, $val1);
             let raw_instruction = (($imm & 0xFFF) << 20) | (1 << 15) | (3 << 7) | $op << 12 | 0x13;
             let instr = OpImm::parse(raw_instruction).expect("couldn't parse instruction");
-            instr.execute(&mut $cpu);
+            instr.execute(&mut $cpu).expect("couldn't execute instruction");
             assert_eq!($cpu.get_register(3), $result);
         }
     }
@@ -319,7 +426,7 @@ mod tests {
             $cpu.set_register(1, $val1);
             let raw_instruction = (($imm & 0xFFF) << 20) | (1 << 15) | (1 << 7) | $op << 12 | 0x13;
             let instr = OpImm::parse(raw_instruction).expect("couldn't parse instruction");
-            instr.execute(&mut $cpu);
+            instr.execute(&mut $cpu).expect("couldn't execute instruction");
             assert_eq!($cpu.get_register(1), $result);
         }
     }
@@ -521,7 +628,7 @@ mod tests {
             $cpu.set_register(2, $val2);
             let raw_instruction = $op2 << 25 | (2 << 20) | (1 << 15) | (3 << 7) | $op1 << 12 | 0x33;
             let instr = Op::parse(raw_instruction).expect("couldn't parse instruction");
-            instr.execute(&mut $cpu);
+            instr.execute(&mut $cpu).expect("couldn't execute instruction");
             assert_eq!($cpu.get_register(3), $result);
         }
     }
@@ -532,7 +639,7 @@ mod tests {
             $cpu.set_register(2, $val2);
             let raw_instruction = $op2 << 25 | (2 << 20) | (1 << 15) | (1 << 7) | $op1 << 12 | 0x33;
             let instr = Op::parse(raw_instruction).expect("couldn't parse instruction");
-            instr.execute(&mut $cpu);
+            instr.execute(&mut $cpu).expect("couldn't execute instruction");
             assert_eq!($cpu.get_register(1), $result);
         }
     }
@@ -543,7 +650,7 @@ mod tests {
             $cpu.set_register(2, $val2);
             let raw_instruction = $op2 << 25 | (2 << 20) | (1 << 15) | (2 << 7) | $op1 << 12 | 0x33;
             let instr = Op::parse(raw_instruction).expect("couldn't parse instruction");
-            instr.execute(&mut $cpu);
+            instr.execute(&mut $cpu).expect("couldn't execute instruction");
             assert_eq!($cpu.get_register(2), $result);
         }
     }
@@ -553,7 +660,7 @@ mod tests {
             $cpu.set_register(1, $val1);
             let raw_instruction = $op2 << 25 | (1 << 20) | (1 << 15) | (1 << 7) | $op1 << 12 | 0x33;
             let instr = Op::parse(raw_instruction).expect("couldn't parse instruction");
-            instr.execute(&mut $cpu);
+            instr.execute(&mut $cpu).expect("couldn't execute instruction");
             assert_eq!($cpu.get_register(1), $result);
         }
     }
@@ -728,12 +835,12 @@ mod tests {
             ($cpu:expr, $result:expr, $imm:expr, $sra:expr) => {
                 let raw_instruction = (($imm & 0xFFFFF) << 12) | (1 << 7) | 0x37;
                 let instr = Lui::parse(raw_instruction).expect("couldn't parse instruction");
-                instr.execute(&mut $cpu);
+                instr.execute(&mut $cpu).expect("couldn't execute instruction");
 
                 let sra_amount = $sra | (1 << 10);
                 let sra_raw_instruction = ((sra_amount & 0xFFF) << 20) | (1 << 15) | (1 << 7) | 0b101 << 12 | 0x13;
                 let sra_instr = OpImm::parse(sra_raw_instruction).expect("couldn't parse SRA instruction");
-                sra_instr.execute(&mut cpu);
+                sra_instr.execute(&mut cpu).expect("couldn't execute instruction");
 
                 assert_eq!($cpu.get_register(1), $result);
             }
@@ -746,7 +853,7 @@ mod tests {
 
         let raw_instruction = ((0x80000 & 0xFFFFF) << 12) | (0 << 7) | 0x37;
         let instr = Lui::parse(raw_instruction).expect("couldn't parse instruction");
-        instr.execute(&mut cpu);
+        instr.execute(&mut cpu).expect("couldn't execute instruction");
         assert_eq!(cpu.get_register(0), 0);
     }
 }
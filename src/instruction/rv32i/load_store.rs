@@ -5,10 +5,13 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::cmp;
+
 use instruction::{encoding, Instruction};
 use cpu::CPU;
+use trap::Exception;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Load {
     typ: LoadType,
     dest: u8,
@@ -16,13 +19,15 @@ pub struct Load {
     base: u8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum LoadType {
     Byte,
     ByteUnsigned,
     HalfWord,
     HalfWordUnsigned,
     Word,
+    WordUnsigned,
+    DoubleWord,
 }
 
 impl Load {
@@ -38,33 +43,48 @@ impl Load {
             0b000 => LoadType::Byte,
             0b001 => LoadType::HalfWord,
             0b010 => LoadType::Word,
+            0b011 => LoadType::DoubleWord,
             0b100 => LoadType::ByteUnsigned,
             0b101 => LoadType::HalfWordUnsigned,
+            0b110 => LoadType::WordUnsigned,
             _ => return None,
         };
 
         Some(Load {
-            typ: typ,
+            typ,
             dest: decoded.rd,
             offset: decoded.immediate,
             base: decoded.rs1,
         })
     }
+
+    /// Whether this is LWU or LD, which only exist on RV64.
+    pub fn is_rv64_only(&self) -> bool {
+        matches!(self.typ, LoadType::WordUnsigned | LoadType::DoubleWord)
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, self.base)
+    }
 }
 
 impl Instruction for Load {
-    fn execute(&self, cpu: &mut CPU) {
-        let addr = cpu.get_register(self.base).wrapping_add(self.offset as u32);
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let base = cpu.get_register(self.base);
+        let addr = cpu.address(base, self.offset, Exception::LoadAccessFault)?;
 
         let value = match self.typ {
-            LoadType::Byte => cpu.ram[addr] as i8 as i32 as u32,
-            LoadType::HalfWord => cpu.ram.get_u16(addr) as i16 as i32 as u32,
-            LoadType::Word => cpu.ram.get_u32(addr),
-            LoadType::ByteUnsigned => cpu.ram[addr] as u32,
-            LoadType::HalfWordUnsigned => cpu.ram.get_u16(addr) as u32,
+            LoadType::Byte => cpu.load_u8(addr)? as i8 as i64 as u64,
+            LoadType::HalfWord => cpu.load_u16(addr)? as i16 as i64 as u64,
+            LoadType::Word => cpu.load_u32(addr)? as i32 as i64 as u64,
+            LoadType::DoubleWord => cpu.load_u64(addr)?,
+            LoadType::ByteUnsigned => cpu.load_u8(addr)? as u64,
+            LoadType::HalfWordUnsigned => cpu.load_u16(addr)? as u64,
+            LoadType::WordUnsigned => cpu.load_u32(addr)? as u64,
         };
 
         cpu.set_register(self.dest, value);
+        Ok(())
     }
 
     fn to_raw(&self) -> u32 {
@@ -74,8 +94,10 @@ impl Instruction for Load {
                 LoadType::Byte => 0b000,
                 LoadType::HalfWord => 0b001,
                 LoadType::Word => 0b010,
+                LoadType::DoubleWord => 0b011,
                 LoadType::ByteUnsigned => 0b100,
                 LoadType::HalfWordUnsigned => 0b101,
+                LoadType::WordUnsigned => 0b110,
             },
             rd: self.dest,
             immediate: self.offset,
@@ -84,7 +106,7 @@ impl Instruction for Load {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Store {
     typ: StoreType,
     offset: i32,
@@ -92,11 +114,12 @@ pub struct Store {
     src: u8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum StoreType {
     Byte,
     HalfWord,
     Word,
+    DoubleWord,
 }
 
 impl Store {
@@ -112,28 +135,40 @@ impl Store {
             0b000 => StoreType::Byte,
             0b001 => StoreType::HalfWord,
             0b010 => StoreType::Word,
+            0b011 => StoreType::DoubleWord,
             _ => return None,
         };
 
         Some(Store {
-            typ: typ,
+            typ,
             offset: decoded.immediate,
             base: decoded.rs1,
             src: decoded.rs2,
         })
     }
+
+    /// Whether this is SD, which only exists on RV64.
+    pub fn is_rv64_only(&self) -> bool {
+        matches!(self.typ, StoreType::DoubleWord)
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.base, self.src)
+    }
 }
 
 impl Instruction for Store {
-    fn execute(&self, cpu: &mut CPU) {
-        let addr = cpu.get_register(self.base).wrapping_add(self.offset as u32);
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let base = cpu.get_register(self.base);
+        let addr = cpu.address(base, self.offset, Exception::StoreAccessFault)?;
         let value = cpu.get_register(self.src);
 
         match self.typ {
-            StoreType::Byte => cpu.ram[addr] = value as u8,
-            StoreType::HalfWord => cpu.ram.set_u16(addr, value as u16),
-            StoreType::Word => cpu.ram.set_u32(addr, value),
-        };
+            StoreType::Byte => cpu.store_u8(addr, value as u8),
+            StoreType::HalfWord => cpu.store_u16(addr, value as u16),
+            StoreType::Word => cpu.store_u32(addr, value as u32),
+            StoreType::DoubleWord => cpu.store_u64(addr, value),
+        }
     }
 
     fn to_raw(&self) -> u32 {
@@ -143,6 +178,7 @@ impl Instruction for Store {
                 StoreType::Byte => 0b000,
                 StoreType::HalfWord => 0b001,
                 StoreType::Word => 0b010,
+                StoreType::DoubleWord => 0b011,
             },
             immediate: self.offset,
             rs1: self.base,
@@ -0,0 +1,77 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MiscMem {
+    typ: MiscMemType,
+    dest: u8,
+    src: u8,
+    immediate: i32, // fm/pred/succ for FENCE, reserved for FENCE.I
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MiscMemType {
+    Fence,
+    FenceInstruction,
+}
+
+impl MiscMem {
+    pub fn parse(instruction: u32) -> Option<MiscMem> {
+        let decoded = encoding::I::parse(instruction);
+
+        if decoded.opcode != 0x0F {
+            // Not a MISC-MEM opcode
+            return None;
+        }
+
+        let typ = match decoded.funct3 {
+            0b000 => MiscMemType::Fence,
+            0b001 => MiscMemType::FenceInstruction,
+            _ => return None,
+        };
+
+        Some(MiscMem {
+            typ,
+            dest: decoded.rd,
+            src: decoded.rs1,
+            immediate: decoded.immediate,
+        })
+    }
+
+    pub fn is_fence_i(&self) -> bool {
+        matches!(self.typ, MiscMemType::FenceInstruction)
+    }
+}
+
+impl Instruction for MiscMem {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        match self.typ {
+            // There's only one hart and no caches, so memory is always
+            // coherent.
+            MiscMemType::Fence => {}
+            MiscMemType::FenceInstruction => cpu.flush_decode_cache(),
+        }
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        encoding::I {
+            opcode: 0x0F,
+            funct3: match self.typ {
+                MiscMemType::Fence => 0b000,
+                MiscMemType::FenceInstruction => 0b001,
+            },
+            rd: self.dest,
+            rs1: self.src,
+            immediate: self.immediate,
+        }.to_raw()
+    }
+}
@@ -8,7 +8,11 @@
 mod control_transfer;
 mod int_compute;
 mod load_store;
+mod misc_mem;
+mod system;
 
 pub use self::control_transfer::*;
 pub use self::int_compute::*;
 pub use self::load_store::*;
+pub use self::misc_mem::*;
+pub use self::system::*;
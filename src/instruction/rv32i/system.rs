@@ -0,0 +1,256 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::{CPU, Privilege};
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct System {
+    typ: SystemType,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SystemType {
+    EnvironmentCall,
+    EnvironmentBreak,
+    MachineReturn,
+    DebugReturn,
+    WaitForInterrupt,
+}
+
+impl System {
+    pub fn parse(instruction: u32) -> Option<System> {
+        let decoded = encoding::I::parse(instruction);
+
+        if decoded.opcode != 0x73 {
+            // Not a SYSTEM opcode
+            return None;
+        }
+
+        if decoded.funct3 != 0 || decoded.rd != 0 || decoded.rs1 != 0 {
+            return None;
+        }
+
+        let typ = match decoded.immediate & 0xFFF {
+            0x000 => SystemType::EnvironmentCall,
+            0x001 => SystemType::EnvironmentBreak,
+            0x302 => SystemType::MachineReturn,
+            0x7B2 => SystemType::DebugReturn,
+            0x105 => SystemType::WaitForInterrupt,
+            _ => return None,
+        };
+
+        Some(System { typ })
+    }
+}
+
+impl Instruction for System {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        match self.typ {
+            SystemType::EnvironmentCall => Err(Exception::EnvironmentCall),
+            SystemType::EnvironmentBreak => Err(Exception::Breakpoint(cpu.pc as u64)),
+            SystemType::MachineReturn => {
+                if cpu.privilege() != Privilege::Machine {
+                    return Err(Exception::IllegalInstruction(self.to_raw()));
+                }
+
+                cpu.return_from_trap();
+                Ok(())
+            }
+            SystemType::DebugReturn => {
+                if !cpu.in_debug_mode() {
+                    return Err(Exception::IllegalInstruction(self.to_raw()));
+                }
+
+                cpu.resume_from_debug_mode();
+                Ok(())
+            }
+            SystemType::WaitForInterrupt => {
+                cpu.wait_for_interrupt();
+                Ok(())
+            }
+        }
+    }
+
+    fn to_raw(&self) -> u32 {
+        encoding::I {
+            opcode: 0x73,
+            funct3: 0,
+            rd: 0,
+            rs1: 0,
+            immediate: match self.typ {
+                SystemType::EnvironmentCall => 0x000,
+                SystemType::EnvironmentBreak => 0x001,
+                SystemType::MachineReturn => 0x302,
+                SystemType::DebugReturn => 0x7B2,
+                SystemType::WaitForInterrupt => 0x105,
+            },
+        }.to_raw()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Csr {
+    typ: CsrType,
+    dest: u8,
+    src: u8, // register, or a 5-bit immediate for the *I variants
+    csr: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CsrType {
+    Write,
+    Set,
+    Clear,
+    WriteImmediate,
+    SetImmediate,
+    ClearImmediate,
+}
+
+impl Csr {
+    pub fn parse(instruction: u32) -> Option<Csr> {
+        let decoded = encoding::I::parse(instruction);
+
+        if decoded.opcode != 0x73 {
+            // Not a SYSTEM opcode
+            return None;
+        }
+
+        let typ = match decoded.funct3 {
+            0b001 => CsrType::Write,
+            0b010 => CsrType::Set,
+            0b011 => CsrType::Clear,
+            0b101 => CsrType::WriteImmediate,
+            0b110 => CsrType::SetImmediate,
+            0b111 => CsrType::ClearImmediate,
+            _ => return None,
+        };
+
+        Some(Csr {
+            typ,
+            dest: decoded.rd,
+            src: decoded.rs1,
+            csr: (decoded.immediate & 0xFFF) as u16,
+        })
+    }
+
+    // The immediate forms keep their immediate where rs1 would be.
+    pub fn max_register(&self) -> u8 {
+        match self.typ {
+            CsrType::Write | CsrType::Set | CsrType::Clear => cmp::max(self.dest, self.src),
+            _ => self.dest,
+        }
+    }
+}
+
+impl Instruction for Csr {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let illegal = |_| Exception::IllegalInstruction(self.to_raw());
+
+        let src = match self.typ {
+            CsrType::Write | CsrType::Set | CsrType::Clear => {
+                cpu.get_register(self.src)
+            }
+            _ => self.src as u64,
+        };
+
+        // CSRRW with rd=x0 doesn't read the CSR, and CSRRS/CSRRC with rs1=x0
+        // don't write it, so neither has side effects from that access.
+        let writes = match self.typ {
+            CsrType::Write | CsrType::WriteImmediate => true,
+            _ => self.src != 0,
+        };
+        let old = if self.dest == 0 &&
+                     (self.typ == CsrType::Write || self.typ == CsrType::WriteImmediate) {
+            0
+        } else {
+            cpu.read_csr(self.csr).map_err(illegal)?
+        };
+
+        if writes {
+            let new = match self.typ {
+                CsrType::Write | CsrType::WriteImmediate => src,
+                CsrType::Set | CsrType::SetImmediate => old | src,
+                CsrType::Clear | CsrType::ClearImmediate => old & !src,
+            };
+            cpu.write_csr(self.csr, new).map_err(illegal)?;
+        }
+
+        cpu.set_register(self.dest, old);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        encoding::I {
+            opcode: 0x73,
+            funct3: match self.typ {
+                CsrType::Write => 0b001,
+                CsrType::Set => 0b010,
+                CsrType::Clear => 0b011,
+                CsrType::WriteImmediate => 0b101,
+                CsrType::SetImmediate => 0b110,
+                CsrType::ClearImmediate => 0b111,
+            },
+            rd: self.dest,
+            rs1: self.src,
+            immediate: self.csr as i32,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CPU;
+    use ram::RAM;
+    use instruction::Instruction;
+    use trap::Exception;
+
+    #[test]
+    fn test_csr_immediate() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        macro_rules! test_csr {
+            ($raw:expr, $dest:expr, $result:expr, $mscratch:expr) => {
+                let instr = Csr::parse($raw).expect("couldn't parse instruction");
+                instr.execute(&mut cpu).expect("couldn't execute instruction");
+                assert_eq!(cpu.get_register($dest), $result);
+                assert_eq!(cpu.get_csr(0x340), Ok($mscratch));
+            }
+        }
+
+        test_csr!(0x3402d573, 10, 0, 5); // csrrwi a0, mscratch, 5
+        test_csr!(0x340165f3, 11, 5, 7); // csrrsi a1, mscratch, 2
+        test_csr!(0x3400f673, 12, 7, 6); // csrrci a2, mscratch, 1
+        test_csr!(0x340026f3, 13, 6, 6); // csrr a3, mscratch
+    }
+
+    #[test]
+    fn test_csr_read_only() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // csrrw a3, cycle, a0
+        let instr = Csr::parse(0xc00516f3).expect("couldn't parse instruction");
+        assert_eq!(instr.execute(&mut cpu),
+                   Err(Exception::IllegalInstruction(0xc00516f3)));
+
+        // csrr a3, cycle
+        let instr = Csr::parse(0xc00026f3).expect("couldn't parse instruction");
+        instr.execute(&mut cpu).expect("couldn't execute instruction");
+    }
+
+    #[test]
+    fn test_system_round_trip() {
+        for &raw in &[0x00000073, 0x00100073, 0x30200073, 0x7b200073, 0x10500073] {
+            let instr = System::parse(raw).expect("couldn't parse instruction");
+            assert_eq!(instr.to_raw(), raw);
+        }
+    }
+}
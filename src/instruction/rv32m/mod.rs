@@ -5,18 +5,24 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::cmp;
+
 use instruction::{encoding, Instruction};
 use cpu::CPU;
+use trap::Exception;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Op {
     typ: OperationType,
     dest: u8,
     operand1: u8, // multiplicand/dividend
     operand2: u8, // multiplier/divisor
+    // RV64's MULW, DIVW and friends, which work on the low 32 bits and
+    // sign-extend the result
+    word: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum OperationType {
     Mul,
     MulHighSigned,
@@ -32,10 +38,12 @@ impl Op {
     pub fn parse(instruction: u32) -> Option<Op> {
         let decoded = encoding::R::parse(instruction);
 
-        if decoded.opcode != 0x33 {
-            // Not a OP opcode
-            return None;
-        }
+        let word = match decoded.opcode {
+            0x33 => false,
+            0x3B => true,
+            // Not a OP or OP-32 opcode
+            _ => return None,
+        };
 
         if decoded.funct7 != 1 {
             return None;
@@ -53,50 +61,71 @@ impl Op {
             _ => unreachable!(),
         };
 
+        if word {
+            match typ {
+                OperationType::MulHighSigned |
+                OperationType::MulHighUnsigned |
+                OperationType::MulHighSignedUnsigned => return None,
+                _ => {}
+            }
+        }
+
         Some(Op {
-            typ: typ,
+            typ,
             dest: decoded.rd,
             operand1: decoded.rs1,
             operand2: decoded.rs2,
+            word,
         })
     }
+
+    /// Whether this is one of the *W instructions, which only exist on RV64.
+    pub fn is_rv64_only(&self) -> bool {
+        self.word
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.operand1, self.operand2))
+    }
 }
 
 impl Instruction for Op {
-    fn execute(&self, cpu: &mut CPU) {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
         let operand1 = cpu.get_register(self.operand1);
         let operand2 = cpu.get_register(self.operand2);
 
+        if self.word {
+            let result = word_op(self.typ, operand1 as u32, operand2 as u32);
+            cpu.set_register(self.dest, result as i32 as i64 as u64);
+            return Ok(());
+        }
+
+        let xlen = cpu.xlen();
+        let (signed1, signed2) = (cpu.signed(operand1), cpu.signed(operand2));
         let result = match self.typ {
             OperationType::Mul => operand1.wrapping_mul(operand2),
             OperationType::MulHighSigned => {
-                (((operand1 as i32 as i64) * (operand2 as i32 as i64)) >> 32) as u32
+                ((signed1 as i128 * signed2 as i128) >> xlen) as u64
             }
             OperationType::MulHighUnsigned => {
-                (((operand1 as u64) * (operand2 as u64)) >> 32) as u32
+                ((operand1 as u128 * operand2 as u128) >> xlen) as u64
             }
             OperationType::MulHighSignedUnsigned => {
-                (((operand1 as i32 as i64) * (operand2 as i64)) >> 32) as u32
+                ((signed1 as i128 * operand2 as i128) >> xlen) as u64
             }
             OperationType::Div => {
                 if operand2 == 0 {
-                    (-1i32) as u32
+                    u64::MAX
                 } else {
-                    (operand1 as i32).wrapping_div(operand2 as i32) as u32
-                }
-            }
-            OperationType::DivUnsigned => {
-                if operand2 == 0 {
-                    (-1i32) as u32
-                } else {
-                    operand1 / operand2
+                    signed1.wrapping_div(signed2) as u64
                 }
             }
+            OperationType::DivUnsigned => operand1.checked_div(operand2).unwrap_or(u64::MAX),
             OperationType::Remainder => {
                 if operand2 == 0 {
                     operand1
                 } else {
-                    (operand1 as i32).wrapping_rem(operand2 as i32) as u32
+                    signed1.wrapping_rem(signed2) as u64
                 }
             }
             OperationType::RemainderUnsigned => {
@@ -109,11 +138,12 @@ impl Instruction for Op {
         };
 
         cpu.set_register(self.dest, result);
+        Ok(())
     }
 
     fn to_raw(&self) -> u32 {
         encoding::R {
-            opcode: 0x33,
+            opcode: if self.word { 0x3B } else { 0x33 },
             funct7: 1,
             funct3: match self.typ {
                 OperationType::Mul => 0b000,
@@ -132,9 +162,39 @@ impl Instruction for Op {
     }
 }
 
+// The 32-bit result of one of the *W instructions.
+fn word_op(typ: OperationType, operand1: u32, operand2: u32) -> u32 {
+    match typ {
+        OperationType::Mul => operand1.wrapping_mul(operand2),
+        OperationType::Div => {
+            if operand2 == 0 {
+                u32::MAX
+            } else {
+                (operand1 as i32).wrapping_div(operand2 as i32) as u32
+            }
+        }
+        OperationType::DivUnsigned => operand1.checked_div(operand2).unwrap_or(u32::MAX),
+        OperationType::Remainder => {
+            if operand2 == 0 {
+                operand1
+            } else {
+                (operand1 as i32).wrapping_rem(operand2 as i32) as u32
+            }
+        }
+        OperationType::RemainderUnsigned => {
+            if operand2 == 0 {
+                operand1
+            } else {
+                operand1 % operand2
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
 mod tests {
-    use std::i32;
     use super::*;
     use ram::RAM;
     use cpu::CPU;
@@ -151,7 +211,7 @@ mod tests {
             ($result:expr, $val1:expr, $val2:expr) => {
                 cpu.set_register(2, $val1);
                 cpu.set_register(3, $val2);
-                instr.execute(&mut cpu);
+                instr.execute(&mut cpu).expect("couldn't execute instruction");
                 assert_eq!(cpu.get_register(1), $result);
             }
         }
@@ -188,7 +248,7 @@ mod tests {
             ($result:expr, $val1:expr, $val2:expr) => {
                 cpu.set_register(2, $val1);
                 cpu.set_register(3, $val2);
-                instr.execute(&mut cpu);
+                instr.execute(&mut cpu).expect("couldn't execute instruction");
                 assert_eq!(cpu.get_register(1), $result);
             }
         }
@@ -222,7 +282,7 @@ mod tests {
             ($result:expr, $val1:expr, $val2:expr) => {
                 cpu.set_register(2, $val1);
                 cpu.set_register(3, $val2);
-                instr.execute(&mut cpu);
+                instr.execute(&mut cpu).expect("couldn't execute instruction");
                 assert_eq!(cpu.get_register(1), $result);
             }
         }
@@ -256,7 +316,7 @@ mod tests {
             ($result:expr, $val1:expr, $val2:expr) => {
                 cpu.set_register(2, $val1);
                 cpu.set_register(3, $val2);
-                instr.execute(&mut cpu);
+                instr.execute(&mut cpu).expect("couldn't execute instruction");
                 assert_eq!(cpu.get_register(1), $result);
             }
         }
@@ -288,10 +348,10 @@ mod tests {
 
         macro_rules! test_div {
             ($result:expr, $val1:expr, $val2:expr) => {
-                cpu.set_register(2, $val1 as u32);
-                cpu.set_register(3, $val2 as u32);
-                instr.execute(&mut cpu);
-                assert_eq!(cpu.get_register(1), $result as u32);
+                cpu.set_register(2, $val1 as u32 as u64);
+                cpu.set_register(3, $val2 as u32 as u64);
+                instr.execute(&mut cpu).expect("couldn't execute instruction");
+                assert_eq!(cpu.get_register(1), $result as u32 as u64);
             }
         }
 
@@ -317,10 +377,10 @@ mod tests {
 
         macro_rules! test_divu {
             ($result:expr, $val1:expr, $val2:expr) => {
-                cpu.set_register(2, $val1 as u32);
-                cpu.set_register(3, $val2 as u32);
-                instr.execute(&mut cpu);
-                assert_eq!(cpu.get_register(1), $result as u32);
+                cpu.set_register(2, $val1 as u32 as u64);
+                cpu.set_register(3, $val2 as u32 as u64);
+                instr.execute(&mut cpu).expect("couldn't execute instruction");
+                assert_eq!(cpu.get_register(1), $result as u32 as u64);
             }
         }
 
@@ -346,10 +406,10 @@ mod tests {
 
         macro_rules! test_rem {
             ($result:expr, $val1:expr, $val2:expr) => {
-                cpu.set_register(2, $val1 as u32);
-                cpu.set_register(3, $val2 as u32);
-                instr.execute(&mut cpu);
-                assert_eq!(cpu.get_register(1), $result as u32);
+                cpu.set_register(2, $val1 as u32 as u64);
+                cpu.set_register(3, $val2 as u32 as u64);
+                instr.execute(&mut cpu).expect("couldn't execute instruction");
+                assert_eq!(cpu.get_register(1), $result as u32 as u64);
             }
         }
 
@@ -375,10 +435,10 @@ mod tests {
 
         macro_rules! test_remu {
             ($result:expr, $val1:expr, $val2:expr) => {
-                cpu.set_register(2, $val1 as u32);
-                cpu.set_register(3, $val2 as u32);
-                instr.execute(&mut cpu);
-                assert_eq!(cpu.get_register(1), $result as u32);
+                cpu.set_register(2, $val1 as u32 as u64);
+                cpu.set_register(3, $val2 as u32 as u64);
+                instr.execute(&mut cpu).expect("couldn't execute instruction");
+                assert_eq!(cpu.get_register(1), $result as u32 as u64);
             }
         }
 
@@ -0,0 +1,539 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The C extension (all of Zca, without F or D), Zcb and Zcmop: 16-bit
+//! forms of common instructions.
+//!
+//! Each one is expanded into the 32-bit instruction it stands for when it's
+//! decoded, and executes as that, except that C.JAL and C.JALR link to the
+//! instruction 2 bytes on rather than 4. The floating point loads and stores
+//! don't decode, and Zcmop's may-be-operations do nothing.
+
+use std::cmp;
+
+use instruction::{encoding, rv32i, rv32m, zba, zbb, zbkb, Instruction};
+use isa::Extension;
+use cpu::CPU;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    raw: u16,
+    extension: Extension,
+    expanded: Expanded,
+    // C.JAL and C.JALR, which expand to jumps that don't link, and set ra
+    // themselves.
+    link: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Expanded {
+    Load(rv32i::Load),
+    Store(rv32i::Store),
+    OpImm(rv32i::OpImm),
+    Op(rv32i::Op),
+    Lui(rv32i::Lui),
+    Branch(rv32i::Branch),
+    Jal(rv32i::Jal),
+    Jalr(rv32i::Jalr),
+    System(rv32i::System),
+    MulDiv(rv32m::Op),
+    Zba(zba::Op),
+    Zbb(zbb::Op),
+    // ZEXT.H, which is PACK with x0
+    Zbkb(zbkb::Op),
+    MayBe,
+}
+
+impl Op {
+    /// Decode a 16-bit instruction. RV32 and RV64 give some encodings
+    /// different meanings, so this needs to know XLEN.
+    pub fn parse(instruction: u16, xlen: u32) -> Option<Op> {
+        let raw = instruction as u32;
+        let bits = |shift: u32, width: u32| (raw >> shift) & ((1 << width) - 1);
+        let rd = bits(7, 5) as u8;
+        let rs2 = bits(2, 5) as u8;
+        // The 3-bit register fields only reach x8-x15.
+        let rd_short = bits(2, 3) as u8 + 8;
+        let rs1_short = bits(7, 3) as u8 + 8;
+        let imm6 = sign_extend(bits(12, 1) << 5 | bits(2, 5), 6);
+        // The offsets of C.LW and C.SW, and of C.LD and C.SD
+        let word_offset = (bits(10, 3) << 3 | bits(6, 1) << 2 | bits(5, 1) << 6) as i32;
+        let double_offset = (bits(10, 3) << 3 | bits(5, 2) << 6) as i32;
+
+        let mut extension = Extension::C;
+        let mut link = false;
+        let expanded = match (raw & 0b11, bits(13, 3)) {
+            // C.ADDI4SPN, where 0 is reserved (which makes all zeros illegal)
+            (0b00, 0b000) => {
+                let offset = bits(11, 2) << 4 | bits(7, 4) << 6 | bits(6, 1) << 2 |
+                             bits(5, 1) << 3;
+                if offset == 0 {
+                    return None;
+                }
+                op_imm(0b000, rd_short, 2, offset as i32)?
+            }
+            (0b00, 0b010) => load(0b010, rd_short, rs1_short, word_offset)?,
+            (0b00, 0b011) if xlen == 64 => load(0b011, rd_short, rs1_short, double_offset)?,
+            (0b00, 0b100) => {
+                extension = Extension::Zcb;
+                // C.LBU, C.LHU, C.LH, C.SB and C.SH, with tiny offsets
+                let byte_offset = (bits(5, 1) << 1 | bits(6, 1)) as i32;
+                let half_offset = (bits(5, 1) << 1) as i32;
+                match (bits(10, 3), bits(6, 1)) {
+                    (0b000, _) => load(0b100, rd_short, rs1_short, byte_offset)?,
+                    (0b001, 0) => load(0b101, rd_short, rs1_short, half_offset)?,
+                    (0b001, _) => load(0b001, rd_short, rs1_short, half_offset)?,
+                    (0b010, _) => store(0b000, rs1_short, rd_short, byte_offset)?,
+                    (0b011, 0) => store(0b001, rs1_short, rd_short, half_offset)?,
+                    _ => return None,
+                }
+            }
+            (0b00, 0b110) => store(0b010, rs1_short, rd_short, word_offset)?,
+            (0b00, 0b111) if xlen == 64 => store(0b011, rs1_short, rd_short, double_offset)?,
+
+            // C.ADDI, and C.NOP with rd of x0
+            (0b01, 0b000) => op_imm(0b000, rd, rd, imm6)?,
+            (0b01, 0b001) if xlen == 32 => {
+                link = true;
+                jal(jump_offset(raw))?
+            }
+            // C.ADDIW, where x0 is reserved
+            (0b01, 0b001) if rd != 0 => {
+                rv32i::OpImm::parse(i_type(0x1B, 0b000, rd, rd, imm6)).map(Expanded::OpImm)?
+            }
+            // C.LI
+            (0b01, 0b010) => op_imm(0b000, rd, 0, imm6)?,
+            // C.ADDI16SP
+            (0b01, 0b011) if rd == 2 => {
+                let offset = sign_extend(bits(12, 1) << 9 | bits(6, 1) << 4 | bits(5, 1) << 6 |
+                                         bits(3, 2) << 7 |
+                                         bits(2, 1) << 5,
+                                         10);
+                if offset == 0 {
+                    return None;
+                }
+                op_imm(0b000, 2, 2, offset)?
+            }
+            // C.LUI with an immediate of 0 is reserved, but Zcmop gives the
+            // odd registers up to x15 to C.MOP.n.
+            (0b01, 0b011) if imm6 == 0 && rd & 1 == 1 && rd < 16 => {
+                extension = Extension::Zcmop;
+                Expanded::MayBe
+            }
+            (0b01, 0b011) if imm6 != 0 => {
+                let lui = encoding::U {
+                    opcode: 0x37,
+                    rd,
+                    immediate: imm6 << 12,
+                };
+                rv32i::Lui::parse(lui.to_raw()).map(Expanded::Lui)?
+            }
+            (0b01, 0b100) => {
+                let rd = rs1_short;
+                match (bits(10, 2), bits(12, 1), bits(5, 2)) {
+                    // C.SRLI and C.SRAI, whose shift amounts of 32 and up
+                    // only exist on RV64
+                    (0b00, _, _) => op_imm(0b101, rd, rd, imm6 & 0x3F)?,
+                    (0b01, _, _) => op_imm(0b101, rd, rd, imm6 & 0x3F | 0x400)?,
+                    (0b10, _, _) => op_imm(0b111, rd, rd, imm6)?,
+                    // C.SUB, C.XOR, C.OR and C.AND
+                    (0b11, 0, 0b00) => op(0x33, 0x20, 0b000, rd, rd, rd_short)?,
+                    (0b11, 0, 0b01) => op(0x33, 0x00, 0b100, rd, rd, rd_short)?,
+                    (0b11, 0, 0b10) => op(0x33, 0x00, 0b110, rd, rd, rd_short)?,
+                    (0b11, 0, _) => op(0x33, 0x00, 0b111, rd, rd, rd_short)?,
+                    // C.SUBW and C.ADDW
+                    (0b11, _, 0b00) if xlen == 64 => op(0x3B, 0x20, 0b000, rd, rd, rd_short)?,
+                    (0b11, _, 0b01) if xlen == 64 => op(0x3B, 0x00, 0b000, rd, rd, rd_short)?,
+                    (0b11, _, 0b10) => {
+                        extension = Extension::Zcb;
+                        let mul = r_type(0x33, 0x01, 0b000, rd, rd, rd_short);
+                        rv32m::Op::parse(mul).map(Expanded::MulDiv)?
+                    }
+                    (0b11, _, 0b11) => {
+                        extension = Extension::Zcb;
+                        unary(bits(2, 3), rd, xlen)?
+                    }
+                    _ => return None,
+                }
+            }
+            // C.J
+            (0b01, 0b101) => jal(jump_offset(raw))?,
+            // C.BEQZ and C.BNEZ
+            (0b01, 0b110) | (0b01, 0b111) => {
+                let offset = sign_extend(bits(12, 1) << 8 | bits(10, 2) << 3 | bits(5, 2) << 6 |
+                                         bits(3, 2) << 1 |
+                                         bits(2, 1) << 5,
+                                         9);
+                let branch = encoding::SB {
+                    opcode: 0x63,
+                    funct3: if bits(13, 3) == 0b110 { 0b000 } else { 0b001 },
+                    rs1: rs1_short,
+                    rs2: 0,
+                    immediate: offset,
+                };
+                rv32i::Branch::parse(branch.to_raw()).map(Expanded::Branch)?
+            }
+
+            // C.SLLI
+            (0b10, 0b000) => op_imm(0b001, rd, rd, imm6 & 0x3F)?,
+            // C.LWSP and C.LDSP, where x0 is reserved
+            (0b10, 0b010) if rd != 0 => {
+                let offset = bits(12, 1) << 5 | bits(4, 3) << 2 | bits(2, 2) << 6;
+                load(0b010, rd, 2, offset as i32)?
+            }
+            (0b10, 0b011) if xlen == 64 && rd != 0 => {
+                let offset = bits(12, 1) << 5 | bits(5, 2) << 3 | bits(2, 3) << 6;
+                load(0b011, rd, 2, offset as i32)?
+            }
+            (0b10, 0b100) => {
+                match (bits(12, 1), rd, rs2) {
+                    // C.JR, where x0 is reserved
+                    (0, 0, 0) => return None,
+                    (0, _, 0) => jalr(rd)?,
+                    // C.MV
+                    (0, _, _) => op(0x33, 0x00, 0b000, rd, 0, rs2)?,
+                    (_, 0, 0) => {
+                        let ebreak = i_type(0x73, 0b000, 0, 0, 1);
+                        rv32i::System::parse(ebreak).map(Expanded::System)?
+                    }
+                    // C.JALR
+                    (_, _, 0) => {
+                        link = true;
+                        jalr(rd)?
+                    }
+                    // C.ADD
+                    _ => op(0x33, 0x00, 0b000, rd, rd, rs2)?,
+                }
+            }
+            // C.SWSP and C.SDSP
+            (0b10, 0b110) => store(0b010, 2, rs2, (bits(9, 4) << 2 | bits(7, 2) << 6) as i32)?,
+            (0b10, 0b111) if xlen == 64 => {
+                store(0b011, 2, rs2, (bits(10, 3) << 3 | bits(7, 3) << 6) as i32)?
+            }
+            _ => return None,
+        };
+
+        Some(Op {
+            raw: instruction,
+            extension,
+            expanded,
+            link,
+        })
+    }
+
+    /// The extension this is from: C, Zcb or Zcmop.
+    pub fn extensions(&self) -> &'static [Extension] {
+        match self.extension {
+            Extension::Zcb => &[Extension::Zcb],
+            Extension::Zcmop => &[Extension::Zcmop],
+            _ => &[Extension::C],
+        }
+    }
+
+    /// The extensions that Zcb's short forms of their instructions also
+    /// need, all of which have to be enabled.
+    pub fn requires(&self) -> &'static [Extension] {
+        match self.expanded {
+            Expanded::MulDiv(_) => &[Extension::M],
+            Expanded::Zba(_) => &[Extension::Zba],
+            Expanded::Zbb(_) | Expanded::Zbkb(_) => &[Extension::Zbb],
+            _ => &[],
+        }
+    }
+
+    pub fn is_rv64_only(&self) -> bool {
+        match self.expanded {
+            Expanded::Load(ref instr) => instr.is_rv64_only(),
+            Expanded::Store(ref instr) => instr.is_rv64_only(),
+            Expanded::OpImm(ref instr) => instr.is_rv64_only(),
+            Expanded::Op(ref instr) => instr.is_rv64_only(),
+            Expanded::Zba(ref instr) => instr.is_rv64_only(),
+            Expanded::Zbb(ref instr) => instr.is_rv64_only(),
+            Expanded::Zbkb(ref instr) => instr.is_rv64_only(),
+            _ => false,
+        }
+    }
+
+    pub fn max_register(&self) -> u8 {
+        let max = match self.expanded {
+            Expanded::Load(ref instr) => instr.max_register(),
+            Expanded::Store(ref instr) => instr.max_register(),
+            Expanded::OpImm(ref instr) => instr.max_register(),
+            Expanded::Op(ref instr) => instr.max_register(),
+            Expanded::Lui(ref instr) => instr.max_register(),
+            Expanded::Branch(ref instr) => instr.max_register(),
+            Expanded::Jal(ref instr) => instr.max_register(),
+            Expanded::Jalr(ref instr) => instr.max_register(),
+            Expanded::MulDiv(ref instr) => instr.max_register(),
+            Expanded::Zba(ref instr) => instr.max_register(),
+            Expanded::Zbb(ref instr) => instr.max_register(),
+            Expanded::Zbkb(ref instr) => instr.max_register(),
+            Expanded::System(_) | Expanded::MayBe => 0,
+        };
+        if self.link { cmp::max(max, 1) } else { max }
+    }
+
+    pub fn ends_block(&self) -> bool {
+        matches!(self.expanded,
+                 Expanded::Branch(_) | Expanded::Jal(_) | Expanded::Jalr(_) | Expanded::System(_))
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        match self.expanded {
+            Expanded::Load(ref instr) => instr.execute(cpu)?,
+            Expanded::Store(ref instr) => instr.execute(cpu)?,
+            Expanded::OpImm(ref instr) => instr.execute(cpu)?,
+            Expanded::Op(ref instr) => instr.execute(cpu)?,
+            Expanded::Lui(ref instr) => instr.execute(cpu)?,
+            Expanded::Branch(ref instr) => instr.execute(cpu)?,
+            Expanded::Jal(ref instr) => instr.execute(cpu)?,
+            Expanded::Jalr(ref instr) => instr.execute(cpu)?,
+            Expanded::System(ref instr) => instr.execute(cpu)?,
+            Expanded::MulDiv(ref instr) => instr.execute(cpu)?,
+            Expanded::Zba(ref instr) => instr.execute(cpu)?,
+            Expanded::Zbb(ref instr) => instr.execute(cpu)?,
+            Expanded::Zbkb(ref instr) => instr.execute(cpu)?,
+            Expanded::MayBe => {}
+        }
+
+        // The jump has already read its base register, which may be ra.
+        if self.link {
+            let jump_back_target = cpu.pc as u64 + 2;
+            cpu.set_register(1, jump_back_target);
+        }
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        self.raw as u32
+    }
+}
+
+// Zcb's C.ZEXT.B, C.SEXT.B, C.ZEXT.H, C.SEXT.H, C.ZEXT.W and C.NOT, on
+// register `rd`.
+fn unary(funct: u32, rd: u8, xlen: u32) -> Option<Expanded> {
+    let zbb = |raw: u32| zbb::Op::parse(raw).map(Expanded::Zbb);
+    let zext_h = |opcode: u8| {
+        zbkb::Op::parse(r_type(opcode, 0x04, 0b100, rd, rd, 0)).map(Expanded::Zbkb)
+    };
+    match funct {
+        0b000 => op_imm(0b111, rd, rd, 0xFF),
+        0b001 => zbb(i_type(0x13, 0b001, rd, rd, 0x604)),
+        0b010 if xlen == 32 => zext_h(0x33),
+        0b010 => zext_h(0x3B),
+        0b011 => zbb(i_type(0x13, 0b001, rd, rd, 0x605)),
+        0b100 if xlen == 64 => {
+            zba::Op::parse(r_type(0x3B, 0x04, 0b000, rd, rd, 0)).map(Expanded::Zba)
+        }
+        0b101 => op_imm(0b100, rd, rd, -1),
+        _ => None,
+    }
+}
+
+// C.JAL's and C.J's offset, which is scrambled across bits 12 to 2.
+fn jump_offset(raw: u32) -> i32 {
+    let bit = |from: u32, to: u32| ((raw >> from) & 1) << to;
+    let offset = bit(12, 11) | bit(11, 4) | bit(10, 9) | bit(9, 8) | bit(8, 10) | bit(7, 6) |
+                 bit(6, 7) | bit(5, 3) | bit(4, 2) | bit(3, 1) | bit(2, 5);
+    sign_extend(offset, 12)
+}
+
+fn sign_extend(value: u32, width: u32) -> i32 {
+    ((value << (32 - width)) as i32) >> (32 - width)
+}
+
+fn i_type(opcode: u8, funct3: u8, rd: u8, rs1: u8, immediate: i32) -> u32 {
+    encoding::I {
+        opcode,
+        funct3,
+        rd,
+        rs1,
+        immediate,
+    }.to_raw()
+}
+
+fn r_type(opcode: u8, funct7: u8, funct3: u8, rd: u8, rs1: u8, rs2: u8) -> u32 {
+    encoding::R {
+        opcode,
+        funct7,
+        funct3,
+        rd,
+        rs1,
+        rs2,
+    }.to_raw()
+}
+
+fn op_imm(funct3: u8, rd: u8, rs1: u8, immediate: i32) -> Option<Expanded> {
+    rv32i::OpImm::parse(i_type(0x13, funct3, rd, rs1, immediate)).map(Expanded::OpImm)
+}
+
+fn op(opcode: u8, funct7: u8, funct3: u8, rd: u8, rs1: u8, rs2: u8) -> Option<Expanded> {
+    rv32i::Op::parse(r_type(opcode, funct7, funct3, rd, rs1, rs2)).map(Expanded::Op)
+}
+
+fn load(funct3: u8, rd: u8, base: u8, offset: i32) -> Option<Expanded> {
+    rv32i::Load::parse(i_type(0x03, funct3, rd, base, offset)).map(Expanded::Load)
+}
+
+fn store(funct3: u8, base: u8, src: u8, offset: i32) -> Option<Expanded> {
+    let store = encoding::S {
+        opcode: 0x23,
+        funct3,
+        rs1: base,
+        rs2: src,
+        immediate: offset,
+    };
+    rv32i::Store::parse(store.to_raw()).map(Expanded::Store)
+}
+
+fn jal(offset: i32) -> Option<Expanded> {
+    let jal = encoding::UJ {
+        opcode: 0x6F,
+        rd: 0,
+        immediate: offset,
+    };
+    rv32i::Jal::parse(jal.to_raw()).map(Expanded::Jal)
+}
+
+fn jalr(base: u8) -> Option<Expanded> {
+    rv32i::Jalr::parse(i_type(0x67, 0b000, 0, base, 0)).map(Expanded::Jalr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::Isa;
+    use instruction::Instruction;
+    use trap::StopReason;
+
+    fn cpu_with_program(isa: &str, program: &[u16]) -> CPU {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.set_isa(Isa::parse(isa).unwrap());
+        for (i, &half) in program.iter().enumerate() {
+            cpu.bus.write(0x100 + 2 * i as u32, 2, half as u32).unwrap();
+        }
+        cpu.pc = 0x100;
+        cpu
+    }
+
+    #[test]
+    fn test_expansion() {
+        // Each compressed instruction and the 32-bit one it stands for, from
+        // llvm-mc
+        let rv32 = [(0x0040, 0x00410413), // c.addi4spn s0, sp, 4
+                    (0x4108, 0x00052503), // c.lw a0, 0(a0)
+                    (0xc10c, 0x00b52023), // c.sw a1, 0(a0)
+                    (0x0505, 0x00150513), // c.addi a0, 1
+                    (0x557d, 0xfff00513), // c.li a0, -1
+                    (0x7179, 0xfd010113), // c.addi16sp sp, -48
+                    (0x6505, 0x00001537), // c.lui a0, 1
+                    (0x8505, 0x40155513), // c.srai a0, 1
+                    (0x8d0d, 0x40b50533), // c.sub a0, a1
+                    (0x892e, 0x00b00933), // c.mv s2, a1
+                    (0x992e, 0x00b90933), // c.add s2, a1
+                    (0x4502, 0x00012503), // c.lwsp a0, 0(sp)
+                    (0xc02a, 0x00a12023), // c.swsp a0, 0(sp)
+                    (0x050a, 0x00251513), // c.slli a0, 2
+                    (0x9002, 0x00100073), // c.ebreak
+                    (0x8082, 0x00008067)]; // c.jr ra
+        for &(raw, expanded) in &rv32 {
+            let instr = Op::parse(raw, 32).expect("couldn't parse instruction");
+            assert_eq!(instr.to_raw(), raw as u32);
+            assert_eq!(expanded_raw(&instr), expanded, "{:04x}", raw);
+        }
+
+        // Zcb, then RV64's C.LD, C.ADDIW and C.ZEXT.W
+        let zcb = [(0x8108, 0x00054503), // c.lbu a0, 0(a0)
+                   (0x8568, 0x00251503), // c.lh a0, 2(a0)
+                   (0x890c, 0x00b50023), // c.sb a1, 0(a0)
+                   (0x9d61, 0x0ff57513), // c.zext.b a0
+                   (0x9d75, 0xfff54513), // c.not a0
+                   (0x9d4d, 0x02b50533)]; // c.mul a0, a1
+        for &(raw, expanded) in &zcb {
+            let instr = Op::parse(raw, 32).expect("couldn't parse instruction");
+            assert_eq!(instr.extensions(), &[Extension::Zcb]);
+            assert_eq!(expanded_raw(&instr), expanded, "{:04x}", raw);
+        }
+        assert_eq!(Op::parse(0x9d4d, 32).unwrap().requires(), &[Extension::M]);
+
+        let rv64 = [(0x6108, 0x00053503), (0x2505, 0x0015051b), (0x9d71, 0x0805053b)];
+        for &(raw, expanded) in &rv64 {
+            assert_eq!(expanded_raw(&Op::parse(raw, 64).unwrap()), expanded, "{:04x}", raw);
+        }
+        // C.JAL on RV32 and C.ADDIW on RV64
+        assert!(Op::parse(0x2505, 32).unwrap().link);
+
+        // All zeros, C.ADDI16SP with 0, C.LWSP into x0, C.JR x0, C.FLD
+        for &raw in &[0x0000, 0x6101, 0x4002, 0x8002, 0x2008] {
+            assert!(Op::parse(raw, 32).is_none(), "{:04x}", raw);
+        }
+    }
+
+    fn expanded_raw(instr: &Op) -> u32 {
+        match instr.expanded {
+            Expanded::Load(ref instr) => instr.to_raw(),
+            Expanded::Store(ref instr) => instr.to_raw(),
+            Expanded::OpImm(ref instr) => instr.to_raw(),
+            Expanded::Op(ref instr) => instr.to_raw(),
+            Expanded::Lui(ref instr) => instr.to_raw(),
+            Expanded::Branch(ref instr) => instr.to_raw(),
+            Expanded::Jal(ref instr) => instr.to_raw(),
+            Expanded::Jalr(ref instr) => instr.to_raw(),
+            Expanded::System(ref instr) => instr.to_raw(),
+            Expanded::MulDiv(ref instr) => instr.to_raw(),
+            Expanded::Zba(ref instr) => instr.to_raw(),
+            Expanded::Zbb(ref instr) => instr.to_raw(),
+            Expanded::Zbkb(ref instr) => instr.to_raw(),
+            Expanded::MayBe => 0,
+        }
+    }
+
+    #[test]
+    fn test_jumps_link_past_two_bytes() {
+        // c.jal 6; c.nop; c.nop; c.jalr a0
+        let mut cpu = cpu_with_program("rv32ic", &[0x2019, 0x0001, 0x0001, 0x9502]);
+        cpu.set_register(10, 0x200);
+
+        assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.get_register(1), 0x108);
+    }
+
+    #[test]
+    fn test_mixed_widths() {
+        // c.li a0, 5; addi a0, a0, 1; c.slli a0, 2; c.mv a1, a0
+        let mut cpu = cpu_with_program("rv32ic", &[0x4515, 0x0513, 0x0015, 0x050a, 0x85aa]);
+
+        assert_eq!(cpu.run_for(4), StopReason::InstructionLimit);
+        assert_eq!(cpu.pc, 0x10a);
+        assert_eq!((cpu.get_register(10), cpu.get_register(11)), (24, 24));
+    }
+
+    #[test]
+    fn test_may_be_operations() {
+        // c.mop.1 and c.mop.15, which leave x1 and x15 alone
+        let mut cpu = cpu_with_program("rv32ic_zcmop", &[0x6081, 0x6781]);
+        cpu.set_register(1, 0x1234);
+        cpu.set_register(15, 0x5678);
+
+        assert_eq!(cpu.run_for(2), StopReason::InstructionLimit);
+        assert_eq!((cpu.get_register(1), cpu.get_register(15)), (0x1234, 0x5678));
+
+        // Without Zcmop they're reserved.
+        let mut cpu = cpu_with_program("rv32ic", &[0x6081]);
+        assert_eq!(cpu.step(),
+                   StopReason::IllegalInstruction {
+                       pc: 0x100,
+                       raw: 0x6081,
+                   });
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use instruction::rvv::VType;
+use cpu::CPU;
+use trap::Exception;
+
+/// `vsetvli`, `vsetivli` and `vsetvl`: set `vtype`, and `vl` to as many of
+/// the requested elements as fit in a group.
+#[derive(Debug, Clone, Copy)]
+pub struct SetVl {
+    typ: SetVlType,
+    dest: u8,
+    // The register holding the requested length, or the length itself for
+    // vsetivli.
+    avl: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetVlType {
+    Immediate(u16),
+    ImmediateLength(u16),
+    Register(u8),
+}
+
+impl SetVl {
+    pub fn parse(instruction: u32) -> Option<SetVl> {
+        let decoded = encoding::R::parse(instruction);
+
+        if decoded.opcode != 0x57 || decoded.funct3 != 0b111 {
+            return None;
+        }
+
+        let typ = match instruction >> 30 {
+            0b00 | 0b01 => SetVlType::Immediate((instruction >> 20) as u16 & 0x7FF),
+            0b11 => SetVlType::ImmediateLength((instruction >> 20) as u16 & 0x3FF),
+            _ if decoded.funct7 == 0x40 => SetVlType::Register(decoded.rs2),
+            _ => return None,
+        };
+
+        Some(SetVl {
+            typ,
+            dest: decoded.rd,
+            avl: decoded.rs1,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        match self.typ {
+            SetVlType::Immediate(_) => cmp::max(self.dest, self.avl),
+            SetVlType::ImmediateLength(_) => self.dest,
+            SetVlType::Register(src) => cmp::max(self.dest, cmp::max(self.avl, src)),
+        }
+    }
+}
+
+impl Instruction for SetVl {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let value = match self.typ {
+            SetVlType::Immediate(vtype) | SetVlType::ImmediateLength(vtype) => vtype as u64,
+            SetVlType::Register(src) => cpu.get_register(src),
+        };
+        let vill = 1 << (cpu.xlen() - 1);
+        let vlen = cpu.isa().vlen();
+        let old_vl = cpu.vector().vl();
+
+        let (vl, vtype) = match VType::decode(value, cpu.isa().elen()) {
+            Some(vtype) => {
+                let vlmax = vtype.vlmax(vlen) as u64;
+                match self.typ {
+                    SetVlType::ImmediateLength(_) => (cmp::min(self.avl as u64, vlmax), value),
+                    _ if self.avl != 0 => {
+                        (cmp::min(cpu.get_register(self.avl), vlmax), value)
+                    }
+                    _ if self.dest != 0 => (vlmax, value),
+                    // Changing vtype while keeping vl is only allowed when
+                    // vl still fits.
+                    _ if old_vl <= vlmax => (old_vl, value),
+                    _ => (0, vill),
+                }
+            }
+            None => (0, vill),
+        };
+
+        let vector = cpu.vector_mut();
+        vector.vl = vl;
+        vector.vtype = vtype;
+        vector.vstart = 0;
+        cpu.set_register(self.dest, vl);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (high, rs2) = match self.typ {
+            SetVlType::Immediate(vtype) => ((vtype as u32) >> 5, vtype as u8 & 0x1F),
+            SetVlType::ImmediateLength(vtype) => {
+                (0b11 << 5 | (vtype as u32) >> 5, vtype as u8 & 0x1F)
+            }
+            SetVlType::Register(src) => (0x40, src),
+        };
+
+        encoding::R {
+            opcode: 0x57,
+            funct7: high as u8,
+            funct3: 0b111,
+            rd: self.dest,
+            rs1: self.avl,
+            rs2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::Isa;
+    use instruction::Instruction;
+
+    // Execute `raw`, returning the new vl.
+    fn run(cpu: &mut CPU, raw: u32) -> u64 {
+        let instr = SetVl::parse(raw).expect("couldn't parse instruction");
+        assert_eq!(instr.to_raw(), raw);
+        instr.execute(cpu).expect("couldn't execute instruction");
+        cpu.vector().vl()
+    }
+
+    #[test]
+    fn test_vsetvl() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // vsetvli x1, x2, e32, m1, ta, ma asks for 100 of 4
+        cpu.set_register(2, 100);
+        assert_eq!(run(&mut cpu, 0x0d0170d7), 4);
+        assert_eq!(cpu.get_register(1), 4);
+        assert_eq!(cpu.vector().vtype(), 0xd0);
+        cpu.set_register(2, 3);
+        assert_eq!(run(&mut cpu, 0x0d0170d7), 3);
+
+        // vsetivli x1, 31, e8, m8, tu, mu
+        assert_eq!(run(&mut cpu, 0xc03ff0d7), 31);
+        // vsetvli x1, x0, e16, mf2, ta, mu gives VLMAX
+        assert_eq!(run(&mut cpu, 0x04f070d7), 4);
+
+        // vsetvl x1, x2, x3 with e64, m8 and e64, mf2
+        cpu.set_register(2, 1000);
+        cpu.set_register(3, 0b011_011);
+        assert_eq!(run(&mut cpu, 0x803170d7), 16);
+        cpu.set_register(3, 0b011_111);
+        assert_eq!(run(&mut cpu, 0x803170d7), 0);
+        assert_eq!(cpu.vector().vtype(), 0x8000_0000);
+
+        // The same without Zve64x
+        cpu.set_isa(Isa::parse("rv64i_zve32x").unwrap());
+        cpu.set_register(3, 0b011_011);
+        assert_eq!(run(&mut cpu, 0x803170d7), 0);
+        assert_eq!(cpu.vector().vtype(), 1 << 63);
+    }
+
+    #[test]
+    fn test_keep_vl() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // vsetivli x0, 6, e16, m1 then vsetvli x0, x0, e8, mf2, which keeps
+        // vl at 6 of 8, then e32, mf2, which can only hold 2
+        run(&mut cpu, 0xc0837057);
+        run(&mut cpu, 0x00707057);
+        assert_eq!(cpu.vector().vl(), 6);
+        assert_eq!(cpu.vector().vtype(), 0b000_111);
+        run(&mut cpu, 0x01707057);
+        assert_eq!(cpu.vector().vtype(), 0x8000_0000);
+    }
+}
@@ -0,0 +1,685 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use instruction::rvv::{self, Operand, VType};
+use cpu::CPU;
+use trap::Exception;
+
+/// Element-wise integer arithmetic: each element of the result comes from
+/// the same element of vs2 and of the other operand, which is vs1, an
+/// integer register or a 5-bit immediate.
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src2: u8,
+    operand: Operand,
+    masked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    Add,
+    Sub,
+    ReverseSub,
+    MinUnsigned,
+    Min,
+    MaxUnsigned,
+    Max,
+    And,
+    Or,
+    Xor,
+    AddWithCarry,
+    CarryOut,
+    SubWithBorrow,
+    BorrowOut,
+    Merge,
+    Equal,
+    NotEqual,
+    LessUnsigned,
+    Less,
+    LessEqualUnsigned,
+    LessEqual,
+    GreaterUnsigned,
+    Greater,
+    SaturatingAddUnsigned,
+    SaturatingAdd,
+    SaturatingSubUnsigned,
+    SaturatingSub,
+    ShiftLeft,
+    FractionalMultiply,
+    ShiftRightLogical,
+    ShiftRightArithmetic,
+    ScalingShiftRightLogical,
+    ScalingShiftRightArithmetic,
+    NarrowShiftRightLogical,
+    NarrowShiftRightArithmetic,
+    NarrowClipUnsigned,
+    NarrowClip,
+    AverageAddUnsigned,
+    AverageAdd,
+    AverageSubUnsigned,
+    AverageSub,
+    ZeroExtend(u8),
+    SignExtend(u8),
+    DivideUnsigned,
+    Divide,
+    RemainderUnsigned,
+    Remainder,
+    MultiplyHighUnsigned,
+    Multiply,
+    MultiplyHighSignedUnsigned,
+    MultiplyHigh,
+    MultiplyAdd,
+    NegativeMultiplySub,
+    MultiplyAccumulate,
+    NegativeMultiplyAccumulate,
+    WideningAddUnsigned,
+    WideningAdd,
+    WideningSubUnsigned,
+    WideningSub,
+    WideAddUnsigned,
+    WideAdd,
+    WideSubUnsigned,
+    WideSub,
+    WideningMultiplyUnsigned,
+    WideningMultiplySignedUnsigned,
+    WideningMultiply,
+    WideningMultiplyAccumulateUnsigned,
+    WideningMultiplyAccumulate,
+    WideningMultiplyAccumulateUnsignedSigned,
+    WideningMultiplyAccumulateSignedUnsigned,
+}
+
+// How the element widths of the operands and result relate to SEW.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shape {
+    Single,
+    // A mask, one bit per element.
+    Mask,
+    // A result of 2*SEW from operands of SEW.
+    Widening,
+    // A result and vs2 of 2*SEW.
+    Wide,
+    // A result of SEW from vs2 of 2*SEW.
+    Narrowing,
+    // A result of SEW from vs2 of SEW divided by this.
+    Extending(u32),
+}
+
+// The forms an operation comes in: .vv, .vx and .vi.
+const VV: u8 = 0b001;
+const VX: u8 = 0b010;
+const VI: u8 = 0b100;
+
+// funct6, whether it's in the OPM half of the encodings rather than OPI,
+// and the forms, for every operation but the extensions.
+const OPERATIONS: [(OperationType, u8, bool, u8); 70] = [
+    (OperationType::Add, 0b000000, false, VV | VX | VI),
+    (OperationType::Sub, 0b000010, false, VV | VX),
+    (OperationType::ReverseSub, 0b000011, false, VX | VI),
+    (OperationType::MinUnsigned, 0b000100, false, VV | VX),
+    (OperationType::Min, 0b000101, false, VV | VX),
+    (OperationType::MaxUnsigned, 0b000110, false, VV | VX),
+    (OperationType::Max, 0b000111, false, VV | VX),
+    (OperationType::And, 0b001001, false, VV | VX | VI),
+    (OperationType::Or, 0b001010, false, VV | VX | VI),
+    (OperationType::Xor, 0b001011, false, VV | VX | VI),
+    (OperationType::AddWithCarry, 0b010000, false, VV | VX | VI),
+    (OperationType::CarryOut, 0b010001, false, VV | VX | VI),
+    (OperationType::SubWithBorrow, 0b010010, false, VV | VX),
+    (OperationType::BorrowOut, 0b010011, false, VV | VX),
+    (OperationType::Merge, 0b010111, false, VV | VX | VI),
+    (OperationType::Equal, 0b011000, false, VV | VX | VI),
+    (OperationType::NotEqual, 0b011001, false, VV | VX | VI),
+    (OperationType::LessUnsigned, 0b011010, false, VV | VX),
+    (OperationType::Less, 0b011011, false, VV | VX),
+    (OperationType::LessEqualUnsigned, 0b011100, false, VV | VX | VI),
+    (OperationType::LessEqual, 0b011101, false, VV | VX | VI),
+    (OperationType::GreaterUnsigned, 0b011110, false, VX | VI),
+    (OperationType::Greater, 0b011111, false, VX | VI),
+    (OperationType::SaturatingAddUnsigned, 0b100000, false, VV | VX | VI),
+    (OperationType::SaturatingAdd, 0b100001, false, VV | VX | VI),
+    (OperationType::SaturatingSubUnsigned, 0b100010, false, VV | VX),
+    (OperationType::SaturatingSub, 0b100011, false, VV | VX),
+    (OperationType::ShiftLeft, 0b100101, false, VV | VX | VI),
+    (OperationType::FractionalMultiply, 0b100111, false, VV | VX),
+    (OperationType::ShiftRightLogical, 0b101000, false, VV | VX | VI),
+    (OperationType::ShiftRightArithmetic, 0b101001, false, VV | VX | VI),
+    (OperationType::ScalingShiftRightLogical, 0b101010, false, VV | VX | VI),
+    (OperationType::ScalingShiftRightArithmetic, 0b101011, false, VV | VX | VI),
+    (OperationType::NarrowShiftRightLogical, 0b101100, false, VV | VX | VI),
+    (OperationType::NarrowShiftRightArithmetic, 0b101101, false, VV | VX | VI),
+    (OperationType::NarrowClipUnsigned, 0b101110, false, VV | VX | VI),
+    (OperationType::NarrowClip, 0b101111, false, VV | VX | VI),
+    (OperationType::AverageAddUnsigned, 0b001000, true, VV | VX),
+    (OperationType::AverageAdd, 0b001001, true, VV | VX),
+    (OperationType::AverageSubUnsigned, 0b001010, true, VV | VX),
+    (OperationType::AverageSub, 0b001011, true, VV | VX),
+    (OperationType::DivideUnsigned, 0b100000, true, VV | VX),
+    (OperationType::Divide, 0b100001, true, VV | VX),
+    (OperationType::RemainderUnsigned, 0b100010, true, VV | VX),
+    (OperationType::Remainder, 0b100011, true, VV | VX),
+    (OperationType::MultiplyHighUnsigned, 0b100100, true, VV | VX),
+    (OperationType::Multiply, 0b100101, true, VV | VX),
+    (OperationType::MultiplyHighSignedUnsigned, 0b100110, true, VV | VX),
+    (OperationType::MultiplyHigh, 0b100111, true, VV | VX),
+    (OperationType::MultiplyAdd, 0b101001, true, VV | VX),
+    (OperationType::NegativeMultiplySub, 0b101011, true, VV | VX),
+    (OperationType::MultiplyAccumulate, 0b101101, true, VV | VX),
+    (OperationType::NegativeMultiplyAccumulate, 0b101111, true, VV | VX),
+    (OperationType::WideningAddUnsigned, 0b110000, true, VV | VX),
+    (OperationType::WideningAdd, 0b110001, true, VV | VX),
+    (OperationType::WideningSubUnsigned, 0b110010, true, VV | VX),
+    (OperationType::WideningSub, 0b110011, true, VV | VX),
+    (OperationType::WideAddUnsigned, 0b110100, true, VV | VX),
+    (OperationType::WideAdd, 0b110101, true, VV | VX),
+    (OperationType::WideSubUnsigned, 0b110110, true, VV | VX),
+    (OperationType::WideSub, 0b110111, true, VV | VX),
+    (OperationType::WideningMultiplyUnsigned, 0b111000, true, VV | VX),
+    (OperationType::WideningMultiplySignedUnsigned, 0b111010, true, VV | VX),
+    (OperationType::WideningMultiply, 0b111011, true, VV | VX),
+    (OperationType::WideningMultiplyAccumulateUnsigned, 0b111100, true, VV | VX),
+    (OperationType::WideningMultiplyAccumulate, 0b111101, true, VV | VX),
+    (OperationType::WideningMultiplyAccumulateUnsignedSigned, 0b111110, true, VX),
+    (OperationType::WideningMultiplyAccumulateSignedUnsigned, 0b111111, true, VV | VX),
+    // Placeholders so that the extensions, which share a funct6 and are
+    // told apart by vs1, have an entry for to_raw.
+    (OperationType::ZeroExtend(0), 0b010010, true, 0),
+    (OperationType::SignExtend(0), 0b010010, true, 0),
+];
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        if decoded.opcode != 0x57 {
+            return None;
+        }
+        let funct6 = (instruction >> 26) as u8;
+        let masked = instruction & (1 << 25) == 0;
+
+        let (opm, operand) = Operand::parse(decoded.funct3, decoded.rs1)?;
+        let form = match operand {
+            Operand::Vector(_) => VV,
+            Operand::Scalar(_) => VX,
+            Operand::Immediate(_) => VI,
+        };
+
+        let typ = if opm && form == VV && funct6 == 0b010010 {
+            match decoded.rs1 {
+                0b00010 => OperationType::ZeroExtend(8),
+                0b00011 => OperationType::SignExtend(8),
+                0b00100 => OperationType::ZeroExtend(4),
+                0b00101 => OperationType::SignExtend(4),
+                0b00110 => OperationType::ZeroExtend(2),
+                0b00111 => OperationType::SignExtend(2),
+                _ => return None,
+            }
+        } else {
+            OPERATIONS.iter()
+                .find(|&&(_, f, m, forms)| f == funct6 && m == opm && forms & form != 0)
+                .map(|&(typ, _, _, _)| typ)?
+        };
+
+        // Adding and subtracting with a carry always take it from v0, and
+        // without v0 vmerge is vmv.v, which doesn't have a vs2.
+        match typ {
+            OperationType::AddWithCarry | OperationType::SubWithBorrow if !masked => return None,
+            OperationType::Merge if !masked && decoded.rs2 != 0 => return None,
+            _ => {}
+        }
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src2: decoded.rs2,
+            operand,
+            masked,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        match self.operand {
+            Operand::Scalar(reg) => reg,
+            _ => 0,
+        }
+    }
+
+    fn shape(&self) -> Shape {
+        match self.typ {
+            OperationType::CarryOut | OperationType::BorrowOut | OperationType::Equal |
+            OperationType::NotEqual | OperationType::LessUnsigned | OperationType::Less |
+            OperationType::LessEqualUnsigned | OperationType::LessEqual |
+            OperationType::GreaterUnsigned | OperationType::Greater => Shape::Mask,
+            OperationType::NarrowShiftRightLogical | OperationType::NarrowShiftRightArithmetic |
+            OperationType::NarrowClipUnsigned | OperationType::NarrowClip => Shape::Narrowing,
+            OperationType::WideAddUnsigned | OperationType::WideAdd |
+            OperationType::WideSubUnsigned | OperationType::WideSub => Shape::Wide,
+            OperationType::WideningAddUnsigned | OperationType::WideningAdd |
+            OperationType::WideningSubUnsigned | OperationType::WideningSub |
+            OperationType::WideningMultiplyUnsigned |
+            OperationType::WideningMultiplySignedUnsigned | OperationType::WideningMultiply |
+            OperationType::WideningMultiplyAccumulateUnsigned |
+            OperationType::WideningMultiplyAccumulate |
+            OperationType::WideningMultiplyAccumulateUnsignedSigned |
+            OperationType::WideningMultiplyAccumulateSignedUnsigned => Shape::Widening,
+            OperationType::ZeroExtend(factor) | OperationType::SignExtend(factor) => {
+                Shape::Extending(factor as u32)
+            }
+            _ => Shape::Single,
+        }
+    }
+
+    // Whether v0 is an operand, rather than a mask: carries and vmerge.
+    fn uses_v0(&self) -> bool {
+        self.masked &&
+        matches!(self.typ,
+                 OperationType::AddWithCarry | OperationType::SubWithBorrow |
+                 OperationType::CarryOut | OperationType::BorrowOut | OperationType::Merge)
+    }
+
+    // Element widths and group sizes of the destination and vs2, or None if
+    // the registers or vtype don't suit this instruction.
+    fn groups(&self, cpu: &CPU, vtype: VType) -> Option<((u32, i32), (u32, i32))> {
+        let (sew, lmul) = (vtype.sew, vtype.lmul);
+        let wide = (2 * sew, lmul + 1);
+        let (dest, src2) = match self.shape() {
+            Shape::Single | Shape::Mask => ((sew, lmul), (sew, lmul)),
+            Shape::Widening => (wide, (sew, lmul)),
+            Shape::Wide => (wide, wide),
+            Shape::Narrowing => ((sew, lmul), wide),
+            Shape::Extending(factor) => ((sew, lmul), (sew / factor, vtype.emul(sew / factor)?)),
+        };
+
+        let elen = cpu.isa().elen();
+        let operand_aligned = match self.operand {
+            Operand::Vector(reg) => {
+                matches!(self.shape(), Shape::Extending(_)) || rvv::is_aligned(reg, lmul)
+            }
+            _ => true,
+        };
+        // Zve64x leaves out the instructions that need the high half of a
+        // 128-bit product.
+        let high_product = sew == 64 &&
+                           matches!(self.typ,
+                                    OperationType::MultiplyHighUnsigned |
+                                    OperationType::MultiplyHighSignedUnsigned |
+                                    OperationType::MultiplyHigh |
+                                    OperationType::FractionalMultiply);
+
+        if dest.0 > elen || src2.0 > elen || src2.0 < 8 || dest.1 > 3 || src2.1 > 3 ||
+           high_product || !operand_aligned || !rvv::is_aligned(self.src2, src2.1) ||
+           (self.shape() != Shape::Mask && !rvv::is_aligned(self.dest, dest.1)) ||
+           (self.masked && self.dest == 0 && self.shape() != Shape::Mask) {
+            return None;
+        }
+        Some((dest, src2))
+    }
+
+    // The result for one element, from vs2, the other operand, the old
+    // destination and the element's bit in v0. Results are truncated to
+    // the destination's width later.
+    fn compute(&self, cpu: &mut CPU, sew: u32, a: u64, b: u64, d: u64, carry: bool) -> i128 {
+        let signed = |value: u64| rvv::sign_extend(value, sew) as i128;
+        let wide_signed = |value: u64| rvv::sign_extend(value, 2 * sew) as i128;
+        let (ua, ub, sa, sb) = (a as i128, b as i128, signed(a), signed(b));
+        let vxrm = cpu.vector().vxrm;
+        let shift = (b & (sew as u64 - 1)) as u32;
+        let narrow_shift = (b & (2 * sew as u64 - 1)) as u32;
+        let (signed_min, signed_max) = rvv::limits(sew, true);
+        let (unsigned_min, unsigned_max) = rvv::limits(sew, false);
+
+        match self.typ {
+            OperationType::Add => ua + ub,
+            OperationType::Sub => ua - ub,
+            OperationType::ReverseSub => ub - ua,
+            OperationType::MinUnsigned => cmp::min(ua, ub),
+            OperationType::Min => cmp::min(sa, sb),
+            OperationType::MaxUnsigned => cmp::max(ua, ub),
+            OperationType::Max => cmp::max(sa, sb),
+            OperationType::And => ua & ub,
+            OperationType::Or => ua | ub,
+            OperationType::Xor => ua ^ ub,
+            OperationType::AddWithCarry => ua + ub + carry as i128,
+            OperationType::CarryOut => ((ua + ub + carry as i128) >> sew != 0) as i128,
+            OperationType::SubWithBorrow => ua - ub - carry as i128,
+            OperationType::BorrowOut => (ua - ub - (carry as i128) < 0) as i128,
+            OperationType::Merge => if !self.masked || carry { ub } else { ua },
+            OperationType::Equal => (a == b) as i128,
+            OperationType::NotEqual => (a != b) as i128,
+            OperationType::LessUnsigned => (ua < ub) as i128,
+            OperationType::Less => (sa < sb) as i128,
+            OperationType::LessEqualUnsigned => (ua <= ub) as i128,
+            OperationType::LessEqual => (sa <= sb) as i128,
+            OperationType::GreaterUnsigned => (ua > ub) as i128,
+            OperationType::Greater => (sa > sb) as i128,
+            OperationType::SaturatingAddUnsigned => {
+                rvv::saturate(cpu, ua + ub, unsigned_min, unsigned_max)
+            }
+            OperationType::SaturatingAdd => rvv::saturate(cpu, sa + sb, signed_min, signed_max),
+            OperationType::SaturatingSubUnsigned => {
+                rvv::saturate(cpu, ua - ub, unsigned_min, unsigned_max)
+            }
+            OperationType::SaturatingSub => rvv::saturate(cpu, sa - sb, signed_min, signed_max),
+            OperationType::ShiftLeft => ua << shift,
+            OperationType::ShiftRightLogical => ua >> shift,
+            OperationType::ShiftRightArithmetic => sa >> shift,
+            OperationType::ScalingShiftRightLogical => rvv::roundoff(ua, shift, vxrm),
+            OperationType::ScalingShiftRightArithmetic => rvv::roundoff(sa, shift, vxrm),
+            OperationType::FractionalMultiply => {
+                let product = rvv::roundoff(sa * sb, sew - 1, vxrm);
+                rvv::saturate(cpu, product, signed_min, signed_max)
+            }
+            OperationType::NarrowShiftRightLogical => ua >> narrow_shift,
+            OperationType::NarrowShiftRightArithmetic => wide_signed(a) >> narrow_shift,
+            OperationType::NarrowClipUnsigned => {
+                let value = rvv::roundoff(ua, narrow_shift, vxrm);
+                rvv::saturate(cpu, value, unsigned_min, unsigned_max)
+            }
+            OperationType::NarrowClip => {
+                let value = rvv::roundoff(wide_signed(a), narrow_shift, vxrm);
+                rvv::saturate(cpu, value, signed_min, signed_max)
+            }
+            OperationType::AverageAddUnsigned => rvv::roundoff(ua + ub, 1, vxrm),
+            OperationType::AverageAdd => rvv::roundoff(sa + sb, 1, vxrm),
+            OperationType::AverageSubUnsigned => rvv::roundoff(ua - ub, 1, vxrm),
+            OperationType::AverageSub => rvv::roundoff(sa - sb, 1, vxrm),
+            OperationType::ZeroExtend(_) => ua,
+            OperationType::SignExtend(factor) => {
+                rvv::sign_extend(a, sew / factor as u32) as i128
+            }
+            // Division by zero and overflow give the same results as the
+            // scalar instructions.
+            OperationType::DivideUnsigned => if b == 0 { -1 } else { ua / ub },
+            OperationType::Divide => if b == 0 { -1 } else { sa / sb },
+            OperationType::RemainderUnsigned => if b == 0 { ua } else { ua % ub },
+            OperationType::Remainder => if b == 0 { sa } else { sa % sb },
+            OperationType::MultiplyHighUnsigned => (ua * ub) >> sew,
+            OperationType::Multiply => a.wrapping_mul(b) as i128,
+            OperationType::MultiplyHighSignedUnsigned => (sa * ub) >> sew,
+            OperationType::MultiplyHigh => (sa * sb) >> sew,
+            OperationType::MultiplyAdd => b.wrapping_mul(d).wrapping_add(a) as i128,
+            OperationType::NegativeMultiplySub => a.wrapping_sub(b.wrapping_mul(d)) as i128,
+            OperationType::MultiplyAccumulate => b.wrapping_mul(a).wrapping_add(d) as i128,
+            OperationType::NegativeMultiplyAccumulate => {
+                d.wrapping_sub(b.wrapping_mul(a)) as i128
+            }
+            OperationType::WideningAddUnsigned | OperationType::WideAddUnsigned => ua + ub,
+            OperationType::WideningAdd => sa + sb,
+            OperationType::WideAdd => wide_signed(a) + sb,
+            OperationType::WideningSubUnsigned | OperationType::WideSubUnsigned => ua - ub,
+            OperationType::WideningSub => sa - sb,
+            OperationType::WideSub => wide_signed(a) - sb,
+            OperationType::WideningMultiplyUnsigned => ua * ub,
+            OperationType::WideningMultiplySignedUnsigned => sa * ub,
+            OperationType::WideningMultiply => sa * sb,
+            OperationType::WideningMultiplyAccumulateUnsigned => d as i128 + ub * ua,
+            OperationType::WideningMultiplyAccumulate => d as i128 + sb * sa,
+            OperationType::WideningMultiplyAccumulateUnsignedSigned => d as i128 + ub * sa,
+            OperationType::WideningMultiplyAccumulateSignedUnsigned => d as i128 + sb * ua,
+        }
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let vtype = rvv::vtype(cpu, self.to_raw())?;
+        let ((dest_eew, dest_emul), (src2_eew, _)) = self.groups(cpu, vtype)
+            .ok_or(Exception::IllegalInstruction(self.to_raw()))?;
+        let sew = vtype.sew;
+        let uses_v0 = self.uses_v0();
+        let masked = self.masked && !uses_v0;
+
+        let operand = |cpu: &CPU, i: usize| {
+            match self.operand {
+                Operand::Vector(reg) => cpu.vector().element(reg, i, sew),
+                Operand::Scalar(reg) => rvv::scalar(cpu, reg, sew),
+                // Shifts take their immediate unsigned.
+                Operand::Immediate(imm) => {
+                    match self.typ {
+                        OperationType::ShiftLeft |
+                        OperationType::ShiftRightLogical |
+                        OperationType::ShiftRightArithmetic |
+                        OperationType::ScalingShiftRightLogical |
+                        OperationType::ScalingShiftRightArithmetic |
+                        OperationType::NarrowShiftRightLogical |
+                        OperationType::NarrowShiftRightArithmetic |
+                        OperationType::NarrowClipUnsigned |
+                        OperationType::NarrowClip => imm as u64 & 0x1F,
+                        _ => rvv::truncate(imm as i64 as u64, sew),
+                    }
+                }
+            }
+        };
+        let result = |cpu: &mut CPU, i: usize| {
+            let a = cpu.vector().element(self.src2, i, src2_eew);
+            let b = operand(cpu, i);
+            let d = cpu.vector().element(self.dest, i, dest_eew);
+            let carry = uses_v0 && cpu.vector().mask(0, i);
+            self.compute(cpu, sew, a, b, d, carry)
+        };
+
+        if self.shape() == Shape::Mask {
+            rvv::write_mask(cpu, vtype, masked, self.dest, |cpu, i| result(cpu, i) != 0);
+        } else {
+            rvv::write_elements(cpu, vtype, masked, self.dest, dest_eew, dest_emul, |cpu, i| {
+                rvv::truncate(result(cpu, i) as u64, dest_eew)
+            });
+        }
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let &(_, funct6, opm, _) = OPERATIONS.iter()
+            .find(|&&(typ, _, _, _)| {
+                match (typ, self.typ) {
+                    (OperationType::ZeroExtend(_), OperationType::ZeroExtend(_)) |
+                    (OperationType::SignExtend(_), OperationType::SignExtend(_)) => true,
+                    (typ, other) => typ == other,
+                }
+            })
+            .unwrap();
+        let (funct3, rs1) = self.operand.to_raw(opm);
+
+        encoding::R {
+            opcode: 0x57,
+            funct7: funct6 << 1 | !self.masked as u8,
+            funct3,
+            rd: self.dest,
+            rs1,
+            rs2: self.src2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    // A CPU with vl and vtype set, and x1 set to `scalar`.
+    fn cpu_with(vl: u64, vtype: u64, scalar: u64) -> CPU {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.vector_mut().vl = vl;
+        cpu.vector_mut().vtype = vtype;
+        cpu.set_register(1, scalar);
+        cpu
+    }
+
+    fn run(cpu: &mut CPU, raw: u32) -> Result<(), Exception> {
+        let instr = Op::parse(raw).expect("couldn't parse instruction");
+        assert_eq!(instr.to_raw(), raw);
+        instr.execute(cpu)
+    }
+
+    fn set(cpu: &mut CPU, reg: u8, eew: u32, values: &[u64]) {
+        for (i, &value) in values.iter().enumerate() {
+            cpu.vector_mut().set_element(reg, i, eew, value);
+        }
+    }
+
+    fn get(cpu: &CPU, reg: u8, eew: u32, count: usize) -> Vec<u64> {
+        (0..count).map(|i| cpu.vector().element(reg, i, eew)).collect()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        // e8 and 4 elements
+        let mut cpu = cpu_with(4, 0b000_000, 0x80);
+        set(&mut cpu, 2, 8, &[1, 2, 0xFF, 0x7F]);
+        set(&mut cpu, 3, 8, &[3, 1, 1, 0x80]);
+
+        // vadd.vv v1, v2, v3, vsub.vx v1, v2, x1, vrsub.vi v1, v2, -1
+        run(&mut cpu, 0x022180d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [4, 3, 0, 0xFF]);
+        run(&mut cpu, 0x0a20c0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0x81, 0x82, 0x7F, 0xFF]);
+        run(&mut cpu, 0x0e2fb0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0xFE, 0xFD, 0, 0x80]);
+
+        // vmin.vv, vmaxu.vv v1, v2, v3
+        run(&mut cpu, 0x162180d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [1, 1, 0xFF, 0x80]);
+        run(&mut cpu, 0x1a2180d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [3, 2, 0xFF, 0x80]);
+
+        // vsra.vi v1, v2, 1 and vsll.vx v1, v2, x1, which only uses the low
+        // three bits of 0x80
+        run(&mut cpu, 0xa620b0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0, 1, 0xFF, 0x3F]);
+        run(&mut cpu, 0x9620c0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [1, 2, 0xFF, 0x7F]);
+
+        // vmul.vv, vmulhu.vv, vdiv.vv, vremu.vx v1, v2, v3
+        run(&mut cpu, 0x9621a0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [3, 2, 0xFF, 0x80]);
+        run(&mut cpu, 0x9221a0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0, 0, 0, 0x3F]);
+        run(&mut cpu, 0x8621a0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0, 2, 0xFF, 0]);
+        cpu.set_register(1, 0);
+        run(&mut cpu, 0x8a20e0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [1, 2, 0xFF, 0x7F]);
+
+        // vmacc.vv v1, v3, v2 adds v2 * v3 to the remainders.
+        run(&mut cpu, 0xb621a0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [4, 4, 0xFE, 0xFF]);
+    }
+
+    #[test]
+    fn test_masks_and_carries() {
+        // e32, m1
+        let mut cpu = cpu_with(4, 0b010_000, 5);
+        set(&mut cpu, 2, 32, &[1, 5, 9, 0xFFFF_FFFF]);
+        set(&mut cpu, 3, 32, &[1, 1, 1, 1]);
+
+        // vmslt.vx v1, v2, x1, then vmsgtu.vi v1, v2, 4
+        run(&mut cpu, 0x6e20c0d7).unwrap();
+        assert_eq!(cpu.vector().register(1)[0], 0b0001 | 0b1000);
+        run(&mut cpu, 0x7a2230d7).unwrap();
+        assert_eq!(cpu.vector().register(1)[0], 0b1110);
+
+        // vadc.vvm v4, v2, v3, v0 and vmadc.vvm v1, v2, v3, v0, carrying in
+        // to elements 1 and 3
+        cpu.vector_mut().set_register(0, &[0b1010]);
+        run(&mut cpu, 0x40218257).unwrap();
+        assert_eq!(get(&cpu, 4, 32, 4), [2, 7, 10, 1]);
+        run(&mut cpu, 0x442180d7).unwrap();
+        assert_eq!(cpu.vector().register(1)[0], 0b1000);
+
+        // vadd.vi v4, v2, 3, v0.t leaves elements 0 and 2 alone.
+        run(&mut cpu, 0x0021b257).unwrap();
+        assert_eq!(get(&cpu, 4, 32, 4), [2, 8, 10, 2]);
+        // vmerge.vxm v4, v2, x1, v0 and vmv.v.i v4, -3
+        run(&mut cpu, 0x5c20c257).unwrap();
+        assert_eq!(get(&cpu, 4, 32, 4), [1, 5, 9, 5]);
+        run(&mut cpu, 0x5e0eb257).unwrap();
+        assert_eq!(get(&cpu, 4, 32, 4), [0xFFFF_FFFD; 4]);
+
+        // vadd.vv v0, v2, v3, v0.t would overwrite its own mask.
+        assert!(run(&mut cpu, 0x00218057).is_err());
+    }
+
+    #[test]
+    fn test_fixed_point() {
+        // e8, with vxrm set to round to nearest, ties up
+        let mut cpu = cpu_with(4, 0b000_000, 0);
+        set(&mut cpu, 2, 8, &[0x7F, 0x80, 250, 5]);
+        set(&mut cpu, 3, 8, &[1, 1, 10, 2]);
+
+        // vsadd.vv, vsaddu.vv, vssubu.vv v1, v2, v3
+        run(&mut cpu, 0x862180d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0x7F, 0x81, 4, 7]);
+        assert!(cpu.vector().vxsat);
+        cpu.vector_mut().vxsat = false;
+        run(&mut cpu, 0x822180d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0x80, 0x81, 0xFF, 7]);
+        assert!(cpu.vector().vxsat);
+        run(&mut cpu, 0x8a2180d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0x7E, 0x7F, 240, 3]);
+
+        // vaaddu.vv and vasub.vv v1, v2, v3, then vssrl.vi v1, v2, 2 with
+        // round-to-odd
+        run(&mut cpu, 0x2221a0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0x40, 0x41, 130, 4]);
+        run(&mut cpu, 0x2e21a0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0x3F, 0xC0, 0xF8, 2]);
+        cpu.vector_mut().vxrm = 3;
+        run(&mut cpu, 0xaa2130d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 4), [0x1F, 0x20, 0x3F, 1]);
+
+        // vsmul.vv v1, v2, v2: 0x80 squared is the one product that doesn't
+        // fit.
+        cpu.vector_mut().vxrm = 0;
+        run(&mut cpu, 0x9e2100d7).unwrap();
+        assert_eq!(get(&cpu, 1, 8, 2), [0x7E, 0x7F]);
+    }
+
+    #[test]
+    fn test_widening_and_narrowing() {
+        // e16, m1
+        let mut cpu = cpu_with(3, 0b001_000, 0xFFFF);
+        set(&mut cpu, 2, 16, &[0xFFFF, 2, 0x8000]);
+        set(&mut cpu, 3, 16, &[1, 3, 0x8000]);
+
+        // vwaddu.vv v4, v2, v3, vwmul.vx v4, v2, x1 and vwadd.wv v4, v4, v2
+        run(&mut cpu, 0xc221a257).unwrap();
+        assert_eq!(get(&cpu, 4, 32, 3), [0x10000, 5, 0x10000]);
+        run(&mut cpu, 0xee20e257).unwrap();
+        assert_eq!(get(&cpu, 4, 32, 3), [1, 0xFFFF_FFFE, 0x8000]);
+        run(&mut cpu, 0xd6412257).unwrap();
+        assert_eq!(get(&cpu, 4, 32, 3), [0, 0, 0]);
+
+        // vwmaccu.vv v4, v3, v2
+        run(&mut cpu, 0xf221a257).unwrap();
+        assert_eq!(get(&cpu, 4, 32, 3), [0xFFFF, 6, 0x4000_0000]);
+
+        // vnsrl.wi v1, v4, 16 and vnclipu.wi v1, v4, 1
+        run(&mut cpu, 0xb24830d7).unwrap();
+        assert_eq!(get(&cpu, 1, 16, 3), [0, 0, 0x4000]);
+        run(&mut cpu, 0xba40b0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 16, 3), [0x8000, 3, 0xFFFF]);
+
+        // vsext.vf2 v1, v2 with e32, and vzext.vf4 v1, v2, which
+        // needs 8-bit sources
+        cpu.vector_mut().vtype = 0b010_000;
+        run(&mut cpu, 0x4a23a0d7).unwrap();
+        assert_eq!(get(&cpu, 1, 32, 3), [0xFFFF_FFFF, 2, 0xFFFF_8000]);
+        run(&mut cpu, 0x4a2220d7).unwrap();
+        assert_eq!(get(&cpu, 1, 32, 3), [0xFF, 0xFF, 2]);
+
+        // vwadd.vv v4, v2, v3 with e32 and m8 would need sixteen registers.
+        cpu.vector_mut().vtype = 0b010_011;
+        assert!(run(&mut cpu, 0xc621a257).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use instruction::{encoding, Instruction};
+use instruction::rvv;
+use cpu::CPU;
+use trap::Exception;
+
+/// Instructions that work on masks: logic between two of them, counting
+/// and finding set bits, and numbering elements.
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+    masked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    AndNot,
+    And,
+    Or,
+    Xor,
+    OrNot,
+    Nand,
+    Nor,
+    Xnor,
+    Count,
+    FindFirst,
+    SetBeforeFirst,
+    SetOnlyFirst,
+    SetIncludingFirst,
+    Iota,
+    Index,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        if decoded.opcode != 0x57 || decoded.funct3 != 0b010 {
+            return None;
+        }
+        let masked = instruction & (1 << 25) == 0;
+
+        let typ = match ((instruction >> 26) as u8, decoded.rs1) {
+            (0b010000, 0b10000) => OperationType::Count,
+            (0b010000, 0b10001) => OperationType::FindFirst,
+            (0b010100, 0b00001) => OperationType::SetBeforeFirst,
+            (0b010100, 0b00010) => OperationType::SetOnlyFirst,
+            (0b010100, 0b00011) => OperationType::SetIncludingFirst,
+            (0b010100, 0b10000) => OperationType::Iota,
+            (0b010100, 0b10001) if decoded.rs2 == 0 => OperationType::Index,
+            // The logical operations can't be masked, since they'd be
+            // reading v0 anyway.
+            (0b011000, _) if !masked => OperationType::AndNot,
+            (0b011001, _) if !masked => OperationType::And,
+            (0b011010, _) if !masked => OperationType::Or,
+            (0b011011, _) if !masked => OperationType::Xor,
+            (0b011100, _) if !masked => OperationType::OrNot,
+            (0b011101, _) if !masked => OperationType::Nand,
+            (0b011110, _) if !masked => OperationType::Nor,
+            (0b011111, _) if !masked => OperationType::Xnor,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: decoded.rs2,
+            masked,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        match self.typ {
+            OperationType::Count | OperationType::FindFirst => self.dest,
+            _ => 0,
+        }
+    }
+
+    fn funct6(&self) -> u8 {
+        match self.typ {
+            OperationType::AndNot => 0b011000,
+            OperationType::And => 0b011001,
+            OperationType::Or => 0b011010,
+            OperationType::Xor => 0b011011,
+            OperationType::OrNot => 0b011100,
+            OperationType::Nand => 0b011101,
+            OperationType::Nor => 0b011110,
+            OperationType::Xnor => 0b011111,
+            OperationType::Count | OperationType::FindFirst => 0b010000,
+            _ => 0b010100,
+        }
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let illegal = Exception::IllegalInstruction(self.to_raw());
+        let vtype = rvv::vtype(cpu, self.to_raw())?;
+        let vl = cpu.vector().vl() as usize;
+        let (src1, src2) = (self.src1, self.src2);
+        let bit = |cpu: &CPU, reg: u8, i: usize| cpu.vector().mask(reg, i);
+
+        // Everything but the logical operations looks at the mask as a
+        // whole, so can't restart part way through.
+        let logical = self.funct6() & 0b011000 == 0b011000;
+        if !logical && cpu.vector().vstart() != 0 {
+            return Err(illegal);
+        }
+
+        // The set bits of vs2 among active elements
+        let set = (0..vl)
+            .filter(|&i| rvv::is_active(cpu, self.masked, i) && bit(cpu, src2, i))
+            .collect::<Vec<_>>();
+        let first = set.first().cloned();
+
+        match self.typ {
+            OperationType::Count => {
+                cpu.set_register(self.dest, set.len() as u64);
+            }
+            OperationType::FindFirst => {
+                cpu.set_register(self.dest, first.map_or(!0, |i| i as u64));
+            }
+            OperationType::SetBeforeFirst |
+            OperationType::SetOnlyFirst |
+            OperationType::SetIncludingFirst => {
+                if self.dest == src2 || (self.masked && self.dest == 0) {
+                    return Err(illegal);
+                }
+                let first = first.unwrap_or(vl);
+                rvv::write_mask(cpu, vtype, self.masked, self.dest, |_, i| {
+                    match self.typ {
+                        OperationType::SetBeforeFirst => i < first,
+                        OperationType::SetOnlyFirst => i == first,
+                        _ => i <= first,
+                    }
+                });
+            }
+            OperationType::Iota | OperationType::Index => {
+                if !rvv::is_aligned(self.dest, vtype.lmul) ||
+                   (self.masked && self.dest == 0) ||
+                   (self.typ == OperationType::Iota &&
+                    rvv::overlaps(self.dest, vtype.lmul, src2, 0)) {
+                    return Err(illegal);
+                }
+                rvv::write_elements(cpu,
+                                    vtype,
+                                    self.masked,
+                                    self.dest,
+                                    vtype.sew,
+                                    vtype.lmul,
+                                    |_, i| {
+                    match self.typ {
+                        OperationType::Iota => set.iter().take_while(|&&j| j < i).count() as u64,
+                        _ => i as u64,
+                    }
+                });
+            }
+            _ => {
+                rvv::write_mask(cpu, vtype, false, self.dest, |cpu, i| {
+                    let (a, b) = (bit(cpu, src2, i), bit(cpu, src1, i));
+                    match self.typ {
+                        OperationType::AndNot => a & !b,
+                        OperationType::And => a & b,
+                        OperationType::Or => a | b,
+                        OperationType::Xor => a ^ b,
+                        OperationType::OrNot => a | !b,
+                        OperationType::Nand => !(a & b),
+                        OperationType::Nor => !(a | b),
+                        _ => !(a ^ b),
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let src1 = match self.typ {
+            OperationType::Count => 0b10000,
+            OperationType::FindFirst => 0b10001,
+            OperationType::SetBeforeFirst => 0b00001,
+            OperationType::SetOnlyFirst => 0b00010,
+            OperationType::SetIncludingFirst => 0b00011,
+            OperationType::Iota => 0b10000,
+            OperationType::Index => 0b10001,
+            _ => self.src1,
+        };
+
+        encoding::R {
+            opcode: 0x57,
+            funct7: self.funct6() << 1 | !self.masked as u8,
+            funct3: 0b010,
+            rd: self.dest,
+            rs1: src1,
+            rs2: self.src2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    fn run(cpu: &mut CPU, raw: u32) -> Result<(), Exception> {
+        let instr = Op::parse(raw).expect("couldn't parse instruction");
+        assert_eq!(instr.to_raw(), raw);
+        instr.execute(cpu)
+    }
+
+    // e8 and vl=8, with v2 = 0b10010100 and v3 = 0b11110000
+    fn cpu_with_masks() -> CPU {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.vector_mut().vl = 8;
+        cpu.vector_mut().vtype = 0;
+        cpu.vector_mut().set_register(2, &[0b1001_0100]);
+        cpu.vector_mut().set_register(3, &[0b1111_0000]);
+        cpu
+    }
+
+    #[test]
+    fn test_logical() {
+        let mut cpu = cpu_with_masks();
+
+        // vmandn.mm, vmxor.mm, vmnor.mm v1, v2, v3
+        let expected = [(0x6221a0d7, 0b0000_0100),
+                        (0x6e21a0d7, 0b0110_0100),
+                        (0x7a21a0d7, 0b0000_1011)];
+        for &(raw, result) in &expected {
+            run(&mut cpu, raw).unwrap();
+            assert_eq!(cpu.vector().register(1)[0], result);
+        }
+    }
+
+    #[test]
+    fn test_count_and_find() {
+        let mut cpu = cpu_with_masks();
+
+        // vcpop.m x1, v2, vfirst.m x1, v2, and both again masked by v0
+        run(&mut cpu, 0x422820d7).unwrap();
+        assert_eq!(cpu.get_register(1), 3);
+        run(&mut cpu, 0x4228a0d7).unwrap();
+        assert_eq!(cpu.get_register(1), 2);
+        cpu.vector_mut().set_register(0, &[0b1111_0000]);
+        run(&mut cpu, 0x402820d7).unwrap();
+        assert_eq!(cpu.get_register(1), 2);
+        run(&mut cpu, 0x4028a0d7).unwrap();
+        assert_eq!(cpu.get_register(1), 4);
+
+        // vfirst.m x1, v3 with vl=4 finds nothing.
+        cpu.vector_mut().vl = 4;
+        run(&mut cpu, 0x4238a0d7).unwrap();
+        assert_eq!(cpu.get_register(1), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn test_set_first() {
+        let mut cpu = cpu_with_masks();
+
+        // vmsbf.m, vmsof.m, vmsif.m v1, v2
+        let expected = [(0x5220a0d7, 0b0000_0011),
+                        (0x522120d7, 0b0000_0100),
+                        (0x5221a0d7, 0b0000_0111)];
+        for &(raw, result) in &expected {
+            run(&mut cpu, raw).unwrap();
+            assert_eq!(cpu.vector().register(1)[0], result);
+        }
+
+        // vmsbf.m v2, v2 overwrites its source.
+        assert!(run(&mut cpu, 0x5220a157).is_err());
+    }
+
+    #[test]
+    fn test_iota_and_index() {
+        let mut cpu = cpu_with_masks();
+
+        // viota.m v4, v2, then vid.v v4, v0.t on the odd elements
+        run(&mut cpu, 0x52282257).unwrap();
+        assert_eq!(&cpu.vector().register(4)[..8], &[0, 0, 0, 1, 1, 2, 2, 2]);
+        cpu.vector_mut().set_register(0, &[0b1010_1010]);
+        run(&mut cpu, 0x5008a257).unwrap();
+        assert_eq!(&cpu.vector().register(4)[..8], &[0, 1, 0, 3, 1, 5, 2, 7]);
+    }
+}
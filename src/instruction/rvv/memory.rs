@@ -0,0 +1,433 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use instruction::rvv::{self, VType};
+use cpu::CPU;
+use trap::Exception;
+
+// A register group: its first register and group size.
+type Group = (u8, i32);
+
+/// Vector loads and stores. Segment accesses, with more than one field,
+/// spread each element's fields across that many register groups.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadStore {
+    typ: LoadStoreType,
+    store: bool,
+    // The element width in the instruction, which for indexed accesses is
+    // the width of the indices.
+    eew: u32,
+    fields: u8,
+    // The data: vd for loads and vs3 for stores.
+    reg: u8,
+    base: u8,
+    masked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadStoreType {
+    UnitStride,
+    FaultOnlyFirst,
+    WholeRegister,
+    Mask,
+    Strided(u8),
+    Indexed { index: u8, ordered: bool },
+}
+
+impl LoadStore {
+    pub fn parse(instruction: u32) -> Option<LoadStore> {
+        let decoded = encoding::R::parse(instruction);
+
+        let store = match decoded.opcode {
+            0x07 => false,
+            0x27 => true,
+            _ => return None,
+        };
+        // The other widths are scalar floating point.
+        let eew = match decoded.funct3 {
+            0b000 => 8,
+            0b101 => 16,
+            0b110 => 32,
+            0b111 => 64,
+            _ => return None,
+        };
+        // mew, for elements wider than 64 bits
+        if instruction & (1 << 28) != 0 {
+            return None;
+        }
+        let fields = (instruction >> 29) as u8 + 1;
+        let masked = instruction & (1 << 25) == 0;
+
+        let typ = match (instruction >> 26) & 0b11 {
+            0b00 => {
+                match decoded.rs2 {
+                    0b00000 => LoadStoreType::UnitStride,
+                    0b01000 if !masked && fields.is_power_of_two() && (!store || eew == 8) => {
+                        LoadStoreType::WholeRegister
+                    }
+                    0b01011 if !masked && fields == 1 && eew == 8 => LoadStoreType::Mask,
+                    0b10000 if !store => LoadStoreType::FaultOnlyFirst,
+                    _ => return None,
+                }
+            }
+            0b01 => {
+                LoadStoreType::Indexed {
+                    index: decoded.rs2,
+                    ordered: false,
+                }
+            }
+            0b10 => LoadStoreType::Strided(decoded.rs2),
+            _ => {
+                LoadStoreType::Indexed {
+                    index: decoded.rs2,
+                    ordered: true,
+                }
+            }
+        };
+
+        Some(LoadStore {
+            typ,
+            store,
+            eew,
+            fields,
+            reg: decoded.rd,
+            base: decoded.rs1,
+            masked,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        match self.typ {
+            LoadStoreType::Strided(stride) => cmp::max(self.base, stride),
+            _ => self.base,
+        }
+    }
+
+    // Load or store element `index`, `eew` bits wide, of the group at `reg`.
+    fn access(&self, cpu: &mut CPU, addr: u64, reg: u8, index: usize, eew: u32)
+              -> Result<(), Exception> {
+        if self.store {
+            let value = cpu.vector().element(reg, index, eew);
+            let addr = cpu.address(addr, 0, Exception::StoreAccessFault)?;
+            match eew {
+                8 => cpu.store_u8(addr, value as u8),
+                16 => cpu.store_u16(addr, value as u16),
+                32 => cpu.store_u32(addr, value as u32),
+                _ => cpu.store_u64(addr, value),
+            }
+        } else {
+            let addr = cpu.address(addr, 0, Exception::LoadAccessFault)?;
+            let value = match eew {
+                8 => cpu.load_u8(addr)? as u64,
+                16 => cpu.load_u16(addr)? as u64,
+                32 => cpu.load_u32(addr)? as u64,
+                _ => cpu.load_u64(addr)?,
+            };
+            cpu.vector_mut().set_element(reg, index, eew, value);
+            Ok(())
+        }
+    }
+
+    // Access elements `vstart` to `end` of the group at `reg`, one after the
+    // other from the base address, without a mask. Whole registers and
+    // masks don't have tails beyond what agnostic allows.
+    fn access_bytes(&self, cpu: &mut CPU, end: usize, eew: u32) -> Result<(), Exception> {
+        let base = cpu.get_register(self.base);
+        for i in cpu.vector().vstart() as usize..end {
+            if let Err(e) = self.access(cpu, base.wrapping_add((i * eew as usize / 8) as u64),
+                                        self.reg, i, eew) {
+                cpu.vector_mut().vstart = i as u64;
+                return Err(e);
+            }
+        }
+        if cpu.vector().vstart() != 0 {
+            cpu.vector_mut().vstart = 0;
+        }
+        Ok(())
+    }
+
+    // The registers holding the data, as the element width, group size and
+    // the index group for indexed accesses.
+    fn data(&self, cpu: &CPU, vtype: VType) -> Option<(u32, i32, Option<Group>)> {
+        let elen = cpu.isa().elen();
+        let (eew, emul, index) = match self.typ {
+            LoadStoreType::Indexed { index, .. } => {
+                let index_emul = vtype.emul(self.eew)?;
+                if self.eew > cmp::min(elen, cpu.xlen()) || !rvv::is_aligned(index, index_emul) {
+                    return None;
+                }
+                (vtype.sew, vtype.lmul, Some((index, index_emul)))
+            }
+            _ if self.eew > elen => return None,
+            _ => (self.eew, vtype.emul(self.eew)?, None),
+        };
+
+        let group = rvv::registers(emul);
+        if self.fields * group > 8 || self.reg + self.fields * group > 32 ||
+           !rvv::is_aligned(self.reg, emul) || (self.masked && !self.store && self.reg == 0) {
+            return None;
+        }
+        Some((eew, emul, index))
+    }
+}
+
+impl Instruction for LoadStore {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let illegal = Exception::IllegalInstruction(self.to_raw());
+
+        // Whole registers don't depend on vtype, so they can save and
+        // restore registers without knowing what's in them.
+        if self.typ == LoadStoreType::WholeRegister {
+            if !self.reg.is_multiple_of(self.fields) || self.eew > cpu.isa().elen() {
+                return Err(illegal);
+            }
+            let end = self.fields as usize * cpu.vector().vlenb() * 8 / self.eew as usize;
+            return self.access_bytes(cpu, end, self.eew);
+        }
+
+        let vtype = rvv::vtype(cpu, self.to_raw())?;
+        let vl = cpu.vector().vl() as usize;
+        let ones = cpu.vector_agnostic_ones();
+
+        if self.typ == LoadStoreType::Mask {
+            let end = vl.div_ceil(8);
+            self.access_bytes(cpu, end, 8)?;
+            if !self.store && ones {
+                for i in end..cpu.vector().vlenb() {
+                    cpu.vector_mut().set_element(self.reg, i, 8, 0xFF);
+                }
+            }
+            return Ok(());
+        }
+
+        let (eew, emul, index) = self.data(cpu, vtype).ok_or(illegal)?;
+        let group = rvv::registers(emul);
+        let size = eew as u64 / 8;
+        let base = cpu.get_register(self.base);
+        let fill = |cpu: &mut CPU, i: usize| {
+            for field in 0..self.fields {
+                cpu.vector_mut().set_element(self.reg + field * group, i, eew, !0);
+            }
+        };
+
+        let mut end = vl;
+        'elements: for i in cpu.vector().vstart() as usize..vl {
+            if !rvv::is_active(cpu, self.masked, i) {
+                if !self.store && ones && vtype.mask_agnostic {
+                    fill(cpu, i);
+                }
+                continue;
+            }
+
+            let offset = match self.typ {
+                LoadStoreType::Strided(stride) => (i as u64).wrapping_mul(cpu.get_register(stride)),
+                _ => match index {
+                    Some((index, _)) => cpu.vector().element(index, i, self.eew),
+                    None => i as u64 * self.fields as u64 * size,
+                },
+            };
+            for field in 0..self.fields {
+                let addr = base.wrapping_add(offset).wrapping_add(field as u64 * size);
+                if let Err(e) = self.access(cpu, addr, self.reg + field * group, i, eew) {
+                    // Fault-only-first loads stop short instead of trapping,
+                    // unless it's the first element that faults.
+                    if self.typ == LoadStoreType::FaultOnlyFirst && i > 0 {
+                        end = i;
+                        break 'elements;
+                    }
+                    cpu.vector_mut().vstart = i as u64;
+                    return Err(e);
+                }
+            }
+        }
+
+        if !self.store {
+            if ones && vtype.tail_agnostic {
+                for i in end..rvv::elements(cpu.vector().vlen(), cmp::max(emul, 0), eew) {
+                    fill(cpu, i);
+                }
+            }
+            cpu.vector_mut().vl = end as u64;
+        }
+        if cpu.vector().vstart() != 0 {
+            cpu.vector_mut().vstart = 0;
+        }
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (mop, rs2) = match self.typ {
+            LoadStoreType::UnitStride => (0b00, 0b00000),
+            LoadStoreType::WholeRegister => (0b00, 0b01000),
+            LoadStoreType::Mask => (0b00, 0b01011),
+            LoadStoreType::FaultOnlyFirst => (0b00, 0b10000),
+            LoadStoreType::Indexed { index, ordered: false } => (0b01, index),
+            LoadStoreType::Strided(stride) => (0b10, stride),
+            LoadStoreType::Indexed { index, ordered: true } => (0b11, index),
+        };
+
+        encoding::R {
+            opcode: if self.store { 0x27 } else { 0x07 },
+            funct7: (self.fields - 1) << 4 | mop << 1 | !self.masked as u8,
+            funct3: match self.eew {
+                8 => 0b000,
+                16 => 0b101,
+                32 => 0b110,
+                _ => 0b111,
+            },
+            rd: self.reg,
+            rs1: self.base,
+            rs2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    fn run(cpu: &mut CPU, raw: u32) -> Result<(), Exception> {
+        let instr = LoadStore::parse(raw).expect("couldn't parse instruction");
+        assert_eq!(instr.to_raw(), raw);
+        instr.execute(cpu)
+    }
+
+    fn cpu_with_data() -> CPU {
+        let mut cpu = CPU::new(RAM::new(1024));
+        for i in 0..64 {
+            cpu.store_u8(0x100 + i, i as u8).unwrap();
+        }
+        cpu.set_register(1, 0x100);
+        cpu
+    }
+
+    // vl elements of `sew` bits, with LMUL=1
+    fn set_vl(cpu: &mut CPU, vl: u64, sew: u64) {
+        let vector = cpu.vector_mut();
+        vector.vl = vl;
+        vector.vtype = (sew.trailing_zeros() as u64 - 3) << 3;
+    }
+
+    #[test]
+    fn test_unit_stride() {
+        let mut cpu = cpu_with_data();
+        set_vl(&mut cpu, 5, 16);
+
+        // vle16.v v2, (x1), then vse32.v v2, (x2), which with SEW=16
+        // covers v2-v3
+        run(&mut cpu, 0x0200d107).unwrap();
+        assert_eq!(cpu.vector().element(2, 4, 16), 0x0908);
+        assert_eq!(cpu.vector().element(2, 5, 16), 0);
+        cpu.vector_mut().set_element(2, 2, 32, 0x12345678);
+        cpu.set_register(2, 0x200);
+        run(&mut cpu, 0x02016127).unwrap();
+        assert_eq!(cpu.load_u32(0x200).unwrap(), 0x0302_0100);
+        assert_eq!(cpu.load_u32(0x210).unwrap(), 0);
+        assert_eq!(cpu.load_u32(0x208).unwrap(), 0x12345678);
+
+        // vle8.v v1, (x1), v0.t with every other element active
+        cpu.vector_mut().set_register(0, &[0b10101]);
+        run(&mut cpu, 0x00008087).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..6], &[0, 0, 2, 0, 4, 0]);
+    }
+
+    #[test]
+    fn test_strided_and_indexed() {
+        let mut cpu = cpu_with_data();
+        set_vl(&mut cpu, 4, 8);
+
+        // vlse8.v v1, (x1), x3 with a stride of -3 from the middle
+        cpu.set_register(1, 0x120);
+        cpu.set_register(3, -3i64 as u64);
+        run(&mut cpu, 0x0a308087).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..4], &[0x20, 0x1d, 0x1a, 0x17]);
+
+        // vluxei8.v v4, (x1), v1 and vloxei8.v: the indices are 8 bits wide
+        // but the data is SEW wide.
+        set_vl(&mut cpu, 3, 16);
+        cpu.set_register(1, 0x100);
+        cpu.vector_mut().set_register(1, &[6, 0, 0x20]);
+        for &raw in &[0x06108207, 0x0e108207] {
+            run(&mut cpu, raw).unwrap();
+            assert_eq!(cpu.vector().element(4, 0, 16), 0x0706);
+            assert_eq!(cpu.vector().element(4, 1, 16), 0x0100);
+            assert_eq!(cpu.vector().element(4, 2, 16), 0x2120);
+        }
+        // vsuxei8.v v4, (x2), v1
+        cpu.set_register(2, 0x200);
+        run(&mut cpu, 0x06110227).unwrap();
+        assert_eq!(cpu.load_u16(0x220).unwrap(), 0x2120);
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut cpu = cpu_with_data();
+        set_vl(&mut cpu, 4, 8);
+
+        // vlseg3e8.v v4, (x1) puts bytes 0, 3, 6, 9 in v4, 1, 4, 7, 10 in
+        // v5 and so on.
+        run(&mut cpu, 0x42008207).unwrap();
+        assert_eq!(&cpu.vector().register(4)[..4], &[0, 3, 6, 9]);
+        assert_eq!(&cpu.vector().register(5)[..4], &[1, 4, 7, 10]);
+        assert_eq!(&cpu.vector().register(6)[..4], &[2, 5, 8, 11]);
+
+        // vsseg3e8.v v4, (x2) puts them back.
+        cpu.set_register(2, 0x200);
+        run(&mut cpu, 0x42010227).unwrap();
+        assert_eq!(cpu.load_u32(0x208).unwrap(), 0x0b0a_0908);
+
+        // vlseg3e8.v v6 needs v6-v8 and vlseg2e8.v with LMUL=8 needs 16
+        assert!(run(&mut cpu, 0x42008307).is_ok());
+        cpu.vector_mut().vtype = 0b011;
+        assert!(run(&mut cpu, 0x22008007).is_err());
+    }
+
+    #[test]
+    fn test_whole_register_and_mask() {
+        let mut cpu = cpu_with_data();
+
+        // vl2re32.v v2, (x1) works without a valid vtype.
+        run(&mut cpu, 0x2280e107).unwrap();
+        assert_eq!(cpu.vector().element(3, 3, 32), 0x1f1e_1d1c);
+        // vs1r.v v3, (x2) and vl4re8.v v3, (x1), which isn't aligned
+        cpu.set_register(2, 0x200);
+        run(&mut cpu, 0x028101a7).unwrap();
+        assert_eq!(cpu.load_u32(0x20c).unwrap(), 0x1f1e_1d1c);
+        assert!(run(&mut cpu, 0x62808187).is_err());
+
+        // vlm.v v1, (x1) loads ceil(vl / 8) bytes.
+        set_vl(&mut cpu, 9, 8);
+        cpu.vector_mut().set_register(1, &[0xFF; 16]);
+        run(&mut cpu, 0x02b08087).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..3], &[0, 1, 0xFF]);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut cpu = cpu_with_data();
+        set_vl(&mut cpu, 4, 32);
+
+        // vle32.v v4, (x1) from the last 8 bytes of RAM faults on the third
+        // element and leaves vstart there, and the load can then restart.
+        cpu.set_register(1, 1016);
+        assert_eq!(run(&mut cpu, 0x0200e207), Err(Exception::LoadAccessFault(1024)));
+        assert_eq!(cpu.vector().vstart(), 2);
+
+        // vle32ff.v v4, (x1) trims vl instead.
+        cpu.vector_mut().vstart = 0;
+        run(&mut cpu, 0x0300e207).unwrap();
+        assert_eq!(cpu.vector().vl(), 2);
+        cpu.set_register(1, 1024);
+        assert_eq!(run(&mut cpu, 0x0300e207), Err(Exception::LoadAccessFault(1024)));
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Vectors: the integer instructions of version 1.0 of the V extension,
+//! which is what Zve32x and Zve64x have.
+//!
+//! Everything here works a register group at a time, with the element width
+//! and group size coming from `vtype`, and the number of elements from `vl`.
+//! Results are worked out for every element before any is written, so
+//! sources overlapping the destination read their old values.
+
+pub mod config;
+pub mod integer;
+pub mod mask;
+pub mod memory;
+pub mod permute;
+pub mod reduction;
+
+use std::cmp;
+
+use cpu::CPU;
+use trap::Exception;
+
+/// The fields of `vtype`, for a `vtype` that isn't `vill`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VType {
+    /// The element width in bits: SEW.
+    pub sew: u32,
+    /// The number of registers in a group, LMUL, as a power of two from -3
+    /// to 3.
+    pub lmul: i32,
+    pub tail_agnostic: bool,
+    pub mask_agnostic: bool,
+}
+
+impl VType {
+    /// Decode a value written by `vsetvl`, which gives None for settings
+    /// with reserved bits, or an element width this hart can't handle.
+    pub fn decode(value: u64, elen: u32) -> Option<VType> {
+        if value >> 8 != 0 || value & 0b100_000 != 0 || value & 0b111 == 0b100 {
+            return None;
+        }
+        let lmul = ((value as i32 & 0b111) << 29) >> 29;
+        let sew = 8 << ((value >> 3) & 0b11);
+
+        // A fractional group has to fit at least one element of ELEN bits.
+        if sew > elen || (lmul < 0 && sew > elen >> -lmul) {
+            return None;
+        }
+
+        Some(VType {
+            sew,
+            lmul,
+            tail_agnostic: value & (1 << 6) != 0,
+            mask_agnostic: value & (1 << 7) != 0,
+        })
+    }
+
+    /// The number of elements in a register group: VLMAX.
+    pub fn vlmax(&self, vlen: u32) -> usize {
+        elements(vlen, self.lmul, self.sew)
+    }
+
+    /// The group size for elements `eew` bits wide, keeping the ratio of
+    /// element width to group size: EMUL. None if that isn't a group size.
+    pub fn emul(&self, eew: u32) -> Option<i32> {
+        let emul = self.lmul + log2(eew) - log2(self.sew);
+        if (-3..=3).contains(&emul) {
+            Some(emul)
+        } else {
+            None
+        }
+    }
+}
+
+/// The operand that isn't vs2, for instructions that have .vv, .vx and .vi
+/// forms: vs1, an integer register, or a 5-bit immediate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Vector(u8),
+    Scalar(u8),
+    Immediate(i8),
+}
+
+impl Operand {
+    /// The operand of an instruction with `funct3` and `rs1`, and whether
+    /// it's one of the OPM encodings rather than OPI. None for OPCFG and the
+    /// floating point encodings.
+    pub fn parse(funct3: u8, rs1: u8) -> Option<(bool, Operand)> {
+        match funct3 {
+            0b000 => Some((false, Operand::Vector(rs1))),
+            0b100 => Some((false, Operand::Scalar(rs1))),
+            0b011 => Some((false, Operand::Immediate((rs1 as i8) << 3 >> 3))),
+            0b010 => Some((true, Operand::Vector(rs1))),
+            0b110 => Some((true, Operand::Scalar(rs1))),
+            _ => None,
+        }
+    }
+
+    /// funct3 and rs1 for this operand.
+    pub fn to_raw(&self, opm: bool) -> (u8, u8) {
+        match (opm, *self) {
+            (false, Operand::Vector(reg)) => (0b000, reg),
+            (false, Operand::Scalar(reg)) => (0b100, reg),
+            (_, Operand::Immediate(imm)) => (0b011, imm as u8 & 0x1F),
+            (true, Operand::Vector(reg)) => (0b010, reg),
+            (true, Operand::Scalar(reg)) => (0b110, reg),
+        }
+    }
+}
+
+/// The current `vtype`, or an illegal instruction exception for `raw` if
+/// it's `vill`.
+pub fn vtype(cpu: &CPU, raw: u32) -> Result<VType, Exception> {
+    VType::decode(cpu.vector().vtype(), cpu.isa().elen())
+        .ok_or(Exception::IllegalInstruction(raw))
+}
+
+fn log2(value: u32) -> i32 {
+    31 - value.leading_zeros() as i32
+}
+
+/// The number of `eew`-bit elements in a group of `2^emul` registers.
+pub fn elements(vlen: u32, emul: i32, eew: u32) -> usize {
+    let bits = if emul < 0 {
+        vlen as usize >> -emul
+    } else {
+        (vlen as usize) << emul
+    };
+    bits / eew as usize
+}
+
+/// The number of registers a group of `2^emul` takes up.
+pub fn registers(emul: i32) -> u8 {
+    1 << cmp::max(emul, 0)
+}
+
+/// Whether `reg` can start a group of `2^emul` registers.
+pub fn is_aligned(reg: u8, emul: i32) -> bool {
+    reg.is_multiple_of(registers(emul))
+}
+
+/// Whether the groups of `2^emul1` registers at `reg1` and `2^emul2` at
+/// `reg2` share a register.
+pub fn overlaps(reg1: u8, emul1: i32, reg2: u8, emul2: i32) -> bool {
+    reg1 < reg2 + registers(emul2) && reg2 < reg1 + registers(emul1)
+}
+
+/// `value` without the bits above `bits`.
+pub fn truncate(value: u64, bits: u32) -> u64 {
+    if bits >= 64 {
+        value
+    } else {
+        value & ((1 << bits) - 1)
+    }
+}
+
+/// The low `bits` of `value` as a signed number.
+pub fn sign_extend(value: u64, bits: u32) -> i64 {
+    ((value << (64 - bits)) as i64) >> (64 - bits)
+}
+
+/// Integer register `reg` as an element `sew` bits wide: sign-extended, or
+/// truncated, from XLEN.
+pub fn scalar(cpu: &CPU, reg: u8, sew: u32) -> u64 {
+    truncate(cpu.signed(cpu.get_register(reg)) as u64, sew)
+}
+
+/// Shift `value` right by `shift` bits, rounding as `vxrm` says: to nearest
+/// with ties up, to nearest with ties to even, down, or to odd.
+pub fn roundoff(value: i128, shift: u32, vxrm: u8) -> i128 {
+    if shift == 0 {
+        return value;
+    }
+    let bit = |n: u32| (value >> n) & 1;
+    let rest = value & ((1 << (shift - 1)) - 1) != 0;
+    let increment = match vxrm {
+        0 => bit(shift - 1),
+        1 => bit(shift - 1) & (rest as i128 | bit(shift)),
+        2 => 0,
+        _ => (bit(shift) == 0 && (bit(shift - 1) != 0 || rest)) as i128,
+    };
+    (value >> shift) + increment
+}
+
+/// Clamp `value` to `min..=max`, setting `vxsat` if that changes it.
+pub fn saturate(cpu: &mut CPU, value: i128, min: i128, max: i128) -> i128 {
+    if value < min || value > max {
+        cpu.vector_mut().vxsat = true;
+    }
+    cmp::min(cmp::max(value, min), max)
+}
+
+/// The smallest and largest signed, or unsigned, numbers `bits` wide.
+pub fn limits(bits: u32, signed: bool) -> (i128, i128) {
+    if signed {
+        (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+    } else {
+        (0, (1 << bits) - 1)
+    }
+}
+
+/// Whether element `index` is active: either the instruction isn't masked,
+/// or its bit in `v0` is set.
+pub fn is_active(cpu: &CPU, masked: bool, index: usize) -> bool {
+    !masked || cpu.vector().mask(0, index)
+}
+
+/// Write the body of a group of `eew`-bit elements at `dest`, from `vstart`
+/// to `vl`, with `f` giving the value of each active element. Inactive and
+/// tail elements are left alone, or filled with ones if `vtype` says
+/// they're agnostic and the CPU is set up to do that. Resets `vstart`.
+pub fn write_elements<F>(cpu: &mut CPU, vtype: VType, masked: bool, dest: u8, eew: u32, emul: i32,
+                         mut f: F)
+    where F: FnMut(&mut CPU, usize) -> u64
+{
+    let start = cpu.vector().vstart() as usize;
+    let vl = cpu.vector().vl() as usize;
+    let values = (start..vl)
+        .map(|i| if is_active(cpu, masked, i) { Some(f(cpu, i)) } else { None })
+        .collect::<Vec<_>>();
+
+    let ones = cpu.vector_agnostic_ones();
+    let vlen = cpu.vector().vlen();
+    let vector = cpu.vector_mut();
+    for (i, value) in (start..vl).zip(values) {
+        match value {
+            Some(value) => vector.set_element(dest, i, eew, value),
+            None if ones && vtype.mask_agnostic => vector.set_element(dest, i, eew, !0),
+            None => {}
+        }
+    }
+    if ones && vtype.tail_agnostic {
+        for i in vl..elements(vlen, cmp::max(emul, 0), eew) {
+            vector.set_element(dest, i, eew, !0);
+        }
+    }
+    vector.vstart = 0;
+}
+
+/// Like `write_elements`, for instructions writing a mask to `dest`. The
+/// tail of a mask is always agnostic.
+pub fn write_mask<F>(cpu: &mut CPU, vtype: VType, masked: bool, dest: u8, mut f: F)
+    where F: FnMut(&mut CPU, usize) -> bool
+{
+    let start = cpu.vector().vstart() as usize;
+    let vl = cpu.vector().vl() as usize;
+    let values = (start..vl)
+        .map(|i| if is_active(cpu, masked, i) { Some(f(cpu, i)) } else { None })
+        .collect::<Vec<_>>();
+
+    let ones = cpu.vector_agnostic_ones();
+    let vlen = cpu.vector().vlen() as usize;
+    let vector = cpu.vector_mut();
+    for (i, value) in (start..vl).zip(values) {
+        match value {
+            Some(value) => vector.set_mask(dest, i, value),
+            None if ones && vtype.mask_agnostic => vector.set_mask(dest, i, true),
+            None => {}
+        }
+    }
+    if ones {
+        for i in vl..vlen {
+            vector.set_mask(dest, i, true);
+        }
+    }
+    vector.vstart = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use trap::StopReason;
+
+    #[test]
+    fn test_vtype() {
+        // e32, m2, ta, mu
+        let vtype = VType::decode(0b0101_0001, 64).unwrap();
+        assert_eq!((vtype.sew, vtype.lmul), (32, 1));
+        assert!(vtype.tail_agnostic && !vtype.mask_agnostic);
+        assert_eq!(vtype.vlmax(128), 8);
+        assert_eq!(vtype.emul(8), Some(-1));
+        assert_eq!(vtype.emul(64), Some(2));
+
+        // e8, mf8
+        assert_eq!(VType::decode(0b0000_0101, 64).unwrap().vlmax(128), 2);
+        // e64 without Zve64x, e16 with mf8 when ELEN is 32, LMUL 4's
+        // reserved neighbour, and vill
+        assert_eq!(VType::decode(0b0001_1000, 32), None);
+        assert_eq!(VType::decode(0b0000_1101, 32), None);
+        assert_eq!(VType::decode(0b0000_0100, 64), None);
+        assert_eq!(VType::decode(1 << 31, 64), None);
+    }
+
+    #[test]
+    fn test_roundoff() {
+        // 0b1011 >> 2 is 2.75 and 0b1010 >> 2 is 2.5: rnu, rne, rdn, rod
+        let expected = [(3, 3), (3, 2), (2, 2), (3, 3)];
+        for (vxrm, &(a, b)) in expected.iter().enumerate() {
+            assert_eq!(roundoff(0b1011, 2, vxrm as u8), a);
+            assert_eq!(roundoff(0b1010, 2, vxrm as u8), b);
+        }
+        assert_eq!(roundoff(-3, 1, 0), -1);
+        assert_eq!(roundoff(0b1000, 2, 3), 2);
+    }
+
+    #[test]
+    fn test_strip_mining() {
+        // vsetvli t0, a0, e32, m1, ta, ma; vle32.v v1, (a1); vle32.v v2, (a2);
+        // vadd.vv v3, v1, v2; vse32.v v3, (a3); vredsum.vs v4, v3, v4;
+        // vmv.x.s a4, v4
+        let program = [0x0d0572d7, 0x0205e087, 0x02066107, 0x021101d7, 0x0206e1a7, 0x02322257,
+                       0x42402757];
+        let mut cpu = CPU::new(RAM::new(4096));
+        for (i, &word) in program.iter().enumerate() {
+            cpu.store_u32(0x100 + 4 * i as u32, word).unwrap();
+        }
+        for i in 0..6 {
+            cpu.store_u32(0x200 + 4 * i, i + 1).unwrap();
+            cpu.store_u32(0x300 + 4 * i, 10 * i).unwrap();
+        }
+        cpu.set_register(10, 6);
+        cpu.set_register(11, 0x200);
+        cpu.set_register(12, 0x300);
+        cpu.set_register(13, 0x400);
+        cpu.pc = 0x100;
+
+        // 128-bit registers hold four of the six elements asked for.
+        assert_eq!(cpu.run_for(7), StopReason::InstructionLimit);
+        assert_eq!(cpu.get_register(5), 4);
+        let sums = (0..5).map(|i| cpu.load_u32(0x400 + 4 * i).unwrap()).collect::<Vec<_>>();
+        assert_eq!(sums, [1, 12, 23, 34, 0]);
+        assert_eq!(cpu.get_register(14), 70);
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use instruction::rvv::{self, Operand};
+use cpu::CPU;
+use trap::Exception;
+
+/// Instructions that move elements between positions, or between vector
+/// and integer registers.
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src2: u8,
+    operand: Operand,
+    masked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    MoveToScalar,
+    MoveFromScalar,
+    SlideUp,
+    SlideDown,
+    Slide1Up,
+    Slide1Down,
+    Gather,
+    GatherEi16,
+    Compress,
+    MoveWhole,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        if decoded.opcode != 0x57 {
+            return None;
+        }
+        let masked = instruction & (1 << 25) == 0;
+        let (opm, operand) = Operand::parse(decoded.funct3, decoded.rs1)?;
+
+        let typ = match ((instruction >> 26) as u8, opm, operand) {
+            (0b010000, true, Operand::Vector(0)) if !masked => OperationType::MoveToScalar,
+            (0b010000, true, Operand::Scalar(_)) if !masked && decoded.rs2 == 0 => {
+                OperationType::MoveFromScalar
+            }
+            (0b001110, false, Operand::Vector(_)) => OperationType::GatherEi16,
+            (0b001110, false, _) => OperationType::SlideUp,
+            (0b001111, false, Operand::Scalar(_)) |
+            (0b001111, false, Operand::Immediate(_)) => OperationType::SlideDown,
+            (0b001110, true, Operand::Scalar(_)) => OperationType::Slide1Up,
+            (0b001111, true, Operand::Scalar(_)) => OperationType::Slide1Down,
+            (0b001100, false, _) => OperationType::Gather,
+            (0b010111, true, Operand::Vector(_)) if !masked => OperationType::Compress,
+            // vmv<nr>r.v, with nr - 1 as the immediate
+            (0b100111, false, Operand::Immediate(imm))
+                if !masked && matches!(imm, 0 | 1 | 3 | 7) => OperationType::MoveWhole,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src2: decoded.rs2,
+            operand,
+            masked,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        match (self.typ, self.operand) {
+            (OperationType::MoveToScalar, _) => self.dest,
+            (_, Operand::Scalar(reg)) => reg,
+            _ => 0,
+        }
+    }
+
+    fn funct6(&self) -> (u8, bool) {
+        match self.typ {
+            OperationType::MoveToScalar | OperationType::MoveFromScalar => (0b010000, true),
+            OperationType::SlideUp | OperationType::GatherEi16 => (0b001110, false),
+            OperationType::SlideDown => (0b001111, false),
+            OperationType::Slide1Up => (0b001110, true),
+            OperationType::Slide1Down => (0b001111, true),
+            OperationType::Gather => (0b001100, false),
+            OperationType::Compress => (0b010111, true),
+            OperationType::MoveWhole => (0b100111, false),
+        }
+    }
+
+    fn src1(&self) -> u8 {
+        match self.operand {
+            Operand::Vector(reg) | Operand::Scalar(reg) => reg,
+            Operand::Immediate(_) => 0,
+        }
+    }
+
+    // vcompress.vm packs the elements of vs2 selected by the mask in vs1
+    // into the start of vd. Everything after them is tail.
+    fn compress(&self, cpu: &mut CPU, vtype: rvv::VType) -> Result<(), Exception> {
+        if cpu.vector().vstart() != 0 {
+            return Err(Exception::IllegalInstruction(self.to_raw()));
+        }
+        let vl = cpu.vector().vl() as usize;
+        let values = (0..vl)
+            .filter(|&i| cpu.vector().mask(self.src1(), i))
+            .map(|i| cpu.vector().element(self.src2, i, vtype.sew))
+            .collect::<Vec<_>>();
+
+        let ones = cpu.vector_agnostic_ones();
+        let end = rvv::elements(cpu.vector().vlen(), cmp::max(vtype.lmul, 0), vtype.sew);
+        let vector = cpu.vector_mut();
+        for (i, &value) in values.iter().enumerate() {
+            vector.set_element(self.dest, i, vtype.sew, value);
+        }
+        if ones && vtype.tail_agnostic {
+            for i in values.len()..end {
+                vector.set_element(self.dest, i, vtype.sew, !0);
+            }
+        }
+        Ok(())
+    }
+
+    // vmv<nr>r.v copies whole registers, whatever vtype says.
+    fn move_whole(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let count = match self.operand {
+            Operand::Immediate(imm) => imm as u8 + 1,
+            _ => unreachable!(),
+        };
+        if !self.dest.is_multiple_of(count) || !self.src2.is_multiple_of(count) {
+            return Err(Exception::IllegalInstruction(self.to_raw()));
+        }
+
+        let eew = rvv::VType::decode(cpu.vector().vtype(), cpu.isa().elen())
+            .map_or(8, |vtype| vtype.sew);
+        let end = count as usize * cpu.vector().vlenb() * 8 / eew as usize;
+        let vector = cpu.vector_mut();
+        for i in vector.vstart() as usize..end {
+            let value = vector.element(self.src2, i, eew);
+            vector.set_element(self.dest, i, eew, value);
+        }
+        vector.vstart = 0;
+        Ok(())
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        if self.typ == OperationType::MoveWhole {
+            return self.move_whole(cpu);
+        }
+
+        let illegal = Exception::IllegalInstruction(self.to_raw());
+        let vtype = rvv::vtype(cpu, self.to_raw())?;
+        let (sew, lmul) = (vtype.sew, vtype.lmul);
+        let vl = cpu.vector().vl() as usize;
+        let vlmax = vtype.vlmax(cpu.vector().vlen()) as u64;
+        let ones = cpu.vector_agnostic_ones();
+
+        match self.typ {
+            OperationType::MoveToScalar => {
+                let value = rvv::sign_extend(cpu.vector().element(self.src2, 0, sew), sew);
+                cpu.set_register(self.dest, value as u64);
+                if cpu.vector().vstart() != 0 {
+                    cpu.vector_mut().vstart = 0;
+                }
+                return Ok(());
+            }
+            OperationType::MoveFromScalar => {
+                let value = rvv::scalar(cpu, self.src1(), sew);
+                let tail = cpu.vector().vlen() / sew;
+                let vector = cpu.vector_mut();
+                if (vector.vstart() as usize) < vl {
+                    vector.set_element(self.dest, 0, sew, value);
+                }
+                if ones && vtype.tail_agnostic {
+                    for i in 1..tail as usize {
+                        vector.set_element(self.dest, i, sew, !0);
+                    }
+                }
+                vector.vstart = 0;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        // The destination can't overlap the source of anything that moves
+        // elements up, since that would change elements before they're
+        // read, on hardware that works through the group in order.
+        let index_emul = match self.typ {
+            OperationType::GatherEi16 => vtype.emul(16).ok_or(illegal)?,
+            OperationType::Compress => 0,
+            _ => lmul,
+        };
+        let overlapping = match (self.typ, self.operand) {
+            (OperationType::SlideDown, _) | (OperationType::Slide1Down, _) => false,
+            (_, Operand::Vector(index)) => {
+                rvv::overlaps(self.dest, lmul, self.src2, lmul) ||
+                rvv::overlaps(self.dest, lmul, index, index_emul)
+            }
+            _ => rvv::overlaps(self.dest, lmul, self.src2, lmul),
+        };
+        let index_aligned = match self.operand {
+            Operand::Vector(index) => rvv::is_aligned(index, index_emul),
+            _ => true,
+        };
+        if !rvv::is_aligned(self.dest, lmul) || !rvv::is_aligned(self.src2, lmul) ||
+           !index_aligned || overlapping || (self.masked && self.dest == 0) {
+            return Err(illegal);
+        }
+
+        if self.typ == OperationType::Compress {
+            return self.compress(cpu, vtype);
+        }
+
+        let offset = match self.operand {
+            Operand::Scalar(reg) => cpu.get_register(reg),
+            Operand::Immediate(imm) => imm as u64 & 0x1F,
+            Operand::Vector(_) => 0,
+        };
+        let scalar = match self.operand {
+            Operand::Scalar(reg) => rvv::scalar(cpu, reg, sew),
+            _ => 0,
+        };
+        // Elements below the offset aren't written at all by vslideup.
+        if self.typ == OperationType::SlideUp {
+            let start = cmp::max(cpu.vector().vstart(), cmp::min(offset, vl as u64));
+            cpu.vector_mut().vstart = start;
+        }
+
+        let src2 = self.src2;
+        let read = move |cpu: &CPU, i: u64| {
+            if i < vlmax {
+                cpu.vector().element(src2, i as usize, sew)
+            } else {
+                0
+            }
+        };
+        rvv::write_elements(cpu, vtype, self.masked, self.dest, sew, lmul, |cpu, i| {
+            let i = i as u64;
+            match (self.typ, self.operand) {
+                (OperationType::SlideUp, _) => read(cpu, i - offset),
+                (OperationType::SlideDown, _) => read(cpu, i.saturating_add(offset)),
+                (OperationType::Slide1Up, _) if i == 0 => scalar,
+                (OperationType::Slide1Up, _) => read(cpu, i - 1),
+                (OperationType::Slide1Down, _) if i + 1 == vl as u64 => scalar,
+                (OperationType::Slide1Down, _) => read(cpu, i + 1),
+                (OperationType::GatherEi16, Operand::Vector(index)) => {
+                    read(cpu, cpu.vector().element(index, i as usize, 16))
+                }
+                (_, Operand::Vector(index)) => {
+                    read(cpu, cpu.vector().element(index, i as usize, sew))
+                }
+                _ => read(cpu, offset),
+            }
+        });
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (funct6, opm) = self.funct6();
+        let (funct3, rs1) = self.operand.to_raw(opm);
+
+        encoding::R {
+            opcode: 0x57,
+            funct7: funct6 << 1 | !self.masked as u8,
+            funct3,
+            rd: self.dest,
+            rs1,
+            rs2: self.src2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    fn run(cpu: &mut CPU, raw: u32) -> Result<(), Exception> {
+        let instr = Op::parse(raw).expect("couldn't parse instruction");
+        assert_eq!(instr.to_raw(), raw);
+        instr.execute(cpu)
+    }
+
+    // e8 and vl=6, with v2 = 10, 11, 12, ...
+    fn cpu_with_elements() -> CPU {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.vector_mut().vl = 6;
+        cpu.vector_mut().vtype = 0;
+        let elements = (10..26).collect::<Vec<_>>();
+        cpu.vector_mut().set_register(2, &elements);
+        cpu
+    }
+
+    #[test]
+    fn test_scalar_moves() {
+        let mut cpu = cpu_with_elements();
+
+        // vmv.x.s x1, v2 sign-extends, and vmv.s.x v1, x1 only writes
+        // element 0.
+        cpu.vector_mut().set_element(2, 0, 8, 0x80);
+        run(&mut cpu, 0x422020d7).unwrap();
+        assert_eq!(cpu.get_register(1), 0xFFFF_FF80);
+        cpu.vector_mut().set_register(1, &[1, 2, 3]);
+        cpu.set_register(1, 0x1234);
+        run(&mut cpu, 0x4200e0d7).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..3], &[0x34, 2, 3]);
+    }
+
+    #[test]
+    fn test_slides() {
+        let mut cpu = cpu_with_elements();
+
+        // vslideup.vi v1, v2, 2 leaves elements 0 and 1 alone.
+        cpu.vector_mut().set_register(1, &[0xFF; 16]);
+        run(&mut cpu, 0x3a2130d7).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..7], &[0xFF, 0xFF, 10, 11, 12, 13, 0xFF]);
+
+        // vslidedown.vx v1, v2, x1 reads zeros past VLMAX.
+        cpu.set_register(1, 12);
+        run(&mut cpu, 0x3e20c0d7).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..6], &[22, 23, 24, 25, 0, 0]);
+
+        // vslide1up.vx and vslide1down.vx v1, v2, x1
+        cpu.set_register(1, 99);
+        run(&mut cpu, 0x3a20e0d7).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..6], &[99, 10, 11, 12, 13, 14]);
+        run(&mut cpu, 0x3e20e0d7).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..6], &[11, 12, 13, 14, 15, 99]);
+
+        // vslideup.vi v2, v2, 1 would overwrite its source.
+        assert!(run(&mut cpu, 0x3a20b157).is_err());
+    }
+
+    #[test]
+    fn test_gather_and_compress() {
+        let mut cpu = cpu_with_elements();
+
+        // vrgather.vv v1, v2, v3 and vrgather.vi v1, v2, 4
+        cpu.vector_mut().set_register(3, &[5, 0, 200, 1, 1, 15]);
+        run(&mut cpu, 0x322180d7).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..6], &[15, 10, 0, 11, 11, 25]);
+        run(&mut cpu, 0x322230d7).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..6], &[14; 6]);
+
+        // vrgatherei16.vv v1, v2, v4 with 16-bit indices in v4
+        for (i, &index) in [300, 2, 1, 0, 7, 3].iter().enumerate() {
+            cpu.vector_mut().set_element(4, i, 16, index);
+        }
+        run(&mut cpu, 0x3a2200d7).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..6], &[0, 12, 11, 10, 17, 13]);
+
+        // vcompress.vm v1, v2, v3 picks elements 0, 2 and 5.
+        cpu.vector_mut().set_register(3, &[0b100101]);
+        run(&mut cpu, 0x5e21a0d7).unwrap();
+        assert_eq!(&cpu.vector().register(1)[..3], &[10, 12, 15]);
+    }
+
+    #[test]
+    fn test_move_whole() {
+        let mut cpu = cpu_with_elements();
+
+        // vmv2r.v v4, v2 works even with vtype set to vill.
+        cpu.vector_mut().vtype = 1 << 31;
+        cpu.vector_mut().set_register(3, &[7; 16]);
+        run(&mut cpu, 0x9e20b257).unwrap();
+        assert_eq!(cpu.vector().register(4)[5], 15);
+        assert_eq!(cpu.vector().register(5), &[7; 16][..]);
+        // vmv2r.v v3, v2 isn't aligned.
+        assert!(run(&mut cpu, 0x9e20b1d7).is_err());
+    }
+}
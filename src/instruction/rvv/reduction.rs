@@ -0,0 +1,184 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use instruction::rvv;
+use cpu::CPU;
+use trap::Exception;
+
+/// Reductions: combine element 0 of vs1 with every active element of vs2,
+/// into element 0 of vd.
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+    masked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    Sum,
+    And,
+    Or,
+    Xor,
+    MinUnsigned,
+    Min,
+    MaxUnsigned,
+    Max,
+    WideningSumUnsigned,
+    WideningSum,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        if decoded.opcode != 0x57 {
+            return None;
+        }
+
+        let typ = match ((instruction >> 26) as u8, decoded.funct3) {
+            (0b000000, 0b010) => OperationType::Sum,
+            (0b000001, 0b010) => OperationType::And,
+            (0b000010, 0b010) => OperationType::Or,
+            (0b000011, 0b010) => OperationType::Xor,
+            (0b000100, 0b010) => OperationType::MinUnsigned,
+            (0b000101, 0b010) => OperationType::Min,
+            (0b000110, 0b010) => OperationType::MaxUnsigned,
+            (0b000111, 0b010) => OperationType::Max,
+            (0b110000, 0b000) => OperationType::WideningSumUnsigned,
+            (0b110001, 0b000) => OperationType::WideningSum,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: decoded.rs2,
+            masked: instruction & (1 << 25) == 0,
+        })
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let vtype = rvv::vtype(cpu, self.to_raw())?;
+        let sew = vtype.sew;
+        let widening = matches!(self.typ,
+                                OperationType::WideningSumUnsigned | OperationType::WideningSum);
+        let eew = if widening { 2 * sew } else { sew };
+
+        // Reductions can't be interrupted, so never start part way through.
+        if cpu.vector().vstart() != 0 || eew > cpu.isa().elen() ||
+           !rvv::is_aligned(self.src2, vtype.lmul) {
+            return Err(Exception::IllegalInstruction(self.to_raw()));
+        }
+        let vl = cpu.vector().vl() as usize;
+        if vl == 0 {
+            return Ok(());
+        }
+
+        let signed = |value: u64| rvv::sign_extend(value, sew);
+        let result = (0..vl)
+            .filter(|&i| rvv::is_active(cpu, self.masked, i))
+            .map(|i| cpu.vector().element(self.src2, i, sew))
+            .fold(cpu.vector().element(self.src1, 0, eew), |acc, value| {
+                match self.typ {
+                    OperationType::Sum | OperationType::WideningSumUnsigned => {
+                        acc.wrapping_add(value)
+                    }
+                    OperationType::WideningSum => acc.wrapping_add(signed(value) as u64),
+                    OperationType::And => acc & value,
+                    OperationType::Or => acc | value,
+                    OperationType::Xor => acc ^ value,
+                    OperationType::MinUnsigned => cmp::min(acc, value),
+                    OperationType::MaxUnsigned => cmp::max(acc, value),
+                    OperationType::Min => cmp::min(signed(acc), signed(value)) as u64,
+                    OperationType::Max => cmp::max(signed(acc), signed(value)) as u64,
+                }
+            });
+
+        let ones = cpu.vector_agnostic_ones();
+        let tail = cpu.vector().vlen() / eew;
+        let vector = cpu.vector_mut();
+        vector.set_element(self.dest, 0, eew, rvv::truncate(result, eew));
+        if ones && vtype.tail_agnostic {
+            for i in 1..tail as usize {
+                vector.set_element(self.dest, i, eew, !0);
+            }
+        }
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (funct6, funct3) = match self.typ {
+            OperationType::Sum => (0b000000, 0b010),
+            OperationType::And => (0b000001, 0b010),
+            OperationType::Or => (0b000010, 0b010),
+            OperationType::Xor => (0b000011, 0b010),
+            OperationType::MinUnsigned => (0b000100, 0b010),
+            OperationType::Min => (0b000101, 0b010),
+            OperationType::MaxUnsigned => (0b000110, 0b010),
+            OperationType::Max => (0b000111, 0b010),
+            OperationType::WideningSumUnsigned => (0b110000, 0b000),
+            OperationType::WideningSum => (0b110001, 0b000),
+        };
+
+        encoding::R {
+            opcode: 0x57,
+            funct7: funct6 << 1 | !self.masked as u8,
+            funct3,
+            rd: self.dest,
+            rs1: self.src1,
+            rs2: self.src2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    #[test]
+    fn test_reductions() {
+        // e16 and m2, so v2-v3 holds 10 elements
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.vector_mut().vl = 10;
+        cpu.vector_mut().vtype = 0b001_001;
+        for i in 0..10 {
+            cpu.vector_mut().set_element(2, i, 16, 0xFFF0 + i as u64);
+        }
+        cpu.vector_mut().set_element(4, 0, 32, 0x10);
+
+        // vredsum.vs, vredmin.vs, vredmaxu.vs v1, v2, v4, then
+        // vwredsumu.vs and vwredsum.vs v1, v2, v4, reading 32 bits of v4
+        let expected = [(0x022220d7, 16, 0xFFF0 * 10 + 45 + 0x10),
+                        (0x162220d7, 16, 0xFFF0),
+                        (0x1a2220d7, 16, 0xFFF9),
+                        (0xc22200d7, 32, 0xFFF0 * 10 + 45 + 0x10),
+                        (0xc62200d7, 32, 0xFFFF_FF9D)];
+        for &(raw, eew, result) in &expected {
+            let instr = Op::parse(raw).expect("couldn't parse instruction");
+            assert_eq!(instr.to_raw(), raw);
+            instr.execute(&mut cpu).expect("couldn't execute instruction");
+            assert_eq!(cpu.vector().element(1, 0, eew), result & ((1 << eew) - 1));
+        }
+
+        // vredsum.vs v1, v2, v4, v0.t with only element 9 active
+        cpu.vector_mut().set_register(0, &[0, 0b10]);
+        Op::parse(0x002220d7).unwrap().execute(&mut cpu).unwrap();
+        assert_eq!(cpu.vector().element(1, 0, 16), 0x10009 & 0xFFFF);
+    }
+}
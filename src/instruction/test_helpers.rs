@@ -0,0 +1,34 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! What the instruction modules' tests share.
+
+use cpu::CPU;
+use instruction::Instruction;
+
+/// Parse `raw` with `parse`, check that it encodes back to `raw`, and
+/// execute it with `src1` in x2 and `src2` in x3. Returns what it wrote to
+/// x1.
+pub fn run_op<I: Instruction>(cpu: &mut CPU, parse: fn(u32) -> Option<I>, raw: u32, src1: u64,
+                              src2: u64)
+                              -> u64 {
+    let instr = parse(raw).expect("couldn't parse instruction");
+    assert_eq!(instr.to_raw(), raw);
+    cpu.set_register(2, src1);
+    cpu.set_register(3, src2);
+    instr.execute(cpu).expect("couldn't execute instruction");
+    cpu.get_register(1)
+}
+
+/// Check that `raw`, an instruction of the calling module's `Op`, writes
+/// `result` to x1 given `val1` in x2 and `val2` in x3.
+macro_rules! test_op {
+    ($cpu:expr, $raw:expr, $result:expr, $val1:expr, $val2:expr) => {
+        assert_eq!(::instruction::test_helpers::run_op(&mut $cpu, Op::parse, $raw, $val1, $val2),
+                   $result);
+    }
+}
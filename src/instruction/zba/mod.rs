@@ -0,0 +1,152 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zba: address generation, shifting an index and adding it to a base.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+    shamt: u8, // for SLLI.UW
+    // RV64's .UW forms, which zero-extend the low word of src1 first
+    unsigned_word: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    /// SH1ADD, SH2ADD and SH3ADD, and ADD.UW as a shift by 0.
+    ShiftAdd(u8),
+    ShiftLeftImmediate,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        let shift_add = |funct3| {
+            match funct3 {
+                0b010 => Some(1),
+                0b100 => Some(2),
+                0b110 => Some(3),
+                _ => None,
+            }
+        };
+        let (typ, unsigned_word) = match (decoded.opcode, decoded.funct7, decoded.funct3) {
+            (0x33, 0x10, funct3) => (OperationType::ShiftAdd(shift_add(funct3)?), false),
+            (0x3B, 0x10, funct3) => (OperationType::ShiftAdd(shift_add(funct3)?), true),
+            (0x3B, 0x04, 0b000) => (OperationType::ShiftAdd(0), true),
+            (0x1B, funct7, 0b001) if funct7 >> 1 == 0x02 => {
+                (OperationType::ShiftLeftImmediate, true)
+            }
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: if typ == OperationType::ShiftLeftImmediate { 0 } else { decoded.rs2 },
+            shamt: ((instruction >> 20) & 0x3F) as u8,
+            unsigned_word,
+        })
+    }
+
+    /// Whether this is one of the .UW forms, which only exist on RV64.
+    pub fn is_rv64_only(&self) -> bool {
+        self.unsigned_word
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.src1, self.src2))
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let mut src1 = cpu.get_register(self.src1);
+        if self.unsigned_word {
+            src1 &= 0xFFFF_FFFF;
+        }
+
+        let result = match self.typ {
+            OperationType::ShiftAdd(shift) => {
+                (src1 << shift).wrapping_add(cpu.get_register(self.src2))
+            }
+            OperationType::ShiftLeftImmediate => src1 << self.shamt,
+        };
+
+        cpu.set_register(self.dest, result);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        match self.typ {
+            OperationType::ShiftAdd(shift) => {
+                encoding::R {
+                    opcode: if self.unsigned_word { 0x3B } else { 0x33 },
+                    funct7: if shift == 0 { 0x04 } else { 0x10 },
+                    funct3: shift << 1,
+                    rd: self.dest,
+                    rs1: self.src1,
+                    rs2: self.src2,
+                }.to_raw()
+            }
+            OperationType::ShiftLeftImmediate => {
+                encoding::I {
+                    opcode: 0x1B,
+                    funct3: 0b001,
+                    rd: self.dest,
+                    rs1: self.src1,
+                    immediate: 0x080 | self.shamt as i32,
+                }.to_raw()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::Isa;
+
+
+    #[test]
+    fn test_shadd() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // sh1add, sh2add, sh3add x1, x2, x3
+        test_op!(cpu, 0x203120b3, 0x1006, 3, 0x1000);
+        test_op!(cpu, 0x203140b3, 0x100c, 3, 0x1000);
+        test_op!(cpu, 0x203160b3, 0x1018, 3, 0x1000);
+        test_op!(cpu, 0x203160b3, 0x7, 0xffffffff, 0xf);
+    }
+
+    #[test]
+    fn test_unsigned_word() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.set_isa(Isa::parse("rv64i_zba").unwrap());
+
+        // add.uw, sh3add.uw x1, x2, x3
+        test_op!(cpu, 0x083100bb, 0x1_00000001, 0xffffffff_ffffffff, 2);
+        test_op!(cpu, 0x203160bb, 0x7_fffffff8, 0xffffffff_ffffffff, 0);
+        // slli.uw x1, x2, 36
+        test_op!(cpu, 0x0a41109b, 0xfffffff0_00000000, 0x12345678_ffffffff, 0);
+
+        assert!(Op::parse(0x083100bb).unwrap().is_rv64_only());
+        assert!(!Op::parse(0x203120b3).unwrap().is_rv64_only());
+    }
+}
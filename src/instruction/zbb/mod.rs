@@ -0,0 +1,319 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zbb: basic bit manipulation.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use isa::Extension;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+    shamt: u8, // for RORI, and REV8 (whose encoding has XLEN - 8 here)
+    // RV64's CLZW, CTZW, CPOPW, ROLW, RORW and RORIW, which work on the low
+    // word and sign-extend the result
+    word: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    AndNot,
+    OrNot,
+    XorNot,
+    CountLeadingZeros,
+    CountTrailingZeros,
+    CountPopulation,
+    Max,
+    MaxUnsigned,
+    Min,
+    MinUnsigned,
+    SignExtendByte,
+    SignExtendHalfWord,
+    RotateLeft,
+    RotateRight,
+    RotateRightImmediate,
+    OrCombineBytes,
+    ReverseBytes,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+        // The unary instructions are OP-IMM with their own immediates.
+        let immediate = instruction >> 20;
+
+        let word = match decoded.opcode {
+            0x13 | 0x33 => false,
+            0x1B | 0x3B => true,
+            _ => return None,
+        };
+
+        let typ = match (decoded.opcode, decoded.funct7, decoded.funct3) {
+            (0x33, 0x20, 0b111) => OperationType::AndNot,
+            (0x33, 0x20, 0b110) => OperationType::OrNot,
+            (0x33, 0x20, 0b100) => OperationType::XorNot,
+            (0x33, 0x05, 0b100) => OperationType::Min,
+            (0x33, 0x05, 0b101) => OperationType::MinUnsigned,
+            (0x33, 0x05, 0b110) => OperationType::Max,
+            (0x33, 0x05, 0b111) => OperationType::MaxUnsigned,
+            (0x33, 0x30, 0b001) | (0x3B, 0x30, 0b001) => OperationType::RotateLeft,
+            (0x33, 0x30, 0b101) | (0x3B, 0x30, 0b101) => OperationType::RotateRight,
+            (0x13, 0x30, 0b001) | (0x1B, 0x30, 0b001) => {
+                match decoded.rs2 {
+                    0b00000 => OperationType::CountLeadingZeros,
+                    0b00001 => OperationType::CountTrailingZeros,
+                    0b00010 => OperationType::CountPopulation,
+                    0b00100 if !word => OperationType::SignExtendByte,
+                    0b00101 if !word => OperationType::SignExtendHalfWord,
+                    _ => return None,
+                }
+            }
+            (0x13, funct7, 0b101) if funct7 >> 1 == 0x18 => OperationType::RotateRightImmediate,
+            (0x1B, 0x30, 0b101) => OperationType::RotateRightImmediate,
+            (0x13, _, 0b101) if immediate == 0x287 => OperationType::OrCombineBytes,
+            (0x13, _, 0b101) if immediate == 0x698 || immediate == 0x6B8 => {
+                OperationType::ReverseBytes
+            }
+            _ => return None,
+        };
+
+        let register_src2 = decoded.opcode == 0x33 || decoded.opcode == 0x3B;
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: if register_src2 { decoded.rs2 } else { 0 },
+            shamt: (immediate & 0x3F) as u8,
+            word,
+        })
+    }
+
+    /// Whether this only exists on RV64: the *W instructions, RORI by 32 or
+    /// more, and RV64's encoding of REV8.
+    pub fn is_rv64_only(&self) -> bool {
+        self.word || (self.typ == OperationType::RotateRightImmediate && self.shamt & 0x20 != 0) ||
+        (self.typ == OperationType::ReverseBytes && self.shamt == 56)
+    }
+
+    /// Whether this is RV32's encoding of REV8, which RV64 encodes
+    /// differently.
+    pub fn is_rv32_only(&self) -> bool {
+        self.typ == OperationType::ReverseBytes && self.shamt == 24
+    }
+
+    /// Zbkb has the logical, rotate and byte-reversal instructions too.
+    pub fn extensions(&self) -> &'static [Extension] {
+        match self.typ {
+            OperationType::AndNot | OperationType::OrNot | OperationType::XorNot |
+            OperationType::RotateLeft | OperationType::RotateRight |
+            OperationType::RotateRightImmediate | OperationType::ReverseBytes => {
+                &[Extension::Zbb, Extension::Zbkb]
+            }
+            _ => &[Extension::Zbb],
+        }
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.src1, self.src2))
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let src1 = cpu.get_register(self.src1);
+        let src2 = cpu.get_register(self.src2);
+        // Whether this works on 32 bits: it's a *W instruction, or XLEN is 32.
+        let narrow = self.word || cpu.xlen() == 32;
+        let rotate = |amount: u64, left: bool| {
+            if narrow {
+                let amount = (amount & 0x1F) as u32;
+                let word = src1 as u32;
+                let rotated = if left {
+                    word.rotate_left(amount)
+                } else {
+                    word.rotate_right(amount)
+                };
+                rotated as i32 as i64 as u64
+            } else {
+                let amount = (amount & 0x3F) as u32;
+                if left { src1.rotate_left(amount) } else { src1.rotate_right(amount) }
+            }
+        };
+
+        let result = match self.typ {
+            OperationType::AndNot => src1 & !src2,
+            OperationType::OrNot => src1 | !src2,
+            OperationType::XorNot => !(src1 ^ src2),
+            OperationType::CountLeadingZeros if narrow => (src1 as u32).leading_zeros() as u64,
+            OperationType::CountLeadingZeros => src1.leading_zeros() as u64,
+            OperationType::CountTrailingZeros if narrow => (src1 as u32).trailing_zeros() as u64,
+            OperationType::CountTrailingZeros => src1.trailing_zeros() as u64,
+            OperationType::CountPopulation if narrow => (src1 as u32).count_ones() as u64,
+            OperationType::CountPopulation => src1.count_ones() as u64,
+            OperationType::Max => cmp::max(cpu.signed(src1), cpu.signed(src2)) as u64,
+            OperationType::MaxUnsigned => cmp::max(src1, src2),
+            OperationType::Min => cmp::min(cpu.signed(src1), cpu.signed(src2)) as u64,
+            OperationType::MinUnsigned => cmp::min(src1, src2),
+            OperationType::SignExtendByte => src1 as i8 as i64 as u64,
+            OperationType::SignExtendHalfWord => src1 as i16 as i64 as u64,
+            OperationType::RotateLeft => rotate(src2, true),
+            OperationType::RotateRight => rotate(src2, false),
+            OperationType::RotateRightImmediate => rotate(self.shamt as u64, false),
+            OperationType::OrCombineBytes => {
+                (0..8).fold(0, |result, byte| {
+                    if src1 & (0xFF << (8 * byte)) != 0 {
+                        result | (0xFF << (8 * byte))
+                    } else {
+                        result
+                    }
+                })
+            }
+            OperationType::ReverseBytes if narrow => (src1 as u32).swap_bytes() as u64,
+            OperationType::ReverseBytes => src1.swap_bytes(),
+        };
+
+        cpu.set_register(self.dest, result);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (funct7, funct3, rs2) = match self.typ {
+            OperationType::AndNot => (0x20, 0b111, self.src2),
+            OperationType::OrNot => (0x20, 0b110, self.src2),
+            OperationType::XorNot => (0x20, 0b100, self.src2),
+            OperationType::Min => (0x05, 0b100, self.src2),
+            OperationType::MinUnsigned => (0x05, 0b101, self.src2),
+            OperationType::Max => (0x05, 0b110, self.src2),
+            OperationType::MaxUnsigned => (0x05, 0b111, self.src2),
+            OperationType::RotateLeft => (0x30, 0b001, self.src2),
+            OperationType::RotateRight => (0x30, 0b101, self.src2),
+            OperationType::CountLeadingZeros => (0x30, 0b001, 0b00000),
+            OperationType::CountTrailingZeros => (0x30, 0b001, 0b00001),
+            OperationType::CountPopulation => (0x30, 0b001, 0b00010),
+            OperationType::SignExtendByte => (0x30, 0b001, 0b00100),
+            OperationType::SignExtendHalfWord => (0x30, 0b001, 0b00101),
+            OperationType::RotateRightImmediate => {
+                (0x30 | (self.shamt >> 5), 0b101, self.shamt & 0x1F)
+            }
+            OperationType::OrCombineBytes => (0x14, 0b101, 0b00111),
+            OperationType::ReverseBytes => (0x34 | (self.shamt >> 5), 0b101, self.shamt & 0x1F),
+        };
+        let register_src2 = matches!(self.typ,
+                                     OperationType::AndNot | OperationType::OrNot |
+                                     OperationType::XorNot | OperationType::Min |
+                                     OperationType::MinUnsigned | OperationType::Max |
+                                     OperationType::MaxUnsigned | OperationType::RotateLeft |
+                                     OperationType::RotateRight);
+        let opcode = match (register_src2, self.word) {
+            (true, false) => 0x33,
+            (true, true) => 0x3B,
+            (false, false) => 0x13,
+            (false, true) => 0x1B,
+        };
+
+        encoding::R {
+            opcode,
+            funct7,
+            funct3,
+            rd: self.dest,
+            rs1: self.src1,
+            rs2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::Isa;
+
+
+    #[test]
+    fn test_logical() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // andn, orn, xnor x1, x2, x3
+        test_op!(cpu, 0x403170b3, 0xf0f0_0000, 0xffff_0000, 0x0f0f_0f0f);
+        test_op!(cpu, 0x403160b3, 0xffff_fff0, 0xffff_0000, 0x0f0f_000f);
+        test_op!(cpu, 0x403140b3, 0x0f0f_f0f0, 0xffff_0000, 0x0f0f_0f0f);
+    }
+
+    #[test]
+    fn test_count() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // clz, ctz, cpop x1, x2
+        test_op!(cpu, 0x60011093, 32, 0, 0);
+        test_op!(cpu, 0x60011093, 3, 0x1000_0000, 0);
+        test_op!(cpu, 0x60111093, 32, 0, 0);
+        test_op!(cpu, 0x60111093, 4, 0x10, 0);
+        test_op!(cpu, 0x60211093, 5, 0x8000_0f00, 0);
+
+        cpu.set_isa(Isa::parse("rv64i_zbb").unwrap());
+        test_op!(cpu, 0x60011093, 35, 0x1000_0000, 0);
+        // clzw, cpopw x1, x2
+        test_op!(cpu, 0x6001109b, 3, 0xffff_ffff_1000_0000, 0);
+        test_op!(cpu, 0x6021109b, 1, 0xffff_ffff_1000_0000, 0);
+    }
+
+    #[test]
+    fn test_min_max() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // min, minu, max, maxu x1, x2, x3
+        test_op!(cpu, 0x0a3140b3, 0xffff_ffff, 0xffff_ffff, 1);
+        test_op!(cpu, 0x0a3150b3, 1, 0xffff_ffff, 1);
+        test_op!(cpu, 0x0a3160b3, 1, 0xffff_ffff, 1);
+        test_op!(cpu, 0x0a3170b3, 0xffff_ffff, 0xffff_ffff, 1);
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // sext.b, sext.h x1, x2 (zext.h is Zbkb's pack x1, x2, x0)
+        test_op!(cpu, 0x60411093, 0xffff_ff80, 0x1234_5680, 0);
+        test_op!(cpu, 0x60511093, 0xffff_8680, 0x1234_8680, 0);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // rol, ror x1, x2, x3; rori x1, x2, 4
+        test_op!(cpu, 0x603110b3, 0x2345_6781, 0x1234_5678, 36);
+        test_op!(cpu, 0x603150b3, 0x8123_4567, 0x1234_5678, 4);
+        test_op!(cpu, 0x60415093, 0x8123_4567, 0x1234_5678, 0);
+
+        cpu.set_isa(Isa::parse("rv64i_zbb").unwrap());
+        test_op!(cpu, 0x603150b3, 0x8000_0000_0123_4567, 0x1234_5678, 4);
+        // rorw x1, x2, x3; roriw x1, x2, 4
+        test_op!(cpu, 0x603150bb, 0xffff_ffff_8123_4567, 0x1234_5678, 4);
+        test_op!(cpu, 0x6041509b, 0xffff_ffff_8123_4567, 0x1234_5678, 0);
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // orc.b, rev8 x1, x2
+        test_op!(cpu, 0x28715093, 0xff00_ffff, 0x1200_0180, 0);
+        test_op!(cpu, 0x69815093, 0x7856_3412, 0x1234_5678, 0);
+
+        cpu.set_isa(Isa::parse("rv64i_zbb").unwrap());
+        test_op!(cpu, 0x6b815093, 0x7856_3412_0000_0000, 0x1234_5678, 0);
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zbc: carry-less multiplication.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use isa::Extension;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    CarryLessMultiply,
+    CarryLessMultiplyHigh,
+    CarryLessMultiplyReversed,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        if decoded.opcode != 0x33 || decoded.funct7 != 0x05 {
+            return None;
+        }
+
+        let typ = match decoded.funct3 {
+            0b001 => OperationType::CarryLessMultiply,
+            0b010 => OperationType::CarryLessMultiplyReversed,
+            0b011 => OperationType::CarryLessMultiplyHigh,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: decoded.rs2,
+        })
+    }
+
+    /// Zbkc has CLMUL and CLMULH, but not CLMULR.
+    pub fn extensions(&self) -> &'static [Extension] {
+        match self.typ {
+            OperationType::CarryLessMultiplyReversed => &[Extension::Zbc],
+            _ => &[Extension::Zbc, Extension::Zbkc],
+        }
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.src1, self.src2))
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let src1 = cpu.get_register(self.src1);
+        let src2 = cpu.get_register(self.src2);
+
+        // The whole 2*XLEN-bit product; each instruction takes part of it.
+        let product = (0..64)
+            .filter(|&bit| src2 & (1 << bit) != 0)
+            .fold(0, |product, bit| product ^ ((src1 as u128) << bit));
+        let result = match self.typ {
+            OperationType::CarryLessMultiply => product as u64,
+            OperationType::CarryLessMultiplyHigh => (product >> cpu.xlen()) as u64,
+            OperationType::CarryLessMultiplyReversed => (product >> (cpu.xlen() - 1)) as u64,
+        };
+
+        cpu.set_register(self.dest, result);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        encoding::R {
+            opcode: 0x33,
+            funct7: 0x05,
+            funct3: match self.typ {
+                OperationType::CarryLessMultiply => 0b001,
+                OperationType::CarryLessMultiplyReversed => 0b010,
+                OperationType::CarryLessMultiplyHigh => 0b011,
+            },
+            rd: self.dest,
+            rs1: self.src1,
+            rs2: self.src2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::{Extension, Isa};
+
+
+    #[test]
+    fn test_clmul() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // clmul, clmulh, clmulr x1, x2, x3
+        test_op!(cpu, 0x0a3110b3, 0b1111, 0b101, 0b11);
+        test_op!(cpu, 0x0a3110b3, 0x5555_5555, 0xffff_ffff, 0xffff_ffff);
+        test_op!(cpu, 0x0a3130b3, 0x5555_5555, 0xffff_ffff, 0xffff_ffff);
+        test_op!(cpu, 0x0a3120b3, 0xaaaa_aaaa, 0xffff_ffff, 0xffff_ffff);
+        test_op!(cpu, 0x0a3130b3, 1, 0x8000_0000, 2);
+        assert_eq!(Op::parse(0x0a3120b3).unwrap().extensions(), &[Extension::Zbc]);
+
+        cpu.set_isa(Isa::parse("rv64i_zbc").unwrap());
+        test_op!(cpu, 0x0a3130b3, 0, 0x8000_0000, 2);
+        test_op!(cpu, 0x0a3110b3, 0x1_0000_0000, 0x8000_0000, 2);
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zbkb: bit manipulation for cryptography. Zbkb also has Zbb's logical,
+//! rotate and REV8 instructions, which live in `zbb`; these are the ones Zbb
+//! doesn't have, apart from ZEXT.H, which is PACK with x0 as src2.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use isa::Extension;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    /// The low halves of src1 and src2, low half first.
+    Pack,
+    /// The low bytes of src1 and src2.
+    PackHalf,
+    /// RV64's PACKW: the low halfwords, sign-extended from 32 bits.
+    PackWord,
+    ReverseBitsInBytes,
+    Zip,
+    Unzip,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        let typ = match (decoded.opcode, decoded.funct7, decoded.funct3) {
+            (0x33, 0x04, 0b100) => OperationType::Pack,
+            (0x33, 0x04, 0b111) => OperationType::PackHalf,
+            (0x3B, 0x04, 0b100) => OperationType::PackWord,
+            (0x13, 0x34, 0b101) if decoded.rs2 == 0b00111 => OperationType::ReverseBitsInBytes,
+            (0x13, 0x04, 0b001) if decoded.rs2 == 0b01111 => OperationType::Zip,
+            (0x13, 0x04, 0b101) if decoded.rs2 == 0b01111 => OperationType::Unzip,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: if decoded.opcode == 0x13 { 0 } else { decoded.rs2 },
+        })
+    }
+
+    /// PACK and PACKW with x0 as src2 are Zbb's ZEXT.H, except that RV64's
+    /// PACK takes a whole word.
+    pub fn extensions(&self, xlen: u32) -> &'static [Extension] {
+        let zero_extend_half = match self.typ {
+            OperationType::Pack => xlen == 32,
+            OperationType::PackWord => true,
+            _ => false,
+        };
+        if zero_extend_half && self.src2 == 0 {
+            &[Extension::Zbb, Extension::Zbkb]
+        } else {
+            &[Extension::Zbkb]
+        }
+    }
+
+    pub fn is_rv64_only(&self) -> bool {
+        self.typ == OperationType::PackWord
+    }
+
+    pub fn is_rv32_only(&self) -> bool {
+        matches!(self.typ, OperationType::Zip | OperationType::Unzip)
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.src1, self.src2))
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let src1 = cpu.get_register(self.src1);
+        let src2 = cpu.get_register(self.src2);
+        let half = cpu.xlen() / 2;
+
+        let result = match self.typ {
+            OperationType::Pack => {
+                let mask = (1 << half) - 1;
+                (src1 & mask) | ((src2 & mask) << half)
+            }
+            OperationType::PackHalf => (src1 & 0xFF) | ((src2 & 0xFF) << 8),
+            OperationType::PackWord => {
+                ((src1 & 0xFFFF) | ((src2 & 0xFFFF) << 16)) as i32 as i64 as u64
+            }
+            OperationType::ReverseBitsInBytes => {
+                (0..8).fold(0, |result, byte| {
+                    let reversed = ((src1 >> (8 * byte)) as u8).reverse_bits();
+                    result | ((reversed as u64) << (8 * byte))
+                })
+            }
+            // Bit i of each half goes to bit 2i (low half) or 2i + 1 (high
+            // half), and back again.
+            OperationType::Zip => {
+                (0..16).fold(0, |result, bit| {
+                    result | (((src1 >> bit) & 1) << (2 * bit)) |
+                    (((src1 >> (bit + 16)) & 1) << (2 * bit + 1))
+                })
+            }
+            OperationType::Unzip => {
+                (0..16).fold(0, |result, bit| {
+                    result | (((src1 >> (2 * bit)) & 1) << bit) |
+                    (((src1 >> (2 * bit + 1)) & 1) << (bit + 16))
+                })
+            }
+        };
+
+        cpu.set_register(self.dest, result);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (opcode, funct7, funct3, rs2) = match self.typ {
+            OperationType::Pack => (0x33, 0x04, 0b100, self.src2),
+            OperationType::PackHalf => (0x33, 0x04, 0b111, self.src2),
+            OperationType::PackWord => (0x3B, 0x04, 0b100, self.src2),
+            OperationType::ReverseBitsInBytes => (0x13, 0x34, 0b101, 0b00111),
+            OperationType::Zip => (0x13, 0x04, 0b001, 0b01111),
+            OperationType::Unzip => (0x13, 0x04, 0b101, 0b01111),
+        };
+
+        encoding::R {
+            opcode,
+            funct7,
+            funct3,
+            rd: self.dest,
+            rs1: self.src1,
+            rs2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::{Extension, Isa};
+
+
+    #[test]
+    fn test_pack() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // pack, packh x1, x2, x3; zext.h x1, x2
+        test_op!(cpu, 0x083140b3, 0x5678_1234, 0xabcd_1234, 0xef01_5678);
+        test_op!(cpu, 0x083170b3, 0x7834, 0xabcd_1234, 0xef01_5678);
+        test_op!(cpu, 0x080140b3, 0x1234, 0xabcd_1234, 0);
+        assert_eq!(Op::parse(0x080140b3).unwrap().extensions(32),
+                   &[Extension::Zbb, Extension::Zbkb]);
+        assert_eq!(Op::parse(0x080140b3).unwrap().extensions(64), &[Extension::Zbkb]);
+
+        cpu.set_isa(Isa::parse("rv64i_zbkb").unwrap());
+        test_op!(cpu, 0x083140b3, 0xef01_5678_abcd_1234, 0xabcd_1234, 0xef01_5678);
+        // packw x1, x2, x3
+        test_op!(cpu, 0x083140bb, 0xffff_ffff_8000_1234, 0xabcd_1234, 0xef01_8000);
+    }
+
+    #[test]
+    fn test_permute() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // brev8, zip, unzip x1, x2
+        test_op!(cpu, 0x68715093, 0x482c_6a1e, 0x1234_5678, 0);
+        test_op!(cpu, 0x08f11093, 0x131c_1f60, 0x1234_5678, 0);
+        test_op!(cpu, 0x08f15093, 0x1234_5678, 0x131c_1f60, 0);
+        assert!(Op::parse(0x08f11093).unwrap().is_rv32_only());
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zbkx: crossbar permutations, which look up each nibble or byte of src2 in
+//! src1 as a table, for constant-time S-boxes.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    PermuteNibbles,
+    PermuteBytes,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        let typ = match (decoded.opcode, decoded.funct7, decoded.funct3) {
+            (0x33, 0x14, 0b010) => OperationType::PermuteNibbles,
+            (0x33, 0x14, 0b100) => OperationType::PermuteBytes,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: decoded.rs2,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.src1, self.src2))
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let src1 = cpu.get_register(self.src1);
+        let src2 = cpu.get_register(self.src2);
+        let width = match self.typ {
+            OperationType::PermuteNibbles => 4,
+            OperationType::PermuteBytes => 8,
+        };
+        let mask = (1 << width) - 1;
+
+        // Indices past the end of the table give 0.
+        let result = (0..cpu.xlen() as u64 / width).fold(0, |result, i| {
+            let index = (src2 >> (i * width)) & mask;
+            if index < cpu.xlen() as u64 / width {
+                result | (((src1 >> (index * width)) & mask) << (i * width))
+            } else {
+                result
+            }
+        });
+
+        cpu.set_register(self.dest, result);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        encoding::R {
+            opcode: 0x33,
+            funct7: 0x14,
+            funct3: match self.typ {
+                OperationType::PermuteNibbles => 0b010,
+                OperationType::PermuteBytes => 0b100,
+            },
+            rd: self.dest,
+            rs1: self.src1,
+            rs2: self.src2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::Isa;
+
+
+    #[test]
+    fn test_xperm() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // xperm4, xperm8 x1, x2, x3
+        test_op!(cpu, 0x283120b3, 0x0123_4567, 0x7654_3210, 0x0123_4567);
+        test_op!(cpu, 0x283120b3, 0x0000_0007, 0x7654_3210, 0x8888_8887);
+        test_op!(cpu, 0x283140b3, 0x1122_3344, 0x4433_2211, 0x0001_0203);
+        test_op!(cpu, 0x283140b3, 0x0044_3322, 0x4433_2211, 0x0403_0201);
+
+        cpu.set_isa(Isa::parse("rv64i_zbkx").unwrap());
+        test_op!(cpu,
+                 0x283140b3,
+                 0x1122_3344_5566_7788,
+                 0x8877_6655_4433_2211,
+                 0x0001_0203_0405_0607);
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zbs: single-bit instructions.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    // The register holding the bit index, or the index itself for the
+    // immediate forms
+    src2: u8,
+    immediate: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OperationType {
+    Clear,
+    Extract,
+    Invert,
+    Set,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        let immediate = match decoded.opcode {
+            0x33 => false,
+            0x13 => true,
+            _ => return None,
+        };
+        // The immediate forms' bit index takes the low bit of funct7 on RV64.
+        let funct7 = if immediate { decoded.funct7 & !1 } else { decoded.funct7 };
+
+        let typ = match (funct7, decoded.funct3) {
+            (0x24, 0b001) => OperationType::Clear,
+            (0x24, 0b101) => OperationType::Extract,
+            (0x34, 0b001) => OperationType::Invert,
+            (0x14, 0b001) => OperationType::Set,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: if immediate { ((instruction >> 20) & 0x3F) as u8 } else { decoded.rs2 },
+            immediate,
+        })
+    }
+
+    /// Whether this is an immediate form with a bit index of 32 or more,
+    /// which only exists on RV64.
+    pub fn is_rv64_only(&self) -> bool {
+        self.immediate && self.src2 & 0x20 != 0
+    }
+
+    pub fn max_register(&self) -> u8 {
+        if self.immediate {
+            cmp::max(self.dest, self.src1)
+        } else {
+            cmp::max(self.dest, cmp::max(self.src1, self.src2))
+        }
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let src1 = cpu.get_register(self.src1);
+        let index = if self.immediate {
+            self.src2 as u64
+        } else {
+            cpu.get_register(self.src2) & (cpu.xlen() as u64 - 1)
+        };
+
+        let result = match self.typ {
+            OperationType::Clear => src1 & !(1 << index),
+            OperationType::Extract => (src1 >> index) & 1,
+            OperationType::Invert => src1 ^ (1 << index),
+            OperationType::Set => src1 | (1 << index),
+        };
+
+        cpu.set_register(self.dest, result);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (funct7, funct3) = match self.typ {
+            OperationType::Clear => (0x24, 0b001),
+            OperationType::Extract => (0x24, 0b101),
+            OperationType::Invert => (0x34, 0b001),
+            OperationType::Set => (0x14, 0b001),
+        };
+
+        encoding::R {
+            opcode: if self.immediate { 0x13 } else { 0x33 },
+            funct7: if self.immediate { funct7 | (self.src2 >> 5) } else { funct7 },
+            funct3,
+            rd: self.dest,
+            rs1: self.src1,
+            rs2: self.src2 & 0x1F,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::Isa;
+
+
+    #[test]
+    fn test_register() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // bclr, bext, binv, bset x1, x2, x3
+        test_op!(cpu, 0x483110b3, 0xffff_fffe, 0xffff_ffff, 32);
+        test_op!(cpu, 0x483150b3, 1, 0x8000_0000, 31);
+        test_op!(cpu, 0x683110b3, 0x8000_0001, 1, 31);
+        test_op!(cpu, 0x283110b3, 0x11, 1, 4);
+    }
+
+    #[test]
+    fn test_immediate() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // bclri, bexti, binvi, bseti x1, x2, 4
+        test_op!(cpu, 0x48411093, 0xffff_ffef, 0xffff_ffff, 0);
+        test_op!(cpu, 0x48415093, 1, 0x10, 0);
+        test_op!(cpu, 0x68411093, 0, 0x10, 0);
+        test_op!(cpu, 0x28411093, 0x10, 0, 0);
+
+        // bseti x1, x2, 40
+        cpu.set_isa(Isa::parse("rv64i_zbs").unwrap());
+        test_op!(cpu, 0x2a811093, 0x100_0000_0000, 0, 0);
+        assert!(Op::parse(0x2a811093).unwrap().is_rv64_only());
+    }
+}
@@ -0,0 +1,294 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zcmp: 16-bit function prologues and epilogues.
+//!
+//! CM.PUSH saves ra and some of s0-s11 below the stack pointer and makes room
+//! for them and a bit more; CM.POP and friends undo it, optionally zeroing
+//! a0 and returning too. CM.MVSA01 and CM.MVA01S move the first two
+//! arguments to and from saved registers.
+
+use instruction::Instruction;
+use cpu::CPU;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PushPop {
+    typ: PushPopType,
+    // The register list: ra, and then s0 up to s(rlist - 5), or all of
+    // s0-s11 for 15
+    rlist: u8,
+    // Extra stack space, in units of 16 bytes
+    spimm: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PushPopType {
+    Push,
+    Pop,
+    /// Pop, set a0 to zero and return
+    PopReturnZero,
+    /// Pop and return
+    PopReturn,
+}
+
+// The registers CM.PUSH saves, from the last one it stores (the lowest
+// address) up.
+const SAVED: [u8; 13] = [1, 8, 9, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27];
+
+impl PushPop {
+    pub fn parse(instruction: u16) -> Option<PushPop> {
+        let raw = instruction as u32;
+        if raw & 0xE003 != 0xA002 {
+            return None;
+        }
+
+        let typ = match (raw >> 8) & 0x1F {
+            0b11000 => PushPopType::Push,
+            0b11010 => PushPopType::Pop,
+            0b11100 => PushPopType::PopReturnZero,
+            0b11110 => PushPopType::PopReturn,
+            _ => return None,
+        };
+        let rlist = ((raw >> 4) & 0xF) as u8;
+        // There's always ra to save.
+        if rlist < 4 {
+            return None;
+        }
+
+        Some(PushPop {
+            typ,
+            rlist,
+            spimm: ((raw >> 2) & 0b11) as u8,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        *self.registers().last().unwrap()
+    }
+
+    /// Whether this returns, which ends a basic block.
+    pub fn returns(&self) -> bool {
+        self.typ == PushPopType::PopReturnZero || self.typ == PushPopType::PopReturn
+    }
+
+    fn registers(&self) -> &'static [u8] {
+        match self.rlist {
+            15 => &SAVED,
+            rlist => &SAVED[..rlist as usize - 3],
+        }
+    }
+
+    // How far the stack pointer moves: room for the registers, rounded up
+    // to keep it 16-byte aligned, and the extra space.
+    fn stack_adjustment(&self, xlen: u32) -> u32 {
+        let bytes = self.registers().len() as u32 * xlen / 8;
+        ((bytes + 15) & !15) + 16 * self.spimm as u32
+    }
+}
+
+impl Instruction for PushPop {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let size = cpu.xlen() / 8;
+        let adjustment = self.stack_adjustment(cpu.xlen()) as i32;
+        let sp = cpu.get_register(2);
+        let registers = self.registers();
+        // The registers are stored in order, ending just below the stack
+        // pointer before the push.
+        let offset = |i: usize| -(size as i32) * (registers.len() - i) as i32;
+
+        if self.typ == PushPopType::Push {
+            for (i, &reg) in registers.iter().enumerate().rev() {
+                let addr = cpu.address(sp, offset(i), Exception::StoreAccessFault)?;
+                let value = cpu.get_register(reg);
+                if size == 8 {
+                    cpu.store_u64(addr, value)?;
+                } else {
+                    cpu.store_u32(addr, value as u32)?;
+                }
+            }
+            cpu.set_register(2, sp.wrapping_sub(adjustment as u64));
+            return Ok(());
+        }
+
+        // Load everything before writing any registers, so that a fault
+        // leaves them all as they were.
+        let mut values = [0; 13];
+        for (i, value) in values[..registers.len()].iter_mut().enumerate().rev() {
+            let addr = cpu.address(sp, adjustment + offset(i), Exception::LoadAccessFault)?;
+            *value = if size == 8 {
+                cpu.load_u64(addr)?
+            } else {
+                cpu.load_u32(addr)? as u64
+            };
+        }
+        for (&reg, &value) in registers.iter().zip(values.iter()) {
+            cpu.set_register(reg, value);
+        }
+        cpu.set_register(2, sp.wrapping_add(adjustment as u64));
+
+        if self.typ == PushPopType::PopReturnZero {
+            cpu.set_register(10, 0);
+        }
+        if self.returns() {
+            let ra = cpu.get_register(1);
+            let target = cpu.address(ra, 0, Exception::InstructionAccessFault)? & !1;
+            cpu.jump(target);
+        }
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let funct = match self.typ {
+            PushPopType::Push => 0b11000,
+            PushPopType::Pop => 0b11010,
+            PushPopType::PopReturnZero => 0b11100,
+            PushPopType::PopReturn => 0b11110,
+        };
+        0xA002 | funct << 8 | (self.rlist as u32) << 4 | (self.spimm as u32) << 2
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MovePair {
+    // CM.MVA01S rather than CM.MVSA01
+    to_arguments: bool,
+    saved1: u8,
+    saved2: u8,
+}
+
+impl MovePair {
+    pub fn parse(instruction: u16) -> Option<MovePair> {
+        let raw = instruction as u32;
+        if raw & 0xFC03 != 0xAC02 {
+            return None;
+        }
+
+        let to_arguments = match (raw >> 5) & 0b11 {
+            0b01 => false,
+            0b11 => true,
+            _ => return None,
+        };
+        let saved1 = saved_register((raw >> 7) & 0b111);
+        let saved2 = saved_register((raw >> 2) & 0b111);
+        // Both arguments can't go to the same register.
+        if !to_arguments && saved1 == saved2 {
+            return None;
+        }
+
+        Some(MovePair {
+            to_arguments,
+            saved1,
+            saved2,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        self.saved1.max(self.saved2)
+    }
+}
+
+impl Instruction for MovePair {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        if self.to_arguments {
+            let (value1, value2) = (cpu.get_register(self.saved1), cpu.get_register(self.saved2));
+            cpu.set_register(10, value1);
+            cpu.set_register(11, value2);
+        } else {
+            let (a0, a1) = (cpu.get_register(10), cpu.get_register(11));
+            cpu.set_register(self.saved1, a0);
+            cpu.set_register(self.saved2, a1);
+        }
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let funct = if self.to_arguments { 0b11 } else { 0b01 };
+        0xAC02 | saved_field(self.saved1) << 7 | funct << 5 | saved_field(self.saved2) << 2
+    }
+}
+
+// The 3-bit saved register fields name s0-s7.
+fn saved_register(field: u32) -> u8 {
+    match field {
+        0 | 1 => 8 + field as u8,
+        _ => 16 + field as u8,
+    }
+}
+
+fn saved_field(reg: u8) -> u32 {
+    match reg {
+        8 | 9 => reg as u32 - 8,
+        _ => reg as u32 - 16,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::Isa;
+    use instruction::Instruction;
+
+    #[test]
+    fn test_push_pop() {
+        for &xlen in &[32, 64] {
+            let mut cpu = CPU::new(RAM::new(1024));
+            cpu.set_isa(Isa::default().with_xlen(xlen));
+            cpu.set_register(2, 0x200);
+            for &reg in &[1, 8, 9, 18] {
+                cpu.set_register(reg, reg as u64 * 0x1111);
+            }
+
+            // cm.push {ra, s0-s2}, -16 on RV32 or -32 on RV64
+            let push = PushPop::parse(0xb872).expect("couldn't parse instruction");
+            assert_eq!(push.to_raw(), 0xb872);
+            let adjustment = if xlen == 32 { 16 } else { 32 };
+            assert_eq!(push.stack_adjustment(xlen), adjustment);
+            push.execute(&mut cpu).unwrap();
+            assert_eq!(cpu.get_register(2), 0x200 - adjustment as u64);
+            let size = xlen / 8;
+            let stored = (1..5).map(|i| cpu.bus.read(0x200 - i * size, 4).unwrap());
+            assert_eq!(stored.collect::<Vec<_>>(), [0x13332, 0x9999, 0x8888, 0x1111]);
+
+            // cm.popretz {ra, s0-s2}, 16 or 32
+            for &reg in &[1, 8, 9, 10, 18] {
+                cpu.set_register(reg, 0xFFFF);
+            }
+            let pop = PushPop::parse(0xbc72).expect("couldn't parse instruction");
+            pop.execute(&mut cpu).unwrap();
+            assert_eq!(cpu.get_register(2), 0x200);
+            let regs = [1, 8, 9, 10, 18].iter().map(|&reg| cpu.get_register(reg));
+            assert_eq!(regs.collect::<Vec<_>>(), [0x1111, 0x8888, 0x9999, 0, 0x13332]);
+        }
+
+        // Reserved register lists, and the other quadrant 2 encodings
+        assert!(PushPop::parse(0xb832).is_none());
+        assert!(PushPop::parse(0xb972).is_none());
+        assert_eq!(PushPop::parse(0xb8f2).unwrap().registers().len(), 13);
+    }
+
+    #[test]
+    fn test_move_pair() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.set_register(10, 1);
+        cpu.set_register(11, 2);
+
+        // cm.mvsa01 s7, s0 then cm.mva01s s0, s0
+        let mvsa01 = MovePair::parse(0xafa2).expect("couldn't parse instruction");
+        assert_eq!(mvsa01.to_raw(), 0xafa2);
+        mvsa01.execute(&mut cpu).unwrap();
+        assert_eq!((cpu.get_register(23), cpu.get_register(8)), (1, 2));
+        let mva01s = MovePair::parse(0xac62).expect("couldn't parse instruction");
+        mva01s.execute(&mut cpu).unwrap();
+        assert_eq!((cpu.get_register(10), cpu.get_register(11)), (2, 2));
+
+        // cm.mvsa01 s0, s0
+        assert!(MovePair::parse(0xac22).is_none());
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zcmt: 16-bit jumps through a table of addresses, which the `jvt` CSR
+//! points to. Entries 0-31 are for CM.JT, which just jumps, and the rest are
+//! for CM.JALT, which links to ra like a call.
+
+use instruction::Instruction;
+use cpu::CPU;
+use trap::Exception;
+
+// Table entries from here on link.
+const FIRST_LINKING: u8 = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TableJump {
+    index: u8,
+}
+
+impl TableJump {
+    pub fn parse(instruction: u16) -> Option<TableJump> {
+        let raw = instruction as u32;
+        if raw & 0xFC03 != 0xA002 {
+            return None;
+        }
+
+        Some(TableJump { index: (raw >> 2) as u8 })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        if self.index >= FIRST_LINKING { 1 } else { 0 }
+    }
+}
+
+impl Instruction for TableJump {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        // Reading the table counts as fetching instructions.
+        let size = cpu.xlen() / 8;
+        let addr = cpu.address(cpu.jvt(),
+                     self.index as i32 * size as i32,
+                     Exception::InstructionAccessFault)?;
+        let fetch = |cpu: &mut CPU, addr: u32| {
+            cpu.bus.read(addr, 4).ok_or(Exception::InstructionAccessFault(addr as u64))
+        };
+        let mut entry = fetch(cpu, addr)? as u64;
+        if size == 8 {
+            let high = addr.checked_add(4).ok_or(Exception::InstructionAccessFault(addr as u64))?;
+            entry |= (fetch(cpu, high)? as u64) << 32;
+        }
+        let target = cpu.address(entry, 0, Exception::InstructionAccessFault)? & !1;
+
+        if self.index >= FIRST_LINKING {
+            let jump_back_target = cpu.pc as u64 + 2;
+            cpu.set_register(1, jump_back_target);
+        }
+        cpu.jump(target);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        0xA002 | (self.index as u32) << 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+    use trap::StopReason;
+
+    #[test]
+    fn test_table_jump() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.set_csr(0x017, 0x200).unwrap();
+        cpu.bus.write(0x208, 4, 0x301).unwrap();
+        cpu.bus.write(0x280, 4, 0x302).unwrap();
+
+        // cm.jt 2 then cm.jalt 32
+        cpu.bus.write(0x100, 2, 0xa00a).unwrap();
+        cpu.bus.write(0x300, 2, 0xa082).unwrap();
+        assert_eq!(TableJump::parse(0xa082).unwrap().to_raw(), 0xa082);
+        cpu.set_register(1, 0);
+        cpu.pc = 0x100;
+        assert_eq!(cpu.step(), StopReason::InstructionLimit);
+        assert_eq!((cpu.pc, cpu.get_register(1)), (0x300, 0));
+        assert_eq!(cpu.step(), StopReason::InstructionLimit);
+        assert_eq!((cpu.pc, cpu.get_register(1)), (0x302, 0x302));
+
+        // Only jump table mode exists.
+        cpu.set_csr(0x017, 0x1FF).unwrap();
+        assert_eq!(cpu.get_csr(0x017), Ok(0x1C0));
+    }
+}
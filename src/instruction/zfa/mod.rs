@@ -0,0 +1,209 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zfa: additional floating point instructions, in their half-precision
+//! forms. The rest need F or D.
+
+use std::cmp::Ordering;
+
+use instruction::{encoding, Instruction};
+use instruction::zfh;
+use cpu::CPU;
+use softfloat::{self, HALF};
+use trap::Exception;
+
+// What FLI.H loads for each value of rs1. 2^-16 and 2^-15 are subnormal in
+// half precision, and 2^16 is too big so it's infinity.
+const IMMEDIATES: [u16; 32] = [0xBC00, 0x0400, 0x0100, 0x0200, 0x1C00, 0x2000, 0x2C00, 0x3000,
+                               0x3400, 0x3500, 0x3600, 0x3700, 0x3800, 0x3900, 0x3A00, 0x3B00,
+                               0x3C00, 0x3D00, 0x3E00, 0x3F00, 0x4000, 0x4100, 0x4200, 0x4400,
+                               0x4800, 0x4C00, 0x5800, 0x5C00, 0x7800, 0x7C00, 0x7C00, 0x7E00];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+    rm: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    LoadImmediate,
+    /// FMINM.H and FMAXM.H, which return NaN if either operand is one
+    Minimum,
+    Maximum,
+    /// FROUND.H, and FROUNDNX.H which also raises the inexact flag
+    RoundToIntegral,
+    RoundToIntegralExact,
+    /// FLEQ.H and FLTQ.H, which are quiet about quiet NaNs
+    QuietLessOrEqual,
+    QuietLessThan,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        if decoded.opcode != 0x53 {
+            return None;
+        }
+        let rm = decoded.funct3;
+
+        let typ = match (decoded.funct7, decoded.funct3, decoded.rs2) {
+            (0b1111010, 0b000, 0b00001) => OperationType::LoadImmediate,
+            (0b0010110, 0b010, _) => OperationType::Minimum,
+            (0b0010110, 0b011, _) => OperationType::Maximum,
+            (0b0100010, _, 0b00100) if zfh::is_valid_rm(rm) => OperationType::RoundToIntegral,
+            (0b0100010, _, 0b00101) if zfh::is_valid_rm(rm) => {
+                OperationType::RoundToIntegralExact
+            }
+            (0b1010010, 0b100, _) => OperationType::QuietLessOrEqual,
+            (0b1010010, 0b101, _) => OperationType::QuietLessThan,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: decoded.rs2,
+            rm,
+        })
+    }
+
+    /// The integer register the comparisons write.
+    pub fn max_register(&self) -> u8 {
+        match self.typ {
+            OperationType::QuietLessOrEqual | OperationType::QuietLessThan => self.dest,
+            _ => 0,
+        }
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let a = cpu.float().half(self.src1) as u64;
+        let b = cpu.float().half(self.src2) as u64;
+
+        let (result, flags) = match self.typ {
+            OperationType::LoadImmediate => (IMMEDIATES[self.src1 as usize] as u64, 0),
+            OperationType::Minimum => softfloat::min_max(HALF, a, b, false, true),
+            OperationType::Maximum => softfloat::min_max(HALF, a, b, true, true),
+            OperationType::RoundToIntegral | OperationType::RoundToIntegralExact => {
+                let rounding = zfh::rounding(cpu, self.rm, self.to_raw())?;
+                let exact = self.typ == OperationType::RoundToIntegralExact;
+                softfloat::round_to_integral(HALF, a, rounding, exact)
+            }
+            OperationType::QuietLessOrEqual | OperationType::QuietLessThan => {
+                let (order, flags) = softfloat::compare(HALF, a, b, false);
+                let result = match self.typ {
+                    OperationType::QuietLessThan => order == Some(Ordering::Less),
+                    _ => order.is_some_and(|order| order != Ordering::Greater),
+                };
+                zfh::accrue(cpu, flags);
+                cpu.set_register(self.dest, result as u64);
+                return Ok(());
+            }
+        };
+
+        zfh::accrue(cpu, flags);
+        cpu.float_mut().set_half(self.dest, result as u16);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (funct7, funct3, rs2) = match self.typ {
+            OperationType::LoadImmediate => (0b1111010, 0b000, 0b00001),
+            OperationType::Minimum => (0b0010110, 0b010, self.src2),
+            OperationType::Maximum => (0b0010110, 0b011, self.src2),
+            OperationType::RoundToIntegral => (0b0100010, self.rm, 0b00100),
+            OperationType::RoundToIntegralExact => (0b0100010, self.rm, 0b00101),
+            OperationType::QuietLessOrEqual => (0b1010010, 0b100, self.src2),
+            OperationType::QuietLessThan => (0b1010010, 0b101, self.src2),
+        };
+
+        encoding::R {
+            opcode: 0x53,
+            funct7,
+            funct3,
+            rd: self.dest,
+            rs1: self.src1,
+            rs2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    fn run(cpu: &mut CPU, raw: u32) {
+        let instr = Op::parse(raw).expect("couldn't parse instruction");
+        assert_eq!(instr.to_raw(), raw);
+        instr.execute(cpu).expect("couldn't execute instruction");
+    }
+
+    #[test]
+    fn test_load_immediate() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // fli.h fa0, -1.0, min, 0.3125, 2^16 and nan
+        let expected = [(0, 0xBC00), (1, 0x0400), (9, 0x3500), (29, 0x7C00), (31, 0x7E00)];
+        for &(index, result) in &expected {
+            run(&mut cpu, 0xf4100553 | index << 15);
+            assert_eq!(cpu.float().register(10), 0xFFFF_FFFF_FFFF_0000 | result);
+        }
+    }
+
+    #[test]
+    fn test_minimum_and_round() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.float_mut().set_half(11, 0x3E00);
+        cpu.float_mut().set_half(12, 0x7E00);
+
+        // fminm.h and fmaxm.h fa0, fa1, fa2 with a quiet NaN in fa2
+        run(&mut cpu, 0x2cc5a553);
+        assert_eq!(cpu.float().half(10), 0x7E00);
+        run(&mut cpu, 0x2cc5b553);
+        assert_eq!(cpu.float().half(10), 0x7E00);
+        assert_eq!(cpu.float().fflags(), 0);
+
+        // fround.h fa0, fa1 and froundnx.h fa0, fa1, rtz of 1.5
+        run(&mut cpu, 0x4445f553);
+        assert_eq!(cpu.float().half(10), 0x4000);
+        assert_eq!(cpu.float().fflags(), 0);
+        run(&mut cpu, 0x44559553);
+        assert_eq!(cpu.float().half(10), 0x3C00);
+        assert_eq!(cpu.float().fflags(), softfloat::INEXACT);
+    }
+
+    #[test]
+    fn test_quiet_compare() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.float_mut().set_half(11, 0x3E00);
+        cpu.float_mut().set_half(12, 0x3E00);
+
+        // fleq.h and fltq.h a0, fa1, fa2
+        run(&mut cpu, 0xa4c5c553);
+        assert_eq!(cpu.get_register(10), 1);
+        run(&mut cpu, 0xa4c5d553);
+        assert_eq!(cpu.get_register(10), 0);
+
+        // A quiet NaN isn't invalid, a signaling one is.
+        cpu.float_mut().set_half(12, 0x7E00);
+        run(&mut cpu, 0xa4c5d553);
+        assert_eq!(cpu.float().fflags(), 0);
+        cpu.float_mut().set_half(12, 0x7D00);
+        run(&mut cpu, 0xa4c5c553);
+        assert_eq!((cpu.get_register(10), cpu.float().fflags()), (0, softfloat::INVALID));
+    }
+}
@@ -0,0 +1,506 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cmp::Ordering;
+
+use instruction::{encoding, Instruction};
+use instruction::zfh;
+use cpu::CPU;
+use isa::Extension;
+use softfloat::{self, HALF};
+use trap::Exception;
+
+// The format field of half-precision instructions.
+const FMT_H: u8 = 0b10;
+
+/// FMADD.H, FMSUB.H, FNMSUB.H and FNMADD.H: a product and a sum, rounded
+/// once.
+#[derive(Debug, Clone, Copy)]
+pub struct FusedMultiplyAdd {
+    typ: FusedMultiplyAddType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+    src3: u8,
+    rm: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FusedMultiplyAddType {
+    MultiplyAdd,
+    MultiplySubtract,
+    NegatedMultiplySubtract,
+    NegatedMultiplyAdd,
+}
+
+impl FusedMultiplyAdd {
+    pub fn parse(instruction: u32) -> Option<FusedMultiplyAdd> {
+        let decoded = encoding::R::parse(instruction);
+
+        let typ = match decoded.opcode {
+            0x43 => FusedMultiplyAddType::MultiplyAdd,
+            0x47 => FusedMultiplyAddType::MultiplySubtract,
+            0x4B => FusedMultiplyAddType::NegatedMultiplySubtract,
+            0x4F => FusedMultiplyAddType::NegatedMultiplyAdd,
+            _ => return None,
+        };
+        if decoded.funct7 & 0b11 != FMT_H || !zfh::is_valid_rm(decoded.funct3) {
+            return None;
+        }
+
+        Some(FusedMultiplyAdd {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: decoded.rs2,
+            src3: decoded.funct7 >> 2,
+            rm: decoded.funct3,
+        })
+    }
+}
+
+impl Instruction for FusedMultiplyAdd {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let rounding = zfh::rounding(cpu, self.rm, self.to_raw())?;
+        let float = cpu.float();
+        let (a, b, c) = (float.half(self.src1) as u64,
+                         float.half(self.src2) as u64,
+                         float.half(self.src3) as u64);
+        let negate = |value: u64| value ^ 0x8000;
+
+        let (result, flags) = match self.typ {
+            FusedMultiplyAddType::MultiplyAdd => softfloat::fma(HALF, a, b, c, rounding),
+            FusedMultiplyAddType::MultiplySubtract => {
+                softfloat::fma(HALF, a, b, negate(c), rounding)
+            }
+            FusedMultiplyAddType::NegatedMultiplySubtract => {
+                softfloat::fma(HALF, negate(a), b, c, rounding)
+            }
+            FusedMultiplyAddType::NegatedMultiplyAdd => {
+                softfloat::fma(HALF, negate(a), b, negate(c), rounding)
+            }
+        };
+
+        zfh::accrue(cpu, flags);
+        cpu.float_mut().set_half(self.dest, result as u16);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        encoding::R {
+            opcode: match self.typ {
+                FusedMultiplyAddType::MultiplyAdd => 0x43,
+                FusedMultiplyAddType::MultiplySubtract => 0x47,
+                FusedMultiplyAddType::NegatedMultiplySubtract => 0x4B,
+                FusedMultiplyAddType::NegatedMultiplyAdd => 0x4F,
+            },
+            funct7: self.src3 << 2 | FMT_H,
+            funct3: self.rm,
+            rd: self.dest,
+            rs1: self.src1,
+            rs2: self.src2,
+        }.to_raw()
+    }
+}
+
+/// The rest of the half-precision instructions, from the OP-FP opcode.
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+    rm: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    SquareRoot,
+    SignInject,
+    SignInjectNegated,
+    SignInjectXor,
+    Min,
+    Max,
+    Equal,
+    LessThan,
+    LessOrEqual,
+    Classify,
+    MoveToInteger,
+    MoveFromInteger,
+    /// FCVT.W.H, FCVT.WU.H, FCVT.L.H and FCVT.LU.H
+    ToInteger { bits: u32, signed: bool },
+    /// FCVT.H.W, FCVT.H.WU, FCVT.H.L and FCVT.H.LU
+    FromInteger { bits: u32, signed: bool },
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        if decoded.opcode != 0x53 || decoded.funct7 & 0b11 != FMT_H {
+            return None;
+        }
+        let rm = decoded.funct3;
+        // The integer type of a conversion, from rs2
+        let integer = |rs2: u8| {
+            (if rs2 & 0b10 != 0 { 64 } else { 32 }, rs2 & 0b01 == 0)
+        };
+
+        let typ = match (decoded.funct7 >> 2, decoded.funct3, decoded.rs2) {
+            (0b00000, _, _) if zfh::is_valid_rm(rm) => OperationType::Add,
+            (0b00001, _, _) if zfh::is_valid_rm(rm) => OperationType::Subtract,
+            (0b00010, _, _) if zfh::is_valid_rm(rm) => OperationType::Multiply,
+            (0b00011, _, _) if zfh::is_valid_rm(rm) => OperationType::Divide,
+            (0b01011, _, 0) if zfh::is_valid_rm(rm) => OperationType::SquareRoot,
+            (0b00100, 0b000, _) => OperationType::SignInject,
+            (0b00100, 0b001, _) => OperationType::SignInjectNegated,
+            (0b00100, 0b010, _) => OperationType::SignInjectXor,
+            (0b00101, 0b000, _) => OperationType::Min,
+            (0b00101, 0b001, _) => OperationType::Max,
+            (0b10100, 0b010, _) => OperationType::Equal,
+            (0b10100, 0b001, _) => OperationType::LessThan,
+            (0b10100, 0b000, _) => OperationType::LessOrEqual,
+            (0b11100, 0b001, 0) => OperationType::Classify,
+            (0b11100, 0b000, 0) => OperationType::MoveToInteger,
+            (0b11110, 0b000, 0) => OperationType::MoveFromInteger,
+            (0b11000, _, rs2) if rs2 < 4 && zfh::is_valid_rm(rm) => {
+                let (bits, signed) = integer(rs2);
+                OperationType::ToInteger {
+                    bits,
+                    signed,
+                }
+            }
+            (0b11010, _, rs2) if rs2 < 4 && zfh::is_valid_rm(rm) => {
+                let (bits, signed) = integer(rs2);
+                OperationType::FromInteger {
+                    bits,
+                    signed,
+                }
+            }
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: match typ {
+                OperationType::SquareRoot |
+                OperationType::Classify |
+                OperationType::MoveToInteger |
+                OperationType::MoveFromInteger |
+                OperationType::ToInteger { .. } |
+                OperationType::FromInteger { .. } => 0,
+                _ => decoded.rs2,
+            },
+            rm,
+        })
+    }
+
+    /// Whether this converts to or from a 64-bit integer, which only RV64
+    /// has.
+    pub fn is_rv64_only(&self) -> bool {
+        match self.typ {
+            OperationType::ToInteger { bits, .. } | OperationType::FromInteger { bits, .. } => {
+                bits == 64
+            }
+            _ => false,
+        }
+    }
+
+    /// Zfhmin has the moves.
+    pub fn extensions(&self) -> &'static [Extension] {
+        match self.typ {
+            OperationType::MoveToInteger | OperationType::MoveFromInteger => &[Extension::Zfhmin],
+            _ => &[Extension::Zfh],
+        }
+    }
+
+    /// The integer register this reads or writes, if any.
+    pub fn max_register(&self) -> u8 {
+        match self.typ {
+            OperationType::Equal |
+            OperationType::LessThan |
+            OperationType::LessOrEqual |
+            OperationType::Classify |
+            OperationType::MoveToInteger |
+            OperationType::ToInteger { .. } => self.dest,
+            OperationType::MoveFromInteger | OperationType::FromInteger { .. } => self.src1,
+            _ => 0,
+        }
+    }
+
+    // funct5, and funct3 or rs2 for the instructions that have fixed ones
+    fn encoding(&self) -> (u8, u8, u8) {
+        let integer = |bits: u32, signed: bool| ((bits == 64) as u8) << 1 | !signed as u8;
+        match self.typ {
+            OperationType::Add => (0b00000, self.rm, self.src2),
+            OperationType::Subtract => (0b00001, self.rm, self.src2),
+            OperationType::Multiply => (0b00010, self.rm, self.src2),
+            OperationType::Divide => (0b00011, self.rm, self.src2),
+            OperationType::SquareRoot => (0b01011, self.rm, 0),
+            OperationType::SignInject => (0b00100, 0b000, self.src2),
+            OperationType::SignInjectNegated => (0b00100, 0b001, self.src2),
+            OperationType::SignInjectXor => (0b00100, 0b010, self.src2),
+            OperationType::Min => (0b00101, 0b000, self.src2),
+            OperationType::Max => (0b00101, 0b001, self.src2),
+            OperationType::Equal => (0b10100, 0b010, self.src2),
+            OperationType::LessThan => (0b10100, 0b001, self.src2),
+            OperationType::LessOrEqual => (0b10100, 0b000, self.src2),
+            OperationType::Classify => (0b11100, 0b001, 0),
+            OperationType::MoveToInteger => (0b11100, 0b000, 0),
+            OperationType::MoveFromInteger => (0b11110, 0b000, 0),
+            OperationType::ToInteger { bits, signed } => {
+                (0b11000, self.rm, integer(bits, signed))
+            }
+            OperationType::FromInteger { bits, signed } => {
+                (0b11010, self.rm, integer(bits, signed))
+            }
+        }
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let raw = self.to_raw();
+        let a = cpu.float().half(self.src1) as u64;
+        let b = cpu.float().half(self.src2) as u64;
+        let sign = |value: u64| value & 0x8000;
+
+        // Results for a floating point register, or for an integer one
+        let (result, flags) = match self.typ {
+            OperationType::Add => softfloat::add(HALF, a, b, zfh::rounding(cpu, self.rm, raw)?),
+            OperationType::Subtract => {
+                softfloat::sub(HALF, a, b, zfh::rounding(cpu, self.rm, raw)?)
+            }
+            OperationType::Multiply => {
+                softfloat::mul(HALF, a, b, zfh::rounding(cpu, self.rm, raw)?)
+            }
+            OperationType::Divide => {
+                softfloat::div(HALF, a, b, zfh::rounding(cpu, self.rm, raw)?)
+            }
+            OperationType::SquareRoot => {
+                softfloat::sqrt(HALF, a, zfh::rounding(cpu, self.rm, raw)?)
+            }
+            OperationType::SignInject => (a & 0x7FFF | sign(b), 0),
+            OperationType::SignInjectNegated => (a & 0x7FFF | sign(!b), 0),
+            OperationType::SignInjectXor => (a ^ sign(b), 0),
+            OperationType::Min => softfloat::min_max(HALF, a, b, false, false),
+            OperationType::Max => softfloat::min_max(HALF, a, b, true, false),
+            OperationType::Equal |
+            OperationType::LessThan |
+            OperationType::LessOrEqual => {
+                let signaling = self.typ != OperationType::Equal;
+                let (order, flags) = softfloat::compare(HALF, a, b, signaling);
+                let result = match self.typ {
+                    OperationType::Equal => order == Some(Ordering::Equal),
+                    OperationType::LessThan => order == Some(Ordering::Less),
+                    _ => order.is_some_and(|order| order != Ordering::Greater),
+                };
+                zfh::accrue(cpu, flags);
+                cpu.set_register(self.dest, result as u64);
+                return Ok(());
+            }
+            OperationType::Classify => {
+                cpu.set_register(self.dest, softfloat::classify(HALF, a));
+                return Ok(());
+            }
+            OperationType::MoveToInteger => {
+                // The low bits, whatever's above them
+                let value = cpu.float().register(self.src1) as u16;
+                cpu.set_register(self.dest, value as i16 as i64 as u64);
+                return Ok(());
+            }
+            OperationType::MoveFromInteger => (cpu.get_register(self.src1) & 0xFFFF, 0),
+            OperationType::ToInteger { bits, signed } => {
+                let rounding = zfh::rounding(cpu, self.rm, raw)?;
+                let (value, flags) = softfloat::to_integer(HALF, a, bits, signed, rounding);
+                // 32-bit results are sign-extended, even unsigned ones.
+                let value = if bits == 32 { value as u32 as i32 as i64 as u64 } else { value };
+                zfh::accrue(cpu, flags);
+                cpu.set_register(self.dest, value);
+                return Ok(());
+            }
+            OperationType::FromInteger { bits, signed } => {
+                let rounding = zfh::rounding(cpu, self.rm, raw)?;
+                let value = cpu.get_register(self.src1);
+                let value = match (bits, signed) {
+                    (32, true) => value as i32 as i64 as u64,
+                    (32, false) => value as u32 as u64,
+                    _ => value,
+                };
+                softfloat::from_integer(HALF, value, signed, rounding)
+            }
+        };
+
+        zfh::accrue(cpu, flags);
+        cpu.float_mut().set_half(self.dest, result as u16);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (funct5, funct3, rs2) = self.encoding();
+        encoding::R {
+            opcode: 0x53,
+            funct7: funct5 << 2 | FMT_H,
+            funct3,
+            rd: self.dest,
+            rs1: self.src1,
+            rs2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    fn run(cpu: &mut CPU, raw: u32) -> Result<(), Exception> {
+        let instr = Op::parse(raw).expect("couldn't parse instruction");
+        assert_eq!(instr.to_raw(), raw);
+        instr.execute(cpu)
+    }
+
+    // fa1 = 1.5 and fa2 = -2.5
+    fn cpu_with_operands() -> CPU {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.float_mut().set_half(11, 0x3E00);
+        cpu.float_mut().set_half(12, 0xC100);
+        cpu
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let mut cpu = cpu_with_operands();
+
+        // fadd.h, fsub.h, fmul.h, fdiv.h fa0, fa1, fa2, then fsqrt.h fa0, fa1
+        let expected = [(0x04c5f553, 0xBC00, 0),
+                        (0x0cc5f553, 0x4400, 0),
+                        (0x14c5f553, 0xC380, 0),
+                        (0x1cc5f553, 0xB8CD, softfloat::INEXACT),
+                        (0x5c05f553, 0x3CE6, softfloat::INEXACT)];
+        for &(raw, result, flags) in &expected {
+            cpu.float_mut().fflags = 0;
+            run(&mut cpu, raw).unwrap();
+            assert_eq!(cpu.float().half(10), result, "{:#x}", raw);
+            assert_eq!(cpu.float().fflags(), flags, "{:#x}", raw);
+        }
+
+        // fdiv.h fa0, fa1, fa2, rtz, and with frm set to round up
+        run(&mut cpu, 0x1cc59553).unwrap();
+        assert_eq!(cpu.float().half(10), 0xB8CC);
+        cpu.float_mut().frm = 0b011;
+        run(&mut cpu, 0x1cc5f553).unwrap();
+        assert_eq!(cpu.float().half(10), 0xB8CC);
+
+        // The reserved rounding modes are illegal, statically or in frm.
+        assert!(Op::parse(0x04c5d553).is_none());
+        cpu.float_mut().frm = 0b101;
+        assert_eq!(run(&mut cpu, 0x04c5f553),
+                   Err(Exception::IllegalInstruction(0x04c5f553)));
+    }
+
+    #[test]
+    fn test_fused_multiply_add() {
+        let mut cpu = cpu_with_operands();
+        cpu.float_mut().set_half(13, 0x3C00);
+
+        // fmadd.h, fmsub.h, fnmsub.h, fnmadd.h fa0, fa1, fa2, fa3
+        let expected = [(0x6cc5f543, 0xC180), (0x6cc5f547, 0xC4C0), (0x6cc5f54b, 0x44C0),
+                        (0x6cc5f54f, 0x4180)];
+        for &(raw, result) in &expected {
+            let instr = FusedMultiplyAdd::parse(raw).expect("couldn't parse instruction");
+            assert_eq!(instr.to_raw(), raw);
+            instr.execute(&mut cpu).unwrap();
+            assert_eq!(cpu.float().half(10), result, "{:#x}", raw);
+        }
+    }
+
+    #[test]
+    fn test_sign_injection_and_nan_boxing() {
+        let mut cpu = cpu_with_operands();
+
+        // fsgnj.h, fsgnjn.h, fsgnjx.h fa0, fa1, fa2
+        let expected = [(0x24c58553, 0xBE00), (0x24c59553, 0x3E00), (0x24c5a553, 0xBE00)];
+        for &(raw, result) in &expected {
+            run(&mut cpu, raw).unwrap();
+            assert_eq!(cpu.float().register(10), 0xFFFF_FFFF_FFFF_0000 | result);
+        }
+
+        // A register that isn't NaN-boxed is the canonical NaN, apart from
+        // to fmv.x.h.
+        cpu.float_mut().set_register(11, 0x3C00);
+        run(&mut cpu, 0x24c58553).unwrap();
+        assert_eq!(cpu.float().half(10), 0xFE00);
+        run(&mut cpu, 0xe4058553).unwrap();
+        assert_eq!(cpu.get_register(10), 0x3C00);
+    }
+
+    #[test]
+    fn test_compare_and_classify() {
+        let mut cpu = cpu_with_operands();
+
+        // feq.h, flt.h, fle.h a0, fa1, fa2, and fclass.h a0, fa1
+        let expected = [(0xa4c5a553, 0), (0xa4c59553, 0), (0xa4c58553, 0), (0xe4059553, 1 << 6)];
+        for &(raw, result) in &expected {
+            run(&mut cpu, raw).unwrap();
+            assert_eq!(cpu.get_register(10), result, "{:#x}", raw);
+        }
+
+        // flt.h a0, fa2, fa1 and fmin.h, fmax.h fa0, fa1, fa2
+        run(&mut cpu, 0xa4b61553).unwrap();
+        assert_eq!(cpu.get_register(10), 1);
+        run(&mut cpu, 0x2cc58553).unwrap();
+        assert_eq!(cpu.float().half(10), 0xC100);
+        run(&mut cpu, 0x2cc59553).unwrap();
+        assert_eq!(cpu.float().half(10), 0x3E00);
+
+        // feq.h with a quiet NaN is quiet, flt.h isn't.
+        cpu.float_mut().set_half(12, 0x7E00);
+        run(&mut cpu, 0xa4c5a553).unwrap();
+        assert_eq!(cpu.float().fflags(), 0);
+        run(&mut cpu, 0xa4c59553).unwrap();
+        assert_eq!(cpu.float().fflags(), softfloat::INVALID);
+    }
+
+    #[test]
+    fn test_conversions_and_moves() {
+        let mut cpu = cpu_with_operands();
+
+        // fcvt.w.h a0, fa2, rne rounds -2.5 to even, and fcvt.wu.h a0, fa2,
+        // rtz is out of range.
+        run(&mut cpu, 0xc4060553).unwrap();
+        assert_eq!(cpu.get_register(10), (-2i64) as u64 & 0xFFFF_FFFF);
+        run(&mut cpu, 0xc4161553).unwrap();
+        assert_eq!(cpu.get_register(10), 0);
+        assert_eq!(cpu.float().fflags(), softfloat::INVALID | softfloat::INEXACT);
+
+        // fcvt.h.w fa0, a1 and fcvt.h.wu fa0, a1 of -1
+        cpu.set_register(11, 0xFFFF_FFFF);
+        run(&mut cpu, 0xd405f553).unwrap();
+        assert_eq!(cpu.float().half(10), 0xBC00);
+        run(&mut cpu, 0xd415f553).unwrap();
+        assert_eq!(cpu.float().half(10), 0x7C00);
+
+        // fmv.h.x fa0, a1 and fmv.x.h a0, fa0, which sign-extends
+        run(&mut cpu, 0xf4058553).unwrap();
+        assert_eq!(cpu.float().register(10), !0);
+        cpu.float_mut().set_half(10, 0xC100);
+        run(&mut cpu, 0xe4050553).unwrap();
+        assert_eq!(cpu.get_register(10), 0xFFFF_C100);
+
+        // fcvt.l.h only exists on RV64.
+        assert!(Op::parse(0xc425f553).unwrap().is_rv64_only());
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use trap::Exception;
+
+/// FLH: load a half-precision number, NaN-boxing it.
+#[derive(Debug, Clone, Copy)]
+pub struct Load {
+    dest: u8,
+    offset: i32,
+    base: u8,
+}
+
+impl Load {
+    pub fn parse(instruction: u32) -> Option<Load> {
+        let decoded = encoding::I::parse(instruction);
+
+        if decoded.opcode != 0x07 || decoded.funct3 != 0b001 {
+            return None;
+        }
+
+        Some(Load {
+            dest: decoded.rd,
+            offset: decoded.immediate,
+            base: decoded.rs1,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        self.base
+    }
+}
+
+impl Instruction for Load {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let base = cpu.get_register(self.base);
+        let addr = cpu.address(base, self.offset, Exception::LoadAccessFault)?;
+        let value = cpu.load_u16(addr)?;
+        cpu.float_mut().set_half(self.dest, value);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        encoding::I {
+            opcode: 0x07,
+            funct3: 0b001,
+            rd: self.dest,
+            rs1: self.base,
+            immediate: self.offset,
+        }.to_raw()
+    }
+}
+
+/// FSH: store the low 16 bits of a register, whether or not they're a
+/// properly NaN-boxed number.
+#[derive(Debug, Clone, Copy)]
+pub struct Store {
+    src: u8,
+    offset: i32,
+    base: u8,
+}
+
+impl Store {
+    pub fn parse(instruction: u32) -> Option<Store> {
+        let decoded = encoding::S::parse(instruction);
+
+        if decoded.opcode != 0x27 || decoded.funct3 != 0b001 {
+            return None;
+        }
+
+        Some(Store {
+            src: decoded.rs2,
+            offset: decoded.immediate,
+            base: decoded.rs1,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        self.base
+    }
+}
+
+impl Instruction for Store {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let base = cpu.get_register(self.base);
+        let addr = cpu.address(base, self.offset, Exception::StoreAccessFault)?;
+        let value = cpu.float().register(self.src);
+        cpu.store_u16(addr, value as u16)
+    }
+
+    fn to_raw(&self) -> u32 {
+        encoding::S {
+            opcode: 0x27,
+            funct3: 0b001,
+            immediate: self.offset,
+            rs1: self.base,
+            rs2: self.src,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    #[test]
+    fn test_load_store() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.set_register(11, 0x100);
+        cpu.store_u16(0x106, 0x3C00).unwrap();
+
+        // flh fa0, 6(a1)
+        let load = Load::parse(0x00659507).expect("couldn't parse instruction");
+        assert_eq!(load.to_raw(), 0x00659507);
+        load.execute(&mut cpu).unwrap();
+        assert_eq!(cpu.float().register(10), 0xFFFF_FFFF_FFFF_3C00);
+
+        // fsh fa0, -2(a1), of a register that isn't NaN-boxed
+        cpu.float_mut().set_register(10, 0x1234);
+        let store = Store::parse(0xfea59f27).expect("couldn't parse instruction");
+        assert_eq!(store.to_raw(), 0xfea59f27);
+        store.execute(&mut cpu).unwrap();
+        assert_eq!(cpu.load_u16(0xFE).unwrap(), 0x1234);
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zfh and Zfhmin: half-precision floating point.
+//!
+//! Zfhmin only has the loads, stores and moves, which copy bits around
+//! without looking at them; Zfh adds the arithmetic, comparisons and
+//! conversions to and from integers. There's no F or D, so no conversions
+//! to wider formats either.
+
+mod compute;
+mod load_store;
+
+pub use self::compute::*;
+pub use self::load_store::*;
+
+use cpu::CPU;
+use softfloat::Rounding;
+use trap::Exception;
+
+/// The rounding mode for an instruction with `rm` in its rounding mode
+/// field, which for the dynamic mode is `frm`. Reserved modes, including
+/// in `frm`, make the instruction illegal.
+pub fn rounding(cpu: &CPU, rm: u8, raw: u32) -> Result<Rounding, Exception> {
+    let rm = if rm == 0b111 { cpu.float().frm() } else { rm };
+    Rounding::from_bits(rm).ok_or(Exception::IllegalInstruction(raw))
+}
+
+/// Whether `rm` could be a valid rounding mode field: the dynamic mode or
+/// one of the five static ones.
+pub fn is_valid_rm(rm: u8) -> bool {
+    rm != 0b101 && rm != 0b110
+}
+
+/// Add `flags` to the exception flags accrued in `fflags`.
+pub fn accrue(cpu: &mut CPU, flags: u8) {
+    if flags != 0 {
+        cpu.float_mut().fflags |= flags;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ram::RAM;
+    use cpu::CPU;
+    use trap::StopReason;
+
+    #[test]
+    fn test_divide_in_memory() {
+        // flh fa0, 0(a1); flh fa1, 2(a1); fdiv.h fa2, fa0, fa1; fsh fa2, 4(a1);
+        // frflags a0
+        let program = [0x00059507, 0x00259587, 0x1cb57653, 0x00c59227, 0x00102573];
+        let mut cpu = CPU::new(RAM::new(4096));
+        for (i, &word) in program.iter().enumerate() {
+            cpu.store_u32(0x100 + 4 * i as u32, word).unwrap();
+        }
+        cpu.store_u16(0x200, 0x3C00).unwrap();
+        cpu.store_u16(0x202, 0x4200).unwrap();
+        cpu.set_register(11, 0x200);
+        cpu.pc = 0x100;
+
+        // 1/3 rounds, which raises the inexact flag.
+        assert_eq!(cpu.run_for(5), StopReason::InstructionLimit);
+        assert_eq!(cpu.load_u16(0x204), Ok(0x3555));
+        assert_eq!(cpu.get_register(10), 1);
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zicbom and Zicboz: cache-block management and zeroing.
+//!
+//! There are no caches to manage, so CBO.CLEAN, CBO.FLUSH and CBO.INVAL only
+//! check that they're allowed to run, which in U-mode is up to `menvcfg`.
+//! CBO.ZERO stores zeros over the whole block containing its address. The
+//! Zicbop prefetches are ORIs to x0, so they need nothing here.
+
+use instruction::{encoding, Instruction};
+use isa::Extension;
+use cpu::{self, CPU, Privilege};
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    base: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    Invalidate,
+    Clean,
+    Flush,
+    Zero,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::I::parse(instruction);
+
+        if decoded.opcode != 0x0F || decoded.funct3 != 0b010 || decoded.rd != 0 {
+            return None;
+        }
+
+        let typ = match decoded.immediate {
+            0 => OperationType::Invalidate,
+            1 => OperationType::Clean,
+            2 => OperationType::Flush,
+            4 => OperationType::Zero,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            base: decoded.rs1,
+        })
+    }
+
+    pub fn extensions(&self) -> &'static [Extension] {
+        match self.typ {
+            OperationType::Zero => &[Extension::Zicboz],
+            _ => &[Extension::Zicbom],
+        }
+    }
+
+    pub fn max_register(&self) -> u8 {
+        self.base
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let enable = match self.typ {
+            OperationType::Invalidate => cpu::MENVCFG_CBIE,
+            OperationType::Clean | OperationType::Flush => cpu::MENVCFG_CBCFE,
+            OperationType::Zero => cpu::MENVCFG_CBZE,
+        };
+        if cpu.privilege() != Privilege::Machine && cpu.menvcfg() & enable == 0 {
+            return Err(Exception::IllegalInstruction(self.to_raw()));
+        }
+
+        if self.typ == OperationType::Zero {
+            let size = cpu.cache_block_size();
+            let base = cpu.get_register(self.base);
+            let addr = cpu.address(base, 0, Exception::StoreAccessFault)? & !(size - 1);
+            for offset in (0..size).step_by(4) {
+                cpu.store_u32(addr + offset, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        encoding::I {
+            opcode: 0x0F,
+            funct3: 0b010,
+            rd: 0,
+            rs1: self.base,
+            immediate: match self.typ {
+                OperationType::Invalidate => 0,
+                OperationType::Clean => 1,
+                OperationType::Flush => 2,
+                OperationType::Zero => 4,
+            },
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::Isa;
+    use instruction::Instruction;
+
+    #[test]
+    fn test_zero() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.set_cache_block_size(32);
+        for addr in (0x100..0x140).step_by(4) {
+            cpu.store_u32(addr, 0xFFFF_FFFF).unwrap();
+        }
+
+        // cbo.zero (a0), with a0 in the middle of a block
+        let instr = Op::parse(0x0045200f).expect("couldn't parse instruction");
+        assert_eq!(instr.to_raw(), 0x0045200f);
+        cpu.set_register(10, 0x12C);
+        instr.execute(&mut cpu).unwrap();
+
+        let words = (0x100..0x140).step_by(4).map(|addr| cpu.load_u32(addr).unwrap());
+        let expected = [!0, !0, !0, !0, !0, !0, !0, !0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(words.collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_default_block_size() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        for addr in (0x200..0x280).step_by(4) {
+            cpu.store_u32(addr, 0xFFFF_FFFF).unwrap();
+        }
+
+        // cbo.zero (a3), with a3 in the second 64-byte block
+        cpu.set_register(13, 0x244);
+        Op::parse(0x0046a00f).unwrap().execute(&mut cpu).unwrap();
+        let zeroed = (0..32).filter(|i| cpu.load_u32(0x200 + 4 * i) == Ok(0));
+        assert_eq!(zeroed.collect::<Vec<_>>(), (16..32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_menvcfg() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.set_isa(Isa::parse("rv32i_zicbom_zicboz_zicsr").unwrap());
+        // cbo.clean (a0), cbo.inval (a0), cbo.zero (a0)
+        let clean = Op::parse(0x0015200f).expect("couldn't parse instruction");
+        let inval = Op::parse(0x0005200f).expect("couldn't parse instruction");
+        let zero = Op::parse(0x0045200f).expect("couldn't parse instruction");
+
+        // The reserved CBIE value reads back as off. M-mode can do anything.
+        cpu.set_csr(0x30A, 0xE0).unwrap();
+        assert_eq!(cpu.get_csr(0x30A), Ok(0xC0));
+        assert_eq!(inval.execute(&mut cpu), Ok(()));
+
+        // Drop to U-mode with CBO.INVAL as a flush, and CBO.CLEAN allowed.
+        cpu.set_csr(0x30A, 0x50).unwrap();
+        cpu.set_csr(0x300, 0).unwrap();
+        cpu.return_from_trap();
+        assert_eq!(cpu.privilege(), Privilege::User);
+        assert_eq!(clean.execute(&mut cpu), Ok(()));
+        assert_eq!(inval.execute(&mut cpu), Ok(()));
+        assert_eq!(zero.execute(&mut cpu), Err(Exception::IllegalInstruction(0x0045200f)));
+    }
+}
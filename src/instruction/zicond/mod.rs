@@ -0,0 +1,112 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zicond: integer conditional operations, zeroing a register depending on
+//! whether another is zero. With an OR they make a branchless select.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    /// CZERO.EQZ: zero if src2 is zero, otherwise src1
+    ZeroIfEqual,
+    /// CZERO.NEZ: zero if src2 isn't zero, otherwise src1
+    ZeroIfNotEqual,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        let typ = match (decoded.opcode, decoded.funct7, decoded.funct3) {
+            (0x33, 0x07, 0b101) => OperationType::ZeroIfEqual,
+            (0x33, 0x07, 0b111) => OperationType::ZeroIfNotEqual,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: decoded.rs2,
+        })
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.src1, self.src2))
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let zero = match self.typ {
+            OperationType::ZeroIfEqual => cpu.get_register(self.src2) == 0,
+            OperationType::ZeroIfNotEqual => cpu.get_register(self.src2) != 0,
+        };
+        let result = if zero { 0 } else { cpu.get_register(self.src1) };
+
+        cpu.set_register(self.dest, result);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        encoding::R {
+            opcode: 0x33,
+            funct7: 0x07,
+            funct3: match self.typ {
+                OperationType::ZeroIfEqual => 0b101,
+                OperationType::ZeroIfNotEqual => 0b111,
+            },
+            rd: self.dest,
+            rs1: self.src1,
+            rs2: self.src2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    macro_rules! test_op {
+        ($cpu:expr, $raw:expr, $result:expr, $val1:expr, $val2:expr) => {
+            let instr = Op::parse($raw).expect("couldn't parse instruction");
+            assert_eq!(instr.to_raw(), $raw);
+            $cpu.set_register(2, $val1);
+            $cpu.set_register(3, $val2);
+            instr.execute(&mut $cpu).expect("couldn't execute instruction");
+            assert_eq!($cpu.get_register(1), $result);
+        }
+    }
+
+    #[test]
+    fn test_czero() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // czero.eqz x1, x2, x3
+        test_op!(cpu, 0x0e3150b3, 0, 0x1234, 0);
+        test_op!(cpu, 0x0e3150b3, 0x1234, 0x1234, 0x80000000);
+        // czero.nez x1, x2, x3
+        test_op!(cpu, 0x0e3170b3, 0x1234, 0x1234, 0);
+        test_op!(cpu, 0x0e3170b3, 0, 0x1234, 1);
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zimop: may-be-operations, encodings that later extensions can give a
+//! meaning to, such as shadow stack checks. Until then they just write zero
+//! to their destination, so code using them still runs.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use trap::Exception;
+
+// The fixed bits of MOP.R.n and MOP.RR.n, and their values.
+const MOP_R_MASK: u32 = 0xB3C0707F;
+const MOP_R: u32 = 0x81C04073;
+const MOP_RR_MASK: u32 = 0xB200707F;
+const MOP_RR: u32 = 0x82004073;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    /// Which MOP.R (0-31) or MOP.RR (0-7) this is
+    number: u8,
+    two_sources: bool,
+    dest: u8,
+    src1: u8,
+    src2: u8,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+        let bits = |shift: u32, width: u32| ((instruction >> shift) & ((1 << width) - 1)) as u8;
+
+        if instruction & MOP_R_MASK == MOP_R {
+            Some(Op {
+                number: bits(30, 1) << 4 | bits(26, 2) << 2 | bits(20, 2),
+                two_sources: false,
+                dest: decoded.rd,
+                src1: decoded.rs1,
+                src2: 0,
+            })
+        } else if instruction & MOP_RR_MASK == MOP_RR {
+            Some(Op {
+                number: bits(30, 1) << 2 | bits(26, 2),
+                two_sources: true,
+                dest: decoded.rd,
+                src1: decoded.rs1,
+                src2: decoded.rs2,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.src1, self.src2))
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        cpu.set_register(self.dest, 0);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let number = self.number as u32;
+        let (fixed, number) = if self.two_sources {
+            (MOP_RR, (number >> 2) << 30 | (number & 0b11) << 26)
+        } else {
+            (MOP_R, (number >> 4) << 30 | ((number >> 2) & 0b11) << 26 | (number & 0b11) << 20)
+        };
+        fixed | number | (self.src2 as u32) << 20 | (self.src1 as u32) << 15 |
+        (self.dest as u32) << 7
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use instruction::Instruction;
+
+    #[test]
+    fn test_may_be_operations() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // mop.r.0 x1, x2; mop.r.31 x1, x2; mop.rr.7 x1, x2, x3
+        for &(raw, number) in &[(0x81c140f3, 0), (0xcdf140f3, 31), (0xce3140f3, 7)] {
+            let instr = Op::parse(raw).expect("couldn't parse instruction");
+            assert_eq!(instr.number, number);
+            assert_eq!(instr.to_raw(), raw);
+            cpu.set_register(1, 0x1234);
+            instr.execute(&mut cpu).expect("couldn't execute instruction");
+            assert_eq!(cpu.get_register(1), 0);
+        }
+
+        // csrrw x1, mscratch, x2 is a real instruction
+        assert!(Op::parse(0x340110f3).is_none());
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Zknh: the SHA-2 sigma functions. RV32 does SHA-512's a half at a time,
+//! with the other half in a second register.
+
+use std::cmp;
+
+use instruction::{encoding, Instruction};
+use cpu::CPU;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    typ: OperationType,
+    dest: u8,
+    src1: u8,
+    src2: u8, // the other half, for RV32's SHA-512 instructions
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationType {
+    Sha256Sig0,
+    Sha256Sig1,
+    Sha256Sum0,
+    Sha256Sum1,
+    Sha512Sig0,
+    Sha512Sig1,
+    Sha512Sum0,
+    Sha512Sum1,
+    Sha512Sig0Low,
+    Sha512Sig0High,
+    Sha512Sig1Low,
+    Sha512Sig1High,
+    Sha512Sum0Half,
+    Sha512Sum1Half,
+}
+
+impl Op {
+    pub fn parse(instruction: u32) -> Option<Op> {
+        let decoded = encoding::R::parse(instruction);
+
+        let typ = match (decoded.opcode, decoded.funct7, decoded.funct3) {
+            (0x13, 0x08, 0b001) => {
+                match decoded.rs2 {
+                    0b00000 => OperationType::Sha256Sum0,
+                    0b00001 => OperationType::Sha256Sum1,
+                    0b00010 => OperationType::Sha256Sig0,
+                    0b00011 => OperationType::Sha256Sig1,
+                    0b00100 => OperationType::Sha512Sum0,
+                    0b00101 => OperationType::Sha512Sum1,
+                    0b00110 => OperationType::Sha512Sig0,
+                    0b00111 => OperationType::Sha512Sig1,
+                    _ => return None,
+                }
+            }
+            (0x33, 0x28, 0b000) => OperationType::Sha512Sum0Half,
+            (0x33, 0x29, 0b000) => OperationType::Sha512Sum1Half,
+            (0x33, 0x2A, 0b000) => OperationType::Sha512Sig0Low,
+            (0x33, 0x2B, 0b000) => OperationType::Sha512Sig1Low,
+            (0x33, 0x2E, 0b000) => OperationType::Sha512Sig0High,
+            (0x33, 0x2F, 0b000) => OperationType::Sha512Sig1High,
+            _ => return None,
+        };
+
+        Some(Op {
+            typ,
+            dest: decoded.rd,
+            src1: decoded.rs1,
+            src2: if decoded.opcode == 0x33 { decoded.rs2 } else { 0 },
+        })
+    }
+
+    /// Whether this is one of the register-pair SHA-512 instructions, which
+    /// RV64 doesn't have.
+    pub fn is_rv32_only(&self) -> bool {
+        matches!(self.typ,
+                 OperationType::Sha512Sig0Low | OperationType::Sha512Sig0High |
+                 OperationType::Sha512Sig1Low | OperationType::Sha512Sig1High |
+                 OperationType::Sha512Sum0Half | OperationType::Sha512Sum1Half)
+    }
+
+    /// Whether this is one of RV64's SHA-512 instructions.
+    pub fn is_rv64_only(&self) -> bool {
+        matches!(self.typ,
+                 OperationType::Sha512Sig0 | OperationType::Sha512Sig1 |
+                 OperationType::Sha512Sum0 | OperationType::Sha512Sum1)
+    }
+
+    pub fn max_register(&self) -> u8 {
+        cmp::max(self.dest, cmp::max(self.src1, self.src2))
+    }
+}
+
+impl Instruction for Op {
+    fn execute(&self, cpu: &mut CPU) -> Result<(), Exception> {
+        let src1 = cpu.get_register(self.src1);
+        let (x, y) = (src1 as u32, cpu.get_register(self.src2) as u32);
+
+        let result = match self.typ {
+            OperationType::Sha256Sig0 => x.rotate_right(7) ^ x.rotate_right(18) ^ (x >> 3),
+            OperationType::Sha256Sig1 => x.rotate_right(17) ^ x.rotate_right(19) ^ (x >> 10),
+            OperationType::Sha256Sum0 => {
+                x.rotate_right(2) ^ x.rotate_right(13) ^ x.rotate_right(22)
+            }
+            OperationType::Sha256Sum1 => {
+                x.rotate_right(6) ^ x.rotate_right(11) ^ x.rotate_right(25)
+            }
+            OperationType::Sha512Sig0 => {
+                cpu.set_register(self.dest,
+                                 src1.rotate_right(1) ^ src1.rotate_right(8) ^ (src1 >> 7));
+                return Ok(());
+            }
+            OperationType::Sha512Sig1 => {
+                cpu.set_register(self.dest,
+                                 src1.rotate_right(19) ^ src1.rotate_right(61) ^ (src1 >> 6));
+                return Ok(());
+            }
+            OperationType::Sha512Sum0 => {
+                cpu.set_register(self.dest,
+                                 src1.rotate_right(28) ^ src1.rotate_right(34) ^
+                                 src1.rotate_right(39));
+                return Ok(());
+            }
+            OperationType::Sha512Sum1 => {
+                cpu.set_register(self.dest,
+                                 src1.rotate_right(14) ^ src1.rotate_right(18) ^
+                                 src1.rotate_right(41));
+                return Ok(());
+            }
+            // Half of the 64-bit function, given that half in src1 and the
+            // other in src2. The shifts in sigma only differ for the high
+            // half.
+            OperationType::Sha512Sig0Low => {
+                (x >> 1) ^ (x >> 7) ^ (x >> 8) ^ (y << 31) ^ (y << 25) ^ (y << 24)
+            }
+            OperationType::Sha512Sig0High => {
+                (x >> 1) ^ (x >> 7) ^ (x >> 8) ^ (y << 31) ^ (y << 24)
+            }
+            OperationType::Sha512Sig1Low => {
+                (x << 3) ^ (x >> 6) ^ (x >> 19) ^ (y >> 29) ^ (y << 26) ^ (y << 13)
+            }
+            OperationType::Sha512Sig1High => {
+                (x << 3) ^ (x >> 6) ^ (x >> 19) ^ (y >> 29) ^ (y << 13)
+            }
+            OperationType::Sha512Sum0Half => {
+                (x << 25) ^ (x << 30) ^ (x >> 28) ^ (y >> 7) ^ (y >> 2) ^ (y << 4)
+            }
+            OperationType::Sha512Sum1Half => {
+                (x << 23) ^ (x >> 14) ^ (x >> 18) ^ (y >> 9) ^ (y << 18) ^ (y << 14)
+            }
+        };
+
+        // The 32-bit results are sign-extended on RV64.
+        cpu.set_register(self.dest, result as i32 as i64 as u64);
+        Ok(())
+    }
+
+    fn to_raw(&self) -> u32 {
+        let (opcode, funct7, funct3, rs2) = match self.typ {
+            OperationType::Sha256Sum0 => (0x13, 0x08, 0b001, 0b00000),
+            OperationType::Sha256Sum1 => (0x13, 0x08, 0b001, 0b00001),
+            OperationType::Sha256Sig0 => (0x13, 0x08, 0b001, 0b00010),
+            OperationType::Sha256Sig1 => (0x13, 0x08, 0b001, 0b00011),
+            OperationType::Sha512Sum0 => (0x13, 0x08, 0b001, 0b00100),
+            OperationType::Sha512Sum1 => (0x13, 0x08, 0b001, 0b00101),
+            OperationType::Sha512Sig0 => (0x13, 0x08, 0b001, 0b00110),
+            OperationType::Sha512Sig1 => (0x13, 0x08, 0b001, 0b00111),
+            OperationType::Sha512Sum0Half => (0x33, 0x28, 0b000, self.src2),
+            OperationType::Sha512Sum1Half => (0x33, 0x29, 0b000, self.src2),
+            OperationType::Sha512Sig0Low => (0x33, 0x2A, 0b000, self.src2),
+            OperationType::Sha512Sig1Low => (0x33, 0x2B, 0b000, self.src2),
+            OperationType::Sha512Sig0High => (0x33, 0x2E, 0b000, self.src2),
+            OperationType::Sha512Sig1High => (0x33, 0x2F, 0b000, self.src2),
+        };
+
+        encoding::R {
+            opcode,
+            funct7,
+            funct3,
+            rd: self.dest,
+            rs1: self.src1,
+            rs2,
+        }.to_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ram::RAM;
+    use cpu::CPU;
+    use isa::Isa;
+    use instruction::test_helpers::run_op;
+
+    // SHA-256's and SHA-512's first initial hash words
+    const H0_256: u64 = 0x6a09e667;
+    const H0_512: u64 = 0x6a09e667_f3bcc908;
+
+    #[test]
+    fn test_sha256() {
+        let mut cpu = CPU::new(RAM::new(1024));
+
+        // sha256sig0, sha256sig1, sha256sum0, sha256sum1 x1, x2
+        assert_eq!(run_op(&mut cpu, Op::parse, 0x10211093, H0_256, 0), 0xba0cf582);
+        assert_eq!(run_op(&mut cpu, Op::parse, 0x10311093, H0_256, 0), 0xcfe5da3c);
+        assert_eq!(run_op(&mut cpu, Op::parse, 0x10011093, H0_256, 0), 0xce20b47e);
+        assert_eq!(run_op(&mut cpu, Op::parse, 0x10111093, H0_256, 0), 0x55b65510);
+
+        cpu.set_isa(Isa::parse("rv64i_zknh").unwrap());
+        assert_eq!(run_op(&mut cpu, Op::parse, 0x10211093, H0_256, 0), 0xffffffff_ba0cf582);
+    }
+
+    #[test]
+    fn test_sha512() {
+        let mut cpu = CPU::new(RAM::new(1024));
+        cpu.set_isa(Isa::parse("rv64i_zknh").unwrap());
+
+        // sha512sig0, sha512sig1, sha512sum0, sha512sum1 x1, x2
+        let expected = [(0x10611093, 0x3dbae919_51caa1df),
+                        (0x10711093, 0xc8c619e7_3ee44510),
+                        (0x10411093, 0x08c4db56_aac80c2a),
+                        (0x10511093, 0x259a6cc1_643336ef)];
+        for &(raw, result) in &expected {
+            assert_eq!(run_op(&mut cpu, Op::parse, raw, H0_512, 0), result);
+        }
+
+        // RV32 gets the same halves with sha512sig0l/h, sha512sig1l/h and
+        // sha512sum0r and sha512sum1r x1, x2, x3.
+        cpu.set_isa(Isa::parse("rv32i_zknh").unwrap());
+        let (low, high) = (H0_512 & 0xFFFFFFFF, H0_512 >> 32);
+        let halves = [(0x543100b3, 0x5c3100b3), (0x563100b3, 0x5e3100b3),
+                      (0x503100b3, 0x503100b3), (0x523100b3, 0x523100b3)];
+        for (&(low_raw, high_raw), &(_, result)) in halves.iter().zip(&expected) {
+            assert_eq!(run_op(&mut cpu, Op::parse, low_raw, low, high), result & 0xFFFFFFFF);
+            assert_eq!(run_op(&mut cpu, Op::parse, high_raw, high, low), result >> 32);
+        }
+    }
+}
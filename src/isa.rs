@@ -0,0 +1,778 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! ISA strings, such as `rv32ima_zicsr_zifencei`, and the extensions they
+//! select.
+//!
+//! Strings are checked against the naming rules in the ISA manual: a base,
+//! then single-letter extensions in the canonical order `MAFDQLCBKJTPVH`,
+//! then multi-letter extensions separated by underscores. `Z` extensions come
+//! first, ordered by the single-letter category their second letter names
+//! (with `I` first) and then alphabetically; `S` and then `X` extensions
+//! follow, each alphabetically. Version numbers are accepted and ignored.
+//!
+//! `Zvl<N>b` isn't an extension of its own: it sets the vector registers'
+//! width, VLEN, to at least `N` bits.
+
+use std::error;
+use std::fmt;
+
+// Single-letter extensions, in canonical order.
+const CANONICAL_ORDER: &str = "mafdqlcbkjtpvh";
+
+const DEFAULT: &str = "rv32imac_zicbom_zicbop_zicboz_zicntr_zicond_zicsr_zifencei_zihintntl_\
+                       zihintpause_zihpm_zimop_zfa_zfh_zcb_zcmop_zcmp_zcmt_zba_zbb_zbc_zbkb_zbkc_\
+                       zbkx_zbs_zknd_zkne_zknh_zve64x_zvl128b_sdext_sdtrig_sscofpmf";
+
+// The widest vector registers `Zvl<N>b` can ask for.
+const MAX_VLEN: u32 = 65536;
+
+/// An extension the emulator implements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    I,
+    /// The embedded base: I with only 16 registers.
+    E,
+    M,
+    A,
+    /// Compressed instructions. Without F or D this is the same as Zca.
+    C,
+    /// Cache-block management. There are no caches, so this is mostly
+    /// about `menvcfg` permissions.
+    Zicbom,
+    /// Cache-block prefetch hints, which are ORIs to x0.
+    Zicbop,
+    /// Zeroing whole cache blocks.
+    Zicboz,
+    /// The `cycle`, `time` and `instret` counters.
+    Zicntr,
+    Zicond,
+    Zicsr,
+    Zifencei,
+    /// Non-temporal locality hints, which are ADDs to x0.
+    Zihintntl,
+    /// The PAUSE hint, which is a FENCE.
+    Zihintpause,
+    /// The programmable counters, `hpmcounter3`-`hpmcounter31`.
+    Zihpm,
+    /// May-be-operations, which write zero until something defines them.
+    Zimop,
+    /// Additional floating point instructions. Without F or D, only their
+    /// half-precision forms, so this needs Zfh.
+    Zfa,
+    /// Half-precision arithmetic. This implies Zfhmin.
+    Zfh,
+    /// Half-precision loads, stores and moves.
+    Zfhmin,
+    /// The compressed instructions that aren't floating point loads and
+    /// stores, which here is all of C.
+    Zca,
+    /// More compressed instructions: byte and halfword loads and stores,
+    /// and short forms of some from M, Zba and Zbb.
+    Zcb,
+    /// Compressed may-be-operations.
+    Zcmop,
+    /// Compressed pushes and pops of saved registers for function prologues
+    /// and epilogues.
+    Zcmp,
+    /// Compressed jumps through a table that `jvt` points to.
+    Zcmt,
+    Zba,
+    Zbb,
+    Zbc,
+    Zbkb,
+    Zbkc,
+    Zbkx,
+    Zbs,
+    Zknd,
+    Zkne,
+    Zknh,
+    /// Vectors with elements of up to 32 bits, and no floating point.
+    Zve32x,
+    /// Vectors with elements of up to 64 bits, and no floating point. This
+    /// implies Zve32x.
+    Zve64x,
+    /// Debug mode, which an external debugger halts the hart into.
+    Sdext,
+    /// The trigger module: hardware breakpoints, watchpoints and
+    /// instruction counting.
+    Sdtrig,
+    /// Overflow interrupts and privilege-mode filtering for the
+    /// programmable counters.
+    Sscofpmf,
+}
+
+impl Extension {
+    // In canonical order.
+    const ALL: [Extension; 39] = [Extension::I,
+                                  Extension::E,
+                                  Extension::M,
+                                  Extension::A,
+                                  Extension::C,
+                                  Extension::Zicbom,
+                                  Extension::Zicbop,
+                                  Extension::Zicboz,
+                                  Extension::Zicntr,
+                                  Extension::Zicond,
+                                  Extension::Zicsr,
+                                  Extension::Zifencei,
+                                  Extension::Zihintntl,
+                                  Extension::Zihintpause,
+                                  Extension::Zihpm,
+                                  Extension::Zimop,
+                                  Extension::Zfa,
+                                  Extension::Zfh,
+                                  Extension::Zfhmin,
+                                  Extension::Zca,
+                                  Extension::Zcb,
+                                  Extension::Zcmop,
+                                  Extension::Zcmp,
+                                  Extension::Zcmt,
+                                  Extension::Zba,
+                                  Extension::Zbb,
+                                  Extension::Zbc,
+                                  Extension::Zbkb,
+                                  Extension::Zbkc,
+                                  Extension::Zbkx,
+                                  Extension::Zbs,
+                                  Extension::Zknd,
+                                  Extension::Zkne,
+                                  Extension::Zknh,
+                                  Extension::Zve32x,
+                                  Extension::Zve64x,
+                                  Extension::Sdext,
+                                  Extension::Sdtrig,
+                                  Extension::Sscofpmf];
+
+    /// The extension's name in an ISA string, in lower case.
+    pub fn name(self) -> &'static str {
+        match self {
+            Extension::I => "i",
+            Extension::E => "e",
+            Extension::M => "m",
+            Extension::A => "a",
+            Extension::C => "c",
+            Extension::Zicbom => "zicbom",
+            Extension::Zicbop => "zicbop",
+            Extension::Zicboz => "zicboz",
+            Extension::Zicntr => "zicntr",
+            Extension::Zicond => "zicond",
+            Extension::Zicsr => "zicsr",
+            Extension::Zifencei => "zifencei",
+            Extension::Zihintntl => "zihintntl",
+            Extension::Zihintpause => "zihintpause",
+            Extension::Zihpm => "zihpm",
+            Extension::Zimop => "zimop",
+            Extension::Zfa => "zfa",
+            Extension::Zfh => "zfh",
+            Extension::Zfhmin => "zfhmin",
+            Extension::Zca => "zca",
+            Extension::Zcb => "zcb",
+            Extension::Zcmop => "zcmop",
+            Extension::Zcmp => "zcmp",
+            Extension::Zcmt => "zcmt",
+            Extension::Zba => "zba",
+            Extension::Zbb => "zbb",
+            Extension::Zbc => "zbc",
+            Extension::Zbkb => "zbkb",
+            Extension::Zbkc => "zbkc",
+            Extension::Zbkx => "zbkx",
+            Extension::Zbs => "zbs",
+            Extension::Zknd => "zknd",
+            Extension::Zkne => "zkne",
+            Extension::Zknh => "zknh",
+            Extension::Zve32x => "zve32x",
+            Extension::Zve64x => "zve64x",
+            Extension::Sdext => "sdext",
+            Extension::Sdtrig => "sdtrig",
+            Extension::Sscofpmf => "sscofpmf",
+        }
+    }
+
+    /// The extension's bit in `misa`, for single-letter extensions.
+    pub fn misa_bit(self) -> Option<u32> {
+        let name = self.name().as_bytes();
+        if name.len() == 1 {
+            Some(1 << (name[0] - b'a'))
+        } else {
+            None
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Extension> {
+        Extension::ALL.iter().cloned().find(|ext| ext.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IsaError {
+    /// The string isn't an ISA string at all, or has a malformed part.
+    Syntax(String),
+    /// An extension appears after one it should come before.
+    Order(String),
+    /// An extension appears more than once.
+    Duplicate(String),
+    /// A well-formed base or extension that the emulator doesn't implement.
+    Unsupported(String),
+    /// An extension that needs another one, which isn't there.
+    Requires(String, String),
+}
+
+impl fmt::Display for IsaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IsaError::Syntax(ref part) => write!(f, "malformed ISA string at \"{}\"", part),
+            IsaError::Order(ref name) => {
+                write!(f, "extension \"{}\" is out of canonical order", name)
+            }
+            IsaError::Duplicate(ref name) => write!(f, "extension \"{}\" appears twice", name),
+            IsaError::Unsupported(ref name) => write!(f, "\"{}\" isn't supported", name),
+            IsaError::Requires(ref name, ref other) => {
+                write!(f, "extension \"{}\" needs \"{}\"", name, other)
+            }
+        }
+    }
+}
+
+impl error::Error for IsaError {}
+
+/// The base ISA and set of extensions a hart implements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Isa {
+    xlen: u32,
+    extensions: u64,
+    // VLEN in bits, or 0 without vectors
+    vlen: u32,
+}
+
+impl Default for Isa {
+    /// RV32IMAC with Zicsr, Zifencei, the counters and Sscofpmf, debug mode
+    /// and triggers, the cache-block, conditional, hint and may-be-operation
+    /// extensions, half-precision floating point with Zfa, the other compressed
+    /// extensions, the bit-manipulation extensions, scalar cryptography and
+    /// 128-bit integer vectors: everything the emulator implements.
+    fn default() -> Isa {
+        Isa::parse(DEFAULT).unwrap()
+    }
+}
+
+impl Isa {
+    pub fn parse(isa: &str) -> Result<Isa, IsaError> {
+        let lower = isa.to_ascii_lowercase();
+        let rest = match lower.strip_prefix("rv") {
+            Some(rest) => rest,
+            None => return Err(IsaError::Syntax(isa.to_string())),
+        };
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let xlen = match &rest[..digits] {
+            "32" => 32,
+            "64" => 64,
+            "128" => return Err(IsaError::Unsupported(lower[..2 + digits].to_string())),
+            _ => return Err(IsaError::Syntax(isa.to_string())),
+        };
+
+        let mut names = Vec::new();
+        let mut rest = &rest[digits..];
+        // The position in canonical order of the last single-letter extension
+        let mut last = None;
+        match rest.chars().next() {
+            Some('i') => names.push("i".to_string()),
+            Some('e') => names.push("e".to_string()),
+            Some('g') => {
+                names.extend(["i", "m", "a", "f", "d", "zicsr", "zifencei"].iter()
+                    .map(|name| name.to_string()));
+                last = CANONICAL_ORDER.find('d');
+            }
+            _ => return Err(IsaError::Syntax(rest.to_string())),
+        }
+        rest = skip_version(&rest[1..]);
+
+        // Single-letter extensions, optionally separated by underscores
+        loop {
+            rest = rest.trim_start_matches('_');
+            let letter = match rest.chars().next() {
+                Some(letter) if !"zsx".contains(letter) => letter,
+                _ => break,
+            };
+            let position = match CANONICAL_ORDER.find(letter) {
+                Some(position) => position,
+                None => return Err(IsaError::Syntax(rest.to_string())),
+            };
+            if last.is_some_and(|last| position <= last) {
+                return Err(if names.contains(&letter.to_string()) {
+                    IsaError::Duplicate(letter.to_string())
+                } else {
+                    IsaError::Order(letter.to_string())
+                });
+            }
+            last = Some(position);
+            names.push(letter.to_string());
+            rest = skip_version(&rest[1..]);
+        }
+
+        // Multi-letter extensions, each after an underscore (or straight
+        // after the single-letter ones)
+        let mut last_key = None;
+        for part in rest.split('_').filter(|part| !part.is_empty()) {
+            let name = strip_version(part);
+            let key = match multi_letter_key(name) {
+                Some(key) => key,
+                None => return Err(IsaError::Syntax(part.to_string())),
+            };
+            if names.iter().any(|other| other == name) {
+                return Err(IsaError::Duplicate(name.to_string()));
+            }
+            if last_key.is_some_and(|last| key < last) {
+                return Err(IsaError::Order(name.to_string()));
+            }
+            last_key = Some(key);
+            names.push(name.to_string());
+        }
+
+        let mut extensions = 0;
+        let mut vlen = 0;
+        for name in &names {
+            if let Some(bits) = zvl_bits(name) {
+                vlen = vlen.max(bits);
+                continue;
+            }
+            match Extension::from_name(name) {
+                Some(ext) => extensions |= 1 << ext as u32,
+                None => return Err(IsaError::Unsupported(name.clone())),
+            }
+        }
+
+        // Zfh implies Zfhmin, and Zfa has nothing to work on without it. Both
+        // need F as well, but there isn't one, so they're taken without it.
+        if extensions & (1 << Extension::Zfh as u32) != 0 {
+            extensions |= 1 << Extension::Zfhmin as u32;
+        } else if extensions & (1 << Extension::Zfa as u32) != 0 {
+            return Err(IsaError::Requires("zfa".to_string(), "zfh".to_string()));
+        }
+
+        // Without F or D, C and Zca are the same thing, and the other Zc*
+        // extensions build on it.
+        let compressed = (1 << Extension::C as u32) | (1 << Extension::Zca as u32);
+        if extensions & compressed != 0 {
+            extensions |= compressed;
+        } else if let Some(&ext) = [Extension::Zcb, Extension::Zcmop, Extension::Zcmp,
+                                    Extension::Zcmt]
+            .iter()
+            .find(|&&ext| extensions & (1 << ext as u32) != 0) {
+            return Err(IsaError::Requires(ext.name().to_string(), "zca".to_string()));
+        }
+        // Zcmt's table base, the counters and the debug registers are CSRs,
+        // and Sscofpmf's interrupts come from the programmable counters.
+        for &(ext, needs) in &[(Extension::Zcmt, Extension::Zicsr),
+                               (Extension::Zicntr, Extension::Zicsr),
+                               (Extension::Zihpm, Extension::Zicsr),
+                               (Extension::Sdext, Extension::Zicsr),
+                               (Extension::Sdtrig, Extension::Zicsr),
+                               (Extension::Sscofpmf, Extension::Zihpm)] {
+            if extensions & (1 << ext as u32) != 0 && extensions & (1 << needs as u32) == 0 {
+                return Err(IsaError::Requires(ext.name().to_string(), needs.name().to_string()));
+            }
+        }
+
+        // Zve64x implies Zve32x, and each sets a minimum VLEN.
+        if extensions & (1 << Extension::Zve64x as u32) != 0 {
+            extensions |= 1 << Extension::Zve32x as u32;
+            vlen = vlen.max(64);
+        } else if extensions & (1 << Extension::Zve32x as u32) != 0 {
+            vlen = vlen.max(32);
+        } else if vlen != 0 {
+            let zvl = names.iter().find(|name| zvl_bits(name).is_some()).unwrap();
+            return Err(IsaError::Unsupported(zvl.clone()));
+        }
+        Ok(Isa {
+            xlen,
+            extensions,
+            vlen,
+        })
+    }
+
+    pub fn xlen(&self) -> u32 {
+        self.xlen
+    }
+
+    /// The same extensions on a base with registers `xlen` (32 or 64) bits
+    /// wide.
+    pub fn with_xlen(self, xlen: u32) -> Isa {
+        assert!(xlen == 32 || xlen == 64, "XLEN must be 32 or 64");
+        Isa {
+            xlen,
+            extensions: self.extensions,
+            vlen: self.vlen,
+        }
+    }
+
+    /// The same extensions on base `I` or `E`.
+    pub fn with_base(self, base: Extension) -> Isa {
+        assert!(base == Extension::I || base == Extension::E, "the base must be I or E");
+        let bases = (1 << Extension::I as u32) | (1 << Extension::E as u32);
+        Isa {
+            xlen: self.xlen,
+            extensions: (self.extensions & !bases) | (1 << base as u32),
+            vlen: self.vlen,
+        }
+    }
+
+    pub fn has(&self, ext: Extension) -> bool {
+        self.extensions & (1 << ext as u32) != 0
+    }
+
+    /// The base integer ISA: `I`, or `E` for the embedded one.
+    pub fn base(&self) -> Extension {
+        if self.has(Extension::E) {
+            Extension::E
+        } else {
+            Extension::I
+        }
+    }
+
+    /// The number of integer registers: 16 on RV32E, otherwise 32.
+    pub fn registers(&self) -> u8 {
+        match self.base() {
+            Extension::E => 16,
+            _ => 32,
+        }
+    }
+
+    /// Whether there are floating point registers, which any of the
+    /// floating point extensions brings.
+    pub fn has_float(&self) -> bool {
+        self.has(Extension::Zfhmin)
+    }
+
+    /// The width of the vector registers in bits, or 0 without vectors.
+    pub fn vlen(&self) -> u32 {
+        self.vlen
+    }
+
+    /// The widest vector element in bits, or 0 without vectors.
+    pub fn elen(&self) -> u32 {
+        if self.has(Extension::Zve64x) {
+            64
+        } else if self.has(Extension::Zve32x) {
+            32
+        } else {
+            0
+        }
+    }
+
+    /// The value `misa` resets to: the XLEN in MXL, the top two bits, and
+    /// every single-letter extension.
+    pub fn misa(&self) -> u64 {
+        let letters = Extension::ALL.iter()
+            .filter(|&&ext| self.has(ext))
+            .filter_map(|ext| ext.misa_bit())
+            .fold(0, |misa, bit| misa | bit as u64);
+        self.mxl() | letters
+    }
+
+    // MXL, in place at the top of `misa`.
+    pub(crate) fn mxl(&self) -> u64 {
+        match self.xlen {
+            32 => 1 << 30,
+            _ => 2 << 62,
+        }
+    }
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rv{}", self.xlen)?;
+        let (single, multi): (Vec<Extension>, Vec<Extension>) =
+            Extension::ALL.iter().filter(|&&ext| self.has(ext)).partition(|ext| {
+                ext.misa_bit().is_some()
+            });
+        for ext in single {
+            write!(f, "{}", ext.name())?;
+        }
+        let (z, s): (Vec<Extension>, Vec<Extension>) =
+            multi.into_iter().partition(|ext| ext.name().starts_with('z'));
+        for ext in z {
+            // Implied by Zfh, C and Zve64x
+            if (ext == Extension::Zfhmin && self.has(Extension::Zfh)) ||
+               ext == Extension::Zca ||
+               (ext == Extension::Zve32x && self.has(Extension::Zve64x)) {
+                continue;
+            }
+            write!(f, "_{}", ext.name())?;
+        }
+        if self.vlen > self.elen() {
+            write!(f, "_zvl{}b", self.vlen)?;
+        }
+        for ext in s {
+            write!(f, "_{}", ext.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// A RISC-V profile: a named set of extensions that software for it can
+/// count on having.
+///
+/// The emulator doesn't implement every extension the application profiles
+/// need, so `isa` is the closest it gets, and `missing` lists the rest. The
+/// extensions that only describe the platform, such as Ziccif, aren't
+/// tracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Rvi20u32,
+    Rvi20u64,
+    Rva20u64,
+    Rva22u64,
+    /// V is only there as its integer half, Zve64x.
+    Rva23u64,
+}
+
+impl Profile {
+    const ALL: [Profile; 5] = [Profile::Rvi20u32,
+                               Profile::Rvi20u64,
+                               Profile::Rva20u64,
+                               Profile::Rva22u64,
+                               Profile::Rva23u64];
+
+    /// The profile's name, in lower case.
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Rvi20u32 => "rvi20u32",
+            Profile::Rvi20u64 => "rvi20u64",
+            Profile::Rva20u64 => "rva20u64",
+            Profile::Rva22u64 => "rva22u64",
+            Profile::Rva23u64 => "rva23u64",
+        }
+    }
+
+    /// The profile called `name`, ignoring case.
+    pub fn from_name(name: &str) -> Option<Profile> {
+        let name = name.to_ascii_lowercase();
+        Profile::ALL.iter().cloned().find(|profile| profile.name() == name)
+    }
+
+    /// The profile's mandatory extensions that the emulator implements.
+    pub fn isa(self) -> Isa {
+        let isa = match self {
+            Profile::Rvi20u32 => "rv32i",
+            Profile::Rvi20u64 => "rv64i",
+            Profile::Rva20u64 => "rv64imac_zicntr_zicsr",
+            Profile::Rva22u64 => {
+                "rv64imac_zicbom_zicbop_zicboz_zicntr_zicsr_zihintpause_zihpm_zfhmin_zba_zbb_zbs"
+            }
+            Profile::Rva23u64 => {
+                "rv64imac_zicbom_zicbop_zicboz_zicntr_zicond_zicsr_zihintntl_zihintpause_zihpm_\
+                 zimop_zfhmin_zcb_zcmop_zba_zbb_zbs_zve64x_zvl128b"
+            }
+        };
+        Isa::parse(isa).unwrap()
+    }
+
+    /// The profile's mandatory extensions that the emulator doesn't
+    /// implement, and `isa` leaves out.
+    pub fn missing(self) -> &'static [&'static str] {
+        match self {
+            Profile::Rvi20u32 | Profile::Rvi20u64 => &[],
+            Profile::Rva20u64 | Profile::Rva22u64 => &["f", "d"],
+            // Zfa is here, but only with Zfh, which RVA23U64 doesn't have.
+            Profile::Rva23u64 => {
+                &["f", "d", "v", "zawrs", "zfa", "zvbb", "zvfhmin", "zvkt", "supm"]
+            }
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Skip a version number like `2` or `2p1` after a single-letter extension.
+fn skip_version(rest: &str) -> &str {
+    let rest = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+    match rest.strip_prefix('p') {
+        Some(minor) if minor.starts_with(|c: char| c.is_ascii_digit()) => {
+            minor.trim_start_matches(|c: char| c.is_ascii_digit())
+        }
+        _ => rest,
+    }
+}
+
+// Strip a version number like `1p0` from the end of a multi-letter
+// extension. Names can end in digits themselves, so only a full
+// major-and-minor version is taken off.
+fn strip_version(name: &str) -> &str {
+    let without_minor = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    if without_minor.len() == name.len() {
+        return name;
+    }
+    match without_minor.strip_suffix('p') {
+        Some(major) if major.ends_with(|c: char| c.is_ascii_digit()) => {
+            major.trim_end_matches(|c: char| c.is_ascii_digit())
+        }
+        _ => name,
+    }
+}
+
+// The VLEN a `Zvl<N>b` name asks for, if that's what it is. `N` has to be a
+// power of two from 32 up.
+fn zvl_bits(name: &str) -> Option<u32> {
+    let bits = name.strip_prefix("zvl")?.strip_suffix('b')?.parse::<u32>().ok()?;
+    if bits.is_power_of_two() && (32..=MAX_VLEN).contains(&bits) {
+        Some(bits)
+    } else {
+        None
+    }
+}
+
+// Where a multi-letter extension goes in canonical order.
+fn multi_letter_key(name: &str) -> Option<(usize, usize, &str)> {
+    if name.len() < 2 || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let category = name[1..].chars().next()?;
+    match name.chars().next()? {
+        'z' => {
+            let position = match category {
+                'i' => 0,
+                _ => CANONICAL_ORDER.find(category)? + 1,
+            };
+            Some((0, position, name))
+        }
+        's' => Some((1, 0, name)),
+        'x' => Some((2, 0, name)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let isa = Isa::parse("RV32IMAC_Zicbom_Zicbop_Zicboz_Zicntr_Zicond_Zicsr_Zifencei_\
+                              Zihintntl_Zihintpause_Zihpm_Zimop_Zfa_Zfh_Zcb_Zcmop_Zcmp_Zcmt_Zba_\
+                              Zbb_Zbc_Zbkb_Zbkc_Zbkx_Zbs_Zknd_Zkne_Zknh_Zve64x_Zvl128b_Sdext_\
+                              Sdtrig_Sscofpmf")
+            .unwrap();
+        assert_eq!(isa, Isa::default());
+        assert_eq!(isa.to_string(), DEFAULT);
+        assert_eq!(isa.misa(), 0x40001105);
+        assert_eq!((isa.vlen(), isa.elen()), (128, 64));
+
+        let isa = Isa::parse("rv32i2p1_m2p0_zicsr2p0").unwrap();
+        assert!(isa.has(Extension::M) && isa.has(Extension::Zicsr));
+        assert!(!isa.has(Extension::A) && !isa.has(Extension::Zifencei));
+        assert_eq!(isa.misa(), 0x40001100);
+
+        let isa = Isa::parse(&DEFAULT.replace("rv32", "rv64")).unwrap();
+        assert_eq!(isa.xlen(), 64);
+        assert_eq!(isa, Isa::default().with_xlen(64));
+        assert_eq!(isa.to_string(), DEFAULT.replace("rv32", "rv64"));
+        assert_eq!(isa.misa(), 0x8000000000001105);
+
+        let isa = Isa::parse("rv32em_zicsr").unwrap();
+        assert_eq!(isa.base(), Extension::E);
+        assert_eq!(isa.registers(), 16);
+        assert!(!isa.has(Extension::I));
+        assert_eq!(isa.to_string(), "rv32em_zicsr");
+        assert_eq!(isa.misa(), 0x40001010);
+        assert_eq!(isa.with_base(Extension::I), Isa::parse("rv32im_zicsr").unwrap());
+
+        let isa = Isa::parse("rv32i_zve32x_zve64x_zvl64b").unwrap();
+        assert!(isa.has(Extension::Zve32x));
+        assert_eq!((isa.vlen(), isa.elen()), (64, 64));
+        assert_eq!(isa.to_string(), "rv32i_zve64x");
+        let isa = Isa::parse("rv32i_zve32x_zvl1024b_zvl256b").unwrap();
+        assert_eq!((isa.vlen(), isa.elen()), (1024, 32));
+        assert_eq!(isa.to_string(), "rv32i_zve32x_zvl1024b");
+        let isa = Isa::parse("rv32i_zicsr_zihpm_zve32x_zvl64b_sscofpmf").unwrap();
+        assert_eq!(isa.to_string(), "rv32i_zicsr_zihpm_zve32x_zvl64b_sscofpmf");
+        assert_eq!(Isa::parse("rv32i").unwrap().vlen(), 0);
+
+        let isa = Isa::parse("rv32i_zfa_zfh").unwrap();
+        assert!(isa.has(Extension::Zfhmin) && isa.has_float());
+        assert_eq!(isa.to_string(), "rv32i_zfa_zfh");
+        assert!(!Isa::parse("rv32i_zfhmin").unwrap().has(Extension::Zfh));
+        assert!(!Isa::parse("rv32i").unwrap().has_float());
+
+        let isa = Isa::parse("rv32i_zca_zcb").unwrap();
+        assert!(isa.has(Extension::C));
+        assert_eq!(isa.to_string(), "rv32ic_zcb");
+        assert_eq!(isa.misa(), 0x40000104);
+    }
+
+    #[test]
+    fn test_profiles() {
+        assert_eq!(Profile::from_name("RVA23U64"), Some(Profile::Rva23u64));
+        assert_eq!(Profile::from_name("rva23s64"), None);
+
+        let isa = Profile::Rva23u64.isa();
+        assert_eq!(isa.xlen(), 64);
+        assert!(isa.has(Extension::Zicond) && isa.has(Extension::Zimop));
+        assert!(!isa.has(Extension::Zfh) && !isa.has(Extension::Zbc));
+        assert!(isa.has(Extension::Zcb) && !isa.has(Extension::Zcmp));
+        assert!(isa.has(Extension::Zihpm) && !isa.has(Extension::Sscofpmf));
+        assert_eq!(isa.misa(), 0x8000000000001105);
+        assert_eq!(Profile::Rvi20u32.isa().to_string(), "rv32i");
+
+        // Every profile's ISA is a subset of everything the emulator does,
+        // without the extensions it's missing.
+        for &profile in &Profile::ALL {
+            let isa = profile.isa();
+            let all = Isa::default().with_xlen(isa.xlen());
+            assert_eq!(isa.extensions & !all.extensions, 0, "{}", profile);
+            for name in profile.missing() {
+                assert!(!Extension::from_name(name).is_some_and(|ext| isa.has(ext)),
+                        "{} has {}",
+                        profile,
+                        name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |isa| Isa::parse(isa).unwrap_err();
+        assert_eq!(error("rv32iam"), IsaError::Order("m".to_string()));
+        assert_eq!(error("rv32imm"), IsaError::Duplicate("m".to_string()));
+        assert_eq!(error("rv32i_zifencei_zicsr"), IsaError::Order("zicsr".to_string()));
+        assert_eq!(error("rv32i_zicsr_zicsr"), IsaError::Duplicate("zicsr".to_string()));
+        assert_eq!(error("rv32i_xfoo_zicsr"), IsaError::Order("zicsr".to_string()));
+        assert_eq!(error("rv32i_zba_zicsr"), IsaError::Order("zicsr".to_string()));
+        assert_eq!(error("rv32imafdc_zicsr_zifencei"), IsaError::Unsupported("f".to_string()));
+        assert_eq!(error("rv32g"), IsaError::Unsupported("f".to_string()));
+        assert_eq!(error("rv32gm"), IsaError::Duplicate("m".to_string()));
+        assert_eq!(error("rv32g_zicsr"), IsaError::Duplicate("zicsr".to_string()));
+        assert_eq!(error("rv128i"), IsaError::Unsupported("rv128".to_string()));
+        assert_eq!(error("rv32i_zbc_zba"), IsaError::Order("zba".to_string()));
+        assert_eq!(error("rv32i_zknh_zbkb"), IsaError::Order("zbkb".to_string()));
+        assert_eq!(error("rv32i_zkn"), IsaError::Unsupported("zkn".to_string()));
+        assert_eq!(error("rv32i_zvl128b"), IsaError::Unsupported("zvl128b".to_string()));
+        assert_eq!(error("rv32i_zve32x_zvl96b"), IsaError::Unsupported("zvl96b".to_string()));
+        assert_eq!(error("rv32iv"), IsaError::Unsupported("v".to_string()));
+        assert_eq!(error("rv32ih"), IsaError::Unsupported("h".to_string()));
+        assert_eq!(error("rv32i_ztso"), IsaError::Unsupported("ztso".to_string()));
+        assert_eq!(error("rv32i_zba_zfh"), IsaError::Order("zfh".to_string()));
+        assert_eq!(error("rv32i_zfa_zfhmin"),
+                   IsaError::Requires("zfa".to_string(), "zfh".to_string()));
+        assert_eq!(error("rv32i_zcb"), IsaError::Requires("zcb".to_string(), "zca".to_string()));
+        assert_eq!(error("rv32ic_zcmt"),
+                   IsaError::Requires("zcmt".to_string(), "zicsr".to_string()));
+        assert_eq!(error("rv32i_zicntr"),
+                   IsaError::Requires("zicntr".to_string(), "zicsr".to_string()));
+        assert_eq!(error("rv32i_zicsr_sscofpmf"),
+                   IsaError::Requires("sscofpmf".to_string(), "zihpm".to_string()));
+        assert_eq!(error("rv32ie"), IsaError::Syntax("e".to_string()));
+        assert_eq!(error("rv32y"), IsaError::Syntax("y".to_string()));
+        assert_eq!(error("rv32iy"), IsaError::Syntax("y".to_string()));
+        assert_eq!(error("rv16i"), IsaError::Syntax("rv16i".to_string()));
+        assert_eq!(error("x86"), IsaError::Syntax("x86".to_string()));
+    }
+}
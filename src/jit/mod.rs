@@ -0,0 +1,488 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Translation of hot basic blocks into x86-64 machine code.
+//!
+//! Compiled blocks work directly on `CPU::regs` and on a `Context` describing
+//! where RAM lives. They only ever retire instructions that the interpreter
+//! would have retired without side effects beyond registers and RAM; anything
+//! else (a load or store outside RAM, a store to a page holding decoded code,
+//! a misaligned jump, or an instruction the compiler doesn't handle) leaves
+//! through an exit stub that hands the rest of the block to the interpreter,
+//! so traps stay precise.
+
+mod x86;
+
+use std::ptr;
+
+use libc;
+
+use instruction::{encoding, Decoded, Instruction};
+use self::x86::{mem, mem_index, Alu, Assembler, Cond, Label, Reg, Shift};
+
+/// How many times a block runs in the interpreter before it's compiled.
+pub const HOT_THRESHOLD: u32 = 16;
+
+const ARENA_SIZE: usize = 16 * 1024 * 1024;
+
+/// The block ran to completion and `Context::pc` holds the next pc.
+pub const EXIT_DONE: u32 = 0;
+/// The block stopped before instruction number `Context::retired`, which
+/// the interpreter has to execute.
+pub const EXIT_INTERPRET: u32 = 1;
+
+/// What compiled code needs to know about the machine, and how it reports
+/// back.
+#[repr(C)]
+pub struct Context {
+    pub ram: *mut u8,
+    pub ram_len: u64,
+    /// The decode cache's code page bitmap. Stores to those pages exit, so
+    /// the interpreter can invalidate whatever they overwrite.
+    pub code_pages: *const u64,
+    pub ram_base: u32,
+    pub pc: u32,
+    pub retired: u32,
+}
+
+const CTX_RAM_LEN: i32 = 8;
+const CTX_RAM_BASE: i32 = 24;
+const CTX_PC: i32 = 28;
+const CTX_RETIRED: i32 = 32;
+
+/// Compiled code: called with a pointer to the guest registers and the
+/// context, returns `EXIT_DONE` or `EXIT_INTERPRET`.
+pub type Code = unsafe extern "sysv64" fn(*mut u64, *mut Context) -> u32;
+
+/// The JIT's state for one basic block.
+#[derive(Clone, Copy)]
+pub enum Native {
+    /// Not compiled yet; counts how many times the block has run.
+    Cold(u32),
+    /// The block starts with something the compiler can't handle.
+    Failed,
+    Compiled(Code),
+}
+
+/// The executable memory compiled blocks live in. It's only ever appended
+/// to; once it fills up the CPU throws every block away and starts again.
+pub struct Jit {
+    arena: *mut u8,
+    used: usize,
+    full: bool,
+}
+
+impl Default for Jit {
+    fn default() -> Jit {
+        Jit::new()
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        if !self.arena.is_null() {
+            unsafe {
+                libc::munmap(self.arena as *mut libc::c_void, ARENA_SIZE);
+            }
+        }
+    }
+}
+
+impl Jit {
+    pub fn new() -> Jit {
+        Jit {
+            arena: ptr::null_mut(),
+            used: 0,
+            full: false,
+        }
+    }
+
+    /// Whether compilation failed for lack of space since the last `reset`.
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// Forget all compiled code. Nothing may call code compiled before this.
+    pub fn reset(&mut self) {
+        self.used = 0;
+        self.full = false;
+    }
+
+    /// Compile the block of `instrs` starting at `start`.
+    pub fn compile(&mut self, start: u32, instrs: &[Decoded]) -> Native {
+        let code = match translate(start, instrs) {
+            Some(code) => code,
+            None => return Native::Failed,
+        };
+
+        if self.arena.is_null() {
+            let arena = unsafe {
+                libc::mmap(ptr::null_mut(),
+                           ARENA_SIZE,
+                           libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                           libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                           -1,
+                           0)
+            };
+            if arena == libc::MAP_FAILED {
+                return Native::Failed;
+            }
+            self.arena = arena as *mut u8;
+        }
+
+        if self.used + code.len() > ARENA_SIZE {
+            self.full = true;
+            return Native::Cold(0);
+        }
+
+        unsafe {
+            let dst = self.arena.add(self.used);
+            ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+            // Keep entry points 16-byte aligned.
+            self.used = (self.used + code.len() + 15) & !15;
+            Native::Compiled(::std::mem::transmute::<*mut u8, Code>(dst))
+        }
+    }
+}
+
+// Register assignment inside compiled code.
+const REGS: Reg = Reg::Rbx;
+const CTX: Reg = Reg::R12;
+const RAM: Reg = Reg::R13;
+const CODE_PAGES: Reg = Reg::R14;
+const SAVED: [Reg; 4] = [Reg::Rbx, Reg::R12, Reg::R13, Reg::R14];
+
+struct Translator {
+    asm: Assembler,
+    epilogue: Label,
+    // Exit stubs to emit after the block, and the instruction they stop at.
+    exits: Vec<(Label, u32)>,
+}
+
+/// Translate as much of the block as we can. Returns `None` if we can't
+/// translate even the first instruction.
+fn translate(start: u32, instrs: &[Decoded]) -> Option<Vec<u8>> {
+    let mut asm = Assembler::new();
+    let epilogue = asm.new_label();
+    let mut t = Translator {
+        asm,
+        epilogue,
+        exits: Vec::new(),
+    };
+
+    for &reg in &SAVED {
+        t.asm.push(reg);
+    }
+    t.asm.mov_r64_r64(REGS, Reg::Rdi);
+    t.asm.mov_r64_r64(CTX, Reg::Rsi);
+    t.asm.mov_r64_m(RAM, mem(CTX, 0));
+    t.asm.mov_r64_m(CODE_PAGES, mem(CTX, 16));
+
+    let mut ended = false;
+    for (i, instr) in instrs.iter().enumerate() {
+        let i = i as u32;
+        let pc = start.wrapping_add(4 * i);
+        match t.instruction(i, pc, instr) {
+            Some(true) => {
+                ended = true;
+                break;
+            }
+            Some(false) => {}
+            None if i == 0 => return None,
+            None => {
+                let exit = t.exit(i);
+                t.asm.jmp(exit);
+                ended = true;
+                break;
+            }
+        }
+    }
+
+    if !ended {
+        let len = instrs.len() as u32;
+        t.exit_done(start.wrapping_add(4 * len), len);
+    }
+
+    let exits = ::std::mem::take(&mut t.exits);
+    for (label, i) in exits {
+        t.asm.bind(label);
+        t.asm.mov_m_imm32(mem(CTX, CTX_RETIRED), i);
+        t.asm.mov_r32_imm(Reg::Rax, EXIT_INTERPRET);
+        t.asm.jmp(t.epilogue);
+    }
+
+    t.asm.bind(t.epilogue);
+    for &reg in SAVED.iter().rev() {
+        t.asm.pop(reg);
+    }
+    t.asm.ret();
+
+    Some(t.asm.finish())
+}
+
+// Registers are 64 bits wide, but the JIT only runs RV32 code, whose
+// registers have their upper halves zero. It works on the lower halves.
+fn guest(reg: u8) -> x86::Mem {
+    mem(REGS, 8 * reg as i32)
+}
+
+impl Translator {
+    /// A label that leaves the block before instruction `i`.
+    fn exit(&mut self, i: u32) -> Label {
+        let label = self.asm.new_label();
+        self.exits.push((label, i));
+        label
+    }
+
+    fn exit_done(&mut self, next_pc: u32, retired: u32) {
+        self.asm.mov_m_imm32(mem(CTX, CTX_PC), next_pc);
+        self.asm.mov_m_imm32(mem(CTX, CTX_RETIRED), retired);
+        self.asm.mov_r32_imm(Reg::Rax, EXIT_DONE);
+        self.asm.jmp(self.epilogue);
+    }
+
+    fn load(&mut self, dst: Reg, reg: u8) {
+        if reg == 0 {
+            self.asm.mov_r32_imm(dst, 0);
+        } else {
+            self.asm.mov_r32_m(dst, guest(reg));
+        }
+    }
+
+    fn store(&mut self, reg: u8, src: Reg) {
+        if reg != 0 {
+            self.asm.mov_m_r32(guest(reg), src);
+        }
+    }
+
+    /// Leave eax holding `rs1 + offset`, and rcx the offset of that address
+    /// into RAM, exiting before instruction `i` unless all `size` bytes are
+    /// in RAM.
+    fn address(&mut self, i: u32, rs1: u8, offset: i32, size: u32) {
+        self.load(Reg::Rax, rs1);
+        self.asm.alu_r32_imm(Alu::Add, Reg::Rax, offset as u32);
+        self.asm.mov_r32_r32(Reg::Rcx, Reg::Rax);
+        self.asm.alu_r32_m(Alu::Sub, Reg::Rcx, mem(CTX, CTX_RAM_BASE));
+        self.asm.lea_r64_m(Reg::Rdx, mem(Reg::Rcx, size as i32));
+        self.asm.cmp_r64_m(Reg::Rdx, mem(CTX, CTX_RAM_LEN));
+        let exit = self.exit(i);
+        self.asm.jcc(Cond::Above, exit);
+    }
+
+    /// Emit code for one instruction. Returns whether it ended the block, or
+    /// `None` if we can't compile it.
+    fn instruction(&mut self, i: u32, pc: u32, instr: &Decoded) -> Option<bool> {
+        let raw = instr.to_raw();
+        match *instr {
+            Decoded::OpImm(_) => {
+                let d = encoding::I::parse(raw);
+                if d.rd == 0 {
+                    return Some(false);
+                }
+
+                let imm = d.immediate as u32;
+                self.load(Reg::Rax, d.rs1);
+                match d.funct3 {
+                    0b000 => self.asm.alu_r32_imm(Alu::Add, Reg::Rax, imm),
+                    0b010 | 0b011 => {
+                        self.asm.alu_r32_imm(Alu::Cmp, Reg::Rax, imm);
+                        let cond = if d.funct3 == 0b010 { Cond::Less } else { Cond::Below };
+                        self.asm.setcc(cond, Reg::Rax);
+                        self.asm.movzx8_r32_r8(Reg::Rax, Reg::Rax);
+                    }
+                    0b100 => self.asm.alu_r32_imm(Alu::Xor, Reg::Rax, imm),
+                    0b110 => self.asm.alu_r32_imm(Alu::Or, Reg::Rax, imm),
+                    0b111 => self.asm.alu_r32_imm(Alu::And, Reg::Rax, imm),
+                    0b001 => self.asm.shift_r32_imm(Shift::Left, Reg::Rax, (imm & 0x1F) as u8),
+                    _ => {
+                        let op = if imm & (1 << 10) != 0 {
+                            Shift::RightArithmetic
+                        } else {
+                            Shift::RightLogical
+                        };
+                        self.asm.shift_r32_imm(op, Reg::Rax, (imm & 0x1F) as u8);
+                    }
+                }
+                self.store(d.rd, Reg::Rax);
+            }
+            Decoded::Op(_) => {
+                let d = encoding::R::parse(raw);
+                if d.rd == 0 {
+                    return Some(false);
+                }
+
+                self.load(Reg::Rax, d.rs1);
+                self.load(Reg::Rcx, d.rs2);
+                match (d.funct3, d.funct7) {
+                    (0b000, 0x00) => self.asm.alu_r32_r32(Alu::Add, Reg::Rax, Reg::Rcx),
+                    (0b000, _) => self.asm.alu_r32_r32(Alu::Sub, Reg::Rax, Reg::Rcx),
+                    (0b001, _) => self.asm.shift_r32_cl(Shift::Left, Reg::Rax),
+                    (0b010, _) | (0b011, _) => {
+                        self.asm.alu_r32_r32(Alu::Cmp, Reg::Rax, Reg::Rcx);
+                        let cond = if d.funct3 == 0b010 { Cond::Less } else { Cond::Below };
+                        self.asm.setcc(cond, Reg::Rax);
+                        self.asm.movzx8_r32_r8(Reg::Rax, Reg::Rax);
+                    }
+                    (0b100, _) => self.asm.alu_r32_r32(Alu::Xor, Reg::Rax, Reg::Rcx),
+                    (0b101, 0x00) => self.asm.shift_r32_cl(Shift::RightLogical, Reg::Rax),
+                    (0b101, _) => self.asm.shift_r32_cl(Shift::RightArithmetic, Reg::Rax),
+                    (0b110, _) => self.asm.alu_r32_r32(Alu::Or, Reg::Rax, Reg::Rcx),
+                    _ => self.asm.alu_r32_r32(Alu::And, Reg::Rax, Reg::Rcx),
+                }
+                self.store(d.rd, Reg::Rax);
+            }
+            Decoded::MulDiv(_) => {
+                let d = encoding::R::parse(raw);
+                // x86 division faults where RISC-V's doesn't, so leave
+                // DIV[U] and REM[U] to the interpreter.
+                if d.funct3 >= 0b100 {
+                    return None;
+                }
+                if d.rd == 0 {
+                    return Some(false);
+                }
+
+                self.load(Reg::Rax, d.rs1);
+                self.load(Reg::Rcx, d.rs2);
+                if d.funct3 == 0b000 {
+                    self.asm.imul_r32_r32(Reg::Rax, Reg::Rcx);
+                } else {
+                    // Extend both operands to 64 bits as MULH, MULHSU or MULHU
+                    // want, and take the top half of the 64-bit product.
+                    if d.funct3 == 0b011 {
+                        self.asm.mov_r32_r32(Reg::Rax, Reg::Rax);
+                    } else {
+                        self.asm.movsxd_r64_r32(Reg::Rax, Reg::Rax);
+                    }
+                    if d.funct3 == 0b001 {
+                        self.asm.movsxd_r64_r32(Reg::Rcx, Reg::Rcx);
+                    } else {
+                        self.asm.mov_r32_r32(Reg::Rcx, Reg::Rcx);
+                    }
+                    self.asm.imul_r64_r64(Reg::Rax, Reg::Rcx);
+                    self.asm.shift_r64_imm(Shift::RightLogical, Reg::Rax, 32);
+                }
+                self.store(d.rd, Reg::Rax);
+            }
+            Decoded::Lui(_) => {
+                let d = encoding::U::parse(raw);
+                if d.rd != 0 {
+                    self.asm.mov_m_imm32(guest(d.rd), d.immediate as u32);
+                }
+            }
+            Decoded::Auipc(_) => {
+                let d = encoding::U::parse(raw);
+                if d.rd != 0 {
+                    self.asm.mov_m_imm32(guest(d.rd), pc.wrapping_add(d.immediate as u32));
+                }
+            }
+            Decoded::Load(_) => {
+                let d = encoding::I::parse(raw);
+                let size = match d.funct3 {
+                    0b000 | 0b100 => 1,
+                    0b001 | 0b101 => 2,
+                    _ => 4,
+                };
+                self.address(i, d.rs1, d.immediate, size);
+
+                let src = mem_index(RAM, Reg::Rcx, 0);
+                match d.funct3 {
+                    0b000 => self.asm.movsx8_r32_m(Reg::Rax, src),
+                    0b001 => self.asm.movsx16_r32_m(Reg::Rax, src),
+                    0b100 => self.asm.movzx8_r32_m(Reg::Rax, src),
+                    0b101 => self.asm.movzx16_r32_m(Reg::Rax, src),
+                    _ => self.asm.mov_r32_m(Reg::Rax, src),
+                }
+                self.store(d.rd, Reg::Rax);
+            }
+            Decoded::Store(_) => {
+                let d = encoding::S::parse(raw);
+                let size = 1 << d.funct3;
+                self.address(i, d.rs1, d.immediate, size);
+
+                // Leave stores to pages with decoded instructions on them to
+                // the interpreter, checking both ends in case the store
+                // straddles two pages.
+                let exit = self.exit(i);
+                for &last in &[0, size - 1] {
+                    self.asm.mov_r32_r32(Reg::Rdx, Reg::Rax);
+                    self.asm.alu_r32_imm(Alu::Add, Reg::Rdx, last);
+                    self.asm.shift_r32_imm(Shift::RightLogical, Reg::Rdx, 12);
+                    self.asm.bt_m_r64(mem(CODE_PAGES, 0), Reg::Rdx);
+                    self.asm.jc(exit);
+                }
+
+                self.load(Reg::Rdx, d.rs2);
+                let dst = mem_index(RAM, Reg::Rcx, 0);
+                match size {
+                    1 => self.asm.mov_m_r8(dst, Reg::Rdx),
+                    2 => self.asm.mov_m_r16(dst, Reg::Rdx),
+                    _ => self.asm.mov_m_r32(dst, Reg::Rdx),
+                }
+            }
+            Decoded::Branch(_) => {
+                let d = encoding::SB::parse(raw);
+                let target = pc.wrapping_add(d.immediate as u32);
+                if target & 0b11 != 0 {
+                    return None;
+                }
+
+                let cond = match d.funct3 {
+                    0b000 => Cond::Equal,
+                    0b001 => Cond::NotEqual,
+                    0b100 => Cond::Less,
+                    0b101 => Cond::GreaterOrEqual,
+                    0b110 => Cond::Below,
+                    _ => Cond::AboveOrEqual,
+                };
+                self.load(Reg::Rax, d.rs1);
+                self.load(Reg::Rcx, d.rs2);
+                self.asm.alu_r32_r32(Alu::Cmp, Reg::Rax, Reg::Rcx);
+                let taken = self.asm.new_label();
+                self.asm.jcc(cond, taken);
+                self.exit_done(pc.wrapping_add(4), i + 1);
+                self.asm.bind(taken);
+                self.exit_done(target, i + 1);
+                return Some(true);
+            }
+            Decoded::Jal(_) => {
+                let d = encoding::UJ::parse(raw);
+                let target = pc.wrapping_add(d.immediate as u32);
+                if target & 0b11 != 0 {
+                    return None;
+                }
+
+                if d.rd != 0 {
+                    self.asm.mov_m_imm32(guest(d.rd), pc.wrapping_add(4));
+                }
+                self.exit_done(target, i + 1);
+                return Some(true);
+            }
+            Decoded::Jalr(_) => {
+                let d = encoding::I::parse(raw);
+                self.load(Reg::Rax, d.rs1);
+                self.asm.alu_r32_imm(Alu::Add, Reg::Rax, d.immediate as u32);
+                self.asm.alu_r32_imm(Alu::And, Reg::Rax, !1);
+                self.asm.test_r32_imm(Reg::Rax, 0b11);
+                let exit = self.exit(i);
+                self.asm.jcc(Cond::NotEqual, exit);
+
+                if d.rd != 0 {
+                    self.asm.mov_m_imm32(guest(d.rd), pc.wrapping_add(4));
+                }
+                self.asm.mov_m_r32(mem(CTX, CTX_PC), Reg::Rax);
+                self.asm.mov_m_imm32(mem(CTX, CTX_RETIRED), i + 1);
+                self.asm.mov_r32_imm(Reg::Rax, EXIT_DONE);
+                self.asm.jmp(self.epilogue);
+                return Some(true);
+            }
+            _ => return None,
+        }
+
+        Some(false)
+    }
+}
@@ -0,0 +1,354 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Just enough of an x86-64 assembler for the JIT. Memory operands always use
+//! a 32-bit displacement, which wastes a few bytes but avoids the special
+//! cases around rbp/r13 bases.
+
+// The JIT doesn't use all of these, but an assembler without them would be
+// odd.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsp = 4,
+    Rbp = 5,
+    Rsi = 6,
+    Rdi = 7,
+    R8 = 8,
+    R9 = 9,
+    R10 = 10,
+    R11 = 11,
+    R12 = 12,
+    R13 = 13,
+    R14 = 14,
+    R15 = 15,
+}
+
+/// `[base + index + disp]`.
+#[derive(Debug, Clone, Copy)]
+pub struct Mem {
+    base: Reg,
+    index: Option<Reg>,
+    disp: i32,
+}
+
+pub fn mem(base: Reg, disp: i32) -> Mem {
+    Mem {
+        base,
+        index: None,
+        disp,
+    }
+}
+
+pub fn mem_index(base: Reg, index: Reg, disp: i32) -> Mem {
+    assert!(index != Reg::Rsp, "rsp can't be an index register");
+    Mem {
+        base,
+        index: Some(index),
+        disp,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Cond {
+    Below = 0x2,
+    AboveOrEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+    Above = 0x7,
+    Less = 0xC,
+    GreaterOrEqual = 0xD,
+}
+
+/// The classic two-operand ALU instructions, numbered by their `/digit`.
+#[derive(Debug, Clone, Copy)]
+pub enum Alu {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+/// Shifts, numbered by their `/digit`.
+#[derive(Debug, Clone, Copy)]
+pub enum Shift {
+    Left = 4,
+    RightLogical = 5,
+    RightArithmetic = 7,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Label(usize);
+
+#[derive(Default)]
+pub struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    // Offsets of rel32 fields to patch, and the label they refer to.
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, imm: u32) {
+        self.bytes(&[imm as u8, (imm >> 8) as u8, (imm >> 16) as u8, (imm >> 24) as u8]);
+    }
+
+    fn rex(&mut self, w: bool, reg: u8, index: u8, base: u8) {
+        let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | (base >> 3);
+        if rex != 0x40 {
+            self.byte(rex);
+        }
+    }
+
+    fn op_mem(&mut self, w: bool, opcode: &[u8], reg: u8, m: Mem) {
+        let base = m.base as u8;
+        let index = m.index.map(|r| r as u8);
+        self.rex(w, reg, index.unwrap_or(0), base);
+        self.bytes(opcode);
+
+        if index.is_none() && base & 7 != 4 {
+            self.byte(0x80 | (reg & 7) << 3 | (base & 7));
+        } else {
+            self.byte(0x80 | (reg & 7) << 3 | 4);
+            // An index of 0b100 without REX.X means no index.
+            self.byte((index.unwrap_or(4) & 7) << 3 | (base & 7));
+        }
+        self.imm32(m.disp as u32);
+    }
+
+    fn op_reg(&mut self, w: bool, opcode: &[u8], reg: u8, rm: Reg) {
+        let rm = rm as u8;
+        self.rex(w, reg, 0, rm);
+        self.bytes(opcode);
+        self.byte(0xC0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    pub fn mov_r32_m(&mut self, dst: Reg, m: Mem) {
+        self.op_mem(false, &[0x8B], dst as u8, m);
+    }
+
+    pub fn mov_r64_m(&mut self, dst: Reg, m: Mem) {
+        self.op_mem(true, &[0x8B], dst as u8, m);
+    }
+
+    pub fn mov_m_r32(&mut self, m: Mem, src: Reg) {
+        self.op_mem(false, &[0x89], src as u8, m);
+    }
+
+    pub fn mov_m_r16(&mut self, m: Mem, src: Reg) {
+        self.byte(0x66);
+        self.op_mem(false, &[0x89], src as u8, m);
+    }
+
+    /// Only the low byte registers that don't need a REX prefix (al, cl, dl,
+    /// bl) can be stored.
+    pub fn mov_m_r8(&mut self, m: Mem, src: Reg) {
+        assert!((src as u8) < 4, "only al, cl, dl and bl can be stored");
+        self.op_mem(false, &[0x88], src as u8, m);
+    }
+
+    pub fn mov_m_imm32(&mut self, m: Mem, imm: u32) {
+        self.op_mem(false, &[0xC7], 0, m);
+        self.imm32(imm);
+    }
+
+    pub fn mov_r32_imm(&mut self, dst: Reg, imm: u32) {
+        self.rex(false, 0, 0, dst as u8);
+        self.byte(0xB8 + (dst as u8 & 7));
+        self.imm32(imm);
+    }
+
+    /// Also zeroes the top half of `dst`.
+    pub fn mov_r32_r32(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(false, &[0x89], src as u8, dst);
+    }
+
+    pub fn mov_r64_r64(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(true, &[0x89], src as u8, dst);
+    }
+
+    pub fn movsxd_r64_r32(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(true, &[0x63], dst as u8, src);
+    }
+
+    pub fn movzx8_r32_m(&mut self, dst: Reg, m: Mem) {
+        self.op_mem(false, &[0x0F, 0xB6], dst as u8, m);
+    }
+
+    pub fn movsx8_r32_m(&mut self, dst: Reg, m: Mem) {
+        self.op_mem(false, &[0x0F, 0xBE], dst as u8, m);
+    }
+
+    pub fn movzx16_r32_m(&mut self, dst: Reg, m: Mem) {
+        self.op_mem(false, &[0x0F, 0xB7], dst as u8, m);
+    }
+
+    pub fn movsx16_r32_m(&mut self, dst: Reg, m: Mem) {
+        self.op_mem(false, &[0x0F, 0xBF], dst as u8, m);
+    }
+
+    /// Zero-extend the low byte of `src` (al, cl, dl or bl) into `dst`.
+    pub fn movzx8_r32_r8(&mut self, dst: Reg, src: Reg) {
+        assert!((src as u8) < 4, "only al, cl, dl and bl can be extended");
+        self.op_reg(false, &[0x0F, 0xB6], dst as u8, src);
+    }
+
+    pub fn lea_r64_m(&mut self, dst: Reg, m: Mem) {
+        self.op_mem(true, &[0x8D], dst as u8, m);
+    }
+
+    pub fn alu_r32_r32(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.op_reg(false, &[(op as u8) << 3 | 1], src as u8, dst);
+    }
+
+    pub fn alu_r32_imm(&mut self, op: Alu, dst: Reg, imm: u32) {
+        self.op_reg(false, &[0x81], op as u8, dst);
+        self.imm32(imm);
+    }
+
+    pub fn alu_r32_m(&mut self, op: Alu, dst: Reg, m: Mem) {
+        self.op_mem(false, &[(op as u8) << 3 | 3], dst as u8, m);
+    }
+
+    pub fn cmp_r64_m(&mut self, reg: Reg, m: Mem) {
+        self.op_mem(true, &[0x3B], reg as u8, m);
+    }
+
+    pub fn test_r32_imm(&mut self, reg: Reg, imm: u32) {
+        self.op_reg(false, &[0xF7], 0, reg);
+        self.imm32(imm);
+    }
+
+    pub fn shift_r32_imm(&mut self, op: Shift, dst: Reg, amount: u8) {
+        self.op_reg(false, &[0xC1], op as u8, dst);
+        self.byte(amount);
+    }
+
+    pub fn shift_r64_imm(&mut self, op: Shift, dst: Reg, amount: u8) {
+        self.op_reg(true, &[0xC1], op as u8, dst);
+        self.byte(amount);
+    }
+
+    /// Shift by cl.
+    pub fn shift_r32_cl(&mut self, op: Shift, dst: Reg) {
+        self.op_reg(false, &[0xD3], op as u8, dst);
+    }
+
+    pub fn imul_r32_r32(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(false, &[0x0F, 0xAF], dst as u8, src);
+    }
+
+    pub fn imul_r64_r64(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(true, &[0x0F, 0xAF], dst as u8, src);
+    }
+
+    /// Set the low byte of `dst` (al, cl, dl or bl) to 1 if `cond` holds.
+    pub fn setcc(&mut self, cond: Cond, dst: Reg) {
+        assert!((dst as u8) < 4, "only al, cl, dl and bl can be set");
+        self.op_reg(false, &[0x0F, 0x90 | cond as u8], 0, dst);
+    }
+
+    /// Test the bit numbered `bit` in the bit string starting at `m`.
+    pub fn bt_m_r64(&mut self, m: Mem, bit: Reg) {
+        self.op_mem(true, &[0x0F, 0xA3], bit as u8, m);
+    }
+
+    pub fn push(&mut self, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8);
+        self.byte(0x50 + (reg as u8 & 7));
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8);
+        self.byte(0x58 + (reg as u8 & 7));
+    }
+
+    pub fn ret(&mut self) {
+        self.byte(0xC3);
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// Jump if carry, i.e. after `bt` found the bit set.
+    pub fn jc(&mut self, label: Label) {
+        self.jcc(Cond::Below, label);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.bytes(&[0x0F, 0x80 | cond as u8]);
+        self.fixups.push((self.code.len(), label));
+        self.imm32(0);
+    }
+
+    pub fn jmp(&mut self, label: Label) {
+        self.byte(0xE9);
+        self.fixups.push((self.code.len(), label));
+        self.imm32(0);
+    }
+
+    /// The machine code, with all jumps resolved. Panics if a label that's
+    /// jumped to was never bound.
+    pub fn finish(mut self) -> Vec<u8> {
+        for &(offset, label) in &self.fixups {
+            let target = self.labels[label.0].expect("jump to unbound label");
+            let rel = target as i64 - (offset as i64 + 4);
+            let rel = rel as i32 as u32;
+            self.code[offset..offset + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        self.code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let mut asm = Assembler::new();
+        asm.mov_r32_m(Reg::Rax, mem(Reg::Rbx, 8)); // mov eax, [rbx+8]
+        asm.mov_m_r32(mem(Reg::R12, 0x1c), Reg::Rax); // mov [r12+0x1c], eax
+        asm.movzx8_r32_m(Reg::Rax, mem_index(Reg::R13, Reg::Rcx, 0)); // movzx eax, byte [r13+rcx]
+        asm.bt_m_r64(mem(Reg::R14, 0), Reg::Rdx); // bt [r14], rdx
+        asm.alu_r32_r32(Alu::Sub, Reg::Rax, Reg::Rcx); // sub eax, ecx
+        asm.push(Reg::R15);
+        let code = asm.finish();
+
+        assert_eq!(code,
+                   vec![0x8B, 0x83, 8, 0, 0, 0,
+                        0x41, 0x89, 0x84, 0x24, 0x1c, 0, 0, 0,
+                        0x41, 0x0F, 0xB6, 0x84, 0x0D, 0, 0, 0, 0,
+                        0x49, 0x0F, 0xA3, 0x96, 0, 0, 0, 0,
+                        0x29, 0xC8,
+                        0x41, 0x57]);
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A RISC-V emulator.
+//!
+//! The quickest way in is `Machine::builder()`, which loads an ELF image into
+//! a fresh `CPU`. Embedders that want more control can assemble a `Bus` and
+//! `CPU` themselves and drive them with `CPU::step` and `CPU::run_for`.
+
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", unix))))]
+compile_error!("the jit feature needs an x86-64 Unix host");
+
+extern crate elf;
+#[cfg(feature = "jit")]
+extern crate libc;
+
+pub mod bus;
+pub mod clint;
+pub mod counters;
+pub mod cpu;
+pub mod debug;
+pub mod decode_cache;
+pub mod float;
+pub mod gdb;
+pub mod hooks;
+pub mod htif;
+pub mod instruction;
+pub mod isa;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod loader;
+pub mod machine;
+pub mod ram;
+pub mod replay;
+pub mod smp;
+pub mod snapshot;
+pub mod softfloat;
+pub mod trap;
+pub mod vector;
+
+pub use bus::{Bus, Device, DeviceEvent, Memory};
+pub use clint::Clint;
+pub use counters::Event;
+pub use debug::DebugCause;
+pub use float::FloatState;
+pub use cpu::{CPU, HaltCondition, Privilege};
+pub use hooks::{CsrAccess, HookAction, Hooks, MemoryAccess, TrapEvent};
+pub use htif::Htif;
+pub use isa::{Extension, Isa, IsaError, Profile};
+pub use loader::{LoadError, Program};
+pub use machine::{Machine, MachineBuilder};
+pub use ram::RAM;
+pub use replay::{Recording, ReplayError, Replayer};
+pub use smp::{Schedule, Scheduler};
+pub use snapshot::SnapshotError;
+pub use trap::{Access, Exception, Interrupt, StopReason};
+pub use vector::VectorState;
@@ -0,0 +1,159 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use elf;
+
+use bus::Bus;
+use snapshot::SnapshotError;
+
+// The e_flags bit for programs built for RV32E.
+const EF_RISCV_RVE: u32 = 0x8;
+
+/// What the loader learned about a program while placing it in memory.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub entry_point: u32,
+    /// 32 or 64, from the ELF class.
+    pub xlen: u32,
+    /// Whether the ELF header says the program uses the RV32E registers and
+    /// the ilp32e ABI.
+    pub rve: bool,
+    /// The addresses of the ELF image's defined symbols, by name.
+    pub symbols: HashMap<String, u32>,
+}
+
+impl Program {
+    /// An RV32 program with no symbols, entered at `entry_point`.
+    pub fn new(entry_point: u32) -> Program {
+        Program {
+            entry_point,
+            xlen: 32,
+            rve: false,
+            symbols: HashMap::new(),
+        }
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).cloned()
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Elf(elf::ParseError),
+    /// A segment's file contents extend past the end of the file.
+    Truncated,
+    /// A segment would be loaded at an address with no memory behind it.
+    OutOfMemory(u64),
+    /// The program is for a different XLEN than the ISA it was to run on.
+    Xlen(u32),
+    Snapshot(SnapshotError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io(ref e) => write!(f, "couldn't read file: {}", e),
+            LoadError::Elf(ref e) => write!(f, "couldn't parse ELF file: {:?}", e),
+            LoadError::Truncated => write!(f, "ELF segment extends past the end of the file"),
+            LoadError::OutOfMemory(addr) => {
+                write!(f, "ELF segment loads to 0x{:08x}, which isn't in memory", addr)
+            }
+            LoadError::Xlen(xlen) => write!(f, "program is RV{}, but the ISA isn't", xlen),
+            LoadError::Snapshot(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> LoadError {
+        LoadError::Io(e)
+    }
+}
+
+impl From<SnapshotError> for LoadError {
+    fn from(e: SnapshotError) -> LoadError {
+        LoadError::Snapshot(e)
+    }
+}
+
+impl From<elf::ParseError> for LoadError {
+    fn from(e: elf::ParseError) -> LoadError {
+        LoadError::Elf(e)
+    }
+}
+
+pub fn load_elf<P: AsRef<Path>>(path: P, bus: &mut Bus) -> Result<Program, LoadError> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    load_elf_bytes(&data, bus)
+}
+
+/// Copy every loadable segment of the ELF image in `data` onto `bus`, zeroing
+/// the part of each segment that isn't backed by the file.
+pub fn load_elf_bytes(data: &[u8], bus: &mut Bus) -> Result<Program, LoadError> {
+    let elf_file = elf::File::open_stream(&mut Cursor::new(data))?;
+    let xlen = if elf_file.ehdr.class == elf::types::ELFCLASS64 { 64 } else { 32 };
+
+    for program_header in &elf_file.phdrs {
+        if program_header.progtype != elf::types::PT_LOAD {
+            continue;
+        }
+
+        let start = program_header.offset as usize;
+        let contents = start.checked_add(program_header.filesz as usize)
+            .and_then(|end| data.get(start..end))
+            .ok_or(LoadError::Truncated)?;
+
+        for i in 0..program_header.memsz {
+            // Addresses are 32 bits wide, even on RV64.
+            let addr = program_header.vaddr.wrapping_add(i);
+            let byte = contents.get(i as usize).cloned().unwrap_or(0);
+            if addr > u32::MAX as u64 || bus.write(addr as u32, 1, byte as u32).is_none() {
+                return Err(LoadError::OutOfMemory(addr));
+            }
+        }
+    }
+
+    let mut program = Program::new(elf_file.ehdr.entry as u32);
+    program.xlen = xlen;
+    program.rve = flags(data, xlen) & EF_RISCV_RVE != 0;
+    for section in &elf_file.sections {
+        if section.shdr.shtype != elf::types::SHT_SYMTAB {
+            continue;
+        }
+        for symbol in elf_file.get_symbols(section)? {
+            // Global symbols win over local ones of the same name.
+            let shadowed = symbol.bind == elf::types::STB_LOCAL &&
+                           program.symbols.contains_key(&symbol.name);
+            if symbol.name.is_empty() || symbol.shndx == 0 || shadowed {
+                continue;
+            }
+            program.symbols.insert(symbol.name, symbol.value as u32);
+        }
+    }
+
+    Ok(program)
+}
+
+// The ELF header's e_flags, which the elf crate doesn't keep. The header has
+// already been parsed, so it's all there.
+fn flags(data: &[u8], xlen: u32) -> u32 {
+    let at = if xlen == 64 { 0x30 } else { 0x24 };
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
@@ -0,0 +1,354 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bus::Bus;
+use clint::{Clint, CLINT_BASE, CLINT_SIZE};
+use cpu::CPU;
+use htif::Htif;
+use isa::{Extension, Isa};
+use loader::{self, LoadError, Program};
+use ram::RAM;
+use smp::{Schedule, Scheduler};
+use snapshot::{self, Reader, SnapshotError, Writer};
+use trap::StopReason;
+
+/// A CPU together with the program that was loaded into its memory.
+///
+/// A machine built with more than one hart also has a CLINT, and `harts`
+/// holds harts 1 and up; `cpu` is always hart 0, and holds the shared bus
+/// whenever the machine isn't running. A program with a `tohost` symbol gets
+/// an HTIF there.
+pub struct Machine {
+    pub cpu: CPU,
+    pub harts: Vec<CPU>,
+    pub clint: Option<Clint>,
+    pub htif: Option<Htif>,
+    pub program: Program,
+    scheduler: Scheduler,
+}
+
+impl Machine {
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::new()
+    }
+
+    /// Run the loaded program from its entry point until something stops
+    /// execution.
+    pub fn run(&mut self) -> StopReason {
+        self.run_harts().1
+    }
+
+    /// Like `run`, but also says which hart stopped execution.
+    pub fn run_harts(&mut self) -> (usize, StopReason) {
+        self.start();
+        self.resume()
+    }
+
+    /// Point every hart at the program's entry point with a return address
+    /// of 0, ready for `run_for` or `resume`.
+    pub fn start(&mut self) {
+        let entry = self.program.entry_point;
+        for hart in Some(&mut self.cpu).into_iter().chain(&mut self.harts) {
+            hart.set_register(1, 0);
+            hart.pc = entry;
+        }
+        self.scheduler.reset();
+    }
+
+    /// Carry on from wherever the harts are until something stops execution.
+    pub fn resume(&mut self) -> (usize, StopReason) {
+        loop {
+            match self.run_for(u64::MAX) {
+                (_, StopReason::InstructionLimit) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Carry on from wherever the harts are for at most `instructions`
+    /// instructions between them. Returns the hart that stopped execution and
+    /// why, or hart 0 and `InstructionLimit`.
+    pub fn run_for(&mut self, instructions: u64) -> (usize, StopReason) {
+        match self.clint {
+            Some(ref clint) => {
+                self.scheduler.run(&mut self.cpu, &mut self.harts, clint, instructions)
+            }
+            None => (0, self.cpu.run_for(instructions)),
+        }
+    }
+
+    /// How many instructions the harts have retired between them.
+    pub fn instret(&self) -> u64 {
+        self.cpu.cycles() + self.harts.iter().map(|hart| hart.cycles()).sum::<u64>()
+    }
+
+    /// The memory between the program's `begin_signature` and
+    /// `end_signature` symbols, in the format of riscv-arch-test reference
+    /// signatures: a line of hex for every `granularity` bytes, most
+    /// significant byte first. `None` if the program has no signature, the
+    /// granularity isn't a power of two, or the memory can't be read.
+    pub fn signature(&mut self, granularity: u32) -> Option<String> {
+        let begin = self.program.symbol("begin_signature")?;
+        let end = self.program.symbol("end_signature")?;
+        if !granularity.is_power_of_two() || end < begin {
+            return None;
+        }
+
+        let mut signature = String::new();
+        let mut addr = begin;
+        while addr < end {
+            for i in (0..granularity).rev() {
+                let byte = self.cpu.bus.read(addr.checked_add(i)?, 1)?;
+                signature.push_str(&format!("{:02x}", byte));
+            }
+            signature.push('\n');
+            addr = addr.checked_add(granularity)?;
+        }
+        Some(signature)
+    }
+
+    /// Write the state of every hart, the memory and devices they share and
+    /// the scheduler to `out`.
+    pub fn save_snapshot<W: Write>(&self, out: W) -> io::Result<()> {
+        let harts: Vec<&CPU> = Some(&self.cpu).into_iter().chain(&self.harts).collect();
+        let mut extra = Writer::new();
+        extra.u32(self.program.entry_point);
+        extra.bytes(&self.scheduler.save_state());
+        snapshot::save(out, &harts, &extra.into_inner())
+    }
+
+    /// Restore a snapshot written by `save_snapshot`, which must be of a
+    /// machine with the same memory and number of harts. Use `resume` or
+    /// `run_for` to carry on from where it was taken.
+    pub fn load_snapshot<R: Read>(&mut self, input: R) -> Result<(), SnapshotError> {
+        let mut program = self.program.clone();
+        let mut scheduler = self.scheduler.clone();
+        let mut harts: Vec<&mut CPU> =
+            Some(&mut self.cpu).into_iter().chain(&mut self.harts).collect();
+        snapshot::load(input, &mut harts, |extra| {
+            let mut input = Reader::new(extra);
+            program.entry_point = input.u32()?;
+            scheduler.load_state(input.bytes()?)
+        })?;
+
+        self.program = program;
+        self.scheduler = scheduler;
+        Ok(())
+    }
+}
+
+enum Image {
+    Path(PathBuf),
+    Bytes(Vec<u8>),
+    Snapshot(PathBuf),
+}
+
+pub struct MachineBuilder {
+    ram_base: u32,
+    ram_size: usize,
+    trace: bool,
+    harts: usize,
+    quantum: u64,
+    schedule: Schedule,
+    isa: Option<Isa>,
+    htif: bool,
+    image: Option<Image>,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> MachineBuilder {
+        MachineBuilder::new()
+    }
+}
+
+impl MachineBuilder {
+    /// 1 MiB of RAM at address 0, one hart, and no program.
+    pub fn new() -> MachineBuilder {
+        MachineBuilder {
+            ram_base: 0,
+            ram_size: 1024 * 1024,
+            trace: false,
+            harts: 1,
+            quantum: 1000,
+            schedule: Schedule::RoundRobin,
+            isa: None,
+            htif: true,
+            image: None,
+        }
+    }
+
+    pub fn ram_base(mut self, ram_base: u32) -> MachineBuilder {
+        self.ram_base = ram_base;
+        self
+    }
+
+    pub fn ram_size(mut self, ram_size: usize) -> MachineBuilder {
+        self.ram_size = ram_size;
+        self
+    }
+
+    pub fn trace(mut self, trace: bool) -> MachineBuilder {
+        self.trace = trace;
+        self
+    }
+
+    /// The number of harts. They all start at the entry point with their
+    /// hart ID in `a0`, and a 4 KiB stack each below the usual one.
+    pub fn harts(mut self, harts: usize) -> MachineBuilder {
+        assert!(harts > 0, "a machine needs at least one hart");
+        self.harts = harts;
+        self
+    }
+
+    /// The longest a hart runs for, in instructions, before the next gets a
+    /// turn.
+    pub fn quantum(mut self, quantum: u64) -> MachineBuilder {
+        self.quantum = quantum;
+        self
+    }
+
+    pub fn schedule(mut self, schedule: Schedule) -> MachineBuilder {
+        self.schedule = schedule;
+        self
+    }
+
+    /// The extensions every hart implements, whose XLEN has to match the
+    /// program's. By default, all of the ones the emulator supports, with the
+    /// program's XLEN.
+    pub fn isa(mut self, isa: Isa) -> MachineBuilder {
+        self.isa = Some(isa);
+        self
+    }
+
+    /// Whether to map an HTIF over the program's `tohost` and `fromhost`
+    /// symbols, if it has them. On by default.
+    pub fn htif(mut self, htif: bool) -> MachineBuilder {
+        self.htif = htif;
+        self
+    }
+
+    pub fn load_elf<P: AsRef<Path>>(mut self, path: P) -> MachineBuilder {
+        self.image = Some(Image::Path(path.as_ref().to_path_buf()));
+        self
+    }
+
+    pub fn load_elf_bytes(mut self, data: &[u8]) -> MachineBuilder {
+        self.image = Some(Image::Bytes(data.to_vec()));
+        self
+    }
+
+    /// Restore a snapshot instead of loading a program. The snapshot decides
+    /// the amount and location of RAM and the number of harts.
+    pub fn restore_snapshot<P: AsRef<Path>>(mut self, path: P) -> MachineBuilder {
+        self.image = Some(Image::Snapshot(path.as_ref().to_path_buf()));
+        self
+    }
+
+    pub fn build(mut self) -> Result<Machine, LoadError> {
+        let mut xlen = None;
+        let snapshot = match self.image {
+            Some(Image::Snapshot(ref path)) => {
+                let mut data = Vec::new();
+                File::open(path)?.read_to_end(&mut data)?;
+                let header = snapshot::header(&data)?;
+                self.ram_base = header.ram_base;
+                self.ram_size = header.ram_size;
+                self.harts = header.harts.max(1);
+                xlen = Some(header.xlen);
+                Some(data)
+            }
+            _ => None,
+        };
+
+        let mut bus = Bus::with_ram_base(self.ram_base, RAM::new(self.ram_size));
+
+        let program = match self.image {
+            Some(Image::Path(ref path)) => Some(loader::load_elf(path, &mut bus)?),
+            Some(Image::Bytes(ref data)) => Some(loader::load_elf_bytes(data, &mut bus)?),
+            Some(Image::Snapshot(_)) | None => None,
+        };
+        let mut program = match program {
+            Some(program) => {
+                xlen = Some(program.xlen);
+                program
+            }
+            None => Program::new(self.ram_base),
+        };
+        let isa = match (self.isa, xlen) {
+            (Some(isa), Some(xlen)) if isa.xlen() != xlen => return Err(LoadError::Xlen(xlen)),
+            (Some(isa), _) => isa,
+            (None, xlen) if program.rve => {
+                Isa::default().with_xlen(xlen.unwrap_or(32)).with_base(Extension::E)
+            }
+            (None, xlen) => Isa::default().with_xlen(xlen.unwrap_or(32)),
+        };
+        program.xlen = isa.xlen();
+
+        let clint = if self.harts > 1 {
+            let clint = Clint::new(self.harts);
+            bus.map_device(CLINT_BASE, CLINT_SIZE, Box::new(clint.clone()));
+            Some(clint)
+        } else {
+            None
+        };
+
+        let htif = match program.symbol("tohost") {
+            Some(tohost) if self.htif => {
+                let htif = Htif::new();
+                bus.map_device(tohost, 8, htif.tohost_device());
+                if let Some(fromhost) = program.symbol("fromhost") {
+                    bus.map_device(fromhost, 8, htif.fromhost_device());
+                }
+                Some(htif)
+            }
+            _ => None,
+        };
+
+        // Leave the top 4 KiB of RAM free above the initial stack, which the
+        // ilp32e ABI only aligns to 4 bytes rather than 16.
+        let alignment = if isa.has(Extension::E) { 4 } else { 16 };
+        let stack_top = (self.ram_base as u64 + self.ram_size as u64).saturating_sub(4096) &
+                        !(alignment - 1);
+        let mut bus = Some(bus);
+        let mut harts: Vec<CPU> = (0..self.harts)
+            .map(|hart| {
+                // Only hart 0 holds the real bus between time slices.
+                let bus = bus.take().unwrap_or_else(|| Bus::new(RAM::new(0)));
+                let mut cpu = CPU::with_bus(bus);
+                cpu.trace = self.trace;
+                cpu.set_isa(isa);
+                cpu.set_hart_id(hart as u32);
+                if let Some(ref clint) = clint {
+                    cpu.set_clint(clint.clone());
+                }
+                cpu.set_register(2, stack_top.saturating_sub(4096 * hart as u64));
+                if hart > 0 {
+                    cpu.set_register(10, hart as u64);
+                }
+                cpu
+            })
+            .collect();
+        let cpu = harts.remove(0);
+
+        let mut machine = Machine {
+            cpu,
+            harts,
+            clint,
+            htif,
+            program,
+            scheduler: Scheduler::new(self.quantum, self.schedule),
+        };
+        if let Some(data) = snapshot {
+            machine.load_snapshot(&data[..])?;
+        }
+        Ok(machine)
+    }
+}
@@ -5,64 +5,282 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-extern crate elf;
+extern crate risc_v_emulator;
 
-mod cpu;
-mod instruction;
-mod ram;
+use risc_v_emulator::gdb;
+use risc_v_emulator::{Isa, Machine, Profile, Recording, Replayer, Schedule, StopReason};
 
-use cpu::CPU;
-use ram::RAM;
-
-use std::cmp::max;
-use std::io::prelude::*;
-use std::io::SeekFrom;
-use std::fs::File;
 use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::TcpListener;
+use std::process;
 
-fn load_elf_to_ram(path: &str, ram: &mut RAM) -> u32 {
-    let elf_file = match elf::File::open_path(path) {
-        Ok(f) => f,
-        Err(e) => panic!("Error: {:?}", e),
-    };
+fn usage(program: &str) -> ! {
+    println!("usage: {} [--trace] [--isa ISA | --profile PROFILE] [--ram-base ADDR]\n       \
+              [--harts N] [--quantum N] [--seed N]\n       \
+              [--signature FILE [--signature-granularity N]]\n       \
+              [--snapshot-at INSTRET [--snapshot-file FILE]]\n       \
+              [--record FILE [--checkpoint-interval N] | --replay FILE [--gdb PORT]]\n       \
+              (program-name | --restore FILE)",
+             program);
+    process::exit(1);
+}
 
-    let mut raw_file = File::open(path).expect("couldn't open file");
+fn fail<E: fmt::Display>(e: E) -> ! {
+    println!("error: {}", e);
+    process::exit(1);
+}
 
-    for program_header in elf_file.phdrs {
-        if program_header.progtype == elf::types::ProgType(1) {
-            let mut buf =
-                vec![0; max(program_header.memsz as usize, program_header.filesz as usize)];
+fn main() {
+    let args: Vec<String> = env::args().collect();
 
-            for i in 0..(program_header.memsz) {
-                buf[i as usize] = 0;
+    let mut builder = Machine::builder();
+    let mut path = None;
+    let mut restore = None;
+    let mut snapshot_at = None;
+    let mut snapshot_file = None;
+    let mut record = None;
+    let mut checkpoint_interval = 100_000;
+    let mut replay = None;
+    let mut gdb_port = None;
+    let mut signature = None;
+    let mut granularity = 4;
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().cloned().unwrap_or_else(|| usage(&args[0]));
+        let number = |value: String| value.parse().unwrap_or_else(|_| usage(&args[0]));
+        let address = |value: String| {
+            u32::from_str_radix(value.trim_start_matches("0x"), 16)
+                .unwrap_or_else(|_| usage(&args[0]))
+        };
+        builder = match arg.as_str() {
+            "--trace" => builder.trace(true),
+            "--isa" => builder.isa(Isa::parse(&value()).unwrap_or_else(|e| fail(e))),
+            "--profile" => {
+                let name = value();
+                let profile = Profile::from_name(&name)
+                    .unwrap_or_else(|| fail(format!("unknown profile \"{}\"", name)));
+                builder.isa(profile.isa())
+            }
+            "--ram-base" => builder.ram_base(address(value())),
+            "--harts" => builder.harts(number(value()) as usize),
+            "--quantum" => builder.quantum(number(value())),
+            "--seed" => builder.schedule(Schedule::Seeded(number(value()))),
+            "--snapshot-at" => {
+                snapshot_at = Some(number(value()));
+                builder
+            }
+            "--snapshot-file" => {
+                snapshot_file = Some(value());
+                builder
+            }
+            "--record" => {
+                record = Some(value());
+                builder
+            }
+            "--checkpoint-interval" => {
+                checkpoint_interval = number(value()).max(1);
+                builder
+            }
+            "--replay" => {
+                replay = Some(value());
+                builder
+            }
+            "--gdb" => {
+                gdb_port = Some(number(value()) as u16);
+                builder
             }
+            "--signature" => {
+                signature = Some(value());
+                builder
+            }
+            "--signature-granularity" => {
+                granularity = number(value()) as u32;
+                builder
+            }
+            "--restore" => {
+                restore = Some(value());
+                builder
+            }
+            _ if arg.starts_with("--") || path.is_some() => usage(&args[0]),
+            _ => {
+                path = Some(arg.clone());
+                builder
+            }
+        };
+    }
 
-            raw_file.seek(SeekFrom::Start(program_header.offset)).expect("couldn't seek in file");
-            raw_file.read(&mut buf[0..(program_header.filesz as usize)])
-                .expect("couldn't read file");
+    if gdb_port.is_some() && replay.is_none() {
+        usage(&args[0]);
+    }
+    let restoring = restore.is_some();
+    let builder = match (path, restore) {
+        (Some(ref path), None) => builder.load_elf(path),
+        (None, Some(ref restore)) => builder.restore_snapshot(restore),
+        _ => usage(&args[0]),
+    };
+    let mut machine = builder.build().unwrap_or_else(|e| fail(e));
+    if !restoring {
+        machine.start();
+    }
 
-            for (i, &data) in buf.iter().enumerate() {
-                let addr = (program_header.vaddr as u32) + (i as u32);
-                ram[addr] = data;
-            }
+    if let Some(file) = replay {
+        let recording = File::open(&file)
+            .map_err(From::from)
+            .and_then(|input| Recording::load(BufReader::new(input)))
+            .unwrap_or_else(|e| fail(e));
+        let mut replayer = Replayer::new(machine, recording).unwrap_or_else(|e| fail(e));
+        match gdb_port {
+            Some(port) => serve_gdb(&mut replayer, port),
+            None => debug(replayer),
         }
+        return;
     }
 
-    elf_file.ehdr.entry as u32
+    if let Some(file) = record {
+        let recording = Recording::record(&mut machine, checkpoint_interval);
+        File::create(&file)
+            .and_then(|out| recording.save(BufWriter::new(out)))
+            .unwrap_or_else(|e| fail(e));
+        println!("recorded {} instructions to {}", machine.instret(), file);
+        print_result(&machine, recording.result());
+        if let Some(file) = signature {
+            write_signature(&mut machine, &file, granularity);
+        }
+        return;
+    }
+
+    let result = match snapshot_at {
+        Some(instret) => {
+            match machine.run_for(instret.saturating_sub(machine.instret())) {
+                (_, StopReason::InstructionLimit) => {
+                    let file = snapshot_file.unwrap_or_else(|| "snapshot.bin".to_string());
+                    File::create(&file)
+                        .and_then(|out| machine.save_snapshot(BufWriter::new(out)))
+                        .unwrap_or_else(|e| fail(e));
+                    println!("saved snapshot at {} instructions to {}", machine.instret(), file);
+                    return;
+                }
+                result => result,
+            }
+        }
+        None => machine.resume(),
+    };
+    print_result(&machine, result);
+    if let Some(file) = signature {
+        write_signature(&mut machine, &file, granularity);
+    }
 }
 
-fn main() {
-    let mut ram = RAM::new(1024 * 1024);
+fn write_signature(machine: &mut Machine, file: &str, granularity: u32) {
+    let signature = match machine.signature(granularity) {
+        Some(signature) => signature,
+        None => {
+            fail(format!("no signature with a granularity of {} (the program needs \
+                          begin_signature and end_signature symbols)",
+                         granularity))
+        }
+    };
+    File::create(file)
+        .and_then(|mut out| out.write_all(signature.as_bytes()))
+        .unwrap_or_else(|e| fail(e));
+}
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("usage: {} program-name", args[0]);
-        return;
+fn print_result(machine: &Machine, result: (usize, StopReason)) {
+    match result {
+        (_, StopReason::Halted(code)) => println!("result: {}", code),
+        (0, reason) => println!("stopped: {:?} at {:08x}", reason, machine.cpu.pc),
+        (hart, reason) => {
+            println!("hart {} stopped: {:?} at {:08x}",
+                     hart,
+                     reason,
+                     machine.harts[hart - 1].pc)
+        }
     }
-    let entry_point = load_elf_to_ram(args[1].as_str(), &mut ram);
+}
 
-    let mut cpu = CPU::new(ram);
-    cpu.run(entry_point);
+// Wait for GDB to connect to `port` on the loopback interface, and let it
+// debug the replay until it detaches.
+fn serve_gdb(replayer: &mut Replayer, port: u16) {
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|e| fail(e));
+    println!("waiting for GDB on 127.0.0.1:{} (target remote :{})", port, port);
+    let (stream, _) = listener.accept().unwrap_or_else(|e| fail(e));
+    gdb::serve(replayer, stream).unwrap_or_else(|e| fail(e));
+}
+
+const DEBUG_HELP: &str = "commands: s [N] (step), c (continue), rs (reverse step),\n          \
+                          rc (reverse continue), seek INSTRET, b ADDR, d ADDR, regs, q";
+
+// A small command-line debugger over a replay, which can run backwards as
+// well as forwards.
+fn debug(mut replayer: Replayer) {
+    println!("{}", DEBUG_HELP);
+    let stdin = io::stdin();
+    loop {
+        print!("[{}] {:08x}> ", replayer.instret(), replayer.machine().cpu.pc);
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
 
-    println!("result: {}", cpu.get_register(10));
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let address = || {
+            words.get(1)
+                .and_then(|word| u32::from_str_radix(word.trim_start_matches("0x"), 16).ok())
+        };
+        let result = match words.first().cloned().unwrap_or("") {
+            "s" => {
+                let count = words.get(1).and_then(|word| word.parse().ok()).unwrap_or(1);
+                replayer.run_for(count).map(Some)
+            }
+            "c" => replayer.resume().map(Some),
+            "rs" => replayer.reverse_step().map(|_| None),
+            "rc" => {
+                replayer.reverse_continue().map(|hart| {
+                    hart.map(|hart| (hart, StopReason::Breakpoint))
+                })
+            }
+            "seek" => {
+                match words.get(1).and_then(|word| word.parse().ok()) {
+                    Some(instret) => replayer.seek(instret).map(|_| None),
+                    None => Ok(None),
+                }
+            }
+            "b" | "d" => {
+                if let Some(addr) = address() {
+                    let machine = replayer.machine_mut();
+                    for hart in Some(&mut machine.cpu).into_iter().chain(&mut machine.harts) {
+                        if words[0] == "b" {
+                            hart.add_breakpoint(addr);
+                        } else {
+                            hart.remove_breakpoint(addr);
+                        }
+                    }
+                }
+                Ok(None)
+            }
+            "regs" => {
+                let machine = replayer.machine();
+                let harts = Some(&machine.cpu).into_iter().chain(&machine.harts);
+                for (hart, cpu) in harts.enumerate() {
+                    println!("hart {}: pc {:08x} {:?}", hart, cpu.pc, cpu);
+                }
+                Ok(None)
+            }
+            "q" => return,
+            _ => {
+                println!("{}", DEBUG_HELP);
+                Ok(None)
+            }
+        };
+
+        match result {
+            Ok(Some((_, StopReason::InstructionLimit))) | Ok(None) => {}
+            Ok(Some(result)) => print_result(replayer.machine(), result),
+            Err(e) => println!("error: {}", e),
+        }
+    }
 }
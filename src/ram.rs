@@ -7,6 +7,7 @@
 
 use std::ops;
 
+#[derive(Clone, PartialEq, Eq)]
 pub struct RAM {
     data: Vec<u8>,
 }
@@ -16,6 +17,32 @@ impl RAM {
         RAM { data: vec![0; capacity] }
     }
 
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.data.as_mut_ptr()
+    }
+
+    /// Whether the `size` bytes starting at `index` are all backed by memory.
+    pub fn contains(&self, index: u32, size: u32) -> bool {
+        (index as u64) + (size as u64) <= self.data.len() as u64
+    }
+
     pub fn get_u16(&self, index: u32) -> u16 {
         (self[index] as u16) | (self[index + 1] as u16) << 8
     }
@@ -0,0 +1,481 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Recording a run of a machine, and replaying it forwards and backwards.
+//!
+//! Everything a machine does follows from its state, except for what it reads
+//! from devices that talk to the host. A recording is a log of every access
+//! to those devices, plus a snapshot every so often. Replaying answers the
+//! accesses from the log, so the run plays out exactly as it did the first
+//! time, and going backwards means restoring the last snapshot before where
+//! you want to be and running forwards to it.
+//!
+//! A recording file is little-endian: the magic bytes `RVRECORD` and a format
+//! version, the instruction count and stop reason the run ended with, each
+//! checkpoint's instruction count, log position and snapshot, and then the
+//! log itself. The log includes what devices wrote to memory themselves and
+//! when they halted the machine, so that replaying can do the same without
+//! them.
+
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use bus::DeviceEvent;
+use debug::DebugCause;
+use machine::Machine;
+use snapshot::{Reader, SnapshotError, Writer};
+use trap::{Access, StopReason};
+
+const MAGIC: &[u8; 8] = b"RVRECORD";
+const VERSION: u32 = 3;
+
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The machine made a device access other than the next one in the log,
+    /// at or before this many instructions. It can't have been set up the
+    /// same way as the machine that was recorded.
+    Diverged { instret: u64 },
+    /// A checkpoint couldn't be restored into the machine.
+    Snapshot(SnapshotError),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplayError::Diverged { instret } => {
+                write!(f, "replay diverged from the recording by {} instructions", instret)
+            }
+            ReplayError::Snapshot(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for ReplayError {}
+
+impl From<SnapshotError> for ReplayError {
+    fn from(e: SnapshotError) -> ReplayError {
+        ReplayError::Snapshot(e)
+    }
+}
+
+struct Checkpoint {
+    instret: u64,
+    // How many device events had been logged when the snapshot was taken.
+    events: usize,
+    snapshot: Vec<u8>,
+}
+
+/// A run of a machine, from wherever it was when recording started to
+/// whatever stopped it.
+pub struct Recording {
+    end: u64,
+    result: (usize, StopReason),
+    checkpoints: Vec<Checkpoint>,
+    events: Vec<DeviceEvent>,
+}
+
+impl Recording {
+    /// Carry on running `machine` until something stops it, taking a
+    /// checkpoint roughly every `checkpoint_interval` instructions. Shorter
+    /// intervals make going backwards quicker and the recording bigger.
+    pub fn record(machine: &mut Machine, checkpoint_interval: u64) -> Recording {
+        assert!(checkpoint_interval > 0, "checkpoint interval must be at least one instruction");
+        machine.cpu.bus.start_recording();
+
+        let mut checkpoints: Vec<Checkpoint> = Vec::new();
+        let result = loop {
+            let instret = machine.instret();
+            if checkpoints.last().map(|last| last.instret) != Some(instret) {
+                let mut snapshot = Vec::new();
+                machine.save_snapshot(&mut snapshot).expect("writing to a Vec can't fail");
+                checkpoints.push(Checkpoint {
+                    instret,
+                    events: machine.cpu.bus.recorded_events(),
+                    snapshot,
+                });
+            }
+
+            match machine.run_for(checkpoint_interval) {
+                (_, StopReason::InstructionLimit) => continue,
+                result => break result,
+            }
+        };
+
+        Recording {
+            end: machine.instret(),
+            result,
+            checkpoints,
+            events: machine.cpu.bus.take_recording(),
+        }
+    }
+
+    /// The instruction count at the start of the recording.
+    pub fn start_instret(&self) -> u64 {
+        self.checkpoints[0].instret
+    }
+
+    /// The instruction count when the recorded run stopped.
+    pub fn end_instret(&self) -> u64 {
+        self.end
+    }
+
+    /// The hart that stopped the recorded run, and why.
+    pub fn result(&self) -> (usize, StopReason) {
+        self.result
+    }
+
+    /// Every access to a non-deterministic device, in order.
+    pub fn events(&self) -> &[DeviceEvent] {
+        &self.events
+    }
+
+    pub fn save<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut writer = Writer::new();
+        writer.u32(VERSION);
+        writer.u64(self.end);
+        writer.u32(self.result.0 as u32);
+        save_stop_reason(self.result.1, &mut writer);
+
+        writer.u32(self.checkpoints.len() as u32);
+        for checkpoint in &self.checkpoints {
+            writer.u64(checkpoint.instret);
+            writer.u64(checkpoint.events as u64);
+            writer.bytes(&checkpoint.snapshot);
+        }
+
+        writer.u64(self.events.len() as u64);
+        for event in &self.events {
+            writer.u32(event.addr);
+            writer.u8(event.size as u8);
+            writer.u8(access_code(event.access));
+            writer.u32(event.value);
+            writer.u8(event.ok as u8 | (event.dma as u8) << 1 | (event.halt as u8) << 2);
+        }
+
+        out.write_all(MAGIC)?;
+        out.write_all(&writer.into_inner())
+    }
+
+    pub fn load<R: Read>(mut input: R) -> Result<Recording, SnapshotError> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let mut reader = Reader::new(&data);
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(SnapshotError::BadMagic);
+        }
+        match reader.u32()? {
+            VERSION => {}
+            version => return Err(SnapshotError::Version(version)),
+        }
+
+        let end = reader.u64()?;
+        let result = (reader.u32()? as usize, load_stop_reason(&mut reader)?);
+
+        let mut checkpoints = Vec::new();
+        for _ in 0..reader.u32()? {
+            checkpoints.push(Checkpoint {
+                instret: reader.u64()?,
+                events: reader.u64()? as usize,
+                snapshot: reader.bytes()?.to_vec(),
+            });
+        }
+
+        let mut events = Vec::new();
+        for _ in 0..reader.u64()? {
+            let (addr, size, access, value) =
+                (reader.u32()?, reader.u8()? as u32, load_access(reader.u8()?)?, reader.u32()?);
+            let flags = reader.u8()?;
+            events.push(DeviceEvent {
+                addr,
+                size,
+                access,
+                value,
+                ok: flags & 1 != 0,
+                dma: flags & 2 != 0,
+                halt: flags & 4 != 0,
+            });
+        }
+        if !reader.is_empty() {
+            return Err(SnapshotError::Corrupt("trailing data"));
+        }
+
+        let ordered = checkpoints.windows(2).all(|pair| {
+            pair[0].instret < pair[1].instret && pair[0].events <= pair[1].events
+        });
+        match checkpoints.last() {
+            Some(last) if ordered && last.instret <= end && last.events <= events.len() => {}
+            _ => return Err(SnapshotError::Corrupt("checkpoints out of order")),
+        }
+
+        Ok(Recording {
+            end,
+            result,
+            checkpoints,
+            events,
+        })
+    }
+}
+
+/// Plays back a `Recording` on a machine set up like the one it was made on:
+/// with the same RAM, number of harts and devices mapped in the same places.
+///
+/// Breakpoints are stepped over when going backwards; set them on the
+/// machine's harts to have `resume` and `reverse_continue` stop on them.
+/// Changing anything else about the machine will make the replay diverge.
+pub struct Replayer {
+    machine: Machine,
+    end: u64,
+    result: (usize, StopReason),
+    checkpoints: Vec<Checkpoint>,
+}
+
+impl Replayer {
+    /// Take over `machine` and rewind it to the start of `recording`.
+    pub fn new(mut machine: Machine, recording: Recording) -> Result<Replayer, ReplayError> {
+        machine.cpu.bus.start_replay(recording.events);
+        let mut replayer = Replayer {
+            machine,
+            end: recording.end,
+            result: recording.result,
+            checkpoints: recording.checkpoints,
+        };
+        replayer.restore(0)?;
+        Ok(replayer)
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    /// Give the machine back, still answering device reads from the log.
+    pub fn into_machine(self) -> Machine {
+        self.machine
+    }
+
+    pub fn instret(&self) -> u64 {
+        self.machine.instret()
+    }
+
+    /// The instruction count at the end of the recording.
+    pub fn end_instret(&self) -> u64 {
+        self.end
+    }
+
+    /// Replay at most `instructions` instructions, like `Machine::run_for`.
+    /// Reaching the end of the recording returns whatever stopped the
+    /// recorded run.
+    pub fn run_for(&mut self, instructions: u64) -> Result<(usize, StopReason), ReplayError> {
+        let left = self.end.saturating_sub(self.instret());
+        if left == 0 {
+            return Ok(self.result);
+        }
+
+        let result = self.machine.run_for(instructions.min(left));
+        self.check_diverged()?;
+        match result {
+            (_, StopReason::InstructionLimit) if self.instret() >= self.end => Ok(self.result),
+            result => Ok(result),
+        }
+    }
+
+    /// Replay until a breakpoint or the end of the recording.
+    pub fn resume(&mut self) -> Result<(usize, StopReason), ReplayError> {
+        loop {
+            match self.run_for(u64::MAX)? {
+                (_, StopReason::InstructionLimit) if self.instret() < self.end => continue,
+                result => return Ok(result),
+            }
+        }
+    }
+
+    /// Go to the point where `instret` instructions had been retired, going
+    /// backwards or forwards and passing any breakpoints on the way. A
+    /// breakpoint where it ends up won't stop the next step forward.
+    pub fn seek(&mut self, instret: u64) -> Result<(), ReplayError> {
+        let target = instret.max(self.checkpoints[0].instret).min(self.end);
+        let index = self.checkpoint_before(target + 1);
+        let now = self.instret();
+        if target < now || self.checkpoints[index].instret > now {
+            self.restore(index)?;
+        }
+
+        while self.instret() < target {
+            let instret = self.instret();
+            match self.machine.run_for(target - instret) {
+                (_, StopReason::InstructionLimit) |
+                (_, StopReason::Breakpoint) |
+                (_, StopReason::Watchpoint { .. }) |
+                (_, StopReason::Hook) => {}
+                _ => break,
+            }
+            self.check_diverged()?;
+        }
+
+        for hart in Some(&mut self.machine.cpu).into_iter().chain(&mut self.machine.harts) {
+            hart.skip_breakpoint();
+        }
+        Ok(())
+    }
+
+    /// Go back one instruction. Returns `false` if already at the start of
+    /// the recording.
+    pub fn reverse_step(&mut self) -> Result<bool, ReplayError> {
+        let now = self.instret();
+        if now <= self.checkpoints[0].instret {
+            return Ok(false);
+        }
+        self.seek(now - 1)?;
+        Ok(true)
+    }
+
+    /// Go back to the last time a hart stopped on a breakpoint, and return
+    /// which hart it was. Goes back to the start of the recording and returns
+    /// `None` if no breakpoint was hit before now.
+    pub fn reverse_continue(&mut self) -> Result<Option<usize>, ReplayError> {
+        let now = self.instret();
+        if now <= self.checkpoints[0].instret {
+            return Ok(None);
+        }
+
+        // Replay the stretch before each checkpoint in turn, working
+        // backwards, and stop at the last breakpoint hit in the first one
+        // that has any.
+        let mut index = self.checkpoint_before(now);
+        loop {
+            let end = self.checkpoints.get(index + 1).map_or(now, |next| next.instret.min(now));
+            self.restore(index)?;
+
+            let mut last = None;
+            while self.instret() < end {
+                let instret = self.instret();
+                match self.machine.run_for(end - instret) {
+                    (hart, StopReason::Breakpoint) => last = Some((self.instret(), hart)),
+                    (_, StopReason::InstructionLimit) |
+                    (_, StopReason::Watchpoint { .. }) |
+                    (_, StopReason::Hook) => {}
+                    _ => break,
+                }
+                self.check_diverged()?;
+            }
+
+            if let Some((instret, hart)) = last {
+                self.seek(instret)?;
+                return Ok(Some(hart));
+            }
+            if index == 0 {
+                self.seek(0)?;
+                return Ok(None);
+            }
+            index -= 1;
+        }
+    }
+
+    // The last checkpoint taken before `instret` instructions.
+    fn checkpoint_before(&self, instret: u64) -> usize {
+        self.checkpoints.iter().rposition(|checkpoint| checkpoint.instret < instret).unwrap_or(0)
+    }
+
+    fn restore(&mut self, index: usize) -> Result<(), ReplayError> {
+        let checkpoint = &self.checkpoints[index];
+        self.machine.load_snapshot(&checkpoint.snapshot[..])?;
+        self.machine.cpu.bus.seek_replay(checkpoint.events);
+        Ok(())
+    }
+
+    fn check_diverged(&self) -> Result<(), ReplayError> {
+        if self.machine.cpu.bus.replay_diverged() {
+            Err(ReplayError::Diverged { instret: self.instret() })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn access_code(access: Access) -> u8 {
+    match access {
+        Access::Fetch => 0,
+        Access::Load => 1,
+        Access::Store => 2,
+    }
+}
+
+fn load_access(code: u8) -> Result<Access, SnapshotError> {
+    match code {
+        0 => Ok(Access::Fetch),
+        1 => Ok(Access::Load),
+        2 => Ok(Access::Store),
+        _ => Err(SnapshotError::Corrupt("invalid access type")),
+    }
+}
+
+// A stop reason is a tag followed by up to three fields, with unused ones
+// left zero.
+fn save_stop_reason(reason: StopReason, writer: &mut Writer) {
+    let (tag, fields) = match reason {
+        StopReason::Halted(code) => (0, [code as u64, 0, 0]),
+        StopReason::Breakpoint => (1, [0, 0, 0]),
+        StopReason::Watchpoint { addr, access } => {
+            (2, [addr as u64, access_code(access) as u64, 0])
+        }
+        StopReason::InstructionLimit => (3, [0, 0, 0]),
+        StopReason::IllegalInstruction { pc, raw } => (4, [pc as u64, raw as u64, 0]),
+        StopReason::MemoryFault { pc, addr, access } => {
+            (5, [pc as u64, addr, access_code(access) as u64])
+        }
+        StopReason::Trap { cause } => (6, [cause, 0, 0]),
+        StopReason::Wfi => (7, [0, 0, 0]),
+        StopReason::Hook => (8, [0, 0, 0]),
+        StopReason::Debug(cause) => (9, [cause as u64, 0, 0]),
+    };
+    writer.u8(tag);
+    for &field in &fields {
+        writer.u64(field);
+    }
+}
+
+fn load_stop_reason(reader: &mut Reader) -> Result<StopReason, SnapshotError> {
+    let tag = reader.u8()?;
+    let fields = [reader.u64()?, reader.u64()?, reader.u64()?];
+    Ok(match tag {
+        0 => StopReason::Halted(fields[0] as u32),
+        1 => StopReason::Breakpoint,
+        2 => {
+            StopReason::Watchpoint {
+                addr: fields[0] as u32,
+                access: load_access(fields[1] as u8)?,
+            }
+        }
+        3 => StopReason::InstructionLimit,
+        4 => {
+            StopReason::IllegalInstruction {
+                pc: fields[0] as u32,
+                raw: fields[1] as u32,
+            }
+        }
+        5 => {
+            StopReason::MemoryFault {
+                pc: fields[0] as u32,
+                addr: fields[1],
+                access: load_access(fields[2] as u8)?,
+            }
+        }
+        6 => StopReason::Trap { cause: fields[0] },
+        7 => StopReason::Wfi,
+        8 => StopReason::Hook,
+        9 => {
+            let cause = DebugCause::from_code(fields[0] as u8)
+                .ok_or(SnapshotError::Corrupt("invalid debug cause"))?;
+            StopReason::Debug(cause)
+        }
+        _ => return Err(SnapshotError::Corrupt("invalid stop reason")),
+    })
+}
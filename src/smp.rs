@@ -0,0 +1,275 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::mem;
+
+use clint::Clint;
+use cpu::CPU;
+use snapshot::{Reader, SnapshotError, Writer};
+use trap::{Interrupt, StopReason};
+
+/// How the `Scheduler` picks which hart runs next, and for how long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Each runnable hart in turn, for a full quantum.
+    RoundRobin,
+    /// A pseudo-random runnable hart for between 1 and `quantum`
+    /// instructions, drawn from a generator seeded with the given value. The
+    /// same seed always gives the same interleaving, which makes it possible
+    /// to go looking for races and then reproduce the ones found.
+    Seeded(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HartState {
+    Running = 0,
+    /// Executed WFI and is waiting for an interrupt.
+    Asleep = 1,
+    /// Met one of its halting conditions.
+    Done = 2,
+}
+
+/// Runs several harts that share one bus by interleaving them on the host
+/// thread.
+///
+/// The harts don't each have a bus of their own: between time slices the
+/// boot hart (hart 0) holds the shared one, and it's swapped into whichever
+/// other hart is running. Each hart's interrupt lines are updated from the
+/// CLINT at the start of its slice, so an interrupt raised by another hart or
+/// the timer is taken at most one slice late. A hart given the CLINT with
+/// `CPU::set_clint` also sees its own stores to it straight away. `mtime`
+/// ticks once per instruction retired by each hart on average.
+#[derive(Clone)]
+pub struct Scheduler {
+    quantum: u64,
+    schedule: Schedule,
+    rng: u64,
+    next: usize,
+    ticks: u64,
+    states: Vec<HartState>,
+    // The hart whose time slice was cut short, and how much of it is left.
+    current: Option<(usize, u64)>,
+}
+
+impl Scheduler {
+    pub fn new(quantum: u64, schedule: Schedule) -> Scheduler {
+        assert!(quantum > 0, "scheduler quantum must be at least one instruction");
+        Scheduler {
+            quantum,
+            schedule,
+            rng: match schedule {
+                // Xorshift gets stuck on zero.
+                Schedule::Seeded(seed) => seed | 1,
+                Schedule::RoundRobin => 0,
+            },
+            next: 0,
+            ticks: 0,
+            states: Vec::new(),
+            current: None,
+        }
+    }
+
+    /// Forget which harts are asleep or done, ready to start them all again.
+    pub fn reset(&mut self) {
+        self.states.clear();
+        self.current = None;
+    }
+
+    /// Run `boot` and `others` (harts 1 and up) from wherever their PCs are
+    /// until they've retired `instructions` instructions between them, or
+    /// something stops one of them. Returns that hart's index and the
+    /// reason, or hart 0 and `InstructionLimit`.
+    ///
+    /// Another hart halting only retires that hart, while the boot hart
+    /// halting stops the whole machine. Any other stop reason, from any
+    /// hart, is returned straight away. If every remaining hart is waiting
+    /// in WFI, `mtime` skips ahead to the next timer that could wake one,
+    /// and if there isn't one the boot hart's `Wfi` is returned.
+    ///
+    /// A time slice cut short by the instruction limit or a breakpoint
+    /// carries on where it left off next time, so how a run is split up
+    /// doesn't change the interleaving.
+    pub fn run(&mut self, boot: &mut CPU, others: &mut [CPU], clint: &Clint, instructions: u64)
+               -> (usize, StopReason) {
+        let harts = others.len() + 1;
+        if self.states.len() != harts {
+            self.states = vec![HartState::Running; harts];
+            self.current = None;
+        }
+
+        let mut remaining = instructions;
+        while remaining > 0 {
+            let (hart, left) = match self.current.take() {
+                Some(current) => current,
+                None => {
+                    let (hart, slice) = match self.next_slice(boot, others, clint) {
+                        Some(next) => next,
+                        None => return (0, StopReason::Wfi),
+                    };
+                    let cpu = if hart == 0 { &mut *boot } else { &mut others[hart - 1] };
+                    update_interrupts(cpu, clint, hart);
+                    (hart, slice)
+                }
+            };
+
+            let budget = left.min(remaining);
+            let (retired, reason) = if hart == 0 {
+                run_hart(boot, budget)
+            } else {
+                let cpu = &mut others[hart - 1];
+                mem::swap(&mut boot.bus, &mut cpu.bus);
+                let result = run_hart(cpu, budget);
+                mem::swap(&mut boot.bus, &mut cpu.bus);
+                result
+            };
+            remaining -= retired;
+            self.ticks += retired;
+            let left = left - retired;
+
+            match reason {
+                StopReason::InstructionLimit if left > 0 => self.current = Some((hart, left)),
+                StopReason::InstructionLimit => self.end_slice(clint, harts),
+                StopReason::Wfi => {
+                    self.end_slice(clint, harts);
+                    self.states[hart] = HartState::Asleep;
+                }
+                StopReason::Halted(_) if hart != 0 => {
+                    self.end_slice(clint, harts);
+                    self.states[hart] = HartState::Done;
+                }
+                reason => {
+                    self.current = Some((hart, left));
+                    return (hart, reason);
+                }
+            }
+        }
+
+        (0, StopReason::InstructionLimit)
+    }
+
+    /// Wake any sleeping harts with interrupts pending, and pick the next
+    /// hart to run and for how long. Returns `None` if every hart is asleep
+    /// and nothing will wake them.
+    fn next_slice(&mut self, boot: &mut CPU, others: &mut [CPU], clint: &Clint)
+                  -> Option<(usize, u64)> {
+        let harts = self.states.len();
+        loop {
+            for (hart, state) in self.states.iter_mut().enumerate() {
+                if *state == HartState::Asleep {
+                    let cpu = if hart == 0 { &mut *boot } else { &mut others[hart - 1] };
+                    update_interrupts(cpu, clint, hart);
+                    if cpu.interrupt_pending() {
+                        *state = HartState::Running;
+                    }
+                }
+            }
+
+            let runnable: Vec<usize> =
+                (0..harts).filter(|&hart| self.states[hart] == HartState::Running).collect();
+            if !runnable.is_empty() {
+                return Some(match self.schedule {
+                    Schedule::RoundRobin => {
+                        let hart = (self.next..harts)
+                            .chain(0..self.next)
+                            .find(|&hart| self.states[hart] == HartState::Running)
+                            .unwrap();
+                        self.next = (hart + 1) % harts;
+                        (hart, self.quantum)
+                    }
+                    Schedule::Seeded(_) => {
+                        let pick = xorshift(&mut self.rng) % runnable.len() as u64;
+                        (runnable[pick as usize], 1 + xorshift(&mut self.rng) % self.quantum)
+                    }
+                });
+            }
+
+            // Everyone's asleep (hart 0 can't be done, or we'd have
+            // returned), so skip to the earliest timer that hasn't fired.
+            let mtime = clint.mtime();
+            let wake = (0..harts)
+                .filter(|&hart| self.states[hart] == HartState::Asleep)
+                .map(|hart| clint.mtimecmp(hart))
+                .filter(|&mtimecmp| mtimecmp > mtime && mtimecmp != u64::MAX)
+                .min();
+            clint.set_mtime(wake?);
+        }
+    }
+
+    fn end_slice(&mut self, clint: &Clint, harts: usize) {
+        clint.advance(self.ticks / harts as u64);
+        self.ticks %= harts as u64;
+    }
+
+    pub(crate) fn save_state(&self) -> Vec<u8> {
+        let mut out = Writer::new();
+        out.u64(self.rng);
+        out.u32(self.next as u32);
+        out.u64(self.ticks);
+        out.u32(self.states.len() as u32);
+        for &state in &self.states {
+            out.u8(state as u8);
+        }
+        let (hart, left) = self.current.map_or((u32::MAX, 0), |(hart, left)| (hart as u32, left));
+        out.u32(hart);
+        out.u64(left);
+        out.into_inner()
+    }
+
+    pub(crate) fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        let mut input = Reader::new(data);
+        let rng = input.u64()?;
+        let next = input.u32()? as usize;
+        let ticks = input.u64()?;
+        let mut states = Vec::new();
+        for _ in 0..input.u32()? {
+            states.push(match input.u8()? {
+                0 => HartState::Running,
+                1 => HartState::Asleep,
+                2 => HartState::Done,
+                _ => return Err(SnapshotError::Corrupt("invalid hart state")),
+            });
+        }
+        let current = match (input.u32()?, input.u64()?) {
+            (u32::MAX, _) => None,
+            (hart, left) if (hart as usize) < states.len() => Some((hart as usize, left)),
+            _ => return Err(SnapshotError::Corrupt("invalid current hart")),
+        };
+
+        self.rng = rng;
+        self.next = next;
+        self.ticks = ticks;
+        self.states = states;
+        self.current = current;
+        Ok(())
+    }
+}
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn update_interrupts(cpu: &mut CPU, clint: &Clint, hart: usize) {
+    cpu.set_interrupt_pending(Interrupt::Software, clint.msip(hart));
+    cpu.set_interrupt_pending(Interrupt::Timer, clint.timer_pending(hart));
+}
+
+/// Run `cpu` until it has retired `instructions` instructions or something
+/// else stops it, returning how many it retired. Instructions that trap
+/// aren't retired, so this can take more than one call to `run_for`.
+fn run_hart(cpu: &mut CPU, instructions: u64) -> (u64, StopReason) {
+    let start = cpu.cycles();
+    loop {
+        let retired = cpu.cycles() - start;
+        match cpu.run_for(instructions - retired) {
+            StopReason::InstructionLimit if cpu.cycles() - start < instructions => continue,
+            reason => return (cpu.cycles() - start, reason),
+        }
+    }
+}
@@ -0,0 +1,401 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Saving and restoring complete machine state.
+//!
+//! A snapshot is a little-endian binary file:
+//!
+//! * the magic bytes `RVSNAPSH` and a format version,
+//! * the RAM base and size, the number of harts and their XLEN,
+//! * each hart's pc, registers, privilege level and CSRs, including the
+//!   floating point and vector registers,
+//! * every non-zero 4 KiB page of RAM, PackBits-compressed,
+//! * outstanding LR reservations,
+//! * each mapped device's state, in mapping order, and
+//! * an opaque block of state belonging to whoever owns the harts (the
+//!   `Machine` keeps its scheduler here).
+//!
+//! Only architectural state is saved. Breakpoints, halting conditions, hooks
+//! and the decode cache belong to the embedder or can be rebuilt, so
+//! restoring leaves them as they are.
+
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use bus::Bus;
+use cpu::CPU;
+
+const MAGIC: &[u8; 8] = b"RVSNAPSH";
+/// Bumped whenever the layout changes, including when CPUs gain CSRs.
+pub const VERSION: u32 = 10;
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The file isn't a snapshot at all.
+    BadMagic,
+    /// The snapshot was written by an incompatible version of the emulator.
+    Version(u32),
+    /// The file ends early or contains impossible values.
+    Corrupt(&'static str),
+    /// The snapshot is valid, but was taken of a differently configured
+    /// machine.
+    Mismatch(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::Io(ref e) => write!(f, "couldn't access snapshot: {}", e),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::Version(version) => {
+                write!(f, "snapshot is version {}, expected {}", version, VERSION)
+            }
+            SnapshotError::Corrupt(what) => write!(f, "snapshot is corrupt: {}", what),
+            SnapshotError::Mismatch(what) => {
+                write!(f, "snapshot doesn't fit this machine: {}", what)
+            }
+        }
+    }
+}
+
+impl error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> SnapshotError {
+        SnapshotError::Io(e)
+    }
+}
+
+/// Appends little-endian values to a buffer.
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer { buf: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A length-prefixed byte string.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads back what a `Writer` wrote.
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if len > self.data.len() {
+            return Err(SnapshotError::Corrupt("unexpected end of file"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// The shape of the machine a snapshot was taken of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub ram_base: u32,
+    pub ram_size: usize,
+    pub harts: usize,
+    pub xlen: u32,
+}
+
+fn read_header(reader: &mut Reader) -> Result<Header, SnapshotError> {
+    if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(SnapshotError::BadMagic);
+    }
+    match reader.u32()? {
+        VERSION => {}
+        version => return Err(SnapshotError::Version(version)),
+    }
+
+    Ok(Header {
+        ram_base: reader.u32()?,
+        ram_size: reader.u64()? as usize,
+        harts: reader.u32()? as usize,
+        xlen: reader.u32()?,
+    })
+}
+
+/// Read just the header of the snapshot in `data`, to find out what kind of
+/// machine to restore it into.
+pub fn header(data: &[u8]) -> Result<Header, SnapshotError> {
+    read_header(&mut Reader::new(data))
+}
+
+/// Save `harts`, which share the bus held by the first, along with `extra`
+/// state belonging to the caller.
+pub(crate) fn save<W: Write>(mut out: W, harts: &[&CPU], extra: &[u8]) -> io::Result<()> {
+    let bus = &harts[0].bus;
+    let mut writer = Writer::new();
+    writer.buf.extend_from_slice(MAGIC);
+    writer.u32(VERSION);
+    writer.u32(bus.ram_base());
+    writer.u64(bus.ram.len() as u64);
+    writer.u32(harts.len() as u32);
+    writer.u32(harts[0].xlen());
+
+    for hart in harts {
+        writer.bytes(&hart.save_state());
+    }
+    save_bus(bus, &mut writer);
+    writer.bytes(extra);
+
+    out.write_all(&writer.into_inner())
+}
+
+/// Restore a snapshot taken by `save` into `harts`, handing the caller's
+/// extra state to `load_extra`. The whole snapshot is checked against the
+/// harts and their bus, and `load_extra` is called, before anything changes;
+/// only a device rejecting its saved state can leave a restore half done.
+pub(crate) fn load<R, F>(mut input: R, harts: &mut [&mut CPU], load_extra: F)
+                         -> Result<(), SnapshotError>
+    where R: Read,
+          F: FnOnce(&[u8]) -> Result<(), SnapshotError>
+{
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+    let mut reader = Reader::new(&data);
+
+    let header = read_header(&mut reader)?;
+    if header.harts != harts.len() {
+        return Err(SnapshotError::Mismatch("number of harts"));
+    }
+    if header.ram_base != harts[0].bus.ram_base() || header.ram_size != harts[0].bus.ram.len() {
+        return Err(SnapshotError::Mismatch("RAM size or location"));
+    }
+    if harts.iter().any(|hart| hart.xlen() != header.xlen) {
+        return Err(SnapshotError::Mismatch("XLEN"));
+    }
+
+    // Parse everything before touching anything.
+    let mut states = Vec::with_capacity(harts.len());
+    for _ in 0..harts.len() {
+        let state = CPU::parse_state(reader.bytes()?)?;
+        if state.xlen != header.xlen {
+            return Err(SnapshotError::Corrupt("harts with different XLENs"));
+        }
+        states.push(state);
+    }
+    if harts.iter().zip(&states).any(|(hart, state)| hart.isa().vlen() != state.vlen()) {
+        return Err(SnapshotError::Mismatch("VLEN"));
+    }
+    let ram = load_ram(&mut reader, header.ram_size)?;
+    let mut reservations = Vec::new();
+    for _ in 0..reader.u32()? {
+        reservations.push((reader.u32()?, reader.u32()?));
+    }
+    let mut devices = Vec::new();
+    for _ in 0..reader.u32()? {
+        devices.push(reader.bytes()?);
+    }
+    let extra = reader.bytes()?;
+    if !reader.is_empty() {
+        return Err(SnapshotError::Corrupt("trailing data"));
+    }
+    load_extra(extra)?;
+
+    harts[0].bus.load_device_states(&devices)?;
+    harts[0].bus.ram.as_mut_slice().copy_from_slice(&ram);
+    harts[0].bus.set_reservations(reservations);
+    for (hart, state) in harts.iter_mut().zip(states) {
+        hart.load_state(state);
+    }
+
+    Ok(())
+}
+
+fn save_bus(bus: &Bus, writer: &mut Writer) {
+    let pages: Vec<(usize, &[u8])> = bus.ram
+        .as_slice()
+        .chunks(PAGE_SIZE)
+        .enumerate()
+        .filter(|&(_, page)| page.iter().any(|&byte| byte != 0))
+        .collect();
+    writer.u32(pages.len() as u32);
+    for (index, page) in pages {
+        writer.u32(index as u32);
+        writer.bytes(&pack_bits(page));
+    }
+
+    let reservations = bus.reservations();
+    writer.u32(reservations.len() as u32);
+    for &(hart, addr) in reservations {
+        writer.u32(hart);
+        writer.u32(addr);
+    }
+
+    let devices = bus.save_device_states();
+    writer.u32(devices.len() as u32);
+    for state in &devices {
+        writer.bytes(state);
+    }
+}
+
+fn load_ram(reader: &mut Reader, size: usize) -> Result<Vec<u8>, SnapshotError> {
+    let mut ram = vec![0; size];
+    for _ in 0..reader.u32()? {
+        let start = reader.u32()? as usize * PAGE_SIZE;
+        let page = ram.get_mut(start..)
+            .and_then(|rest| {
+                let len = rest.len().min(PAGE_SIZE);
+                rest.get_mut(..len)
+            })
+            .filter(|page| !page.is_empty())
+            .ok_or(SnapshotError::Corrupt("page outside of RAM"))?;
+        unpack_bits(reader.bytes()?, page)?;
+    }
+    Ok(ram)
+}
+
+/// Compress `data` with PackBits: a header byte `n` is followed by `n + 1`
+/// literal bytes if it's below 128, and otherwise by one byte to repeat
+/// `257 - n` times.
+fn pack_bits(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..].iter().take(128).take_while(|&&byte| byte == data[i]).count();
+        if run >= 2 {
+            out.push((257 - run) as u8);
+            out.push(data[i]);
+            i += run;
+            continue;
+        }
+
+        // Take literals up to the next run of at least three, which is where
+        // switching to a run starts to pay off.
+        let start = i;
+        while i < data.len() && i - start < 128 {
+            if i + 2 < data.len() && data[i] == data[i + 1] && data[i] == data[i + 2] {
+                break;
+            }
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&data[start..i]);
+    }
+    out
+}
+
+/// Decompress PackBits data, which must fill `out` exactly.
+fn unpack_bits(mut data: &[u8], out: &mut [u8]) -> Result<(), SnapshotError> {
+    let corrupt = SnapshotError::Corrupt("bad page data");
+    let mut pos = 0;
+    while let Some((&header, rest)) = data.split_first() {
+        let (len, literal) = if header < 128 {
+            (header as usize + 1, true)
+        } else {
+            (257 - header as usize, false)
+        };
+        if pos + len > out.len() {
+            return Err(corrupt);
+        }
+
+        if literal {
+            let bytes = rest.get(..len).ok_or(SnapshotError::Corrupt("bad page data"))?;
+            out[pos..pos + len].copy_from_slice(bytes);
+            data = &rest[len..];
+        } else {
+            let &byte = rest.first().ok_or(SnapshotError::Corrupt("bad page data"))?;
+            for b in &mut out[pos..pos + len] {
+                *b = byte;
+            }
+            data = &rest[1..];
+        }
+        pos += len;
+    }
+
+    if pos == out.len() {
+        Ok(())
+    } else {
+        Err(corrupt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_bits() {
+        let mut page = vec![0u8; PAGE_SIZE];
+        page[100] = 1;
+        page[101] = 2;
+        page[102] = 2;
+        for (i, byte) in page[1000..1300].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        page[PAGE_SIZE - 1] = 7;
+
+        let packed = pack_bits(&page);
+        assert!(packed.len() < 400);
+        let mut unpacked = vec![0xAA; PAGE_SIZE];
+        unpack_bits(&packed, &mut unpacked).expect("couldn't unpack");
+        assert!(unpacked == page);
+
+        let mut short = vec![0; PAGE_SIZE - 1];
+        assert!(unpack_bits(&packed, &mut short).is_err());
+        assert!(unpack_bits(&packed[..packed.len() - 1], &mut unpacked).is_err());
+    }
+}
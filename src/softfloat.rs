@@ -0,0 +1,670 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! IEEE 754 binary floating point in software, the way RISC-V wants it:
+//! every result is rounded once in any of the five rounding modes, tininess
+//! is detected after rounding, and any NaN a computation produces is the
+//! canonical one.
+//!
+//! Numbers go in and out as their encodings, in the low bits of a `u64`.
+//! In between, a finite number is a sign and an integer significand scaled
+//! by a power of two. Significands are kept in 128 bits with the leading
+//! one well above the bits any format keeps, and whatever is shifted out
+//! below is only remembered as a sticky bit in the lowest place.
+
+use std::cmp::{self, Ordering};
+
+/// The exception flags, as `fflags` has them.
+pub const INVALID: u8 = 1 << 4;
+pub const DIVIDE_BY_ZERO: u8 = 1 << 3;
+pub const OVERFLOW: u8 = 1 << 2;
+pub const UNDERFLOW: u8 = 1 << 1;
+pub const INEXACT: u8 = 1;
+
+// Where significands are lined up for addition and division: a couple of
+// bits below the top, so sums have room to carry.
+const TOP: u32 = 125;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    NearestEven,
+    TowardZero,
+    Down,
+    Up,
+    NearestMaxMagnitude,
+}
+
+impl Rounding {
+    /// The rounding mode an `rm` field or `frm` encodes, or None for the
+    /// reserved encodings and the dynamic one.
+    pub fn from_bits(rm: u8) -> Option<Rounding> {
+        match rm {
+            0 => Some(Rounding::NearestEven),
+            1 => Some(Rounding::TowardZero),
+            2 => Some(Rounding::Down),
+            3 => Some(Rounding::Up),
+            4 => Some(Rounding::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+/// A binary interchange format: how many bits of exponent and fraction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    exponent: u32,
+    fraction: u32,
+}
+
+pub const HALF: Format = Format {
+    exponent: 5,
+    fraction: 10,
+};
+
+/// The classes `fclass` tells apart, as the bit each sets.
+pub const NEGATIVE_INFINITY: u64 = 1;
+pub const NEGATIVE_NORMAL: u64 = 1 << 1;
+pub const NEGATIVE_SUBNORMAL: u64 = 1 << 2;
+pub const NEGATIVE_ZERO: u64 = 1 << 3;
+pub const POSITIVE_ZERO: u64 = 1 << 4;
+pub const POSITIVE_SUBNORMAL: u64 = 1 << 5;
+pub const POSITIVE_NORMAL: u64 = 1 << 6;
+pub const POSITIVE_INFINITY: u64 = 1 << 7;
+pub const SIGNALING_NAN: u64 = 1 << 8;
+pub const QUIET_NAN: u64 = 1 << 9;
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exponent - 1)) - 1
+    }
+
+    // The exponents of the smallest and largest normal numbers.
+    fn emin(self) -> i32 {
+        1 - self.bias()
+    }
+
+    fn emax(self) -> i32 {
+        self.bias()
+    }
+
+    fn sign_bit(self) -> u64 {
+        1 << (self.exponent + self.fraction)
+    }
+
+    fn exponent_mask(self) -> u64 {
+        ((1 << self.exponent) - 1) << self.fraction
+    }
+
+    fn fraction_mask(self) -> u64 {
+        (1 << self.fraction) - 1
+    }
+
+    /// The canonical NaN: positive and quiet, with nothing else in its
+    /// fraction.
+    pub fn canonical_nan(self) -> u64 {
+        self.exponent_mask() | 1 << (self.fraction - 1)
+    }
+
+    pub fn infinity(self, sign: bool) -> u64 {
+        self.zero(sign) | self.exponent_mask()
+    }
+
+    pub fn zero(self, sign: bool) -> u64 {
+        if sign { self.sign_bit() } else { 0 }
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.infinity(sign) - 1
+    }
+
+    pub fn is_nan(self, a: u64) -> bool {
+        a & self.exponent_mask() == self.exponent_mask() && a & self.fraction_mask() != 0
+    }
+
+    pub fn is_signaling(self, a: u64) -> bool {
+        self.is_nan(a) && a & 1 << (self.fraction - 1) == 0
+    }
+
+    fn sign(self, a: u64) -> bool {
+        a & self.sign_bit() != 0
+    }
+
+    fn unpack(self, a: u64) -> Value {
+        let sign = self.sign(a);
+        let biased = ((a & self.exponent_mask()) >> self.fraction) as i32;
+        let fraction = a & self.fraction_mask();
+        if a & self.exponent_mask() == self.exponent_mask() {
+            if fraction == 0 { Value::Infinite(sign) } else { Value::NaN }
+        } else if biased == 0 {
+            if fraction == 0 {
+                Value::Zero(sign)
+            } else {
+                Value::Finite(sign, self.emin() - self.fraction as i32, fraction as u128)
+            }
+        } else {
+            let significand = fraction | 1 << self.fraction;
+            Value::Finite(sign,
+                          biased - self.bias() - self.fraction as i32,
+                          significand as u128)
+        }
+    }
+
+    // Round sign * significand * 2^exponent to this format.
+    fn round(self, sign: bool, exponent: i32, significand: u128, rounding: Rounding) -> (u64, u8) {
+        let fraction = self.fraction as i32;
+        let top = exponent + 127 - significand.leading_zeros() as i32;
+        // The exponent of the last bit kept, which is fixed below emin.
+        let mut last = cmp::max(top, self.emin()) - fraction;
+        let (mut kept, inexact) = shift_round(significand, last - exponent, sign, rounding);
+        if kept >> (fraction + 1) != 0 {
+            // Rounding carried into a new bit
+            kept >>= 1;
+            last += 1;
+        }
+
+        // Tiny if, rounded with an unbounded exponent, it'd still be below
+        // the smallest normal number.
+        let tiny = top < self.emin() &&
+                   !(top == self.emin() - 1 &&
+                     shift_round(significand, top - fraction - exponent, sign, rounding).0 >>
+                     (fraction + 1) != 0);
+        let mut flags = if inexact { INEXACT } else { 0 };
+        if tiny && inexact {
+            flags |= UNDERFLOW;
+        }
+
+        if kept == 0 {
+            return (self.zero(sign), flags);
+        }
+        if last + fraction > self.emax() {
+            let infinite = match rounding {
+                Rounding::NearestEven | Rounding::NearestMaxMagnitude => true,
+                Rounding::TowardZero => false,
+                Rounding::Down => sign,
+                Rounding::Up => !sign,
+            };
+            let result = if infinite { self.infinity(sign) } else { self.max_finite(sign) };
+            return (result, OVERFLOW | INEXACT);
+        }
+
+        let kept = kept as u64;
+        let biased = if kept >> fraction != 0 { (last + fraction + self.bias()) as u64 } else { 0 };
+        (self.zero(sign) | biased << fraction | (kept & self.fraction_mask()), flags)
+    }
+
+    // Round an integer, as some of a computation's results are.
+    fn round_integer(self, sign: bool, magnitude: u128, rounding: Rounding) -> (u64, u8) {
+        if magnitude == 0 {
+            (self.zero(sign), 0)
+        } else {
+            self.round(sign, 0, magnitude, rounding)
+        }
+    }
+
+    // The result of an operation with a NaN operand.
+    fn propagate_nan(self, operands: &[u64]) -> (u64, u8) {
+        let signaling = operands.iter().any(|&a| self.is_signaling(a));
+        (self.canonical_nan(), if signaling { INVALID } else { 0 })
+    }
+}
+
+// A number, taken apart.
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    NaN,
+    Infinite(bool),
+    Zero(bool),
+    // The sign, then an exponent and significand for the magnitude
+    // significand * 2^exponent. The significand isn't zero.
+    Finite(bool, i32, u128),
+}
+
+// `significand` shifted right by `shift` bits, rounded, and whether that
+// lost anything. Shifting left is exact.
+fn shift_round(significand: u128, shift: i32, sign: bool, rounding: Rounding) -> (u128, bool) {
+    if shift <= 0 {
+        return (significand << -shift, false);
+    }
+    // The bits shifted out, and half of the last bit kept
+    let (kept, rest, half) = match shift {
+        1..=127 => {
+            (significand >> shift, significand & ((1 << shift) - 1), 1 << (shift - 1))
+        }
+        128 => (0, significand, 1 << 127),
+        // Less than half, but not nothing
+        _ => (0, 1, 2),
+    };
+    let up = match rounding {
+        Rounding::NearestEven => rest > half || (rest == half && kept & 1 != 0),
+        Rounding::NearestMaxMagnitude => rest >= half,
+        Rounding::TowardZero => false,
+        Rounding::Down => sign && rest != 0,
+        Rounding::Up => !sign && rest != 0,
+    };
+    (kept + up as u128, rest != 0)
+}
+
+// `significand` shifted right by `shift` bits, with anything shifted out
+// kept as a sticky lowest bit.
+fn shift_right_sticky(significand: u128, shift: u32) -> u128 {
+    if shift >= 128 {
+        (significand != 0) as u128
+    } else {
+        significand >> shift | (significand & ((1 << shift) - 1) != 0) as u128
+    }
+}
+
+// The exponent of the leading one of significand * 2^exponent.
+fn top(exponent: i32, significand: u128) -> i32 {
+    exponent + 127 - significand.leading_zeros() as i32
+}
+
+// The sum of two non-zero finite numbers.
+fn add_finite(format: Format,
+              (sign1, exp1, sig1): (bool, i32, u128),
+              (sign2, exp2, sig2): (bool, i32, u128),
+              rounding: Rounding)
+              -> (u64, u8) {
+    // Line both up so that the larger one's leading one is at TOP.
+    let exponent = cmp::max(top(exp1, sig1), top(exp2, sig2)) - TOP as i32;
+    let align = |exp: i32, sig: u128| {
+        if exp >= exponent {
+            sig << (exp - exponent)
+        } else {
+            shift_right_sticky(sig, (exponent - exp) as u32)
+        }
+    };
+    let (sig1, sig2) = (align(exp1, sig1), align(exp2, sig2));
+
+    let (sign, sum) = if sign1 == sign2 {
+        (sign1, sig1 + sig2)
+    } else if sig1 >= sig2 {
+        (sign1, sig1 - sig2)
+    } else {
+        (sign2, sig2 - sig1)
+    };
+    if sum == 0 {
+        // Exact cancellation is +0, except when rounding down.
+        (format.zero(rounding == Rounding::Down), 0)
+    } else {
+        format.round(sign, exponent, sum, rounding)
+    }
+}
+
+pub fn add(format: Format, a: u64, b: u64, rounding: Rounding) -> (u64, u8) {
+    match (format.unpack(a), format.unpack(b)) {
+        (Value::NaN, _) | (_, Value::NaN) => format.propagate_nan(&[a, b]),
+        (Value::Infinite(sign1), Value::Infinite(sign2)) if sign1 != sign2 => {
+            (format.canonical_nan(), INVALID)
+        }
+        (Value::Infinite(sign), _) | (_, Value::Infinite(sign)) => (format.infinity(sign), 0),
+        (Value::Zero(sign1), Value::Zero(sign2)) => {
+            let sign = if sign1 == sign2 { sign1 } else { rounding == Rounding::Down };
+            (format.zero(sign), 0)
+        }
+        (Value::Zero(_), _) => (b, 0),
+        (_, Value::Zero(_)) => (a, 0),
+        (Value::Finite(sign1, exp1, sig1), Value::Finite(sign2, exp2, sig2)) => {
+            add_finite(format, (sign1, exp1, sig1), (sign2, exp2, sig2), rounding)
+        }
+    }
+}
+
+pub fn sub(format: Format, a: u64, b: u64, rounding: Rounding) -> (u64, u8) {
+    // Flipping a NaN's sign doesn't matter, since the result is canonical.
+    add(format, a, b ^ format.sign_bit(), rounding)
+}
+
+pub fn mul(format: Format, a: u64, b: u64, rounding: Rounding) -> (u64, u8) {
+    let sign = format.sign(a) != format.sign(b);
+    match (format.unpack(a), format.unpack(b)) {
+        (Value::NaN, _) | (_, Value::NaN) => format.propagate_nan(&[a, b]),
+        (Value::Infinite(_), Value::Zero(_)) | (Value::Zero(_), Value::Infinite(_)) => {
+            (format.canonical_nan(), INVALID)
+        }
+        (Value::Infinite(_), _) | (_, Value::Infinite(_)) => (format.infinity(sign), 0),
+        (Value::Zero(_), _) | (_, Value::Zero(_)) => (format.zero(sign), 0),
+        (Value::Finite(_, exp1, sig1), Value::Finite(_, exp2, sig2)) => {
+            format.round(sign, exp1 + exp2, sig1 * sig2, rounding)
+        }
+    }
+}
+
+/// a * b + c, rounded once.
+pub fn fma(format: Format, a: u64, b: u64, c: u64, rounding: Rounding) -> (u64, u8) {
+    let sign = format.sign(a) != format.sign(b);
+    let (a_value, b_value, c_value) = (format.unpack(a), format.unpack(b), format.unpack(c));
+    let infinity_times_zero = matches!((a_value, b_value),
+                                       (Value::Infinite(_), Value::Zero(_)) |
+                                       (Value::Zero(_), Value::Infinite(_)));
+    // Infinity times zero is invalid even when c is a quiet NaN.
+    if infinity_times_zero {
+        return (format.canonical_nan(), INVALID);
+    }
+
+    let product = match (a_value, b_value) {
+        (Value::NaN, _) | (_, Value::NaN) => return format.propagate_nan(&[a, b, c]),
+        _ if format.is_nan(c) => return format.propagate_nan(&[c]),
+        (Value::Infinite(_), _) | (_, Value::Infinite(_)) => Value::Infinite(sign),
+        (Value::Zero(_), _) | (_, Value::Zero(_)) => Value::Zero(sign),
+        (Value::Finite(_, exp1, sig1), Value::Finite(_, exp2, sig2)) => {
+            Value::Finite(sign, exp1 + exp2, sig1 * sig2)
+        }
+    };
+
+    match (product, c_value) {
+        (Value::Infinite(sign1), Value::Infinite(sign2)) if sign1 != sign2 => {
+            (format.canonical_nan(), INVALID)
+        }
+        (Value::Infinite(sign), _) | (_, Value::Infinite(sign)) => (format.infinity(sign), 0),
+        (Value::Zero(sign1), Value::Zero(sign2)) => {
+            let sign = if sign1 == sign2 { sign1 } else { rounding == Rounding::Down };
+            (format.zero(sign), 0)
+        }
+        (Value::Zero(_), _) => (c, 0),
+        (Value::Finite(sign, exp, sig), Value::Zero(_)) => format.round(sign, exp, sig, rounding),
+        (Value::Finite(sign1, exp1, sig1), Value::Finite(sign2, exp2, sig2)) => {
+            add_finite(format, (sign1, exp1, sig1), (sign2, exp2, sig2), rounding)
+        }
+        (Value::NaN, _) | (_, Value::NaN) => unreachable!(),
+    }
+}
+
+pub fn div(format: Format, a: u64, b: u64, rounding: Rounding) -> (u64, u8) {
+    let sign = format.sign(a) != format.sign(b);
+    match (format.unpack(a), format.unpack(b)) {
+        (Value::NaN, _) | (_, Value::NaN) => format.propagate_nan(&[a, b]),
+        (Value::Infinite(_), Value::Infinite(_)) | (Value::Zero(_), Value::Zero(_)) => {
+            (format.canonical_nan(), INVALID)
+        }
+        (Value::Infinite(_), _) => (format.infinity(sign), 0),
+        (_, Value::Infinite(_)) | (Value::Zero(_), _) => (format.zero(sign), 0),
+        (_, Value::Zero(_)) => (format.infinity(sign), DIVIDE_BY_ZERO),
+        (Value::Finite(_, exp1, sig1), Value::Finite(_, exp2, sig2)) => {
+            // Dividing with the dividend at TOP leaves far more quotient
+            // bits than any format keeps.
+            let shift = TOP as i32 - (127 - sig1.leading_zeros() as i32);
+            let dividend = sig1 << shift;
+            let quotient = (dividend / sig2) | !dividend.is_multiple_of(sig2) as u128;
+            format.round(sign, exp1 - shift - exp2, quotient, rounding)
+        }
+    }
+}
+
+pub fn sqrt(format: Format, a: u64, rounding: Rounding) -> (u64, u8) {
+    match format.unpack(a) {
+        Value::NaN => format.propagate_nan(&[a]),
+        Value::Zero(_) => (a, 0),
+        Value::Infinite(false) => (a, 0),
+        Value::Infinite(true) | Value::Finite(true, _, _) => (format.canonical_nan(), INVALID),
+        Value::Finite(false, exp, sig) => {
+            // Take the root of a significand at TOP or just below it,
+            // with an even exponent left over to halve.
+            let mut shift = TOP as i32 - 1 - (127 - sig.leading_zeros() as i32);
+            if (exp - shift) % 2 != 0 {
+                shift += 1;
+            }
+            let (root, rest) = isqrt(sig << shift);
+            format.round(false, (exp - shift) / 2, root | (rest != 0) as u128, rounding)
+        }
+    }
+}
+
+// The integer square root of `value`, and what's left over.
+fn isqrt(value: u128) -> (u128, u128) {
+    let mut root = 0;
+    let mut rest = value;
+    let mut bit = 1 << 126;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rest >= root + bit {
+            rest -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    (root, rest)
+}
+
+// A key that orders numbers other than NaNs, with -0 below +0.
+fn order_key(format: Format, a: u64) -> i64 {
+    let magnitude = (a & !format.sign_bit()) as i64;
+    if format.sign(a) { -magnitude - 1 } else { magnitude }
+}
+
+/// Compare `a` and `b`, which is unordered if either is a NaN. Signaling
+/// comparisons are invalid for any NaN, quiet ones only for signaling NaNs.
+pub fn compare(format: Format, a: u64, b: u64, signaling: bool) -> (Option<Ordering>, u8) {
+    if format.is_nan(a) || format.is_nan(b) {
+        let invalid = signaling || format.is_signaling(a) || format.is_signaling(b);
+        return (None, if invalid { INVALID } else { 0 });
+    }
+    // -0 and +0 are equal.
+    let key = |a: u64| if a & !format.sign_bit() == 0 { 0 } else { order_key(format, a) };
+    (Some(key(a).cmp(&key(b))), 0)
+}
+
+/// The smaller, or with `max` the larger, of `a` and `b`, with -0 below +0.
+/// A NaN loses to a number, unless `nan_wins`.
+pub fn min_max(format: Format, a: u64, b: u64, max: bool, nan_wins: bool) -> (u64, u8) {
+    let flags = if format.is_signaling(a) || format.is_signaling(b) { INVALID } else { 0 };
+    let result = match (format.is_nan(a), format.is_nan(b)) {
+        (true, true) => format.canonical_nan(),
+        (true, false) | (false, true) if nan_wins => format.canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        (false, false) => {
+            let a_first = order_key(format, a) < order_key(format, b);
+            if a_first != max { a } else { b }
+        }
+    };
+    (result, flags)
+}
+
+/// Which class `a` is in, as one of the class bits.
+pub fn classify(format: Format, a: u64) -> u64 {
+    match format.unpack(a) {
+        Value::NaN if format.is_signaling(a) => SIGNALING_NAN,
+        Value::NaN => QUIET_NAN,
+        Value::Infinite(true) => NEGATIVE_INFINITY,
+        Value::Infinite(false) => POSITIVE_INFINITY,
+        Value::Zero(true) => NEGATIVE_ZERO,
+        Value::Zero(false) => POSITIVE_ZERO,
+        Value::Finite(sign, _, _) => {
+            let normal = a & format.exponent_mask() != 0;
+            match (sign, normal) {
+                (true, true) => NEGATIVE_NORMAL,
+                (true, false) => NEGATIVE_SUBNORMAL,
+                (false, false) => POSITIVE_SUBNORMAL,
+                (false, true) => POSITIVE_NORMAL,
+            }
+        }
+    }
+}
+
+/// `a` as a signed or unsigned integer `bits` wide, in the low bits of the
+/// result. Out of range values, including infinities and NaNs, saturate
+/// and are invalid; NaNs count as positive.
+pub fn to_integer(format: Format, a: u64, bits: u32, signed: bool, rounding: Rounding)
+                  -> (u64, u8) {
+    let (min, max): (i128, i128) = if signed {
+        (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+    } else {
+        (0, (1 << bits) - 1)
+    };
+    let saturate = |negative: bool| {
+        let value = if negative { min } else { max };
+        (value as u64 & (!0 >> (64 - bits)), INVALID)
+    };
+
+    let (sign, exp, sig) = match format.unpack(a) {
+        Value::NaN => return saturate(false),
+        Value::Infinite(sign) => return saturate(sign),
+        Value::Zero(_) => return (0, 0),
+        Value::Finite(sign, exp, sig) => (sign, exp, sig),
+    };
+    // Anything with more than 65 bits before the point is out of range.
+    if top(exp, sig) > 64 {
+        return saturate(sign);
+    }
+    let (magnitude, inexact) = shift_round(sig, -exp, sign, rounding);
+    let value = if sign { -(magnitude as i128) } else { magnitude as i128 };
+    if value < min || value > max {
+        return saturate(sign);
+    }
+    (value as u64 & (!0 >> (64 - bits)), if inexact { INEXACT } else { 0 })
+}
+
+/// The integer `value`, signed or not, rounded to `format`.
+pub fn from_integer(format: Format, value: u64, signed: bool, rounding: Rounding) -> (u64, u8) {
+    let sign = signed && (value as i64) < 0;
+    let magnitude = if sign { (value as i64).unsigned_abs() } else { value };
+    format.round_integer(sign, magnitude as u128, rounding)
+}
+
+/// `a` rounded to an integer, staying in `format`. Only sets the inexact
+/// flag if `exact` says to.
+pub fn round_to_integral(format: Format, a: u64, rounding: Rounding, exact: bool) -> (u64, u8) {
+    match format.unpack(a) {
+        Value::NaN => format.propagate_nan(&[a]),
+        Value::Finite(sign, exp, sig) if exp < 0 => {
+            let (magnitude, inexact) = shift_round(sig, -exp, sign, rounding);
+            let (result, _) = format.round_integer(sign, magnitude, rounding);
+            (result, if exact && inexact { INEXACT } else { 0 })
+        }
+        // Infinities, zeros, and numbers too big to have a fraction
+        _ => (a, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RNE: Rounding = Rounding::NearestEven;
+
+    #[test]
+    fn test_arithmetic() {
+        // 1 + 2 = 3, 1 - 1 = 0, 3 * 0.5 = 1.5, 1 / 3, sqrt(2)
+        assert_eq!(add(HALF, 0x3C00, 0x4000, RNE), (0x4200, 0));
+        assert_eq!(sub(HALF, 0x3C00, 0x3C00, RNE), (0x0000, 0));
+        assert_eq!(sub(HALF, 0x3C00, 0x3C00, Rounding::Down), (0x8000, 0));
+        assert_eq!(mul(HALF, 0x4200, 0x3800, RNE), (0x3E00, 0));
+        assert_eq!(div(HALF, 0x3C00, 0x4200, RNE), (0x3555, INEXACT));
+        assert_eq!(div(HALF, 0x3C00, 0x4200, Rounding::Up), (0x3556, INEXACT));
+        assert_eq!(sqrt(HALF, 0x4000, RNE), (0x3DA8, INEXACT));
+        assert_eq!(sqrt(HALF, 0x4400, RNE), (0x4000, 0));
+
+        // 1 + 2^-11 is a tie, which goes to even, or away with RMM.
+        assert_eq!(add(HALF, 0x3C00, 0x1000, RNE), (0x3C00, INEXACT));
+        assert_eq!(add(HALF, 0x3C00, 0x1000, Rounding::NearestMaxMagnitude),
+                   (0x3C01, INEXACT));
+        // 1 - 2^-24: the smallest subnormal is far below the last bit kept
+        assert_eq!(sub(HALF, 0x3C00, 0x0001, Rounding::TowardZero), (0x3BFF, INEXACT));
+
+        // (1 + 2^-10)^2 - (1 + 2^-9) is exactly 2^-20 when fused.
+        assert_eq!(fma(HALF, 0x3C01, 0x3C01, 0xBC02, RNE), (0x0010, 0));
+        assert_eq!(mul(HALF, 0x3C01, 0x3C01, RNE), (0x3C02, INEXACT));
+    }
+
+    #[test]
+    fn test_special_values() {
+        let nan = HALF.canonical_nan();
+        let (inf, snan) = (HALF.infinity(false), 0x7C01);
+        assert_eq!(add(HALF, inf, HALF.infinity(true), RNE), (nan, INVALID));
+        assert_eq!(add(HALF, 0x3C00, snan, RNE), (nan, INVALID));
+        assert_eq!(add(HALF, 0x3C00, 0x7E55, RNE), (nan, 0));
+        assert_eq!(mul(HALF, inf, 0x8000, RNE), (nan, INVALID));
+        assert_eq!(div(HALF, 0xBC00, 0x0000, RNE), (HALF.infinity(true), DIVIDE_BY_ZERO));
+        assert_eq!(div(HALF, 0x0000, 0x0000, RNE), (nan, INVALID));
+        assert_eq!(sqrt(HALF, 0x8000, RNE), (0x8000, 0));
+        assert_eq!(sqrt(HALF, 0xBC00, RNE), (nan, INVALID));
+        assert_eq!(fma(HALF, inf, 0x0000, 0x7E00, RNE), (nan, INVALID));
+
+        // 65504 * 2 overflows to infinity, or the largest number toward 0.
+        assert_eq!(mul(HALF, 0x7BFF, 0x4000, RNE), (inf, OVERFLOW | INEXACT));
+        assert_eq!(mul(HALF, 0x7BFF, 0x4000, Rounding::TowardZero),
+                   (0x7BFF, OVERFLOW | INEXACT));
+        assert_eq!(mul(HALF, 0xFBFF, 0x4000, Rounding::Up), (0xFBFF, OVERFLOW | INEXACT));
+    }
+
+    #[test]
+    fn test_underflow() {
+        // 2^-24 * 0.5 is a tie between 0 and the smallest subnormal.
+        assert_eq!(mul(HALF, 0x0001, 0x3800, RNE), (0x0000, UNDERFLOW | INEXACT));
+        assert_eq!(mul(HALF, 0x0001, 0x3800, Rounding::Up), (0x0001, UNDERFLOW | INEXACT));
+        // Subnormal results that are exact don't underflow.
+        assert_eq!(mul(HALF, 0x0002, 0x3800, RNE), (0x0001, 0));
+        // Just below the smallest normal number, but rounding up to it: not
+        // tiny after rounding, when the unbounded result rounds up too.
+        assert_eq!(mul(HALF, 0x03FF, 0x3C01, RNE), (0x0400, INEXACT));
+        assert_eq!(mul(HALF, 0x03FF, 0x3C01, Rounding::TowardZero),
+                   (0x03FF, UNDERFLOW | INEXACT));
+    }
+
+    #[test]
+    fn test_compare_and_min_max() {
+        assert_eq!(compare(HALF, 0x8000, 0x0000, false), (Some(Ordering::Equal), 0));
+        assert_eq!(compare(HALF, 0xBC00, 0x3C00, true), (Some(Ordering::Less), 0));
+        assert_eq!(compare(HALF, 0x7E00, 0x3C00, false), (None, 0));
+        assert_eq!(compare(HALF, 0x7E00, 0x3C00, true), (None, INVALID));
+        assert_eq!(compare(HALF, 0x7C01, 0x3C00, false), (None, INVALID));
+
+        assert_eq!(min_max(HALF, 0x8000, 0x0000, false, false), (0x8000, 0));
+        assert_eq!(min_max(HALF, 0x8000, 0x0000, true, false), (0x0000, 0));
+        assert_eq!(min_max(HALF, 0x7E00, 0x3C00, false, false), (0x3C00, 0));
+        assert_eq!(min_max(HALF, 0x7C01, 0x3C00, true, false), (0x3C00, INVALID));
+        assert_eq!(min_max(HALF, 0x7E00, 0x3C00, false, true), (0x7E00, 0));
+    }
+
+    #[test]
+    fn test_conversions() {
+        // 2.5 to the nearest even integer, and away from zero
+        assert_eq!(to_integer(HALF, 0x4100, 32, true, RNE), (2, INEXACT));
+        assert_eq!(to_integer(HALF, 0x4100, 32, true, Rounding::NearestMaxMagnitude),
+                   (3, INEXACT));
+        assert_eq!(to_integer(HALF, 0xC100, 32, true, Rounding::Down),
+                   (0xFFFF_FFFD, INEXACT));
+        // Negative numbers rounding to zero are fine unsigned; -1 isn't.
+        assert_eq!(to_integer(HALF, 0xB400, 32, false, Rounding::TowardZero), (0, INEXACT));
+        assert_eq!(to_integer(HALF, 0xBC00, 32, false, RNE), (0, INVALID));
+        assert_eq!(to_integer(HALF, 0x7C00, 64, true, RNE), (i64::MAX as u64, INVALID));
+        assert_eq!(to_integer(HALF, 0x7E00, 32, false, RNE), (0xFFFF_FFFF, INVALID));
+        assert_eq!(to_integer(HALF, 0x7BFF, 8, true, RNE), (0x7F, INVALID));
+
+        assert_eq!(from_integer(HALF, (-3i64) as u64, true, RNE), (0xC200, 0));
+        assert_eq!(from_integer(HALF, 2049, false, RNE), (0x6800, INEXACT));
+        assert_eq!(from_integer(HALF, 70000, false, RNE), (0x7C00, OVERFLOW | INEXACT));
+        assert_eq!(from_integer(HALF, 0, true, RNE), (0x0000, 0));
+
+        assert_eq!(round_to_integral(HALF, 0x3E00, RNE, true), (0x4000, INEXACT));
+        assert_eq!(round_to_integral(HALF, 0x3E00, Rounding::Down, false), (0x3C00, 0));
+        assert_eq!(round_to_integral(HALF, 0xB400, RNE, true), (0x8000, INEXACT));
+        assert_eq!(round_to_integral(HALF, 0x7C01, RNE, false), (0x7E00, INVALID));
+    }
+
+    #[test]
+    fn test_classify() {
+        let expected = [(0xFC00, NEGATIVE_INFINITY),
+                        (0xBC00, NEGATIVE_NORMAL),
+                        (0x8001, NEGATIVE_SUBNORMAL),
+                        (0x8000, NEGATIVE_ZERO),
+                        (0x0000, POSITIVE_ZERO),
+                        (0x03FF, POSITIVE_SUBNORMAL),
+                        (0x0400, POSITIVE_NORMAL),
+                        (0x7C00, POSITIVE_INFINITY),
+                        (0x7D00, SIGNALING_NAN),
+                        (0xFE00, QUIET_NAN)];
+        for &(a, class) in &expected {
+            assert_eq!(classify(HALF, a), class, "{:#x}", a);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use debug::DebugCause;
+
+/// A synchronous exception raised while executing an instruction.
+///
+/// The value carried by most variants is what ends up in `mtval`:
+/// the faulting address for memory exceptions and the raw instruction word for
+/// illegal instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u32),
+    Breakpoint(u64),
+    /// Only raised by LR; ordinary loads may be misaligned.
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    /// Only raised by SC and AMOs; ordinary stores may be misaligned.
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    EnvironmentCall,
+}
+
+impl Exception {
+    /// The exception code written to `mcause`. Environment calls are reported
+    /// relative to the privilege level they were made from, so the caller
+    /// supplies that level's encoding.
+    pub fn cause(&self, privilege: u8) -> u64 {
+        match *self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCall => 8 + privilege as u64,
+        }
+    }
+
+    pub fn tval(&self) -> u64 {
+        match *self {
+            Exception::IllegalInstruction(raw) => raw as u64,
+            Exception::InstructionAddressMisaligned(value) |
+            Exception::InstructionAccessFault(value) |
+            Exception::Breakpoint(value) |
+            Exception::LoadAddressMisaligned(value) |
+            Exception::LoadAccessFault(value) |
+            Exception::StoreAddressMisaligned(value) |
+            Exception::StoreAccessFault(value) => value,
+            Exception::EnvironmentCall => 0,
+        }
+    }
+}
+
+/// The machine-level interrupts, numbered by their bit in `mip` and `mie`
+/// and their exception code in `mcause`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    Software = 3,
+    Timer = 7,
+    External = 11,
+    /// A programmable counter overflowed (Sscofpmf).
+    CounterOverflow = 13,
+}
+
+/// The kind of memory access that caused a `StopReason::MemoryFault` or hit a
+/// watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+/// Why `CPU::step` or `CPU::run_for` handed control back to the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// One of the CPU's halting conditions was met. Carries the program's exit
+    /// code (the value of `a0` at the time).
+    Halted(u32),
+    /// Execution reached an address with a breakpoint set, or executed EBREAK
+    /// without a trap handler installed.
+    Breakpoint,
+    /// The last instruction accessed a watched address.
+    Watchpoint { addr: u32, access: Access },
+    /// The requested number of instructions has been executed.
+    InstructionLimit,
+    IllegalInstruction { pc: u32, raw: u32 },
+    MemoryFault { pc: u32, addr: u64, access: Access },
+    /// An exception was raised with no trap handler installed to take it.
+    Trap { cause: u64 },
+    /// The hart executed WFI.
+    Wfi,
+    /// A hook asked for execution to stop.
+    Hook,
+    /// The hart is in debug mode, having entered it for this reason or
+    /// finished running instructions there with EBREAK. It resumes with
+    /// DRET or `CPU::resume_from_debug_mode`.
+    Debug(DebugCause),
+}
@@ -0,0 +1,135 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The vector register file, and the CSRs describing what's in it.
+//!
+//! The 32 registers are stored back to back, little-endian, so a register
+//! group is just a longer run of bytes and element `i` of a group starting
+//! at `v8` is wherever `i` elements past the start of `v8` happens to be.
+
+/// The vector unit's architectural state.
+#[derive(Clone, PartialEq, Eq)]
+pub struct VectorState {
+    vlen: u32,
+    regs: Vec<u8>,
+    pub(crate) vl: u64,
+    pub(crate) vtype: u64,
+    pub(crate) vstart: u64,
+    pub(crate) vxrm: u8,
+    pub(crate) vxsat: bool,
+}
+
+impl VectorState {
+    /// Registers `vlen` bits wide, with `vtype.vill` set until the first
+    /// `vsetvl`. A `vlen` of 0 is a hart without vectors.
+    pub fn new(vlen: u32, xlen: u32) -> VectorState {
+        VectorState {
+            vlen,
+            regs: vec![0; 32 * vlen as usize / 8],
+            vl: 0,
+            vtype: 1 << (xlen - 1),
+            vstart: 0,
+            vxrm: 0,
+            vxsat: false,
+        }
+    }
+
+    /// The width of each register in bits.
+    pub fn vlen(&self) -> u32 {
+        self.vlen
+    }
+
+    /// The width of each register in bytes, which is what `vlenb` reads.
+    pub fn vlenb(&self) -> usize {
+        self.vlen as usize / 8
+    }
+
+    pub fn vl(&self) -> u64 {
+        self.vl
+    }
+
+    pub fn vtype(&self) -> u64 {
+        self.vtype
+    }
+
+    pub fn vstart(&self) -> u64 {
+        self.vstart
+    }
+
+    /// The contents of register `reg`.
+    pub fn register(&self, reg: u8) -> &[u8] {
+        let start = reg as usize * self.vlenb();
+        &self.regs[start..start + self.vlenb()]
+    }
+
+    /// Overwrite the start of register group `reg` with `bytes`.
+    pub fn set_register(&mut self, reg: u8, bytes: &[u8]) {
+        let start = reg as usize * self.vlenb();
+        self.regs[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Element `index`, `eew` bits wide, of the group starting at `reg`,
+    /// zero-extended.
+    pub fn element(&self, reg: u8, index: usize, eew: u32) -> u64 {
+        let size = eew as usize / 8;
+        let start = reg as usize * self.vlenb() + index * size;
+        self.regs[start..start + size].iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+    }
+
+    pub fn set_element(&mut self, reg: u8, index: usize, eew: u32, value: u64) {
+        let size = eew as usize / 8;
+        let start = reg as usize * self.vlenb() + index * size;
+        for (i, byte) in self.regs[start..start + size].iter_mut().enumerate() {
+            *byte = (value >> (8 * i)) as u8;
+        }
+    }
+
+    /// Bit `index` of mask register `reg`.
+    pub fn mask(&self, reg: u8, index: usize) -> bool {
+        self.regs[reg as usize * self.vlenb() + index / 8] & (1 << (index % 8)) != 0
+    }
+
+    pub fn set_mask(&mut self, reg: u8, index: usize, value: bool) {
+        let start = reg as usize * self.vlenb();
+        let byte = &mut self.regs[start + index / 8];
+        if value {
+            *byte |= 1 << (index % 8);
+        } else {
+            *byte &= !(1 << (index % 8));
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.regs
+    }
+
+    pub(crate) fn as_mut_bytes(&mut self) -> &mut [u8] {
+        &mut self.regs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elements() {
+        let mut vector = VectorState::new(128, 32);
+        assert_eq!(vector.vlenb(), 16);
+        assert_eq!(vector.vtype(), 0x8000_0000);
+
+        // Element 5 of a 32-bit group starting at v2 is the second word of v3.
+        vector.set_element(2, 5, 32, 0x1234_5678);
+        assert_eq!(&vector.register(3)[4..8], &[0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(vector.element(3, 1, 32), 0x1234_5678);
+        assert_eq!(vector.element(3, 3, 16), 0x1234);
+
+        vector.set_mask(0, 9, true);
+        assert_eq!(vector.register(0)[1], 0b10);
+        assert!(vector.mask(0, 9) && !vector.mask(0, 8));
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Runs riscv-arch-test binaries found under `tests/arch-test` (see the
+//! README there), each as a test of its own, and compares the signature each
+//! leaves in memory with its `.reference_output` file.
+//!
+//! Like `riscv_tests`, this has its own `main` to list tests found at run
+//! time.
+
+extern crate risc_v_emulator;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use common::{build_elf_with_symbols, run_tests, words, Elf, Segment, Test, Verdict};
+use risc_v_emulator::{Machine, StopReason};
+
+// The rv32i_m I and M tests, named as they are once copied in as the README
+// describes. Any that are missing are listed as ignored.
+const EXPECTED: [&str; 46] = [
+    "I/src/add-01", "I/src/addi-01", "I/src/and-01", "I/src/andi-01", "I/src/auipc-01",
+    "I/src/beq-01", "I/src/bge-01", "I/src/bgeu-01", "I/src/blt-01", "I/src/bltu-01",
+    "I/src/bne-01", "I/src/fence-01", "I/src/jal-01", "I/src/jalr-01", "I/src/lb-align-01",
+    "I/src/lbu-align-01", "I/src/lh-align-01", "I/src/lhu-align-01", "I/src/lui-01",
+    "I/src/lw-align-01", "I/src/or-01", "I/src/ori-01", "I/src/sb-align-01",
+    "I/src/sh-align-01", "I/src/sll-01", "I/src/slli-01", "I/src/slt-01", "I/src/slti-01",
+    "I/src/sltiu-01", "I/src/sltu-01", "I/src/sra-01", "I/src/srai-01", "I/src/srl-01",
+    "I/src/srli-01", "I/src/sub-01", "I/src/sw-align-01", "I/src/xor-01", "I/src/xori-01",
+    "M/src/div-01", "M/src/divu-01", "M/src/mul-01", "M/src/mulh-01", "M/src/mulhsu-01",
+    "M/src/mulhu-01", "M/src/rem-01", "M/src/remu-01",
+];
+// The tests are linked to run from here.
+const RAM_BASE: u32 = 0x80000000;
+// Enough for the largest signature regions.
+const RAM_SIZE: usize = 8 * 1024 * 1024;
+const MAX_INSTRUCTIONS: u64 = 10_000_000;
+// The reference outputs have one 32-bit word per line.
+const GRANULARITY: u32 = 4;
+
+// Stores four words at begin_signature, 0x80002000, and halts through tohost
+// at 0x80001000.
+const SIGNATURE: [u32; 15] = [0x800022b7, 0x12345537, 0x67850513, 0x00a2a023, 0xfff00513,
+                              0x00a2a223, 0x0002a423, 0x0000c537, 0xeef50513, 0x00a2a623,
+                              0x80001337, 0x00100513, 0x00a32023, 0x00032223, 0x0000006f];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    Pass,
+    /// The first line, counting from 1, that differs from the reference.
+    Mismatch(usize),
+    NoSignature,
+    Stopped(StopReason),
+    Timeout,
+}
+
+enum Reference {
+    Text(&'static str),
+    File(PathBuf),
+}
+
+fn run_test(elf: &[u8], reference: &str) -> Outcome {
+    let mut machine = match Machine::builder()
+        .ram_base(RAM_BASE)
+        .ram_size(RAM_SIZE)
+        .load_elf_bytes(elf)
+        .build() {
+        Ok(machine) => machine,
+        Err(e) => panic!("couldn't load ELF: {}", e),
+    };
+    machine.start();
+
+    loop {
+        let instret = machine.instret();
+        if instret >= MAX_INSTRUCTIONS {
+            return Outcome::Timeout;
+        }
+        match machine.run_for(MAX_INSTRUCTIONS - instret) {
+            (_, StopReason::Halted(_)) => break,
+            (_, StopReason::InstructionLimit) => {}
+            (_, reason) => return Outcome::Stopped(reason),
+        }
+    }
+
+    let signature = match machine.signature(GRANULARITY) {
+        Some(signature) => signature,
+        None => return Outcome::NoSignature,
+    };
+    let mut actual = signature.lines();
+    let mut expected = reference.lines().map(str::trim).filter(|line| !line.is_empty());
+    for line in 1.. {
+        match (actual.next(), expected.next()) {
+            (None, None) => return Outcome::Pass,
+            (Some(a), Some(e)) if a.eq_ignore_ascii_case(e) => {}
+            _ => return Outcome::Mismatch(line),
+        }
+    }
+    unreachable!()
+}
+
+fn self_test() -> Vec<u8> {
+    let text = words(&SIGNATURE);
+    build_elf_with_symbols(RAM_BASE,
+                           &[Segment {
+                                 vaddr: RAM_BASE,
+                                 data: &text,
+                                 memsz: text.len() as u32,
+                             }],
+                           &[("tohost", RAM_BASE + 0x1000),
+                             ("begin_signature", RAM_BASE + 0x2000),
+                             ("end_signature", RAM_BASE + 0x2010)])
+}
+
+// The reference output for an ELF built from `NAME.S`, either alongside it or
+// in the suite's `references` directory.
+fn reference_for(elf: &Path) -> Option<PathBuf> {
+    let name = format!("{}.reference_output", elf.file_stem()?.to_string_lossy());
+    let dir = elf.parent()?;
+    let candidates = [dir.join(&name), dir.parent()?.join("references").join(&name)];
+    candidates.iter().find(|path| path.is_file()).cloned()
+}
+
+fn find_tests(root: &Path, dir: &Path, tests: &mut Vec<Test<(Outcome, Reference)>>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        if path.is_dir() {
+            find_tests(root, &path, tests);
+        } else if path.extension().is_some_and(|extension| extension == "elf") {
+            if let Some(reference) = reference_for(&path) {
+                tests.push(Test {
+                    name: path.strip_prefix(root).unwrap_or(&path).display().to_string(),
+                    elf: Elf::File(path.clone()),
+                    data: (Outcome::Pass, Reference::File(reference)),
+                });
+            }
+        }
+    }
+}
+
+fn main() {
+    let dir = env::var_os("RISCV_ARCH_TEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/arch-test"));
+
+    // The harness checks its own signature comparison before anything else.
+    let mut tests = vec![Test {
+                             name: "harness::match".to_string(),
+                             elf: Elf::Built(self_test()),
+                             data: (Outcome::Pass,
+                                    Reference::Text("12345678\nFFFFFFFF\n00000000\n0000beef\n")),
+                         },
+                         Test {
+                             name: "harness::mismatch".to_string(),
+                             elf: Elf::Built(self_test()),
+                             data: (Outcome::Mismatch(3),
+                                    Reference::Text("12345678\nffffffff\n00000001\n0000beef\n")),
+                         },
+                         Test {
+                             name: "harness::short".to_string(),
+                             elf: Elf::Built(self_test()),
+                             data: (Outcome::Mismatch(5),
+                                    Reference::Text("12345678\nffffffff\n00000000\n0000beef\n\
+                                                     00000000\n")),
+                         }];
+    let mut found = Vec::new();
+    find_tests(&dir, &dir, &mut found);
+    if found.is_empty() {
+        println!("note: no riscv-arch-test binaries in {}", dir.display());
+    }
+    for &name in &EXPECTED {
+        let name = format!("{}.elf", name);
+        if !found.iter().any(|test| test.name == name) {
+            found.push(Test {
+                name,
+                elf: Elf::Missing,
+                data: (Outcome::Pass, Reference::Text("")),
+            });
+        }
+    }
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    tests.extend(found);
+
+    run_tests(tests, |_, elf, (expected, reference)| {
+        let reference = match reference {
+            Reference::Text(text) => text.to_string(),
+            Reference::File(path) => fs::read_to_string(&path).expect("couldn't read reference"),
+        };
+        let outcome = run_test(&elf, &reference);
+        if outcome == expected {
+            return Verdict::Pass;
+        }
+        Verdict::Fail(match outcome {
+            Outcome::Pass => "signature matched".to_string(),
+            Outcome::Mismatch(line) => format!("signature differs at line {}", line),
+            Outcome::NoSignature => "no signature".to_string(),
+            Outcome::Stopped(reason) => format!("stopped: {:?}", reason),
+            Outcome::Timeout => format!("still running after {} instructions", MAX_INSTRUCTIONS),
+        })
+    });
+}
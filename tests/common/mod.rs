@@ -0,0 +1,304 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Every test crate compiles its own copy of this module, and none of them
+// uses all of it.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use risc_v_emulator::{CPU, Machine, RAM, Schedule};
+
+/// Every hart adds 1000 to the word at 0x8000 with an LR/SC loop, then bumps
+/// the word at 0x8004 with AMOADD. Hart 0 waits for all four to finish and
+/// returns the total.
+pub const COUNT: [u32; 18] = [0xf14022f3, 0x00008337, 0x3e800393, 0x10032e2f, 0x001e0e13,
+                              0x19c32eaf, 0xfe0e9ae3, 0xfff38393, 0xfe0396e3, 0x00430f93,
+                              0x00100f13, 0x01efa02f, 0x00029a63, 0x00400f93, 0x00432f03,
+                              0xffff1ee3, 0x00032503, 0x00008067];
+
+/// A loadable segment for `build_elf`.
+pub struct Segment<'a> {
+    pub vaddr: u32,
+    pub data: &'a [u8],
+    pub memsz: u32,
+}
+
+/// Little-endian bytes for a sequence of instruction or data words.
+pub fn words(words: &[u32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(words.len() * 4);
+    for &word in words {
+        push_u32(&mut bytes, word);
+    }
+    bytes
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    push_u32(buf, value as u32);
+    push_u32(buf, (value >> 32) as u32);
+}
+
+/// A minimal ELF32 RISC-V executable with one PT_LOAD program header per
+/// segment and no sections.
+pub fn build_elf(entry: u32, segments: &[Segment]) -> Vec<u8> {
+    build_elf_with_symbols(entry, segments, &[])
+}
+
+/// Like `build_elf`, but with a symbol table holding `symbols` as absolute
+/// global symbols, if there are any.
+pub fn build_elf_with_symbols(entry: u32, segments: &[Segment], symbols: &[(&str, u32)])
+                              -> Vec<u8> {
+    const EHDR_SIZE: u32 = 52;
+    const PHDR_SIZE: u32 = 32;
+    const SHDR_SIZE: u32 = 40;
+
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    push_u16(&mut elf, 2); // ET_EXEC
+    push_u16(&mut elf, 243); // EM_RISCV
+    push_u32(&mut elf, 1); // EV_CURRENT
+    push_u32(&mut elf, entry);
+    push_u32(&mut elf, EHDR_SIZE); // phoff
+    let shoff_at = elf.len();
+    push_u32(&mut elf, 0); // shoff, filled in below
+    push_u32(&mut elf, 0); // flags
+    push_u16(&mut elf, EHDR_SIZE as u16);
+    push_u16(&mut elf, PHDR_SIZE as u16);
+    push_u16(&mut elf, segments.len() as u16);
+    push_u16(&mut elf, SHDR_SIZE as u16);
+    push_u16(&mut elf, if symbols.is_empty() { 0 } else { 4 }); // shnum
+    push_u16(&mut elf, if symbols.is_empty() { 0 } else { 3 }); // shstrndx
+
+    let mut offset = EHDR_SIZE + PHDR_SIZE * segments.len() as u32;
+    for segment in segments {
+        push_u32(&mut elf, 1); // PT_LOAD
+        push_u32(&mut elf, offset);
+        push_u32(&mut elf, segment.vaddr);
+        push_u32(&mut elf, segment.vaddr);
+        push_u32(&mut elf, segment.data.len() as u32);
+        push_u32(&mut elf, segment.memsz);
+        push_u32(&mut elf, 0b111); // RWX
+        push_u32(&mut elf, 4);
+        offset += segment.data.len() as u32;
+    }
+
+    for segment in segments {
+        elf.extend_from_slice(segment.data);
+    }
+
+    if symbols.is_empty() {
+        return elf;
+    }
+
+    // .symtab, .strtab and .shstrtab, then the section headers.
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 16];
+    for &(name, value) in symbols {
+        push_u32(&mut symtab, strtab.len() as u32);
+        push_u32(&mut symtab, value);
+        push_u32(&mut symtab, 0); // size
+        symtab.extend_from_slice(&[0x10, 0]); // STB_GLOBAL, STT_NOTYPE
+        push_u16(&mut symtab, 0xFFF1); // SHN_ABS
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+    }
+    let shstrtab = b"\0.symtab\0.strtab\0.shstrtab\0";
+
+    while elf.len() % 4 != 0 {
+        elf.push(0);
+    }
+    let symtab_offset = elf.len() as u32;
+    elf.extend_from_slice(&symtab);
+    let strtab_offset = elf.len() as u32;
+    elf.extend_from_slice(&strtab);
+    let shstrtab_offset = elf.len() as u32;
+    elf.extend_from_slice(shstrtab);
+    while elf.len() % 4 != 0 {
+        elf.push(0);
+    }
+
+    let shoff = elf.len() as u32;
+    elf[shoff_at..shoff_at + 4].copy_from_slice(&shoff.to_le_bytes());
+    let headers = [[0; 10],
+                   // name, type, flags, addr, offset, size, link, info, align, entsize
+                   [1, 2, 0, 0, symtab_offset, symtab.len() as u32, 2, 1, 4, 16],
+                   [9, 3, 0, 0, strtab_offset, strtab.len() as u32, 0, 0, 1, 0],
+                   [17, 3, 0, 0, shstrtab_offset, shstrtab.len() as u32, 0, 0, 1, 0]];
+    for header in &headers {
+        for &field in header {
+            push_u32(&mut elf, field);
+        }
+    }
+
+    elf
+}
+
+/// The ELF64 version of `build_elf`, for RV64 programs.
+pub fn build_elf64(entry: u32, segments: &[Segment]) -> Vec<u8> {
+    const EHDR_SIZE: u32 = 64;
+    const PHDR_SIZE: u32 = 56;
+
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    push_u16(&mut elf, 2); // ET_EXEC
+    push_u16(&mut elf, 243); // EM_RISCV
+    push_u32(&mut elf, 1); // EV_CURRENT
+    push_u64(&mut elf, entry as u64);
+    push_u64(&mut elf, EHDR_SIZE as u64); // phoff
+    push_u64(&mut elf, 0); // shoff
+    push_u32(&mut elf, 0); // flags
+    push_u16(&mut elf, EHDR_SIZE as u16);
+    push_u16(&mut elf, PHDR_SIZE as u16);
+    push_u16(&mut elf, segments.len() as u16);
+    push_u16(&mut elf, 64); // shentsize
+    push_u16(&mut elf, 0); // shnum
+    push_u16(&mut elf, 0); // shstrndx
+
+    let mut offset = EHDR_SIZE + PHDR_SIZE * segments.len() as u32;
+    for segment in segments {
+        push_u32(&mut elf, 1); // PT_LOAD
+        push_u32(&mut elf, 0b111); // RWX
+        push_u64(&mut elf, offset as u64);
+        push_u64(&mut elf, segment.vaddr as u64);
+        push_u64(&mut elf, segment.vaddr as u64);
+        push_u64(&mut elf, segment.data.len() as u64);
+        push_u64(&mut elf, segment.memsz as u64);
+        push_u64(&mut elf, 8);
+        offset += segment.data.len() as u32;
+    }
+
+    for segment in segments {
+        elf.extend_from_slice(segment.data);
+    }
+    elf
+}
+
+/// An ELF image with `program` as its only segment, loaded at and entered at
+/// 0x1000.
+pub fn program_elf(program: &[u32]) -> Vec<u8> {
+    let text = words(program);
+    build_elf(0x1000,
+              &[Segment {
+                    vaddr: 0x1000,
+                    data: &text,
+                    memsz: text.len() as u32,
+                }])
+}
+
+/// Write `program` into `cpu`'s memory at `addr`.
+pub fn load_program(cpu: &mut CPU, addr: u32, program: &[u32]) {
+    for (i, &word) in program.iter().enumerate() {
+        cpu.bus.write(addr + 4 * i as u32, 4, word).expect("program doesn't fit in RAM");
+    }
+}
+
+/// A CPU with 64 KiB of RAM and `program` loaded at `addr`, ready to run it.
+pub fn cpu_with_program(addr: u32, program: &[u32]) -> CPU {
+    let mut cpu = CPU::new(RAM::new(64 * 1024));
+    load_program(&mut cpu, addr, program);
+    cpu.pc = addr;
+    cpu
+}
+
+/// A machine running `program` from `program_elf` on `harts` harts.
+pub fn build_machine(program: &[u32], harts: usize, quantum: u64, schedule: Schedule) -> Machine {
+    Machine::builder()
+        .harts(harts)
+        .quantum(quantum)
+        .schedule(schedule)
+        .load_elf_bytes(&program_elf(program))
+        .build()
+        .expect("couldn't load ELF")
+}
+
+/// Where the ELF for a test found at run time comes from.
+pub enum Elf {
+    Built(Vec<u8>),
+    File(PathBuf),
+    /// One the runner expected that isn't in the directory.
+    Missing,
+}
+
+/// A test for `run_tests`, with whatever else its runner needs to run it.
+pub struct Test<T> {
+    pub name: String,
+    pub elf: Elf,
+    pub data: T,
+}
+
+/// How a test went.
+pub enum Verdict {
+    Pass,
+    /// Why it failed.
+    Fail(String),
+    Ignored,
+}
+
+/// Run the `tests` whose names match the filters on the command line, and
+/// print the results the way libtest does, for the runners with their own
+/// `main`. Exits with libtest's status if any of them fail.
+pub fn run_tests<T, F>(mut tests: Vec<Test<T>>, mut run: F)
+    where F: FnMut(&str, Vec<u8>, T) -> Verdict
+{
+    // Anything that isn't a flag filters tests by name, like libtest.
+    let filters: Vec<String> = env::args().skip(1).filter(|arg| !arg.starts_with('-')).collect();
+    tests.retain(|test| {
+        filters.is_empty() || filters.iter().any(|filter| test.name.contains(filter.as_str()))
+    });
+
+    println!("\nrunning {} tests", tests.len());
+    let (mut passed, mut failed, mut ignored) = (0, Vec::new(), 0);
+    for Test { name, elf, data } in tests {
+        let elf = match elf {
+            Elf::Built(elf) => elf,
+            Elf::File(path) => fs::read(&path).expect("couldn't read test"),
+            Elf::Missing => {
+                println!("test {} ... ignored, not vendored", name);
+                ignored += 1;
+                continue;
+            }
+        };
+        match run(&name, elf, data) {
+            Verdict::Pass => {
+                println!("test {} ... ok", name);
+                passed += 1;
+            }
+            Verdict::Fail(why) => {
+                println!("test {} ... FAILED ({})", name, why);
+                failed.push(name);
+            }
+            Verdict::Ignored => {
+                println!("test {} ... ignored", name);
+                ignored += 1;
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        println!("\nfailures:");
+        for name in &failed {
+            println!("    {}", name);
+        }
+    }
+    println!("\ntest result: {}. {} passed; {} failed; {} ignored\n",
+             if failed.is_empty() { "ok" } else { "FAILED" },
+             passed,
+             failed.len(),
+             ignored);
+    if !failed.is_empty() {
+        process::exit(101);
+    }
+}
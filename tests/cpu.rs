@@ -0,0 +1,166 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+extern crate risc_v_emulator;
+
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use common::{cpu_with_program, load_program};
+use risc_v_emulator::instruction::{self, Instruction};
+use risc_v_emulator::{Bus, CPU, Device, Extension, Isa, Profile, RAM, StopReason};
+
+#[test]
+fn test_decode_encode() {
+    for &raw in &[0x00100513, 0x02b50533, 0xfe059ce3, 0x10a02023, 0x00008067, 0x341026f3] {
+        let instr = instruction::parse(raw).expect("couldn't parse instruction");
+        assert_eq!(instr.to_raw(), raw);
+    }
+
+    assert!(instruction::parse(0xFFFFFFFF).is_none());
+    // slli x1, x2, 32 with bits above the shift amount set, which is no
+    // instruction at all
+    assert!(instruction::parse(0x42011093).is_none());
+}
+
+#[test]
+fn test_bitmanip_extensions() {
+    // sh1add, clz, clmul, bset, then rev8 in its RV64 encoding
+    let program = [0x203120b3, 0x60011093, 0x0a3110b3, 0x283110b3, 0x6b815093];
+    for &raw in &program {
+        let instr = instruction::parse(raw).expect("couldn't parse instruction");
+        assert_eq!(instr.to_raw(), raw);
+    }
+
+    let mut cpu = cpu_with_program(0x100, &program);
+    assert_eq!(cpu.run_for(4), StopReason::InstructionLimit);
+    assert_eq!(cpu.step(),
+               StopReason::IllegalInstruction {
+                   pc: 0x110,
+                   raw: 0x6b815093,
+               });
+
+    cpu.set_isa(Isa::parse("rv32i_zba").unwrap());
+    cpu.pc = 0x100;
+    assert_eq!(cpu.step(), StopReason::InstructionLimit);
+    assert_eq!(cpu.step(),
+               StopReason::IllegalInstruction {
+                   pc: 0x104,
+                   raw: 0x60011093,
+               });
+}
+
+#[test]
+fn test_crypto_extensions() {
+    // zext.h x1, x2 (pack x1, x2, x0); clmul x1, x2, x3; clmulr x1, x2, x3
+    let program = [0x080140b3, 0x0a3110b3, 0x0a3120b3];
+    let mut cpu = cpu_with_program(0x100, &program);
+
+    // Zbkb and Zbkc share instructions with Zbb and Zbc, but not all of them,
+    // and RV64's ZEXT.H has another encoding.
+    for &(isa, legal) in &[("rv32i_zbb_zbkc", 2), ("rv32i_zbkb_zbkc", 2), ("rv32i_zbc", 0),
+                           ("rv64i_zbkb_zbkc", 2), ("rv64i_zbb_zbkc", 0)] {
+        cpu.set_isa(Isa::parse(isa).unwrap());
+        cpu.pc = 0x100;
+        for _ in 0..legal {
+            assert_eq!(cpu.step(), StopReason::InstructionLimit, "{}", isa);
+        }
+        assert_eq!(cpu.step(),
+                   StopReason::IllegalInstruction {
+                       pc: 0x100 + 4 * legal,
+                       raw: program[legal as usize],
+                   },
+                   "{}",
+                   isa);
+    }
+
+    // sha256sig0 x1, x2 needs Zknh.
+    assert_eq!(instruction::parse(0x10211093).unwrap().extensions(32), &[Extension::Zknh]);
+}
+
+#[test]
+fn test_extension_gating() {
+    // Each runs with the default ISA, and is illegal in the one given.
+    let cases = [
+        // vsetvli t0, a0, e32, m1, ta, ma
+        (0x0d0572d7, Isa::parse("rv32im").unwrap()),
+        // flh fa0, 0(a1)
+        (0x00059507, Isa::parse("rv32im").unwrap()),
+        // czero.eqz a0, a1, a2, which RVA20 predates
+        (0x0ec5d533, Profile::Rva20u64.isa().with_xlen(32)),
+        // c.li a0, 5, then the first half of addi a0, a0, 1
+        (0x05134515, Isa::parse("rv32im").unwrap()),
+    ];
+    for &(raw, isa) in &cases {
+        let mut cpu = cpu_with_program(0x100, &[raw]);
+        assert_eq!(cpu.step(), StopReason::InstructionLimit, "{:#010x}", raw);
+
+        cpu.set_isa(isa);
+        cpu.pc = 0x100;
+        assert_eq!(cpu.step(),
+                   StopReason::IllegalInstruction { pc: 0x100, raw },
+                   "{:#010x}",
+                   raw);
+    }
+}
+
+#[test]
+fn test_step_and_run_for() {
+    // li a0, 1; li a1, 5; mul a0, a0, a1; addi a1, a1, -1; bnez a1, -8; ret
+    let mut cpu = cpu_with_program(0x100,
+                                   &[0x00100513, 0x00500593, 0x02b50533, 0xfff58593,
+                                     0xfe059ce3, 0x00008067]);
+    cpu.set_register(1, 0);
+
+    assert_eq!(cpu.step(), StopReason::InstructionLimit);
+    assert_eq!(cpu.get_register(10), 1);
+    assert_eq!(cpu.run_for(4), StopReason::InstructionLimit);
+    assert_eq!(cpu.get_register(10), 5);
+    assert_eq!(cpu.run_for(1000), StopReason::Halted(120));
+    assert_eq!(cpu.cycles(), 18);
+}
+
+struct Uart {
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u32, _size: u32) -> Option<u32> {
+        match offset {
+            4 => Some(0x1234),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32) -> Option<()> {
+        match offset {
+            0 => {
+                self.output.borrow_mut().push(value as u8);
+                Some(())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_memory_mapped_device() {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let mut bus = Bus::new(RAM::new(4096));
+    bus.map_device(0x10000000, 8, Box::new(Uart { output: output.clone() }));
+
+    let mut cpu = CPU::with_bus(bus);
+    // lui a1, 0x10000; li a0, 65; sb a0, 0(a1); lw a0, 4(a1); ret
+    load_program(&mut cpu,
+                 0x100,
+                 &[0x100005b7, 0x04100513, 0x00a58023, 0x0045a503, 0x00008067]);
+
+    assert_eq!(cpu.run(0x100), StopReason::Halted(0x1234));
+    assert_eq!(*output.borrow(), b"A");
+}
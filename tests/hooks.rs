@@ -0,0 +1,157 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+extern crate risc_v_emulator;
+
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use common::cpu_with_program;
+use risc_v_emulator::{Access, HookAction, MemoryAccess, StopReason};
+
+// li a0, 42; sw a0, 0x100(zero); lw a1, 0x100(zero); csrr a2, mscratch; ecall; ret
+const PROGRAM: [u32; 6] = [0x02a00513, 0x10a02023, 0x10002583, 0x34002673, 0x00000073,
+                           0x00008067];
+
+#[test]
+fn test_instruction_hooks() {
+    let mut cpu = cpu_with_program(0x200, &PROGRAM);
+    let before = Rc::new(RefCell::new(Vec::new()));
+    let after = Rc::new(RefCell::new(Vec::new()));
+
+    {
+        let before = before.clone();
+        cpu.hooks_mut().on_before_instruction(move |_, pc, instr| {
+            before.borrow_mut().push((pc, instr.to_raw()));
+            HookAction::Continue
+        });
+    }
+    {
+        let after = after.clone();
+        cpu.hooks_mut().on_after_instruction(move |cpu, pc, _| {
+            after.borrow_mut().push((pc, cpu.get_register(10)));
+            HookAction::Continue
+        });
+    }
+
+    assert_eq!(cpu.run_for(3), StopReason::InstructionLimit);
+    assert_eq!(*before.borrow(),
+               vec![(0x200, PROGRAM[0]), (0x204, PROGRAM[1]), (0x208, PROGRAM[2])]);
+    assert_eq!(*after.borrow(), vec![(0x200, 42), (0x204, 42), (0x208, 42)]);
+}
+
+#[test]
+fn test_stop_before_instruction() {
+    let mut cpu = cpu_with_program(0x200, &PROGRAM);
+    cpu.hooks_mut().on_before_instruction(|_, pc, _| {
+        if pc == 0x208 {
+            HookAction::Stop
+        } else {
+            HookAction::Continue
+        }
+    });
+
+    assert_eq!(cpu.run(0x200), StopReason::Hook);
+    assert_eq!(cpu.pc, 0x208);
+    assert_eq!(cpu.get_register(11), 0);
+}
+
+#[test]
+fn test_override_instruction() {
+    let mut cpu = cpu_with_program(0x200, &PROGRAM);
+    cpu.hooks_mut().on_before_instruction(|_, pc, _| {
+        if pc == 0x200 {
+            HookAction::Override(0x00700513) // li a0, 7
+        } else {
+            HookAction::Continue
+        }
+    });
+
+    assert_eq!(cpu.step(), StopReason::InstructionLimit);
+    assert_eq!(cpu.get_register(10), 7);
+}
+
+#[test]
+fn test_memory_hook() {
+    let mut cpu = cpu_with_program(0x200, &PROGRAM);
+    let accesses = Rc::new(RefCell::new(Vec::new()));
+
+    {
+        let accesses = accesses.clone();
+        cpu.hooks_mut().on_memory_access(move |access| {
+            accesses.borrow_mut().push(*access);
+            match access.access {
+                Access::Load => HookAction::Override(access.value as u64 + 1),
+                _ => HookAction::Continue,
+            }
+        });
+    }
+
+    assert_eq!(cpu.run_for(3), StopReason::InstructionLimit);
+    assert_eq!(*accesses.borrow(),
+               vec![MemoryAccess {
+                        addr: 0x100,
+                        size: 4,
+                        value: 42,
+                        access: Access::Store,
+                    },
+                    MemoryAccess {
+                        addr: 0x100,
+                        size: 4,
+                        value: 42,
+                        access: Access::Load,
+                    }]);
+    assert_eq!(cpu.get_register(11), 43);
+    assert_eq!(cpu.bus.ram.get_u32(0x100), 42);
+}
+
+#[test]
+fn test_csr_hook() {
+    let mut cpu = cpu_with_program(0x200, &PROGRAM);
+    cpu.hooks_mut().on_csr_access(|access| {
+        assert_eq!(access.csr, 0x340);
+        assert!(!access.write);
+        HookAction::Override(0x1234)
+    });
+
+    assert_eq!(cpu.run_for(4), StopReason::InstructionLimit);
+    assert_eq!(cpu.get_register(12), 0x1234);
+}
+
+#[test]
+fn test_ecall_hook() {
+    let mut cpu = cpu_with_program(0x200, &PROGRAM);
+    cpu.hooks_mut().on_ecall(|cpu| {
+        let a0 = cpu.get_register(10);
+        HookAction::Override(a0 * 2)
+    });
+
+    assert_eq!(cpu.run(0x200), StopReason::Halted(84));
+}
+
+#[test]
+fn test_trap_hook() {
+    let mut cpu = cpu_with_program(0x200, &PROGRAM);
+    let causes = Rc::new(RefCell::new(Vec::new()));
+
+    {
+        let causes = causes.clone();
+        cpu.hooks_mut().on_trap(move |_, event| {
+            causes.borrow_mut().push((event.pc, event.cause));
+            HookAction::Stop
+        });
+    }
+
+    assert_eq!(cpu.run(0x200), StopReason::Hook);
+    assert_eq!(*causes.borrow(), vec![(0x210, 11)]);
+    assert_eq!(cpu.pc, 0x210);
+
+    cpu.clear_hooks();
+    assert_eq!(cpu.step(), StopReason::Trap { cause: 11 });
+}
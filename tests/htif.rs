@@ -0,0 +1,153 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+extern crate risc_v_emulator;
+
+mod common;
+
+use std::cell::RefCell;
+use std::io::{self, Cursor, Write};
+use std::rc::Rc;
+
+use common::{build_elf_with_symbols, words, Segment};
+use risc_v_emulator::{Machine, Recording, Replayer, StopReason};
+
+const TOHOST: u32 = 0x2000;
+const FROMHOST: u32 = 0x2008;
+
+// Prints "Hi" on the console, reads a character and echoes it, then exits
+// with 21.
+const CONSOLE: [u32; 21] = [0x000022b7, 0x01010337, 0x010003b7, 0x04800513, 0x00a2a023,
+                            0x0062a223, 0x06900513, 0x00a2a023, 0x0062a223, 0x0002a023,
+                            0x0072a223, 0x0082a503, 0xfe050ee3, 0x0002a423, 0x0ff57513,
+                            0x00a2a023, 0x0062a223, 0x02b00513, 0x00a2a023, 0x0002a223,
+                            0x0000006f];
+// Writes 1 to the low half of tohost over and over, as older riscv-tests do.
+const LEGACY_PASS: [u32; 4] = [0x000022b7, 0x00100513, 0x00a2a023, 0xffdff06f];
+// Writes the 6 bytes at 0x3100 to standard output and reads 4 bytes from
+// standard input into 0x3200 with system calls through the buffer at
+// 0x3000, then exits with the first byte read plus the results of the
+// write and read, shifted left by 8 and 16.
+const SYSCALLS: [u32; 40] = [0x000022b7, 0x000033b7, 0x04000513, 0x00a3a023, 0x00100513,
+                             0x00a3a423, 0x10038513, 0x00a3a823, 0x00600513, 0x00a3ac23,
+                             0x0072a023, 0x0002a223, 0x0082a503, 0xfe050ee3, 0x0002a423,
+                             0x0003a403, 0x03f00513, 0x00a3a023, 0x0003a423, 0x20038513,
+                             0x00a3a823, 0x00400513, 0x00a3ac23, 0x0072a023, 0x0002a223,
+                             0x0082a503, 0xfe050ee3, 0x0002a423, 0x0003a483, 0x2003c603,
+                             0x00841413, 0x01049493, 0x00860633, 0x00960633, 0x05d00513,
+                             0x00a3a023, 0x00c3a423, 0x0072a023, 0x0002a223, 0x0000006f];
+
+// Console output that the test can look at afterwards.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn htif_machine(program: &[u32], input: &str) -> (Machine, Output) {
+    let text = words(program);
+    let elf = build_elf_with_symbols(0x1000,
+                                     &[Segment {
+                                           vaddr: 0x1000,
+                                           data: &text,
+                                           memsz: text.len() as u32,
+                                       },
+                                       Segment {
+                                           vaddr: 0x3100,
+                                           data: b"hello\n",
+                                           memsz: 6,
+                                       }],
+                                     &[("tohost", TOHOST), ("fromhost", FROMHOST)]);
+    let mut machine = Machine::builder().load_elf_bytes(&elf).build().expect("couldn't load ELF");
+    let output = Output::default();
+    machine.htif
+        .as_ref()
+        .expect("no HTIF")
+        .set_console(Box::new(Cursor::new(input.as_bytes().to_vec())), Box::new(output.clone()));
+    machine.start();
+    (machine, output)
+}
+
+#[test]
+fn test_console() {
+    let (mut machine, output) = htif_machine(&CONSOLE, "!");
+    assert_eq!(machine.run_for(1000), (0, StopReason::Halted(21)));
+    assert_eq!(&output.0.borrow()[..], b"Hi!");
+    assert_eq!(machine.cpu.bus.read(TOHOST, 4), Some(0));
+}
+
+#[test]
+fn test_legacy_exit() {
+    let (mut machine, _) = htif_machine(&LEGACY_PASS, "");
+    assert_eq!(machine.run_for(1000), (0, StopReason::Halted(0)));
+    assert_eq!(machine.instret(), 5);
+}
+
+#[test]
+fn test_syscalls() {
+    let (mut machine, output) = htif_machine(&SYSCALLS, "xy");
+    assert_eq!(machine.run_for(1000),
+               (0, StopReason::Halted(2 << 16 | 6 << 8 | b'x' as u32)));
+    assert_eq!(&output.0.borrow()[..], b"hello\n");
+    assert_eq!(machine.cpu.bus.read(0x3200, 4), Some(0x7978));
+}
+
+#[test]
+fn test_huge_read() {
+    // Ask to read 2 GiB, far more than there is RAM for.
+    let mut program = SYSCALLS;
+    assert_eq!(program[21], 0x00400513);
+    program[21] = 0x80000537;
+    let (mut machine, _) = htif_machine(&program, "xy");
+    assert_eq!(machine.run_for(1000),
+               (0, StopReason::Halted(2 << 16 | 6 << 8 | b'x' as u32)));
+    assert_eq!(machine.cpu.bus.read(0x3200, 4), Some(0x7978));
+}
+
+#[test]
+fn test_without_htif() {
+    let text = words(&LEGACY_PASS);
+    let elf = build_elf_with_symbols(0x1000,
+                                     &[Segment {
+                                           vaddr: 0x1000,
+                                           data: &text,
+                                           memsz: text.len() as u32,
+                                       }],
+                                     &[("tohost", TOHOST)]);
+    let mut machine = Machine::builder().htif(false).load_elf_bytes(&elf).build().unwrap();
+    assert!(machine.htif.is_none());
+    machine.start();
+    assert_eq!(machine.run_for(1000), (0, StopReason::InstructionLimit));
+    assert_eq!(machine.cpu.bus.read(TOHOST, 4), Some(1));
+}
+
+#[test]
+fn test_replay_syscalls() {
+    let (mut recorded, _) = htif_machine(&SYSCALLS, "xyz!");
+    let recording = Recording::record(&mut recorded, 10);
+    let result = (0, StopReason::Halted(4 << 16 | 6 << 8 | b'x' as u32));
+    assert_eq!(recording.result(), result);
+
+    // What the program read comes from the recording, and nothing is printed
+    // again.
+    let (machine, output) = htif_machine(&SYSCALLS, "abcd");
+    let mut replayer = Replayer::new(machine, recording).unwrap();
+    assert_eq!(replayer.resume().unwrap(), result);
+    assert_eq!(replayer.machine_mut().cpu.bus.read(0x3200, 4), Some(0x217a7978));
+    assert!(output.0.borrow().is_empty());
+
+    replayer.seek(20).unwrap();
+    assert_eq!(replayer.resume().unwrap(), result);
+}
@@ -0,0 +1,133 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+#![cfg(feature = "jit")]
+
+extern crate risc_v_emulator;
+
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use common::{cpu_with_program, load_program};
+use risc_v_emulator::{Bus, CPU, Device, RAM, StopReason};
+
+// Loops a0 times over every RV32IM computational instruction, loads and
+// stores of each size (some misaligned), AUIPC, JAL and JALR, folding
+// everything into a checksum returned in a0. The DIVU makes the JIT hand
+// each iteration back to the interpreter part way through.
+const KITCHEN_SINK: &[u32] = &[
+    0x00008913, 0x00000413, 0x000024b7, 0xff900f93, 0xffd50293, 0x0052a313,
+    0x009fb393, 0x5a52ce13, 0xff02ee93, 0x7f0fff13, 0x007f9593, 0x003fd613,
+    0x403fd693, 0x00640433, 0x00740433, 0x01c40433, 0x41d40433, 0x01e44433,
+    0x00b40433, 0x00c40433, 0x00d40433, 0x00a29733, 0x00afd7b3, 0x40afd833,
+    0x005fa8b3, 0x005fb333, 0x00f763b3, 0x01f87e33, 0x00e40433, 0x00f44433,
+    0x01040433, 0x01140433, 0x00640433, 0x00740433, 0x01c40433, 0x03f40333,
+    0x03f413b3, 0x028fae33, 0x03f43eb3, 0x00640433, 0x00740433, 0x01c40433,
+    0x01d40433, 0x02a45f33, 0x01e40433, 0x0ff57293, 0x009282b3, 0x00828023,
+    0x008290a3, 0x0082a1a3, 0x00028303, 0x00129383, 0x0032ae03, 0x0022ce83,
+    0x0042df03, 0x00640433, 0x00740433, 0x01c40433, 0x01d40433, 0x01e40433,
+    0x00001317, 0x00640433, 0xabcde3b7, 0x00744433, 0x018000ef, 0xfff50513,
+    0xf00514e3, 0x00040513, 0x00090093, 0x00008067, 0x00140433, 0x00008067,
+];
+
+fn run(program: &[u32], a0: u64, jit: bool) -> (StopReason, u64) {
+    let mut cpu = cpu_with_program(0x1000, program);
+    cpu.set_jit_enabled(jit);
+    cpu.set_jit_differential(jit);
+    cpu.set_register(10, a0);
+
+    let reason = cpu.run(0x1000);
+    (reason, cpu.cycles())
+}
+
+#[test]
+fn matches_interpreter() {
+    let interpreted = run(KITCHEN_SINK, 300, false);
+    assert_eq!(run(KITCHEN_SINK, 300, true), interpreted);
+}
+
+#[test]
+fn self_modifying_code() {
+    let program = [0x00602c23, // loop: sw t1, 24(zero)
+                   0xfff58593, // addi a1, a1, -1
+                   0xfe059ce3, // bnez a1, loop
+                   0x00000013, // nop
+                   0x00000013, // nop
+                   0x00000013, // nop
+                   0x00100513, // li a0, 1
+                   0x00008067 /* ret */];
+
+    let mut cpu = CPU::new(RAM::new(1024));
+    for (i, &word) in program.iter().enumerate() {
+        cpu.bus.ram.set_u32(4 * i as u32, word);
+    }
+    cpu.set_jit_differential(true);
+    cpu.set_register(6, 0x00200513); // li a0, 2
+    cpu.set_register(11, 100);
+
+    // Returning to 0 would halt straight away.
+    cpu.remove_halt_condition(risc_v_emulator::HaltCondition::PcEquals(0));
+    cpu.add_halt_condition(risc_v_emulator::HaltCondition::PcEquals(0x100));
+    cpu.set_register(1, 0x100);
+    cpu.pc = 0;
+    assert_eq!(cpu.run_for(1000), StopReason::Halted(2));
+}
+
+struct Counter(Rc<Cell<u32>>);
+
+impl Device for Counter {
+    fn read(&mut self, _offset: u32, _size: u32) -> Option<u32> {
+        self.0.set(self.0.get() + 1);
+        Some(self.0.get())
+    }
+
+    fn write(&mut self, _offset: u32, _size: u32, _value: u32) -> Option<()> {
+        None
+    }
+}
+
+#[test]
+fn device_accesses_fall_back() {
+    // loop: lw a0, 0(a2); addi a1, a1, -1; bnez a1, loop; ret
+    let program = [0x00062503, 0xfff58593, 0xfe059ce3, 0x00008067];
+    let reads = Rc::new(Cell::new(0));
+
+    let mut bus = Bus::new(RAM::new(64 * 1024));
+    bus.map_device(0x10000000, 4, Box::new(Counter(reads.clone())));
+    let mut cpu = CPU::with_bus(bus);
+    load_program(&mut cpu, 0x1000, &program);
+    cpu.set_jit_differential(true);
+    cpu.set_register(11, 100);
+    cpu.set_register(12, 0x10000000);
+
+    assert_eq!(cpu.run(0x1000), StopReason::Halted(100));
+    assert_eq!(reads.get(), 100);
+    assert_eq!(cpu.cycles(), 301);
+}
+
+#[test]
+fn precise_fault_in_compiled_block() {
+    // loop: addi a1, a1, -1; lw a0, 0(a2); add a2, a2, a3; bnez a1, loop; ret
+    let program = [0xfff58593, 0x00062503, 0x00d60633, 0xfe059ae3, 0x00008067];
+    let mut cpu = cpu_with_program(0x1000, &program);
+    cpu.set_jit_differential(true);
+    cpu.set_register(11, 1000);
+    cpu.set_register(12, 0xF000);
+    cpu.set_register(13, 0x20);
+
+    // 0xF000 + 0x20 * 128 is the first word past the end of RAM.
+    assert_eq!(cpu.run(0x1000),
+               StopReason::MemoryFault {
+                   pc: 0x1004,
+                   addr: 0x10000,
+                   access: risc_v_emulator::Access::Load,
+               });
+    assert_eq!(cpu.get_register(11), 1000 - 129);
+    assert_eq!(cpu.cycles(), 128 * 4 + 1);
+}
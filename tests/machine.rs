@@ -0,0 +1,231 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+extern crate risc_v_emulator;
+
+mod common;
+
+use common::{build_elf, build_elf64, build_elf_with_symbols, words, Segment};
+use risc_v_emulator::{Access, Extension, Isa, LoadError, Machine, StopReason};
+
+// factorial(5), written the way test-program/test.c's loop would be:
+//
+//     li a0, 1
+//     li a1, 5
+// 1:  mul a0, a0, a1
+//     addi a1, a1, -1
+//     bnez a1, 1b
+//     ret
+const FACTORIAL: [u32; 6] = [0x00100513, 0x00500593, 0x02b50533, 0xfff58593, 0xfe059ce3,
+                             0x00008067];
+
+#[test]
+fn test_run_elf() {
+    let text = words(&FACTORIAL);
+    let elf = build_elf(0x1000,
+                        &[Segment {
+                              vaddr: 0x1000,
+                              data: &text,
+                              memsz: text.len() as u32,
+                          }]);
+
+    let mut machine = Machine::builder().load_elf_bytes(&elf).build().expect("couldn't load ELF");
+    assert_eq!(machine.program.entry_point, 0x1000);
+    assert_eq!(machine.run(), StopReason::Halted(120));
+}
+
+// Shifts that only give these results with 64-bit registers:
+//
+//     li a0, 1
+//     slli a0, a0, 40
+//     srli a0, a0, 38
+//     addiw a1, zero, -1
+//     srli a1, a1, 60
+//     add a0, a0, a1
+//     ret
+const RV64: [u32; 7] = [0x00100513, 0x02851513, 0x02655513, 0xfff0059b, 0x03c5d593, 0x00b50533,
+                        0x00008067];
+
+fn rv64_elf() -> Vec<u8> {
+    let text = words(&RV64);
+    build_elf64(0x1000,
+                &[Segment {
+                      vaddr: 0x1000,
+                      data: &text,
+                      memsz: text.len() as u32,
+                  }])
+}
+
+#[test]
+fn test_run_elf64() {
+    let mut machine = Machine::builder()
+        .load_elf_bytes(&rv64_elf())
+        .build()
+        .expect("couldn't load ELF");
+    assert_eq!(machine.cpu.xlen(), 64);
+    assert_eq!(machine.cpu.get_csr(0x301).map(|misa| misa >> 62), Ok(2));
+    assert_eq!(machine.run(), StopReason::Halted(19));
+}
+
+#[test]
+fn test_elf64_needs_rv64_isa() {
+    let isa = Isa::parse("rv32ima").unwrap();
+    match Machine::builder().isa(isa).load_elf_bytes(&rv64_elf()).build() {
+        Err(LoadError::Xlen(64)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("loaded an RV64 program on RV32"),
+    }
+
+    let isa = Isa::parse("rv64ima").unwrap();
+    let mut machine = Machine::builder().isa(isa).load_elf_bytes(&rv64_elf()).build().unwrap();
+    assert_eq!(machine.run(), StopReason::Halted(19));
+}
+
+#[test]
+fn test_rve_elf() {
+    let text = words(&FACTORIAL);
+    let mut elf = build_elf(0x1000,
+                            &[Segment {
+                                  vaddr: 0x1000,
+                                  data: &text,
+                                  memsz: text.len() as u32,
+                              }]);
+    elf[0x24] = 0x8; // EF_RISCV_RVE
+
+    let mut machine = Machine::builder().load_elf_bytes(&elf).build().expect("couldn't load ELF");
+    assert!(machine.program.rve);
+    assert_eq!(machine.cpu.isa().base(), Extension::E);
+    assert_eq!(machine.cpu.registers(), 16);
+    assert_eq!(machine.run(), StopReason::Halted(120));
+}
+
+#[test]
+fn test_symbols() {
+    let text = words(&FACTORIAL);
+    let segments = [Segment {
+                        vaddr: 0x1000,
+                        data: &text,
+                        memsz: text.len() as u32,
+                    }];
+    let elf = build_elf_with_symbols(0x1000, &segments, &[("main", 0x1000), ("tohost", 0x2000)]);
+
+    let machine = Machine::builder().load_elf_bytes(&elf).build().expect("couldn't load ELF");
+    assert_eq!(machine.program.symbol("tohost"), Some(0x2000));
+    assert_eq!(machine.program.symbol("main"), Some(0x1000));
+    assert_eq!(machine.program.symbol("fromhost"), None);
+}
+
+#[test]
+fn test_signature() {
+    let text = words(&FACTORIAL);
+    let data = words(&[0x12345678, 0x9abcdef0, 0x00000001, 0xffffffff]);
+    let segments = [Segment {
+                        vaddr: 0x1000,
+                        data: &text,
+                        memsz: text.len() as u32,
+                    },
+                    Segment {
+                        vaddr: 0x2000,
+                        data: &data,
+                        memsz: data.len() as u32,
+                    }];
+    let elf = build_elf_with_symbols(0x1000,
+                                     &segments,
+                                     &[("begin_signature", 0x2000), ("end_signature", 0x2010)]);
+
+    let mut machine = Machine::builder().load_elf_bytes(&elf).build().expect("couldn't load ELF");
+    assert_eq!(machine.signature(4).unwrap(),
+               "12345678\n9abcdef0\n00000001\nffffffff\n");
+    assert_eq!(machine.signature(8).unwrap(), "9abcdef012345678\nffffffff00000001\n");
+    assert_eq!(machine.signature(2).unwrap().lines().take(2).collect::<Vec<_>>(),
+               ["5678", "1234"]);
+    assert_eq!(machine.signature(3), None);
+
+    let elf = build_elf_with_symbols(0x1000, &segments, &[("begin_signature", 0x2000)]);
+    let mut machine = Machine::builder().load_elf_bytes(&elf).build().expect("couldn't load ELF");
+    assert_eq!(machine.signature(4), None);
+}
+
+#[test]
+fn test_ram_base() {
+    let text = words(&FACTORIAL);
+    let elf = build_elf(0x80000000,
+                        &[Segment {
+                              vaddr: 0x80000000,
+                              data: &text,
+                              memsz: text.len() as u32,
+                          }]);
+
+    let mut machine = Machine::builder()
+        .ram_base(0x80000000)
+        .ram_size(64 * 1024)
+        .load_elf_bytes(&elf)
+        .build()
+        .expect("couldn't load ELF");
+    assert_eq!(machine.cpu.get_register(2), 0x8000F000);
+    assert_eq!(machine.run(), StopReason::Halted(120));
+}
+
+#[test]
+fn test_data_segment() {
+    // lw a0, 0x100(zero); lw a1, 0x104(zero); add a0, a0, a1; ret
+    let text = words(&[0x10002503, 0x10402583, 0x00b50533, 0x00008067]);
+    let data = words(&[40, 2]);
+    let elf = build_elf(0x1000,
+                        &[Segment {
+                              vaddr: 0x1000,
+                              data: &text,
+                              memsz: text.len() as u32,
+                          },
+                          Segment {
+                              vaddr: 0x100,
+                              data: &data,
+                              memsz: 64,
+                          }]);
+
+    let mut machine = Machine::builder().load_elf_bytes(&elf).build().expect("couldn't load ELF");
+    assert_eq!(machine.run(), StopReason::Halted(42));
+}
+
+#[test]
+fn test_segment_outside_memory() {
+    let text = words(&FACTORIAL);
+    let elf = build_elf(0x1000,
+                        &[Segment {
+                              vaddr: 0x200000,
+                              data: &text,
+                              memsz: text.len() as u32,
+                          }]);
+
+    match Machine::builder().load_elf_bytes(&elf).build() {
+        Err(LoadError::OutOfMemory(0x200000)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("loaded a segment outside of memory"),
+    }
+}
+
+#[test]
+fn test_not_an_elf() {
+    match Machine::builder().load_elf_bytes(b"not an ELF file").build() {
+        Err(LoadError::Elf(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("loaded garbage"),
+    }
+}
+
+#[test]
+fn test_fetch_fault_outside_memory() {
+    let mut machine = Machine::builder().build().expect("couldn't build machine");
+    machine.cpu.pc = 0x100000;
+
+    assert_eq!(machine.cpu.step(),
+               StopReason::MemoryFault {
+                   pc: 0x100000,
+                   addr: 0x100000,
+                   access: Access::Fetch,
+               });
+}
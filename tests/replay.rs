@@ -0,0 +1,258 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+extern crate risc_v_emulator;
+
+mod common;
+
+use std::io::{self, Read, Write};
+
+use common::{program_elf, COUNT};
+use risc_v_emulator::gdb;
+use risc_v_emulator::{Device, Machine, Recording, ReplayError, Replayer, Schedule,
+                      SnapshotError, StopReason};
+
+// Reads the word at 0x10000000 200 times, folding each into a0 (xor, then
+// rotate left) and storing a0 from 0x8000 up, and returns a0.
+const NOISE: [u32; 14] = [0x100002b7, 0x0c800313, 0x00000513, 0x000083b7, 0x0002ae03,
+                          0x01c54533, 0x00151e93, 0x01f55513, 0x01d56533, 0x00a3a023,
+                          0x00438393, 0xfff30313, 0xfe0310e3, 0x00008067];
+// The SW at the end of each iteration of NOISE's loop, first reached after 9
+// instructions and every 9 after that.
+const NOISE_STORE: u32 = 0x1024;
+
+// Stands in for a device whose reads depend on the outside world.
+struct Random(u32);
+
+impl Device for Random {
+    fn read(&mut self, _offset: u32, _size: u32) -> Option<u32> {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        Some(self.0)
+    }
+
+    fn write(&mut self, _offset: u32, _size: u32, _value: u32) -> Option<()> {
+        None
+    }
+}
+
+// One end of a GDB connection: `input` holds every packet GDB will send, and
+// `output` collects the stub's acks and replies.
+struct Connection {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Send `packets` to a GDB stub serving `replayer`, and return its replies.
+fn gdb_session(replayer: &mut Replayer, packets: &[&str]) -> Vec<String> {
+    let mut input = Vec::new();
+    for packet in packets {
+        let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        input.extend(format!("${}#{:02x}+", packet, checksum).into_bytes());
+    }
+    let mut connection = Connection {
+        input: io::Cursor::new(input),
+        output: Vec::new(),
+    };
+    gdb::serve(replayer, &mut connection).expect("GDB session failed");
+
+    let output = String::from_utf8(connection.output).unwrap();
+    output.split('$').skip(1).map(|reply| reply.split('#').next().unwrap().to_string()).collect()
+}
+
+fn noise_machine(seed: u32) -> Machine {
+    let mut machine = Machine::builder()
+        .load_elf_bytes(&program_elf(&NOISE))
+        .build()
+        .expect("couldn't load ELF");
+    machine.cpu.bus.map_device(0x10000000, 8, Box::new(Random(seed)));
+    machine.start();
+    machine
+}
+
+fn record_noise() -> (Recording, Machine) {
+    let mut machine = noise_machine(1);
+    let recording = Recording::record(&mut machine, 100);
+    (recording, machine)
+}
+
+#[test]
+fn test_replay_matches_recording() {
+    let (recording, recorded) = record_noise();
+    let result = recording.result();
+    assert!(matches!(result, (0, StopReason::Halted(_))), "result {:?}", result);
+    assert!(noise_machine(2).resume() != result);
+    assert_eq!(recording.end_instret(), 1805);
+    assert_eq!(recording.events().len(), 200);
+
+    // A differently seeded device would give a different answer, but every
+    // read comes from the log.
+    let mut saved = Vec::new();
+    recording.save(&mut saved).expect("couldn't save recording");
+    let loaded = Recording::load(&saved[..]).expect("couldn't load recording");
+    assert_eq!(loaded.events(), recording.events());
+    let mut replayer = Replayer::new(noise_machine(2), loaded).expect("couldn't start replay");
+    assert_eq!(replayer.instret(), 0);
+    assert_eq!(replayer.resume().unwrap(), result);
+    assert!(replayer.machine().cpu.bus.ram == recorded.cpu.bus.ram);
+
+    // Running again from the end just reports how the recording ended.
+    assert_eq!(replayer.run_for(10).unwrap(), result);
+    assert_eq!(replayer.instret(), 1805);
+
+    match Recording::load(&saved[..saved.len() - 1]) {
+        Err(SnapshotError::Corrupt(_)) => {}
+        result => panic!("unexpected result: {:?}", result.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_reverse_step() {
+    let (recording, _) = record_noise();
+    let mut replayer = Replayer::new(noise_machine(2), recording).unwrap();
+    assert!(!replayer.reverse_step().unwrap());
+
+    // Stepping back over a checkpoint and a device read
+    assert_eq!(replayer.run_for(1003).unwrap(), (0, StopReason::InstructionLimit));
+    let machine = replayer.machine();
+    let (pc, a0) = (machine.cpu.pc, machine.cpu.get_register(10));
+    assert_eq!(replayer.run_for(1).unwrap(), (0, StopReason::InstructionLimit));
+    assert!(replayer.reverse_step().unwrap());
+    assert_eq!(replayer.instret(), 1003);
+    assert_eq!(replayer.machine().cpu.pc, pc);
+    assert_eq!(replayer.machine().cpu.get_register(10), a0);
+
+    for _ in 0..5 {
+        assert!(replayer.reverse_step().unwrap());
+    }
+    assert_eq!(replayer.instret(), 998);
+    assert_eq!(replayer.resume().unwrap(), record_noise().0.result());
+}
+
+#[test]
+fn test_reverse_continue() {
+    let (recording, _) = record_noise();
+    let mut replayer = Replayer::new(noise_machine(2), recording).unwrap();
+    replayer.machine_mut().cpu.add_breakpoint(NOISE_STORE);
+
+    assert_eq!(replayer.resume().unwrap(), (0, StopReason::Breakpoint));
+    assert_eq!(replayer.instret(), 9);
+
+    replayer.seek(1500).unwrap();
+    assert_eq!(replayer.reverse_continue().unwrap(), Some(0));
+    assert_eq!(replayer.instret(), 1494);
+    assert_eq!(replayer.machine().cpu.pc, NOISE_STORE);
+
+    // Across a checkpoint
+    replayer.seek(1400).unwrap();
+    assert_eq!(replayer.reverse_continue().unwrap(), Some(0));
+    assert_eq!(replayer.instret(), 1395);
+    assert_eq!(replayer.reverse_continue().unwrap(), Some(0));
+    assert_eq!(replayer.instret(), 1386);
+
+    // Forwards again, not stopping where it already is
+    assert_eq!(replayer.resume().unwrap(), (0, StopReason::Breakpoint));
+    assert_eq!(replayer.instret(), 1395);
+
+    replayer.seek(5).unwrap();
+    assert_eq!(replayer.reverse_continue().unwrap(), None);
+    assert_eq!(replayer.instret(), 0);
+}
+
+#[test]
+fn test_divergence() {
+    let (recording, _) = record_noise();
+    let mut replayer = Replayer::new(noise_machine(2), recording).unwrap();
+    replayer.seek(500).unwrap();
+
+    // Reading from somewhere other than where the recording did
+    replayer.machine_mut().cpu.set_register(5, 0x10000004);
+    match replayer.resume() {
+        Err(ReplayError::Diverged { instret }) => assert!(instret >= 500, "instret {}", instret),
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    // Going back puts things right.
+    replayer.seek(0).unwrap();
+    assert_eq!(replayer.resume().unwrap(), record_noise().0.result());
+}
+
+#[test]
+fn test_smp_replay() {
+    let machine = || {
+        let mut machine = Machine::builder()
+            .harts(4)
+            .quantum(5)
+            .schedule(Schedule::Seeded(3))
+            .load_elf_bytes(&program_elf(&COUNT))
+            .build()
+            .expect("couldn't load ELF");
+        machine.start();
+        machine
+    };
+    let cycles = |machine: &Machine| {
+        Some(&machine.cpu).into_iter().chain(&machine.harts).map(|hart| hart.cycles()).collect()
+    };
+
+    let mut recorded = machine();
+    let recording = Recording::record(&mut recorded, 250);
+    assert_eq!(recording.result(), (0, StopReason::Halted(4000)));
+    let expected: Vec<u64> = cycles(&recorded);
+
+    let mut replayer = Replayer::new(machine(), recording).unwrap();
+    replayer.seek(1234).unwrap();
+    let at_1234: Vec<u64> = cycles(replayer.machine());
+    replayer.seek(3000).unwrap();
+    replayer.seek(1235).unwrap();
+    assert!(replayer.reverse_step().unwrap());
+    assert_eq!(cycles(replayer.machine()), at_1234);
+
+    assert_eq!(replayer.resume().unwrap(), (0, StopReason::Halted(4000)));
+    assert_eq!(cycles(replayer.machine()), expected);
+}
+
+#[test]
+fn test_gdb_reverse_execution() {
+    let (recording, _) = record_noise();
+    let mut replayer = Replayer::new(noise_machine(2), recording).unwrap();
+    let replies = gdb_session(&mut replayer,
+                              &["qSupported:swbreak+", "Z0,1024,4", "c", "c", "bc", "p20", "bs",
+                                "bc", "z0,1024,4", "c", "m1000,4", "M1000,4:00000000", "D", "?"]);
+    assert!(replies[0].contains("ReverseContinue+"), "{}", replies[0]);
+    assert_eq!(replies[1..],
+               ["OK",
+                "T05swbreak:;thread:1;",
+                "T05swbreak:;thread:1;",
+                "T05swbreak:;thread:1;",
+                // pc is 0x1024, in target byte order
+                "24100000",
+                "T05thread:1;",
+                "T05replaylog:begin;thread:1;",
+                "OK",
+                "T05replaylog:end;thread:1;",
+                "b7020010",
+                "E01",
+                "OK"]);
+    assert_eq!(replayer.instret(), 1805);
+}
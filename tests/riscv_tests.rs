@@ -0,0 +1,190 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Runs the official riscv-tests ISA tests vendored in `tests/riscv-tests`
+//! (see the README there), each as a test of its own.
+//!
+//! The tests report back through the HTIF's `tohost` word: 1 means every
+//! check passed, and any other odd value is the number of the failing check
+//! shifted left by one. The libtest harness can't list tests found at run
+//! time, so this has its own `main` that prints results the same way.
+
+extern crate risc_v_emulator;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use common::{build_elf_with_symbols, run_tests, words, Elf, Segment, Test, Verdict};
+use risc_v_emulator::{Isa, Machine, StopReason};
+
+const SUITES: [&str; 14] = ["rv32ui-p-", "rv32um-p-", "rv32ua-p-", "rv32uf-p-", "rv32ud-p-",
+                            "rv32mi-p-", "rv32si-p-", "rv64ui-p-", "rv64um-p-", "rv64ua-p-",
+                            "rv64uf-p-", "rv64ud-p-", "rv64mi-p-", "rv64si-p-"];
+// The RV32 integer and machine-mode tests in riscv-tests, which are listed as
+// ignored when they haven't been vendored rather than left out silently.
+const EXPECTED: [&str; 72] = [
+    "rv32ui-p-add", "rv32ui-p-addi", "rv32ui-p-and", "rv32ui-p-andi", "rv32ui-p-auipc",
+    "rv32ui-p-beq", "rv32ui-p-bge", "rv32ui-p-bgeu", "rv32ui-p-blt", "rv32ui-p-bltu",
+    "rv32ui-p-bne", "rv32ui-p-fence_i", "rv32ui-p-jal", "rv32ui-p-jalr", "rv32ui-p-lb",
+    "rv32ui-p-lbu", "rv32ui-p-lh", "rv32ui-p-lhu", "rv32ui-p-lui", "rv32ui-p-lw",
+    "rv32ui-p-or", "rv32ui-p-ori", "rv32ui-p-sb", "rv32ui-p-sh", "rv32ui-p-simple",
+    "rv32ui-p-sll", "rv32ui-p-slli", "rv32ui-p-slt", "rv32ui-p-slti", "rv32ui-p-sltiu",
+    "rv32ui-p-sltu", "rv32ui-p-sra", "rv32ui-p-srai", "rv32ui-p-srl", "rv32ui-p-srli",
+    "rv32ui-p-sub", "rv32ui-p-sw", "rv32ui-p-xor", "rv32ui-p-xori",
+    "rv32um-p-div", "rv32um-p-divu", "rv32um-p-mul", "rv32um-p-mulh", "rv32um-p-mulhsu",
+    "rv32um-p-mulhu", "rv32um-p-rem", "rv32um-p-remu",
+    "rv32ua-p-amoadd_w", "rv32ua-p-amoand_w", "rv32ua-p-amomax_w", "rv32ua-p-amomaxu_w",
+    "rv32ua-p-amomin_w", "rv32ua-p-amominu_w", "rv32ua-p-amoor_w", "rv32ua-p-amoswap_w",
+    "rv32ua-p-amoxor_w", "rv32ua-p-lrsc",
+    "rv32mi-p-breakpoint", "rv32mi-p-csr", "rv32mi-p-illegal", "rv32mi-p-lh-misaligned",
+    "rv32mi-p-lw-misaligned", "rv32mi-p-ma_addr", "rv32mi-p-ma_fetch", "rv32mi-p-mcsr",
+    "rv32mi-p-sbreak", "rv32mi-p-scall", "rv32mi-p-sh-misaligned", "rv32mi-p-shamt",
+    "rv32mi-p-sw-misaligned", "rv32mi-p-zicntr", "rv32mi-p-instret_overflow",
+];
+// The tests are linked to run from here.
+const RAM_BASE: u32 = 0x80000000;
+// Far more than any of the tests need.
+const MAX_INSTRUCTIONS: u64 = 10_000_000;
+
+// Writes 1 to tohost at 0x80001000, as a passing test does.
+const PASS: [u32; 5] = [0x00100193, 0x80001f37, 0x003f2023, 0x000f2223, 0x0000006f];
+// Writes the code for a failure in check 3.
+const FAIL_3: [u32; 7] = [0x00300193, 0x00119193, 0x0011e193, 0x80001f37, 0x003f2023,
+                          0x000f2223, 0x0000006f];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Pass,
+    /// The number of the check that failed.
+    Fail(u32),
+    NoToHost,
+    Stopped(StopReason),
+    Timeout,
+}
+
+fn run_test(elf: &[u8]) -> Outcome {
+    let mut machine = match Machine::builder().ram_base(RAM_BASE).load_elf_bytes(elf).build() {
+        Ok(machine) => machine,
+        Err(e) => panic!("couldn't load ELF: {}", e),
+    };
+    if machine.htif.is_none() {
+        return Outcome::NoToHost;
+    }
+    machine.start();
+
+    // The HTIF halts the machine with the failing check, or 0 for a pass.
+    while machine.instret() < MAX_INSTRUCTIONS {
+        let instret = machine.instret();
+        match machine.run_for(MAX_INSTRUCTIONS - instret) {
+            (_, StopReason::Halted(0)) => return Outcome::Pass,
+            (_, StopReason::Halted(check)) => return Outcome::Fail(check),
+            (_, StopReason::InstructionLimit) => {}
+            (_, reason) => return Outcome::Stopped(reason),
+        }
+    }
+    Outcome::Timeout
+}
+
+// Whether a machine with `misa` implements everything the suite a test is
+// from needs.
+fn supported(name: &str, misa: u64) -> bool {
+    // The XLEN comes from the ELF, so only the extension matters here.
+    let needs = match name.get(4..6) {
+        Some("uf") => 'F',
+        Some("ud") => 'D',
+        Some("si") => 'S',
+        _ => return true,
+    };
+    misa & (1 << (needs as u32 - 'A' as u32)) != 0
+}
+
+fn self_test(program: &[u32]) -> Vec<u8> {
+    let text = words(program);
+    build_elf_with_symbols(RAM_BASE,
+                           &[Segment {
+                                 vaddr: RAM_BASE,
+                                 data: &text,
+                                 memsz: text.len() as u32,
+                             }],
+                           &[("tohost", RAM_BASE + 0x1000)])
+}
+
+fn vendored_tests(dir: &Path) -> Vec<Test<Outcome>> {
+    let mut tests: Vec<Test<Outcome>> = match fs::read_dir(dir) {
+        Ok(entries) => {
+            entries.filter_map(|entry| entry.ok())
+                .map(|entry| {
+                    Test {
+                        name: entry.file_name().to_string_lossy().into_owned(),
+                        elf: Elf::File(entry.path()),
+                        data: Outcome::Pass,
+                    }
+                })
+                .filter(|test| {
+                    SUITES.iter().any(|suite| test.name.starts_with(suite)) &&
+                    !test.name.contains('.')
+                })
+                .collect()
+        }
+        Err(_) => Vec::new(),
+    };
+    for &name in &EXPECTED {
+        if !tests.iter().any(|test| test.name == name) {
+            tests.push(Test {
+                name: name.to_string(),
+                elf: Elf::Missing,
+                data: Outcome::Pass,
+            });
+        }
+    }
+    tests.sort_by(|a, b| a.name.cmp(&b.name));
+    tests
+}
+
+fn main() {
+    let dir = env::var_os("RISCV_TESTS_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/riscv-tests"));
+
+    // The harness checks its own decoding of tohost before anything else.
+    let mut tests = vec![Test {
+                             name: "harness::pass".to_string(),
+                             elf: Elf::Built(self_test(&PASS)),
+                             data: Outcome::Pass,
+                         },
+                         Test {
+                             name: "harness::fail".to_string(),
+                             elf: Elf::Built(self_test(&FAIL_3)),
+                             data: Outcome::Fail(3),
+                         }];
+    let vendored = vendored_tests(&dir);
+    if vendored.iter().all(|test| matches!(test.elf, Elf::Missing)) {
+        println!("note: no riscv-tests binaries in {}", dir.display());
+    }
+    tests.extend(vendored);
+
+    let misa = Isa::default().misa();
+    run_tests(tests, |name, elf, expected| {
+        if !supported(name, misa) {
+            return Verdict::Ignored;
+        }
+        let outcome = run_test(&elf);
+        if outcome == expected {
+            return Verdict::Pass;
+        }
+        Verdict::Fail(match outcome {
+            Outcome::Pass => "passed".to_string(),
+            Outcome::Fail(check) => format!("failed check #{}", check),
+            Outcome::NoToHost => "no tohost symbol".to_string(),
+            Outcome::Stopped(reason) => format!("stopped: {:?}", reason),
+            Outcome::Timeout => format!("still running after {} instructions", MAX_INSTRUCTIONS),
+        })
+    });
+}
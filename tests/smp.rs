@@ -0,0 +1,136 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+extern crate risc_v_emulator;
+
+mod common;
+
+use common::{build_machine, COUNT};
+use risc_v_emulator::{Machine, Schedule, StopReason};
+
+// Hart 0 raises hart 1's MSIP and spins on 0x8000. Hart 1 enables MSIE, waits
+// in WFI until it sees its MSIP set, clears it, and writes 42 to 0x8000.
+const IPI: [u32; 18] = [0xf14022f3, 0x02000337, 0x00008e37, 0x00029c63, 0x00100393, 0x00732223,
+                        0x000e2503, 0xfe050ee3, 0x00008067, 0x00800393, 0x30439073,
+                        0x10500073, 0x00432383, 0xfe038ce3, 0x00032223, 0x02a00393,
+                        0x007e2023, 0x00008067];
+
+// Hart 0 sets its mtimecmp to 5000, enables MTIE, waits in WFI and returns
+// the low word of mtime. Hart 1 returns straight away.
+const TIMER: [u32; 13] = [0xf14022f3, 0x02029663, 0x02004337, 0x000013b7, 0x38838393,
+                          0x00732023, 0x00032223, 0x08000393, 0x30439073, 0x10500073,
+                          0x0200c337, 0xff832503, 0x00008067];
+
+// Hart 0 points mtvec at a handler that counts its entries in a0 and
+// disarms the timer, sets its mtimecmp to 100, enables timer interrupts,
+// spins for 2000 iterations and returns the count. Hart 1 returns straight
+// away.
+const DISARM: [u32; 22] = [0xf14022f3, 0x02029e63, 0x00000513, 0x00000317, 0x03830313,
+                           0x30531073, 0x02004337, 0x06400393, 0x00732023, 0x00032223,
+                           0x08000393, 0x30439073, 0x30046073, 0x7d000393, 0xfff38393,
+                           0xfe039ee3, 0x00008067, 0x00150513, 0xfff00e13, 0x01c32223,
+                           0x01c32023, 0x30200073];
+
+// csrr a0, mhartid; ret
+const MHARTID: [u32; 2] = [0xf1402573, 0x00008067];
+
+#[test]
+fn test_lr_sc_contention() {
+    for &quantum in &[1, 3, 7, 1000] {
+        let mut machine = build_machine(&COUNT, 4, quantum, Schedule::RoundRobin);
+        assert_eq!(machine.run_harts(), (0, StopReason::Halted(4000)));
+    }
+
+    for seed in 0..8 {
+        let mut machine = build_machine(&COUNT, 4, 5, Schedule::Seeded(seed));
+        assert_eq!(machine.run_harts(), (0, StopReason::Halted(4000)));
+    }
+}
+
+#[test]
+fn test_seeded_schedule_is_deterministic() {
+    let cycles = |seed| {
+        let mut machine = build_machine(&COUNT, 4, 5, Schedule::Seeded(seed));
+        assert_eq!(machine.run(), StopReason::Halted(4000));
+        let mut cycles = vec![machine.cpu.cycles()];
+        cycles.extend(machine.harts.iter().map(|hart| hart.cycles()));
+        cycles
+    };
+
+    assert_eq!(cycles(1), cycles(1));
+    assert!(cycles(1) != cycles(2));
+}
+
+#[test]
+fn test_schedule_ignores_run_splits() {
+    let cycles = |machine: &Machine| {
+        let mut cycles = vec![machine.cpu.cycles()];
+        cycles.extend(machine.harts.iter().map(|hart| hart.cycles()));
+        cycles
+    };
+
+    let mut whole = build_machine(&COUNT, 4, 5, Schedule::Seeded(9));
+    assert_eq!(whole.run(), StopReason::Halted(4000));
+
+    let mut split = build_machine(&COUNT, 4, 5, Schedule::Seeded(9));
+    split.start();
+    let result = loop {
+        match split.run_for(3) {
+            (_, StopReason::InstructionLimit) => continue,
+            (_, reason) => break reason,
+        }
+    };
+    assert_eq!(result, StopReason::Halted(4000));
+    assert_eq!(cycles(&split), cycles(&whole));
+}
+
+#[test]
+fn test_harts() {
+    let machine = build_machine(&COUNT, 3, 100, Schedule::RoundRobin);
+    assert_eq!(machine.harts.len(), 2);
+    assert_eq!(machine.clint.as_ref().map(|clint| clint.harts()), Some(3));
+    assert_eq!(machine.cpu.get_register(2), 0xFF000);
+    assert_eq!(machine.harts[1].get_register(2), 0xFD000);
+    assert_eq!(machine.harts[1].get_register(10), 2);
+
+    // A single hart runs on its own, without a CLINT.
+    let mut machine = build_machine(&MHARTID, 1, 100, Schedule::RoundRobin);
+    assert!(machine.clint.is_none());
+    assert_eq!(machine.run(), StopReason::Halted(0));
+}
+
+#[test]
+fn test_software_interrupt() {
+    let mut machine = build_machine(&IPI, 2, 10, Schedule::RoundRobin);
+    assert_eq!(machine.run(), StopReason::Halted(42));
+    assert_eq!(machine.clint.as_ref().map(|clint| clint.msip(1)), Some(false));
+}
+
+#[test]
+fn test_timer_wakes_wfi() {
+    let mut machine = build_machine(&TIMER, 2, 10, Schedule::RoundRobin);
+    match machine.run() {
+        StopReason::Halted(mtime) => {
+            assert!((5000..5010).contains(&mtime), "mtime {}", mtime)
+        }
+        reason => panic!("unexpected stop: {:?}", reason),
+    }
+
+    // Nothing will ever wake a hart that waits with no interrupts enabled.
+    let mut machine = build_machine(&[0x10500073, 0x00008067], 2, 10, Schedule::RoundRobin);
+    assert_eq!(machine.run_harts(), (0, StopReason::Wfi));
+}
+
+#[test]
+fn test_handler_disarms_timer() {
+    // The handler's store to mtimecmp clears MTIP straight away, not at the
+    // end of the time slice.
+    for &quantum in &[1, 10, 1000] {
+        let mut machine = build_machine(&DISARM, 2, quantum, Schedule::RoundRobin);
+        assert_eq!(machine.run(), StopReason::Halted(1), "quantum {}", quantum);
+    }
+}
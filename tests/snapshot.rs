@@ -0,0 +1,158 @@
+// Copyright 2016 risc-v-emulator Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+extern crate risc_v_emulator;
+
+mod common;
+
+use std::env;
+use std::fs::{self, File};
+use std::process;
+
+use common::{build_machine, COUNT};
+use risc_v_emulator::{CPU, Machine, RAM, Schedule, SnapshotError, StopReason};
+
+// Sums 1000 down to 1, storing each partial sum from 0x8000 up, and returns
+// the total.
+const SUM: [u32; 9] = [0x00000513, 0x3e800593, 0x000082b7, 0x00b50533, 0x00a2a023, 0x00428293,
+                       0xfff58593, 0xfe0598e3, 0x00008067];
+
+fn cycles(machine: &Machine) -> Vec<u64> {
+    Some(&machine.cpu).into_iter().chain(&machine.harts).map(|hart| hart.cycles()).collect()
+}
+
+#[test]
+fn test_resume_from_snapshot() {
+    let mut machine = build_machine(&SUM, 1, 5, Schedule::Seeded(7));
+    machine.start();
+    assert_eq!(machine.run_for(2000), (0, StopReason::InstructionLimit));
+
+    let mut snapshot = Vec::new();
+    machine.save_snapshot(&mut snapshot).expect("couldn't save snapshot");
+    // Only the pages with code and partial sums are stored.
+    assert!(snapshot.len() < 4096, "snapshot is {} bytes", snapshot.len());
+
+    assert_eq!(machine.resume(), (0, StopReason::Halted(500500)));
+    let expected = machine.cpu.bus.ram.clone();
+
+    // Restored into a machine that has never run
+    let mut restored = build_machine(&[], 1, 5, Schedule::Seeded(7));
+    restored.load_snapshot(&snapshot[..]).expect("couldn't load snapshot");
+    assert_eq!(restored.instret(), 2000);
+    assert_eq!(restored.resume(), (0, StopReason::Halted(500500)));
+    assert_eq!(cycles(&restored), cycles(&machine));
+    assert!(restored.cpu.bus.ram == expected);
+
+    // And into one that has run to completion, twice
+    for _ in 0..2 {
+        machine.load_snapshot(&snapshot[..]).expect("couldn't load snapshot");
+        assert_eq!(machine.resume(), (0, StopReason::Halted(500500)));
+        assert!(machine.cpu.bus.ram == expected);
+    }
+}
+
+#[test]
+fn test_smp_snapshot_is_deterministic() {
+    let mut machine = build_machine(&COUNT, 4, 5, Schedule::Seeded(7));
+    machine.start();
+    assert_eq!(machine.run_for(3000), (0, StopReason::InstructionLimit));
+
+    let mut snapshot = Vec::new();
+    machine.save_snapshot(&mut snapshot).expect("couldn't save snapshot");
+    assert_eq!(machine.resume(), (0, StopReason::Halted(4000)));
+
+    // The scheduler picks up exactly where it left off.
+    let mut restored = build_machine(&[], 4, 5, Schedule::Seeded(7));
+    restored.load_snapshot(&snapshot[..]).expect("couldn't load snapshot");
+    assert_eq!(restored.resume(), (0, StopReason::Halted(4000)));
+    assert_eq!(cycles(&restored), cycles(&machine));
+    assert_eq!(restored.clint.as_ref().map(|clint| clint.mtime()),
+               machine.clint.as_ref().map(|clint| clint.mtime()));
+}
+
+#[test]
+fn test_restore_from_file() {
+    let mut machine = Machine::builder()
+        .ram_base(0x80000000)
+        .ram_size(64 * 1024)
+        .harts(2)
+        .build()
+        .expect("couldn't build machine");
+    machine.cpu.set_register(10, 42);
+    machine.harts[0].pc = 0x80001234;
+    machine.cpu.bus.ram.set_u32(0xFFFC, 0xDEADBEEF);
+
+    let path = env::temp_dir().join(format!("risc-v-emulator-{}.snapshot", process::id()));
+    machine.save_snapshot(File::create(&path).unwrap()).expect("couldn't save snapshot");
+    let restored = Machine::builder().restore_snapshot(&path).build();
+    fs::remove_file(&path).unwrap();
+
+    let restored = restored.expect("couldn't restore snapshot");
+    assert_eq!(restored.cpu.bus.ram_base(), 0x80000000);
+    assert_eq!(restored.cpu.bus.ram.len(), 64 * 1024);
+    assert_eq!(restored.harts.len(), 1);
+    assert_eq!(restored.cpu.get_register(10), 42);
+    assert_eq!(restored.harts[0].pc, 0x80001234);
+    assert_eq!(restored.harts[0].hart_id(), 1);
+    assert_eq!(restored.cpu.bus.ram.get_u32(0xFFFC), 0xDEADBEEF);
+}
+
+#[test]
+fn test_cpu_snapshot() {
+    let mut cpu = CPU::new(RAM::new(64 * 1024));
+    cpu.bus.ram.set_u32(0x100, 0x00100073); // ebreak
+    cpu.write_csr(0x340, 0x1234).unwrap(); // mscratch
+    cpu.write_csr(0x305, 0x200).unwrap(); // mtvec
+    cpu.pc = 0x100;
+    assert_eq!(cpu.step(), StopReason::InstructionLimit);
+
+    let mut snapshot = Vec::new();
+    cpu.save_snapshot(&mut snapshot).expect("couldn't save snapshot");
+
+    let mut restored = CPU::new(RAM::new(64 * 1024));
+    restored.load_snapshot(&snapshot[..]).expect("couldn't load snapshot");
+    assert_eq!(restored.pc, 0x200);
+    assert_eq!(restored.cycles(), cpu.cycles());
+    for &csr in &[0x300, 0x305, 0x340, 0x341, 0x342, 0x343] {
+        assert_eq!(restored.get_csr(csr), cpu.get_csr(csr));
+    }
+    assert_eq!(restored.bus.ram.get_u32(0x100), 0x00100073);
+}
+
+#[test]
+fn test_bad_snapshots() {
+    let mut snapshot = Vec::new();
+    build_machine(&SUM, 2, 5, Schedule::Seeded(7)).save_snapshot(&mut snapshot).unwrap();
+
+    let mut machine = build_machine(&SUM, 1, 5, Schedule::Seeded(7));
+    match machine.load_snapshot(&snapshot[..]) {
+        Err(SnapshotError::Mismatch(_)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    let mut cpu = CPU::new(RAM::new(1024));
+    match cpu.load_snapshot(&snapshot[..]) {
+        Err(SnapshotError::Mismatch(_)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    let mut machine = build_machine(&SUM, 2, 5, Schedule::Seeded(7));
+    match machine.load_snapshot(&snapshot[..snapshot.len() - 1]) {
+        Err(SnapshotError::Corrupt(_)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match machine.load_snapshot(&b"not a snapshot"[..]) {
+        Err(SnapshotError::BadMagic) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    snapshot[8] = 99;
+    match machine.load_snapshot(&snapshot[..]) {
+        Err(SnapshotError::Version(99)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}